@@ -44,6 +44,7 @@ pub enum SearchVal {
     Bool(bool),
     String(String),
     Int(i32),
+    BigInt(i64),
 }
 impl ToSql for SearchVal {
     fn to_sql(&self) -> rusqlite::Result<ToSqlOutput> {
@@ -57,6 +58,9 @@ impl ToSql for SearchVal {
             SearchVal::Int(ref x) => {
                 ToSqlOutput::from(x.clone())
             }
+            SearchVal::BigInt(ref x) => {
+                ToSqlOutput::from(x.clone())
+            }
         };
         Ok(res)
     }
@@ -125,6 +125,20 @@ TURTL_EXPORT char* TURTL_CONV turtlc_lasterr();
 // Free an error string returned from `turtlc_lasterr()`.
 TURTL_EXPORT int32_t TURTL_CONV turtlc_free_err(char*);
 
+// -----------------------------------------------------------------------------
+// turtlc_set_device_key(key_bytes, key_len) -> i32
+//   key_bytes:
+//     a pointer to a 32-byte key (or null to clear a previously set key)
+//   key_len:
+//     the length in bytes of `key_bytes`
+//   -> returns 0 on success
+// -----------------------------------------------------------------------------
+// Hand the core a device key from the host platform's keystore. This key is
+// used to encrypt login tokens and saved logins, meaning they can't be used
+// off this device (or without the keystore). If never called, the core
+// generates a key and keeps it in its own storage.
+TURTL_EXPORT int32_t TURTL_CONV turtlc_set_device_key(const uint8_t*, size_t);
+
 #ifdef __cplusplus
 }		// extern "C" { ... }
 #endif
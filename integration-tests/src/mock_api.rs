@@ -0,0 +1,1232 @@
+// An in-process stand-in for the Turtl API, so sync, invite, and file flows
+// can be tested with a plain `cargo test` (no server, no network, no test
+// accounts).
+//
+// It speaks just enough HTTP/1.1 to keep core's API client happy, and keeps
+// everything (users, the sync log, spaces/boards/notes, files) in memory. It
+// is NOT a reimplementation of the server: it doesn't validate much of
+// anything, and it only knows about the endpoints core actually calls.
+//
+// Include it next to util.rs, inside its own module:
+//
+//     include!("../src/util.rs");
+//     #[allow(dead_code)]
+//     mod mock_api { include!("../src/mock_api.rs"); }
+//
+// and point core at it with `init_with_api(Some(mock.endpoint()))`.
+
+use ::std::collections::HashMap;
+use ::std::io::{self, BufRead, BufReader, Read, Write};
+use ::std::net::{TcpListener, TcpStream};
+use ::std::sync::{Arc, Mutex, MutexGuard, Condvar};
+use ::std::sync::atomic::{AtomicBool, Ordering};
+use ::std::thread;
+use ::std::time::{Duration, Instant};
+use ::jedi::{self, Value};
+
+/// How long a long-poll on `/sync` waits for changes before giving up. This
+/// needs to stay under the `sync.poll_timeout` the tests give core.
+const POLL_WAIT: u64 = 2000;
+
+/// A parsed HTTP request
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    /// Header names are lowercased
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&String> {
+        self.headers.get(name)
+    }
+
+    fn json(&self) -> Result<Value, Response> {
+        let body = String::from_utf8(self.body.clone())
+            .map_err(|_| Response::error(400, "body isn't utf8"))?;
+        jedi::parse(&body).map_err(|_| Response::error(400, "body isn't json"))
+    }
+}
+
+/// An HTTP response
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, val: &Value) -> Response {
+        Response {
+            status: status,
+            headers: vec![(String::from("Content-Type"), String::from("application/json"))],
+            body: jedi::stringify(val).expect("MockApi -- failed to stringify response").into_bytes(),
+        }
+    }
+
+    fn ok(val: Value) -> Response {
+        Response::json(200, &val)
+    }
+
+    fn error(status: u16, msg: &str) -> Response {
+        Response::json(status, &json!({"error": {"message": msg}}))
+    }
+
+    fn bytes(status: u16, body: Vec<u8>) -> Response {
+        Response {
+            status: status,
+            headers: vec![(String::from("Content-Type"), String::from("application/octet-stream"))],
+            body: body,
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            206 => "Partial Content",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            409 => "Conflict",
+            416 => "Range Not Satisfiable",
+            500 => "Internal Server Error",
+            _ => "Whatever",
+        }
+    }
+
+    fn write_to(&self, stream: &mut TcpStream) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, self.reason());
+        for &(ref name, ref val) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, val));
+        }
+        head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", self.body.len()));
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(&self.body)?;
+        stream.flush()
+    }
+}
+
+/// A user account
+#[derive(Debug, Clone)]
+pub struct MockUser {
+    pub id: String,
+    pub username: String,
+    pub auth: String,
+    pub data: Value,
+    /// The (wrapped key, auth) pair from `PUT /users/:id/recovery`
+    pub recovery: Option<(String, String)>,
+}
+
+/// One entry in our sync log. An entry goes to `user_id` (if set) and every
+/// member of `space_id` (if set).
+#[derive(Debug, Clone)]
+struct LogEntry {
+    sync_id: i64,
+    user_id: Option<String>,
+    space_id: Option<String>,
+    record: Value,
+}
+
+/// A chunked upload in progress
+#[derive(Debug, Clone)]
+struct Upload {
+    note_id: String,
+    file_id: String,
+    size: u64,
+    data: Vec<u8>,
+}
+
+/// Everything the mock API knows. Tests can poke at this (via
+/// `MockApi::state()`) to check what made it to the "server" or to set up
+/// data behind core's back.
+#[derive(Default)]
+pub struct MockState {
+    next_id: i64,
+    sync_id: i64,
+    pub users: Vec<MockUser>,
+    /// Our current objects, by type ("keychain", "space", "board", "note",
+    /// "invite") and then by id
+    pub objects: HashMap<String, HashMap<String, Value>>,
+    log: Vec<LogEntry>,
+    /// Note attachments, by attachment id
+    pub files: HashMap<String, Vec<u8>>,
+    /// Which note each attachment belongs to (attachment id -> note id)
+    pub file_notes: HashMap<String, String>,
+    uploads: HashMap<String, Upload>,
+    /// Every request we've seen, as "METHOD /path"
+    pub requests: Vec<String>,
+    /// Canned failures: the next request matching (method, path prefix) gets
+    /// the given status instead of a real answer
+    failures: Vec<(String, String, u16)>,
+    /// Set to act like an API that can't push changes (no `/sync/stream`)
+    pub push_disabled: bool,
+    /// How many times we've been long-polled
+    pub polls: usize,
+}
+
+impl MockState {
+    fn gen_id(&mut self) -> String {
+        self.next_id += 1;
+        self.next_id.to_string()
+    }
+
+    fn user_by_auth(&self, req: &Request) -> Option<MockUser> {
+        let (username, auth) = parse_basic_auth(req.header("authorization")?)?;
+        self.users.iter()
+            .find(|x| x.username == username && x.auth == auth)
+            .cloned()
+    }
+
+    fn user_by_id(&self, user_id: &str) -> Option<&MockUser> {
+        self.users.iter().find(|x| x.id == user_id)
+    }
+
+    fn user_by_name(&self, username: &str) -> Option<&MockUser> {
+        let username = username.to_lowercase();
+        self.users.iter().find(|x| x.username.to_lowercase() == username)
+    }
+
+    fn get_obj(&self, ty: &str, id: &str) -> Option<Value> {
+        self.objects.get(ty).and_then(|x| x.get(id)).cloned()
+    }
+
+    fn set_obj(&mut self, ty: &str, id: &str, data: Value) {
+        self.objects.entry(String::from(ty)).or_insert_with(HashMap::new).insert(String::from(id), data);
+    }
+
+    fn remove_obj(&mut self, ty: &str, id: &str) -> Option<Value> {
+        self.objects.get_mut(ty).and_then(|x| x.remove(id))
+    }
+
+    /// Drop all of a note's attachments
+    fn remove_note_files(&mut self, note_id: &str) {
+        let file_ids = self.file_notes.iter()
+            .filter(|&(_, x)| x == note_id)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        for file_id in file_ids {
+            self.files.remove(&file_id);
+            self.file_notes.remove(&file_id);
+        }
+    }
+
+    /// Grab the user ids that can see a space
+    fn members(&self, space_id: &str) -> Vec<String> {
+        let space = match self.get_obj("space", space_id) {
+            Some(x) => x,
+            None => return vec![],
+        };
+        let mut members = match jedi::get_opt::<Vec<Value>>(&["members"], &space) {
+            Some(x) => x.iter().filter_map(|m| id_str(m.get("user_id"))).collect::<Vec<_>>(),
+            None => vec![],
+        };
+        if let Some(owner) = id_str(space.get("user_id")) {
+            if !members.contains(&owner) { members.push(owner); }
+        }
+        members
+    }
+
+    fn is_member(&self, space_id: &str, user_id: &str) -> bool {
+        self.members(space_id).iter().any(|x| x == user_id)
+    }
+
+    fn find_member(&self, space_id: &str, user_id: &str) -> Option<Value> {
+        let space = self.get_obj("space", space_id)?;
+        jedi::get_opt::<Vec<Value>>(&["members"], &space)?
+            .into_iter()
+            .find(|m| id_str(m.get("user_id")).map(|x| x == user_id).unwrap_or(false))
+    }
+
+    /// Grab the space a board/note lives in
+    fn space_of(&self, ty: &str, id: &str) -> Option<String> {
+        self.get_obj(ty, id).and_then(|x| id_str(x.get("space_id")))
+    }
+
+    /// Append a change to our sync log and wake up any long-polls. Returns the
+    /// new sync id.
+    fn log(&mut self, user_id: Option<String>, space_id: Option<String>, actor: &str, action: &str, ty: &str, item_id: &str, data: Option<Value>) -> i64 {
+        self.sync_id += 1;
+        let sync_id = self.sync_id;
+        let mut record = json!({
+            "id": sync_id,
+            "action": action,
+            "type": ty,
+            "item_id": item_id,
+            "user_id": actor,
+        });
+        if let Some(data) = data {
+            record["data"] = data;
+        }
+        self.log.push(LogEntry {
+            sync_id: sync_id,
+            user_id: user_id,
+            space_id: space_id,
+            record: record,
+        });
+        sync_id
+    }
+
+    /// Log a change to a space (along with its current data) for everyone in
+    /// it to see
+    fn log_space(&mut self, actor: &str, space_id: &str) -> i64 {
+        let space = self.get_obj("space", space_id);
+        self.log(None, Some(String::from(space_id)), actor, "edit", "space", space_id, space)
+    }
+
+    fn visible(&self, user_id: &str, entry: &LogEntry) -> bool {
+        if entry.user_id.as_ref().map(|x| x == user_id).unwrap_or(false) { return true; }
+        match entry.space_id {
+            Some(ref space_id) => self.is_member(space_id, user_id),
+            None => false,
+        }
+    }
+
+    /// Grab the changes a user hasn't seen yet
+    fn changes_since(&self, user_id: &str, sync_id: i64) -> Vec<Value> {
+        self.log.iter()
+            .filter(|x| x.sync_id > sync_id && self.visible(user_id, x))
+            .map(|x| x.record.clone())
+            .collect()
+    }
+
+    /// Build a user's full profile, as a set of add records
+    fn full_profile(&self, user: &MockUser) -> Vec<Value> {
+        let add = |ty: &str, id: &str, data: &Value| {
+            json!({"id": 0, "action": "add", "type": ty, "item_id": id, "user_id": user.id, "data": data})
+        };
+        let mut records = vec![add("user", &user.id, &user.data)];
+        let empty = HashMap::new();
+        let objects = |ty: &str| self.objects.get(ty).unwrap_or(&empty).iter().collect::<Vec<_>>();
+        for (id, data) in objects("keychain") {
+            if id_str(data.get("user_id")).map(|x| x == user.id).unwrap_or(false) {
+                records.push(add("keychain", id, data));
+            }
+        }
+        let spaces = objects("space").into_iter()
+            .filter(|&(id, _)| self.is_member(id, &user.id))
+            .collect::<Vec<_>>();
+        for &(id, data) in &spaces {
+            records.push(add("space", id, data));
+        }
+        let in_my_spaces = |data: &Value| {
+            id_str(data.get("space_id"))
+                .map(|s| spaces.iter().any(|&(id, _)| id == &s))
+                .unwrap_or(false)
+        };
+        for (id, data) in objects("board") {
+            if in_my_spaces(data) { records.push(add("board", id, data)); }
+        }
+        for (id, data) in objects("note") {
+            if !in_my_spaces(data) { continue; }
+            records.push(add("note", id, data));
+            for (file_id, note_id) in &self.file_notes {
+                if note_id != id { continue; }
+                records.push(add("file", file_id, &json!({"id": file_id, "note_id": note_id})));
+            }
+        }
+        for (id, data) in objects("template") {
+            if in_my_spaces(data) { records.push(add("template", id, data)); }
+        }
+        for (id, data) in objects("invite") {
+            let to_me = jedi::get_opt::<String>(&["to_user"], data)
+                .map(|x| x.to_lowercase() == user.username.to_lowercase())
+                .unwrap_or(false);
+            if to_me { records.push(add("invite", id, data)); }
+        }
+        records
+    }
+
+    /// Apply one record from an outgoing sync. Returns the record (with its
+    /// sync ids) on success, or the record with an error attached on failure.
+    fn apply_sync(&mut self, user: &MockUser, mut rec: Value) -> Result<Value, Value> {
+        fn fail(mut rec: Value, code: u16, msg: &str) -> Result<Value, Value> {
+            rec["error"] = json!({"code": code, "msg": msg});
+            Err(rec)
+        }
+        let action = jedi::get_opt::<String>(&["action"], &rec).unwrap_or_default();
+        let ty = jedi::get_opt::<String>(&["type"], &rec).unwrap_or_default();
+        let item_id = match id_str(rec.get("item_id")) {
+            Some(x) => x,
+            None => return fail(rec, 400, "missing item_id"),
+        };
+        let data = rec.get("data").cloned();
+        let sync_id = match ty.as_str() {
+            "user" => {
+                if item_id != user.id { return fail(rec, 403, "that's not you"); }
+                if let Some(data) = data {
+                    if let Some(u) = self.users.iter_mut().find(|x| x.id == user.id) {
+                        u.data = data.clone();
+                    }
+                    self.log(Some(user.id.clone()), None, &user.id, &action, "user", &item_id, Some(data))
+                } else {
+                    return fail(rec, 400, "missing data");
+                }
+            }
+            "keychain" => {
+                if action == "delete" {
+                    self.remove_obj("keychain", &item_id);
+                    self.log(Some(user.id.clone()), None, &user.id, "delete", "keychain", &item_id, None)
+                } else {
+                    let mut data = data.unwrap_or(json!({}));
+                    data["user_id"] = json!(user.id);
+                    self.set_obj("keychain", &item_id, data.clone());
+                    self.log(Some(user.id.clone()), None, &user.id, &action, "keychain", &item_id, Some(data))
+                }
+            }
+            "space" => {
+                let existing = self.get_obj("space", &item_id);
+                match existing {
+                    Some(ref space) => {
+                        let owner = id_str(space.get("user_id")).unwrap_or_default();
+                        if action == "delete" && owner != user.id {
+                            return fail(rec, 403, "only the owner can delete a space");
+                        }
+                        if !self.is_member(&item_id, &user.id) {
+                            return fail(rec, 403, "you aren't in that space");
+                        }
+                    }
+                    None => {
+                        if action != "add" { return fail(rec, 404, "no such space"); }
+                    }
+                }
+                if let Some(res) = check_version(&rec, existing.as_ref()) { return res; }
+                if action == "delete" {
+                    // everyone in the space hears about the delete, so log
+                    // it before the space (and its members) go away
+                    let sync_id = self.log(None, Some(item_id.clone()), &user.id, "delete", "space", &item_id, None);
+                    self.remove_obj("space", &item_id);
+                    sync_id
+                } else {
+                    let mut data = data.unwrap_or(json!({}));
+                    match existing {
+                        // members/invites are managed via their own endpoints
+                        Some(ref space) => {
+                            data["user_id"] = space["user_id"].clone();
+                            data["members"] = space.get("members").cloned().unwrap_or(json!([]));
+                            data["invites"] = space.get("invites").cloned().unwrap_or(json!([]));
+                        }
+                        None => {
+                            data["user_id"] = json!(user.id);
+                            data["members"] = json!([self.member_record(&item_id, user, "owner")]);
+                            data["invites"] = json!([]);
+                        }
+                    }
+                    self.set_obj("space", &item_id, data.clone());
+                    self.log(None, Some(item_id.clone()), &user.id, &action, "space", &item_id, Some(data))
+                }
+            }
+            "board" | "note" | "revision" | "template" => {
+                let existing = self.get_obj(&ty, &item_id);
+                let old_space = existing.as_ref().and_then(|x| id_str(x.get("space_id")));
+                let new_space = data.as_ref().and_then(|x| id_str(x.get("space_id")));
+                for space_id in old_space.iter().chain(new_space.iter()) {
+                    if !self.is_member(space_id, &user.id) {
+                        return fail(rec, 403, "you aren't in that space");
+                    }
+                }
+                if existing.is_none() && action != "add" {
+                    return fail(rec, 404, "no such item");
+                }
+                if let Some(res) = check_version(&rec, existing.as_ref()) { return res; }
+                if action == "delete" {
+                    self.remove_obj(&ty, &item_id);
+                    if ty == "note" { self.remove_note_files(&item_id); }
+                    self.log(None, old_space, &user.id, "delete", &ty, &item_id, None)
+                } else {
+                    let data = data.unwrap_or(json!({}));
+                    self.set_obj(&ty, &item_id, data.clone());
+                    // if this moved spaces, make sure the old space's members
+                    // hear about it
+                    if action == "move-space" && old_space.is_some() && old_space != new_space {
+                        self.log(None, old_space, &user.id, "move-space", &ty, &item_id, Some(data.clone()));
+                    }
+                    self.log(None, new_space, &user.id, &action, &ty, &item_id, Some(data))
+                }
+            }
+            "file" => {
+                let note_id = data.as_ref()
+                    .and_then(|x| id_str(x.get("note_id")))
+                    .or_else(|| self.file_notes.get(&item_id).cloned())
+                    .unwrap_or(item_id.clone());
+                let space_id = self.space_of("note", &note_id);
+                match space_id {
+                    Some(ref x) if self.is_member(x, &user.id) => {}
+                    _ => return fail(rec, 403, "you can't touch that file"),
+                }
+                if action != "delete" { return fail(rec, 400, "files are uploaded via /notes/:id/attachments/:id"); }
+                self.files.remove(&item_id);
+                self.file_notes.remove(&item_id);
+                let logdata = json!({"id": item_id, "note_id": note_id});
+                self.log(None, space_id, &user.id, "delete", "file", &item_id, Some(logdata))
+            }
+            "invite" => {
+                // invitees can delete invites sent to them
+                let invite = match self.get_obj("invite", &item_id) {
+                    Some(x) => x,
+                    None => return fail(rec, 404, "no such invite"),
+                };
+                if action != "delete" { return fail(rec, 400, "invites are managed via /spaces/:id/invites"); }
+                let space_id = id_str(invite.get("space_id")).unwrap_or_default();
+                self.remove_invite(&user.id, &space_id, &item_id)
+            }
+            _ => return fail(rec, 400, "unknown sync type"),
+        };
+        rec["sync_ids"] = json!([sync_id]);
+        Ok(rec)
+    }
+
+    fn member_record(&mut self, space_id: &str, user: &MockUser, role: &str) -> Value {
+        json!({
+            "id": self.gen_id(),
+            "space_id": space_id,
+            "user_id": user.id,
+            "username": user.username,
+            "role": role,
+            "created": "2017-01-01T00:00:00Z",
+            "updated": "2017-01-01T00:00:00Z",
+        })
+    }
+
+    /// Remove an invite from everywhere it lives, letting both the space and
+    /// the invitee know. Returns the last sync id.
+    fn remove_invite(&mut self, actor: &str, space_id: &str, invite_id: &str) -> i64 {
+        let invite = self.remove_obj("invite", invite_id);
+        if let Some(space) = self.objects.get_mut("space").and_then(|x| x.get_mut(space_id)) {
+            if let Some(invites) = space.get_mut("invites").and_then(|x| x.as_array_mut()) {
+                invites.retain(|x| id_str(x.get("id")).map(|x| x != invite_id).unwrap_or(true));
+            }
+        }
+        let to_user = invite.as_ref()
+            .and_then(|x| jedi::get_opt::<String>(&["to_user"], x))
+            .and_then(|x| self.user_by_name(&x).map(|u| u.id.clone()));
+        self.log_space(actor, space_id);
+        self.log(to_user, None, actor, "delete", "invite", invite_id, None)
+    }
+}
+
+/// If an edit was made against an old version of an item, fail it with a
+/// conflict
+fn check_version(rec: &Value, existing: Option<&Value>) -> Option<Result<Value, Value>> {
+    let base = jedi::get_opt::<i64>(&["base_version"], rec)?;
+    let current = existing.and_then(|x| jedi::get_opt::<i64>(&["version"], x)).unwrap_or(0);
+    if current <= base { return None; }
+    let mut rec = rec.clone();
+    rec["error"] = json!({"code": 409, "msg": "item was edited since your base version"});
+    Some(Err(rec))
+}
+
+/// Ids come through as strings or numbers, depending on who you ask
+fn id_str(val: Option<&Value>) -> Option<String> {
+    match val {
+        Some(&Value::String(ref x)) => Some(x.clone()),
+        Some(&Value::Number(ref x)) => Some(x.to_string()),
+        _ => None,
+    }
+}
+
+fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    fn val(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a' + 26) as u32),
+            b'0'..=b'9' => Some((c - b'0' + 52) as u32),
+            b'+' | b'-' => Some(62),
+            b'/' | b'_' => Some(63),
+            _ => None,
+        }
+    }
+    let mut out = Vec::new();
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for &c in input.trim().trim_end_matches('=').as_bytes() {
+        acc = (acc << 6) | val(c)?;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+/// Pull the (username, auth) pair out of a Basic auth header
+fn parse_basic_auth(header: &str) -> Option<(String, String)> {
+    if !header.starts_with("Basic ") { return None; }
+    let decoded = String::from_utf8(base64_decode(&header[6..])?).ok()?;
+    let idx = decoded.find(':')?;
+    Some((String::from(&decoded[..idx]), String::from(&decoded[idx + 1..])))
+}
+
+fn url_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = String::from_utf8_lossy(&bytes[i + 1..i + 3]).into_owned();
+            if let Ok(x) = u8::from_str_radix(&hex, 16) {
+                out.push(x);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(if bytes[i] == b'+' { b' ' } else { bytes[i] });
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Read one request off the wire
+fn read_request(stream: &TcpStream) -> io::Result<Option<Request>> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 { return Ok(None); }
+    let mut parts = line.trim().split(' ');
+    let method = String::from(parts.next().unwrap_or(""));
+    let target = String::from(parts.next().unwrap_or("/"));
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 { break; }
+        let line = line.trim_end();
+        if line.is_empty() { break; }
+        if let Some(idx) = line.find(':') {
+            headers.insert(line[..idx].trim().to_lowercase(), String::from(line[idx + 1..].trim()));
+        }
+    }
+    let mut body = Vec::new();
+    let chunked = headers.get("transfer-encoding").map(|x| x.to_lowercase().contains("chunked")).unwrap_or(false);
+    if chunked {
+        loop {
+            let mut size_line = String::new();
+            reader.read_line(&mut size_line)?;
+            let size_str = size_line.trim().split(';').next().unwrap_or("0");
+            let size = usize::from_str_radix(size_str, 16).unwrap_or(0);
+            if size == 0 {
+                // eat the trailer
+                let mut end = String::new();
+                reader.read_line(&mut end)?;
+                break;
+            }
+            let mut chunk = vec![0; size];
+            reader.read_exact(&mut chunk)?;
+            body.append(&mut chunk);
+            let mut crlf = [0; 2];
+            reader.read_exact(&mut crlf)?;
+        }
+    } else if let Some(len) = headers.get("content-length").and_then(|x| x.parse::<usize>().ok()) {
+        body = vec![0; len];
+        reader.read_exact(&mut body)?;
+    }
+    let (path, query_str) = match target.find('?') {
+        Some(idx) => (String::from(&target[..idx]), String::from(&target[idx + 1..])),
+        None => (target.clone(), String::new()),
+    };
+    let mut query = HashMap::new();
+    for pair in query_str.split('&').filter(|x| !x.is_empty()) {
+        let mut kv = pair.splitn(2, '=');
+        let key = url_decode(kv.next().unwrap_or(""));
+        let val = url_decode(kv.next().unwrap_or(""));
+        query.insert(key, val);
+    }
+    Ok(Some(Request {
+        method: method,
+        path: url_decode(&path),
+        query: query,
+        headers: headers,
+        body: body,
+    }))
+}
+
+/// Our in-process API server. Shuts down when dropped.
+pub struct MockApi {
+    port: u16,
+    state: Arc<(Mutex<MockState>, Condvar)>,
+    running: Arc<AtomicBool>,
+}
+
+impl MockApi {
+    /// Start a mock API on a random local port
+    pub fn start() -> MockApi {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("MockApi::start() -- failed to bind");
+        let port = listener.local_addr().expect("MockApi::start() -- failed to get local addr").port();
+        let state = Arc::new((Mutex::new(MockState::default()), Condvar::new()));
+        let running = Arc::new(AtomicBool::new(true));
+        let state2 = state.clone();
+        let running2 = running.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if !running2.load(Ordering::SeqCst) { break; }
+                let mut stream = match stream {
+                    Ok(x) => x,
+                    Err(_) => continue,
+                };
+                let state = state2.clone();
+                let running = running2.clone();
+                thread::spawn(move || {
+                    let req = match read_request(&stream) {
+                        Ok(Some(x)) => x,
+                        _ => return,
+                    };
+                    let push = req.method == "GET" && req.path == "/sync/stream"
+                        && !state.0.lock().expect("MockApi -- failed to lock state").push_disabled;
+                    if push {
+                        let _ = stream_sync(&state, &running, &mut stream, &req);
+                        return;
+                    }
+                    let res = handle(&state, port, req);
+                    let _ = res.write_to(&mut stream);
+                });
+            }
+        });
+        MockApi {
+            port: port,
+            state: state,
+            running: running,
+        }
+    }
+
+    /// The URL to point core's `api.endpoint` at
+    pub fn endpoint(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    /// Grab the mock's state (users, objects, files, requests seen, etc)
+    pub fn state(&self) -> MutexGuard<MockState> {
+        self.state.0.lock().expect("MockApi::state() -- failed to lock")
+    }
+
+    /// Have the next request matching the method and path prefix fail with the
+    /// given status (say, to test retries)
+    pub fn fail_next(&self, method: &str, path_prefix: &str, status: u16) {
+        self.state().failures.push((String::from(method), String::from(path_prefix), status));
+    }
+
+    /// Wait (up to ten seconds) for the mock's state to satisfy a check, say,
+    /// for some data to sync up. Returns false if it never does.
+    pub fn wait_for<F>(&self, check: F) -> bool
+        where F: Fn(&MockState) -> bool
+    {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if check(&self.state()) { return true; }
+            thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    /// Grab an attachment (by its id), if it made it up here
+    pub fn file(&self, file_id: &str) -> Option<Vec<u8>> {
+        self.state().files.get(file_id).cloned()
+    }
+}
+
+impl Drop for MockApi {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        // poke the listener so it notices we're done
+        let _ = TcpStream::connect(("127.0.0.1", self.port));
+        self.state.1.notify_all();
+    }
+}
+
+/// Stream change notifications (as server-sent events) to a client until they
+/// hang up or we shut down
+fn stream_sync(state: &Arc<(Mutex<MockState>, Condvar)>, running: &Arc<AtomicBool>, stream: &mut TcpStream, req: &Request) -> io::Result<()> {
+    let &(ref lock, ref cvar) = &**state;
+    let (user, mut sync_id) = {
+        let mut guard = lock.lock().expect("MockApi -- failed to lock state");
+        guard.requests.push(format!("{} {}", req.method, req.path));
+        let sync_id = req.query.get("sync_id").and_then(|x| x.parse::<i64>().ok()).unwrap_or(0);
+        match guard.user_by_auth(req) {
+            Some(user) => (user, sync_id),
+            None => {
+                drop(guard);
+                return Response::error(401, "not logged in").write_to(stream);
+            }
+        }
+    };
+    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n: hello\n\n")?;
+    stream.flush()?;
+    while running.load(Ordering::SeqCst) {
+        let latest = {
+            let mut guard = lock.lock().expect("MockApi -- failed to lock state");
+            if guard.changes_since(&user.id, sync_id).len() == 0 {
+                guard = cvar.wait_timeout(guard, Duration::from_millis(1000)).expect("MockApi -- failed to wait").0;
+            }
+            if guard.changes_since(&user.id, sync_id).len() > 0 { Some(guard.sync_id) } else { None }
+        };
+        let msg = match latest {
+            Some(latest) => {
+                sync_id = latest;
+                format!("event: sync\ndata: {{\"sync_id\": {}}}\n\n", latest)
+            }
+            None => String::from(": ping\n\n"),
+        };
+        stream.write_all(msg.as_bytes())?;
+        stream.flush()?;
+    }
+    Ok(())
+}
+
+fn handle(state: &Arc<(Mutex<MockState>, Condvar)>, port: u16, req: Request) -> Response {
+    let &(ref lock, ref cvar) = &**state;
+    let mut guard = lock.lock().expect("MockApi -- failed to lock state");
+    guard.requests.push(format!("{} {}", req.method, req.path));
+    let failure = guard.failures.iter()
+        .position(|&(ref m, ref p, _)| m == &req.method && req.path.starts_with(p.as_str()));
+    if let Some(idx) = failure {
+        let (_, _, status) = guard.failures.remove(idx);
+        return Response::error(status, "canned failure");
+    }
+
+    // long-polls wait (without holding the lock) for something to come in
+    let is_poll = req.method == "GET" && req.path == "/sync"
+        && req.query.get("type").map(|x| x == "poll").unwrap_or(false);
+    if is_poll {
+        guard.polls += 1;
+        if let Some(user) = guard.user_by_auth(&req) {
+            let sync_id = req.query.get("sync_id").and_then(|x| x.parse::<i64>().ok()).unwrap_or(0);
+            let deadline = Instant::now() + Duration::from_millis(POLL_WAIT);
+            while guard.changes_since(&user.id, sync_id).len() == 0 {
+                let now = Instant::now();
+                if now >= deadline { break; }
+                guard = cvar.wait_timeout(guard, deadline - now).expect("MockApi -- failed to wait").0;
+            }
+        }
+    }
+
+    let before = guard.sync_id;
+    let res = match route(&mut guard, port, &req) {
+        Ok(x) => x,
+        Err(x) => x,
+    };
+    if guard.sync_id != before { cvar.notify_all(); }
+    res
+}
+
+fn route(state: &mut MockState, port: u16, req: &Request) -> Result<Response, Response> {
+    let mut segs = req.path.trim_matches('/').split('/').collect::<Vec<_>>();
+    let method = req.method.as_str();
+    // a note's single file from the old layout (/notes/:id/attachment) is the
+    // attachment with the note's id
+    if segs.len() >= 3 && segs[0] == "notes" && segs[2] == "attachment" {
+        let note_id = segs[1];
+        segs[2] = "attachments";
+        segs.insert(3, note_id);
+    }
+
+    // the routes you don't need to be logged in for
+    match (method, &segs[..]) {
+        ("POST", ["users"]) => return join(state, req),
+        ("POST", ["auth"]) => {
+            return match state.user_by_auth(req) {
+                Some(user) => Ok(Response::ok(json!(user.id))),
+                None => Err(Response::error(401, "bad login")),
+            };
+        }
+        ("POST", ["users", "recovery"]) => return recovery_login(state, req),
+        ("PUT", ["users", "recovery", user_id]) => return recovery_reset(state, req, user_id),
+        ("GET", ["mock-files", file_id]) => return serve_file(state, req, file_id),
+        _ => {}
+    }
+
+    let user = state.user_by_auth(req).ok_or_else(|| Response::error(401, "not logged in"))?;
+    match (method, &segs[..]) {
+        ("GET", ["users", "email", email]) => {
+            let found = state.user_by_name(email).map(|u| {
+                json!({
+                    "id": u.id,
+                    "username": u.username,
+                    "pubkey": u.data.get("pubkey").cloned().unwrap_or(Value::Null),
+                    "sign_pubkey": u.data.get("sign_pubkey").cloned().unwrap_or(Value::Null),
+                })
+            });
+            Ok(Response::ok(found.unwrap_or(Value::Null)))
+        }
+        ("GET", ["users", user_id]) => {
+            if user_id != &user.id { return Err(Response::error(403, "that's not you")); }
+            Ok(Response::ok(user.data.clone()))
+        }
+        ("PUT", ["users", user_id]) => change_password(state, req, &user, user_id),
+        ("DELETE", ["users", user_id]) => {
+            if user_id != &user.id { return Err(Response::error(403, "that's not you")); }
+            state.users.retain(|x| x.id != user.id);
+            Ok(Response::ok(json!(true)))
+        }
+        ("PUT", ["users", user_id, "recovery"]) => {
+            if user_id != &user.id { return Err(Response::error(403, "that's not you")); }
+            let body = req.json()?;
+            let key = jedi::get_opt::<String>(&["key"], &body).ok_or_else(|| Response::error(400, "missing key"))?;
+            let auth = jedi::get_opt::<String>(&["auth"], &body).ok_or_else(|| Response::error(400, "missing auth"))?;
+            if let Some(u) = state.users.iter_mut().find(|x| x.id == user.id) {
+                u.recovery = Some((key, auth));
+            }
+            Ok(Response::ok(json!(true)))
+        }
+        ("POST", ["users", "confirmation", "resend"]) => Ok(Response::ok(json!(true))),
+        ("POST", ["feedback"]) => Ok(Response::ok(json!(true))),
+
+        ("GET", ["sync"]) => {
+            let sync_id = req.query.get("sync_id").and_then(|x| x.parse::<i64>().ok()).unwrap_or(0);
+            Ok(Response::ok(json!({
+                "records": state.changes_since(&user.id, sync_id),
+                "sync_id": state.sync_id,
+            })))
+        }
+        ("GET", ["sync", "full"]) => {
+            Ok(Response::ok(json!({
+                "records": state.full_profile(&user),
+                "sync_id": state.sync_id,
+            })))
+        }
+        ("POST", ["sync"]) => {
+            let records = jedi::from_val::<Vec<Value>>(req.json()?)
+                .map_err(|_| Response::error(400, "expected a list of sync records"))?;
+            let mut success = Vec::new();
+            let mut failures = Vec::new();
+            let mut blocked = Vec::new();
+            for rec in records {
+                // once something fails, the rest are blocked (the client
+                // retries them in order)
+                if failures.len() > 0 {
+                    blocked.push(rec);
+                    continue;
+                }
+                match state.apply_sync(&user, rec) {
+                    Ok(x) => success.push(x),
+                    Err(x) => failures.push(x),
+                }
+            }
+            Ok(Response::ok(json!({"success": success, "failures": failures, "blocked": blocked})))
+        }
+
+        ("PUT", ["notes", note_id, "attachments", file_id]) => {
+            can_touch_note(state, &user, note_id)?;
+            let data = req.body.clone();
+            Ok(Response::ok(json!({"sync_ids": [save_file(state, &user, note_id, file_id, data)]})))
+        }
+        ("GET", ["notes", note_id, "attachments", file_id]) => {
+            can_touch_note(state, &user, note_id)?;
+            if !state.files.contains_key(*file_id) { return Err(Response::error(404, "no such file")); }
+            Ok(Response::ok(json!(format!("http://127.0.0.1:{}/mock-files/{}", port, file_id))))
+        }
+        ("POST", ["notes", note_id, "attachments", file_id, "uploads"]) => {
+            can_touch_note(state, &user, note_id)?;
+            let size = jedi::get_opt::<u64>(&["size"], &req.json()?).unwrap_or(0);
+            let upload_id = state.gen_id();
+            let upload = Upload {
+                note_id: String::from(*note_id),
+                file_id: String::from(*file_id),
+                size: size,
+                data: Vec::new(),
+            };
+            state.uploads.insert(upload_id.clone(), upload);
+            Ok(Response::ok(json!({"upload_id": upload_id})))
+        }
+        ("GET", ["notes", note_id, "attachments", _file_id, "uploads", upload_id]) => {
+            can_touch_note(state, &user, note_id)?;
+            match state.uploads.get(*upload_id) {
+                Some(upload) => Ok(Response::ok(json!({"offset": upload.data.len()}))),
+                None => Err(Response::error(404, "no such upload")),
+            }
+        }
+        ("PUT", ["notes", note_id, "attachments", _file_id, "uploads", upload_id]) => {
+            can_touch_note(state, &user, note_id)?;
+            upload_chunk(state, req, &user, upload_id)
+        }
+
+        ("POST", ["spaces", space_id, "invites"]) => {
+            require_member(state, space_id, &user)?;
+            let mut invite = req.json()?;
+            let invite_id = match id_str(invite.get("id")) {
+                Some(x) => x,
+                None => state.gen_id(),
+            };
+            invite["id"] = json!(invite_id);
+            invite["space_id"] = json!(space_id);
+            invite["from_user_id"] = json!(user.id);
+            invite["from_username"] = json!(user.username);
+            let to_user = jedi::get_opt::<String>(&["to_user"], &invite)
+                .and_then(|x| state.user_by_name(&x).map(|u| u.id.clone()));
+            state.set_obj("invite", &invite_id, invite.clone());
+            if let Some(space) = state.objects.get_mut("space").and_then(|x| x.get_mut(*space_id)) {
+                if let Some(invites) = space.get_mut("invites").and_then(|x| x.as_array_mut()) {
+                    invites.push(invite.clone());
+                }
+            }
+            let sid1 = state.log_space(&user.id, space_id);
+            let sid2 = state.log(to_user, None, &user.id, "add", "invite", &invite_id, Some(invite.clone()));
+            invite["sync_ids"] = json!([sid1, sid2]);
+            Ok(Response::ok(invite))
+        }
+        ("POST", ["spaces", space_id, "invites", "accepted", invite_id]) => {
+            let invite = state.get_obj("invite", invite_id).ok_or_else(|| Response::error(404, "no such invite"))?;
+            let to_me = jedi::get_opt::<String>(&["to_user"], &invite)
+                .map(|x| x.to_lowercase() == user.username.to_lowercase())
+                .unwrap_or(false);
+            if !to_me { return Err(Response::error(403, "that invite isn't for you")); }
+            let role = jedi::get_opt::<String>(&["role"], &invite).unwrap_or(String::from("guest"));
+            let mut member = state.member_record(space_id, &user, &role);
+            if let Some(claim) = jedi::get_opt::<Value>(&["member"], &req.json()?) {
+                for key in &["pubkey", "sign_pubkey", "signature"] {
+                    if let Some(val) = claim.get(*key) { member[*key] = val.clone(); }
+                }
+            }
+            if let Some(space) = state.objects.get_mut("space").and_then(|x| x.get_mut(*space_id)) {
+                if let Some(members) = space.get_mut("members").and_then(|x| x.as_array_mut()) {
+                    members.push(member);
+                }
+            }
+            let sync_id = state.remove_invite(&user.id, space_id, invite_id);
+            let mut space = state.get_obj("space", space_id).ok_or_else(|| Response::error(404, "no such space"))?;
+            space["sync_ids"] = json!([sync_id]);
+            Ok(Response::ok(space))
+        }
+        ("PUT", ["spaces", space_id, "invites", invite_id]) => {
+            require_member(state, space_id, &user)?;
+            let mut invite = req.json()?;
+            invite["id"] = json!(invite_id);
+            invite["space_id"] = json!(space_id);
+            state.set_obj("invite", invite_id, invite.clone());
+            if let Some(space) = state.objects.get_mut("space").and_then(|x| x.get_mut(*space_id)) {
+                if let Some(invites) = space.get_mut("invites").and_then(|x| x.as_array_mut()) {
+                    for existing in invites.iter_mut() {
+                        if id_str(existing.get("id")).map(|x| &x == invite_id).unwrap_or(false) {
+                            *existing = invite.clone();
+                        }
+                    }
+                }
+            }
+            let sync_id = state.log_space(&user.id, space_id);
+            invite["sync_ids"] = json!([sync_id]);
+            Ok(Response::ok(invite))
+        }
+        ("DELETE", ["spaces", space_id, "invites", invite_id]) => {
+            require_member(state, space_id, &user)?;
+            let sync_id = state.remove_invite(&user.id, space_id, invite_id);
+            Ok(Response::ok(json!({"sync_ids": [sync_id]})))
+        }
+        ("PUT", ["spaces", space_id, "members", member_id]) => {
+            require_member(state, space_id, &user)?;
+            let mut member = state.find_member(space_id, member_id).ok_or_else(|| Response::error(404, "no such member"))?;
+            if let Some(role) = jedi::get_opt::<String>(&["role"], &req.json()?) {
+                member["role"] = json!(role);
+            }
+            set_member(state, space_id, member_id, Some(member.clone()));
+            let sync_id = state.log_space(&user.id, space_id);
+            member["sync_ids"] = json!([sync_id]);
+            Ok(Response::ok(member))
+        }
+        ("DELETE", ["spaces", space_id, "members", member_id]) => {
+            require_member(state, space_id, &user)?;
+            if state.find_member(space_id, member_id).is_none() {
+                return Err(Response::error(404, "no such member"));
+            }
+            set_member(state, space_id, member_id, None);
+            // the member that left won't see the space edit, so tell them
+            // directly
+            let sid1 = state.log(Some(String::from(*member_id)), None, &user.id, "delete", "space", space_id, None);
+            let sid2 = state.log_space(&user.id, space_id);
+            Ok(Response::ok(json!({"sync_ids": [sid1, sid2]})))
+        }
+        ("PUT", ["spaces", space_id, "owner", new_owner]) => {
+            let mut space = state.get_obj("space", space_id).ok_or_else(|| Response::error(404, "no such space"))?;
+            if id_str(space.get("user_id")).map(|x| x != user.id).unwrap_or(true) {
+                return Err(Response::error(403, "only the owner can do that"));
+            }
+            let mut new_member = state.find_member(space_id, new_owner).ok_or_else(|| Response::error(404, "no such member"))?;
+            let mut old_member = state.find_member(space_id, &user.id).ok_or_else(|| Response::error(404, "no such member"))?;
+            new_member["role"] = json!("owner");
+            old_member["role"] = json!("admin");
+            set_member(state, space_id, new_owner, Some(new_member));
+            set_member(state, space_id, &user.id, Some(old_member));
+            if let Some(x) = state.objects.get_mut("space").and_then(|x| x.get_mut(*space_id)) {
+                x["user_id"] = json!(new_owner);
+            }
+            let sync_id = state.log_space(&user.id, space_id);
+            space = state.get_obj("space", space_id).unwrap_or(space);
+            space["sync_ids"] = json!([sync_id]);
+            Ok(Response::ok(space))
+        }
+
+        _ => Err(Response::error(404, "no such route")),
+    }
+}
+
+fn join(state: &mut MockState, req: &Request) -> Result<Response, Response> {
+    let body = req.json()?;
+    let username = jedi::get_opt::<String>(&["username"], &body).ok_or_else(|| Response::error(400, "missing username"))?;
+    let auth = jedi::get_opt::<String>(&["auth"], &body).ok_or_else(|| Response::error(400, "missing auth"))?;
+    if state.user_by_name(&username).is_some() {
+        return Err(Response::error(403, "the email you entered is already in use"));
+    }
+    let user_id = state.gen_id();
+    let mut data = body.get("data").cloned().unwrap_or(json!({}));
+    data["id"] = json!(user_id);
+    data["username"] = json!(username);
+    state.users.push(MockUser {
+        id: user_id.clone(),
+        username: username,
+        auth: auth,
+        data: data.clone(),
+        recovery: None,
+    });
+    Ok(Response::ok(json!({"id": user_id, "data": data})))
+}
+
+fn change_password(state: &mut MockState, req: &Request, user: &MockUser, user_id: &str) -> Result<Response, Response> {
+    if user_id != user.id { return Err(Response::error(403, "that's not you")); }
+    let sync_ids = reset_auth(state, user, &req.json()?)?;
+    Ok(Response::ok(json!({"sync_ids": sync_ids})))
+}
+
+/// Swap out a user's auth, user data, and keychain (for a password change or
+/// an account recovery). Returns the sync ids of the changes.
+fn reset_auth(state: &mut MockState, user: &MockUser, body: &Value) -> Result<Vec<i64>, Response> {
+    let auth = jedi::get_opt::<String>(&["auth"], body).ok_or_else(|| Response::error(400, "missing auth"))?;
+    let mut sync_ids = Vec::new();
+    if let Some(mut data) = body.get("user").cloned() {
+        data["id"] = json!(user.id);
+        if let Some(u) = state.users.iter_mut().find(|x| x.id == user.id) {
+            u.data = data.clone();
+        }
+        sync_ids.push(state.log(Some(user.id.clone()), None, &user.id, "edit", "user", &user.id, Some(data)));
+    }
+    for mut entry in jedi::get_opt::<Vec<Value>>(&["keychain"], body).unwrap_or_default() {
+        let id = match id_str(entry.get("id")) {
+            Some(x) => x,
+            None => continue,
+        };
+        entry["user_id"] = json!(user.id);
+        state.set_obj("keychain", &id, entry.clone());
+        sync_ids.push(state.log(Some(user.id.clone()), None, &user.id, "edit", "keychain", &id, Some(entry)));
+    }
+    if let Some(u) = state.users.iter_mut().find(|x| x.id == user.id) {
+        u.auth = auth;
+        // the recovery key wraps the old master key, so it's dead now
+        u.recovery = None;
+    }
+    Ok(sync_ids)
+}
+
+fn recovery_login(state: &mut MockState, req: &Request) -> Result<Response, Response> {
+    let body = req.json()?;
+    let username = jedi::get_opt::<String>(&["username"], &body).unwrap_or_default();
+    let auth = jedi::get_opt::<String>(&["auth"], &body).unwrap_or_default();
+    let user = state.user_by_name(&username).cloned().ok_or_else(|| Response::error(401, "bad recovery login"))?;
+    let key = match user.recovery {
+        Some((ref key, ref rauth)) if rauth == &auth => key.clone(),
+        _ => return Err(Response::error(401, "bad recovery login")),
+    };
+    let keychain = state.objects.get("keychain")
+        .map(|x| x.values().filter(|k| id_str(k.get("user_id")).map(|u| u == user.id).unwrap_or(false)).cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+    Ok(Response::ok(json!({"id": user.id, "key": key, "user": user.data, "keychain": keychain})))
+}
+
+fn recovery_reset(state: &mut MockState, req: &Request, user_id: &str) -> Result<Response, Response> {
+    let body = req.json()?;
+    let user = state.user_by_id(user_id).cloned().ok_or_else(|| Response::error(404, "no such user"))?;
+    let rauth = jedi::get_opt::<String>(&["recovery_auth"], &body).unwrap_or_default();
+    if user.recovery.as_ref().map(|x| x.1 != rauth).unwrap_or(true) {
+        return Err(Response::error(401, "bad recovery login"));
+    }
+    let sync_ids = reset_auth(state, &user, &body)?;
+    Ok(Response::ok(json!({"sync_ids": sync_ids})))
+}
+
+fn require_member(state: &MockState, space_id: &str, user: &MockUser) -> Result<(), Response> {
+    if state.is_member(space_id, &user.id) {
+        Ok(())
+    } else {
+        Err(Response::error(403, "you aren't in that space"))
+    }
+}
+
+fn can_touch_note(state: &MockState, user: &MockUser, note_id: &str) -> Result<(), Response> {
+    let space_id = state.space_of("note", note_id).ok_or_else(|| Response::error(404, "no such note"))?;
+    require_member(state, &space_id, user)
+}
+
+/// Replace (or remove) a member in a space's member list
+fn set_member(state: &mut MockState, space_id: &str, user_id: &str, member: Option<Value>) {
+    let space = match state.objects.get_mut("space").and_then(|x| x.get_mut(space_id)) {
+        Some(x) => x,
+        None => return,
+    };
+    if let Some(members) = space.get_mut("members").and_then(|x| x.as_array_mut()) {
+        let is_user = |m: &Value| id_str(m.get("user_id")).map(|x| x == user_id).unwrap_or(false);
+        match member {
+            Some(member) => {
+                for existing in members.iter_mut() {
+                    if is_user(existing) { *existing = member.clone(); }
+                }
+            }
+            None => members.retain(|m| !is_user(m)),
+        }
+    }
+}
+
+/// Store a note's attachment and let the note's space know about it
+fn save_file(state: &mut MockState, user: &MockUser, note_id: &str, file_id: &str, data: Vec<u8>) -> i64 {
+    let logdata = json!({"id": file_id, "note_id": note_id, "size": data.len()});
+    state.files.insert(String::from(file_id), data);
+    state.file_notes.insert(String::from(file_id), String::from(note_id));
+    let space_id = state.space_of("note", note_id);
+    state.log(None, space_id, &user.id, "add", "file", file_id, Some(logdata))
+}
+
+fn upload_chunk(state: &mut MockState, req: &Request, user: &MockUser, upload_id: &str) -> Result<Response, Response> {
+    let (start, total) = req.header("content-range")
+        .and_then(|x| {
+            // bytes <start>-<end>/<total>
+            let x = x.trim_start_matches("bytes").trim();
+            let slash = x.find('/')?;
+            let dash = x.find('-')?;
+            Some((x[..dash].parse::<u64>().ok()?, x[slash + 1..].parse::<u64>().ok()?))
+        })
+        .ok_or_else(|| Response::error(400, "bad content-range"))?;
+    let (note_id, file_id, done, offset) = {
+        let upload = state.uploads.get_mut(upload_id).ok_or_else(|| Response::error(404, "no such upload"))?;
+        if start != upload.data.len() as u64 {
+            // tell them where we really are
+            return Ok(Response::ok(json!({"offset": upload.data.len()})));
+        }
+        upload.data.extend_from_slice(&req.body);
+        let offset = upload.data.len() as u64;
+        (upload.note_id.clone(), upload.file_id.clone(), offset >= total && offset >= upload.size, offset)
+    };
+    if !done { return Ok(Response::ok(json!({"offset": offset}))); }
+    let upload = state.uploads.remove(upload_id).expect("MockApi -- upload disappeared");
+    let sync_id = save_file(state, user, &note_id, &file_id, upload.data);
+    Ok(Response::ok(json!({"offset": offset, "sync_ids": [sync_id]})))
+}
+
+/// Serve up an attachment, with support for resuming via Range/If-Range
+fn serve_file(state: &MockState, req: &Request, file_id: &str) -> Result<Response, Response> {
+    let data = state.files.get(file_id).ok_or_else(|| Response::error(404, "no such file"))?;
+    let etag = format!("\"{}-{}\"", file_id, data.len());
+    let range_start = req.header("range")
+        .and_then(|x| x.trim_start_matches("bytes=").split('-').next().and_then(|x| x.parse::<u64>().ok()));
+    let etag_ok = req.header("if-range").map(|x| x == &etag).unwrap_or(true);
+    let mut res = match range_start {
+        Some(start) if etag_ok => {
+            let len = data.len() as u64;
+            if start >= len {
+                return Err(Response::error(416, "range not satisfiable"));
+            }
+            let mut res = Response::bytes(206, data[(start as usize)..].to_vec());
+            res.headers.push((String::from("Content-Range"), format!("bytes {}-{}/{}", start, len - 1, len)));
+            res
+        }
+        _ => Response::bytes(200, data.clone()),
+    };
+    res.headers.push((String::from("ETag"), etag));
+    res.headers.push((String::from("Accept-Ranges"), String::from("bytes")));
+    Ok(res)
+}
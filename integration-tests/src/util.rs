@@ -11,7 +11,7 @@ extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 
-use ::std::{env, thread, str};
+use ::std::{env, fs, thread, str};
 use ::std::time::Duration;
 use ::std::sync::RwLock;
 use ::std::error::Error;
@@ -89,13 +89,33 @@ pub fn sleep(millis: u64) {
     thread::sleep(Duration::from_millis(millis));
 }
 
+/// The config our tests build on
+const BASE_CONFIG: &'static str = "../config.yaml.default";
+
+/// Load the (optional) config file pointed to by TURTL_CONFIG_FILE. This is
+/// where the server endpoint and test logins go when running against a real
+/// Turtl server.
+fn local_config() -> Value {
+    let path = match env::var("TURTL_CONFIG_FILE") {
+        Ok(ref x) if x != "" => x.clone(),
+        _ => return json!({}),
+    };
+    let contents = fs::read_to_string(&path).expect("integration-tests::local_config() -- failed to read config file");
+    jedi::parse_yaml(&contents).expect("integration-tests::local_config() -- failed to parse config file")
+}
+
 pub fn init() -> thread::JoinHandle<()> {
-    if env::var("TURTL_CONFIG_FILE").is_err() {
-        env::set_var("TURTL_CONFIG_FILE", "../config.yaml");
-    }
-    // load the local config
-    config::load_config(None).expect("integration-tests::init() -- failed to load config");
-    let handle = cwrap::init(r#"{
+    init_with_api(None)
+}
+
+/// Like init(), but lets us point core at a different API endpoint (say, the
+/// in-process mock API from mock_api.rs)
+pub fn init_with_api(endpoint: Option<String>) -> thread::JoinHandle<()> {
+    // start from the default config, then lay the local config (if any) over
+    // it. this way the mock tests run without a config.yaml at all.
+    config::load_config(Some(String::from(BASE_CONFIG))).expect("integration-tests::init() -- failed to load base config");
+    config::merge(&local_config()).expect("integration-tests::init() -- failed to merge local config");
+    let mut app_config = json!({
         "data_folder": ":memory:",
         "wrap_errors": true,
         "messaging": {"reqres_append_mid": true},
@@ -105,9 +125,21 @@ pub fn init() -> thread::JoinHandle<()> {
             "enable_outgoing": true,
             "enable_files_incoming": true,
             "enable_files_outgoing": true,
-            "poll_timeout": 5
+            "poll_timeout": 5,
+            // off by default, but the mock api knows how to push
+            "push": {"enabled": true}
         }
-    }"#);
+    });
+    if let Some(endpoint) = endpoint {
+        app_config["api"] = json!({"endpoint": endpoint});
+    }
+    config::merge(&app_config).expect("integration-tests::init() -- failed to merge app config");
+    // hand core the whole thing, and tell it not to load a config file of its
+    // own over top of ours
+    let mut app_config = config::dump().expect("integration-tests::init() -- failed to dump config");
+    app_config["config_file"] = json!(":null:");
+    let app_config = jedi::stringify(&app_config).expect("integration-tests::init() -- failed to stringify config");
+    let handle = cwrap::init(app_config.as_str());
     wait_on("messaging:ready");
     handle
 }
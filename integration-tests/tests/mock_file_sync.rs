@@ -0,0 +1,99 @@
+include!("../src/util.rs");
+#[allow(dead_code)]
+mod mock_api { include!("../src/mock_api.rs"); }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::mock_api::MockApi;
+
+    #[test]
+    fn mock_file_sync() {
+        let mock = MockApi::start();
+        let handle = init_with_api(Some(mock.endpoint()));
+
+        dispatch_ass(json!(["app:wipe-app-data"]));
+        dispatch_ass(json!(["user:join", "mock-files@turtlapp.com", "slappy"]));
+        wait_on("user:login");
+        dispatch_ass(json!(["sync:start"]));
+        wait_on("profile:loaded");
+        wait_on("profile:indexed");
+
+        let profile_data = dispatch_ass(json!(["profile:load"]));
+        let user_id: String = jedi::get(&["user", "id"], &profile_data).unwrap();
+        let space_id: String = jedi::get(&["spaces", "0", "id"], &profile_data).unwrap();
+        let note = dispatch_ass(json!([
+            "profile:sync:model",
+            "add",
+            "note",
+            {
+                "title": "mai file LOL",
+                "type": "file",
+                "space_id": space_id,
+                "user_id": user_id,
+                "attachments": [
+                    {
+                        "name": "slappy.txt",
+                        "type": "text/plain",
+                        "filedata": {"data": "c2xhcHB5IHNheXMgaGk="},
+                    },
+                    {
+                        "name": "larry.txt",
+                        "type": "text/plain",
+                        "filedata": {"data": "bGFycnkgc2F5cyBoaQ=="},
+                    },
+                ],
+            },
+        ]));
+        let note_id: String = jedi::get(&["id"], &note).unwrap();
+        let file_id: String = jedi::get(&["attachments", "0", "id"], &note).unwrap();
+        let file_id2: String = jedi::get(&["attachments", "1", "id"], &note).unwrap();
+        assert!(file_id != file_id2);
+        wait_on("sync:file:uploaded");
+        wait_on("sync:file:uploaded");
+        // the files are encrypted, so all we can say is that they made it
+        assert!(mock.file(&file_id).map(|x| x.len() > 0).unwrap_or(false));
+        assert!(mock.file(&file_id2).map(|x| x.len() > 0).unwrap_or(false));
+        let slappy = dispatch_ass(json!(["profile:note:get-file", note_id, file_id]));
+        assert_eq!(slappy, json!("c2xhcHB5IHNheXMgaGk="));
+
+        // big files can be read a piece at a time...
+        let info = dispatch_ass(json!(["profile:note:file:open", note_id, file_id]));
+        let handle: String = jedi::get(&["handle"], &info).unwrap();
+        assert_eq!(jedi::get::<u64>(&["size"], &info).unwrap(), 14);
+        let chunk = dispatch_ass(json!(["profile:note:file:read", handle, 7, 4]));
+        assert_eq!(jedi::get::<String>(&["data"], &chunk).unwrap(), "c2F5cw==");
+        dispatch_ass(json!(["profile:note:file:close", handle]));
+        // ...or decrypted straight to disk
+        let mut path = ::std::env::temp_dir();
+        path.push(format!("turtl-export-{}.txt", file_id));
+        let path = String::from(path.to_str().unwrap());
+        dispatch_ass(json!(["profile:note:file:export", note_id, file_id, path]));
+        assert_eq!(::std::fs::read_to_string(&path).unwrap(), "slappy says hi");
+        ::std::fs::remove_file(&path).unwrap();
+        assert!(mock.state().objects.get("note").map(|x| x.contains_key(&note_id)).unwrap_or(false));
+
+        // log back in from "another device" and make sure we get it back
+        dispatch_ass(json!(["app:wipe-user-data"]));
+        wait_on("user:logout");
+        dispatch_ass(json!(["user:login", "mock-files@turtlapp.com", "slappy"]));
+        wait_on("user:login");
+        dispatch_ass(json!(["sync:start"]));
+        let evdata = wait_on("sync:file:downloaded");
+        let note_id2: String = jedi::get(&["note_id"], &evdata).unwrap();
+        assert_eq!(note_id, note_id2);
+        wait_on("sync:file:downloaded");
+
+        // removing one attachment leaves the other alone
+        dispatch_ass(json!(["profile:sync:model", "delete", "file", {"id": file_id, "note_id": note_id}]));
+        assert!(mock.wait_for(|state| !state.files.contains_key(&file_id)));
+        assert!(mock.file(&file_id2).is_some());
+        let notes = dispatch_ass(json!(["profile:get-notes", [note_id]]));
+        let attachments: Vec<Value> = jedi::get(&["0", "attachments"], &notes).unwrap();
+        assert_eq!(attachments.len(), 1);
+        let larry = dispatch_ass(json!(["profile:note:get-file", note_id, file_id2]));
+        assert_eq!(larry, json!("bGFycnkgc2F5cyBoaQ=="));
+
+        end(handle);
+    }
+}
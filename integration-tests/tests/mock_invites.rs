@@ -0,0 +1,88 @@
+include!("../src/util.rs");
+#[allow(dead_code)]
+mod mock_api { include!("../src/mock_api.rs"); }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::mock_api::MockApi;
+
+    const SENDER: &'static str = "mock-sender@turtlapp.com";
+    const RECEIVER: &'static str = "mock-receiver@turtlapp.com";
+    const PASSWORD: &'static str = "slappy";
+
+    fn join(mock: &MockApi, username: &str) -> String {
+        dispatch_ass(json!(["app:wipe-user-data"]));
+        let ret = dispatch_ass(json!(["user:join", username, PASSWORD]));
+        wait_on("user:login");
+        dispatch_ass(json!(["sync:start"]));
+        wait_on("profile:loaded");
+        wait_on("profile:indexed");
+        let user_id: String = jedi::get(&["id"], &ret).unwrap();
+        // make sure our new space makes it up before we log out
+        let synced = mock.wait_for(|state| {
+            state.objects.get("space")
+                .map(|x| x.values().any(|s| s["user_id"] == json!(user_id)))
+                .unwrap_or(false)
+        });
+        assert!(synced);
+        user_id
+    }
+
+    fn login(username: &str) -> String {
+        dispatch_ass(json!(["app:wipe-user-data"]));
+        let ret = dispatch_ass(json!(["user:login", username, PASSWORD]));
+        wait_on("user:login");
+        dispatch_ass(json!(["sync:start"]));
+        wait_on("sync:connected");
+        wait_on("profile:loaded");
+        wait_on("profile:indexed");
+        jedi::get(&["id"], &ret).unwrap()
+    }
+
+    #[test]
+    fn mock_invites() {
+        let mock = MockApi::start();
+        let handle = init_with_api(Some(mock.endpoint()));
+        dispatch_ass(json!(["app:wipe-app-data"]));
+
+        let receiver_id = join(&mock, RECEIVER);
+        join(&mock, SENDER);
+        let profile = dispatch_ass(json!(["profile:load"]));
+        let space_id: String = jedi::get(&["spaces", "0", "id"], &profile).unwrap();
+        let to_user = dispatch_ass(json!(["user:find-by-email", RECEIVER]));
+        let pubkey: String = jedi::get(&["pubkey"], &to_user).unwrap();
+        dispatch_ass(json!(["profile:space:send-invite", {
+            "space_id": space_id,
+            "to_user": RECEIVER,
+            "role": "member",
+            "title": "welcome to my dumb space",
+            "their_pubkey": pubkey,
+        }]));
+        assert_eq!(mock.state().objects.get("invite").map(|x| x.len()).unwrap_or(0), 1);
+
+        // the receiver gets the invite and accepts it
+        login(RECEIVER);
+        let profile = dispatch_ass(json!(["profile:load"]));
+        let invite: Value = jedi::get(&["invites", "0"], &profile).unwrap();
+        dispatch_ass(json!(["profile:accept-invite", invite]));
+        let space = loop {
+            let data = wait_on("sync:update");
+            let ty: String = jedi::get(&["type"], &data).unwrap();
+            let id: String = jedi::get(&["item_id"], &data).unwrap();
+            if ty == "space" && id == space_id {
+                break jedi::get::<Value>(&["data"], &data).unwrap();
+            }
+        };
+        let title: String = jedi::get(&["title"], &space).unwrap();
+        assert_eq!(title, "Personal");
+        assert_eq!(mock.state().objects.get("invite").map(|x| x.len()).unwrap_or(0), 0);
+
+        // ...and then leaves
+        dispatch_ass(json!(["profile:space:leave", space_id]));
+        let members: Vec<Value> = jedi::get(&["members"], &mock.state().objects["space"][&space_id]).unwrap();
+        assert!(members.iter().all(|m| m["user_id"] != json!(receiver_id)));
+
+        end(handle);
+    }
+}
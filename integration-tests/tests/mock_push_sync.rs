@@ -0,0 +1,60 @@
+include!("../src/util.rs");
+#[allow(dead_code)]
+mod mock_api { include!("../src/mock_api.rs"); }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::mock_api::MockApi;
+
+    fn stream_requests(mock: &MockApi) -> usize {
+        mock.state().requests.iter().filter(|x| x.as_str() == "GET /sync/stream").count()
+    }
+
+    #[test]
+    fn mock_push_sync() {
+        let mock = MockApi::start();
+        let handle = init_with_api(Some(mock.endpoint()));
+
+        // an api that can't push gets long-polled
+        mock.state().push_disabled = true;
+        dispatch_ass(json!(["app:wipe-app-data"]));
+        dispatch_ass(json!(["user:join", "mock-push@turtlapp.com", "slappy"]));
+        wait_on("user:login");
+        dispatch_ass(json!(["sync:start"]));
+        wait_on("profile:loaded");
+        assert!(mock.wait_for(|state| state.polls > 0));
+        assert_eq!(stream_requests(&mock), 1);
+
+        // an api that can push doesn't
+        mock.state().push_disabled = false;
+        dispatch_ass(json!(["app:wipe-user-data"]));
+        wait_on("user:logout");
+        dispatch_ass(json!(["user:login", "mock-push@turtlapp.com", "slappy"]));
+        wait_on("user:login");
+        dispatch_ass(json!(["sync:start"]));
+        wait_on("profile:loaded");
+        assert!(mock.wait_for(|state| state.requests.iter().filter(|x| x.as_str() == "GET /sync/stream").count() > 1));
+        let polls = mock.state().polls;
+
+        // changes still make it through
+        let profile = dispatch_ass(json!(["profile:load"]));
+        let user_id: String = jedi::get(&["user", "id"], &profile).unwrap();
+        let space_id: String = jedi::get(&["spaces", "0", "id"], &profile).unwrap();
+        let note = dispatch_ass(json!(["profile:sync:model", "add", "note", {
+            "title": "pushy",
+            "type": "text",
+            "space_id": space_id,
+            "user_id": user_id,
+            "text": "i hear you",
+        }]));
+        let note_id: String = jedi::get(&["id"], &note).unwrap();
+        assert!(mock.wait_for(|state| state.objects.get("note").map(|x| x.contains_key(&note_id)).unwrap_or(false)));
+        sleep(3000);
+        assert_eq!(mock.state().polls, polls);
+        let status = dispatch_ass(json!(["sync:status"]));
+        assert_eq!(jedi::get::<bool>(&["push"], &status).unwrap(), true);
+
+        end(handle);
+    }
+}
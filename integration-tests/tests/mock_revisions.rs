@@ -0,0 +1,101 @@
+include!("../src/util.rs");
+#[allow(dead_code)]
+mod mock_api { include!("../src/mock_api.rs"); }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::mock_api::MockApi;
+
+    fn edit_note(note_id: &String, space_id: &String, text: &str) {
+        dispatch_ass(json!([
+            "profile:sync:model",
+            "edit",
+            "note",
+            {"id": note_id, "space_id": space_id, "type": "text", "title": "groceries", "text": text},
+        ]));
+    }
+
+    #[test]
+    fn mock_revisions() {
+        let mock = MockApi::start();
+        let handle = init_with_api(Some(mock.endpoint()));
+
+        dispatch_ass(json!(["app:wipe-app-data"]));
+        dispatch_ass(json!(["user:join", "mock-revisions@turtlapp.com", "slappy"]));
+        wait_on("user:login");
+        dispatch_ass(json!(["sync:start"]));
+        wait_on("profile:loaded");
+        wait_on("profile:indexed");
+
+        let profile_data = dispatch_ass(json!(["profile:load"]));
+        let space_id: String = jedi::get(&["spaces", "0", "id"], &profile_data).unwrap();
+        let note = dispatch_ass(json!([
+            "profile:sync:model",
+            "add",
+            "note",
+            {"space_id": space_id, "type": "text", "title": "groceries", "text": "milk\neggs"},
+        ]));
+        let note_id: String = jedi::get(&["id"], &note).unwrap();
+        edit_note(&note_id, &space_id, "milk\neggs\nbread");
+        edit_note(&note_id, &space_id, "oat milk\neggs\nbread");
+
+        // newest first
+        let revisions = dispatch_ass(json!(["profile:note:revisions", note_id]));
+        assert_eq!(revisions.as_array().unwrap().len(), 2);
+        assert_eq!(jedi::get::<String>(&["0", "title"], &revisions).unwrap(), "groceries");
+        let first_id: String = jedi::get(&["1", "id"], &revisions).unwrap();
+        let second_id: String = jedi::get(&["0", "id"], &revisions).unwrap();
+
+        let rev = dispatch_ass(json!(["profile:note:get-revision", first_id]));
+        assert_eq!(jedi::get::<String>(&["note", "text"], &rev).unwrap(), "milk\neggs");
+        let diff: Vec<Value> = jedi::get(&["diff"], &rev).unwrap();
+        let ops = diff.iter()
+            .map(|x| format!("{} {}", x["op"].as_str().unwrap(), x["line"].as_str().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(ops, vec!["remove milk", "add oat milk", "same eggs", "add bread"]);
+
+        let rev = dispatch_ass(json!(["profile:note:get-revision", first_id, second_id]));
+        let diff: Vec<Value> = jedi::get(&["diff"], &rev).unwrap();
+        assert_eq!(diff.len(), 3);
+
+        // bring back the first version, which keeps the current one around
+        dispatch_ass(json!(["profile:note:restore-revision", first_id]));
+        let notes = dispatch_ass(json!(["profile:get-notes", [note_id]]));
+        assert_eq!(jedi::get::<String>(&["0", "text"], &notes).unwrap(), "milk\neggs");
+        let revisions = dispatch_ass(json!(["profile:note:revisions", note_id]));
+        assert_eq!(revisions.as_array().unwrap().len(), 3);
+
+        // revisions stay local unless we ask for them to sync
+        assert!(mock.wait_for(|state| {
+            state.objects.get("note")
+                .and_then(|x| x.get(&note_id))
+                .map(|x| x["version"] == json!(4))
+                .unwrap_or(false)
+        }));
+        assert_eq!(mock.state().objects.get("revision").map(|x| x.len()).unwrap_or(0), 0);
+
+        // only the user's edits are kept: toggling a checklist item isn't one
+        let list = dispatch_ass(json!([
+            "profile:sync:model",
+            "add",
+            "note",
+            {"space_id": space_id, "type": "checklist", "title": "chores", "items": [{"id": "dishes", "text": "dishes"}]},
+        ]));
+        let list_id: String = jedi::get(&["id"], &list).unwrap();
+        dispatch_ass(json!(["profile:note:checklist:toggle", list_id, "dishes"]));
+        let revisions = dispatch_ass(json!(["profile:note:revisions", list_id]));
+        assert_eq!(revisions.as_array().unwrap().len(), 0);
+
+        // deleting the note (for good, so past the trash) takes its revisions
+        // with it
+        dispatch_ass(json!(["profile:sync:model", "delete", "note", {"id": note_id}]));
+        let revisions = dispatch_ass(json!(["profile:note:revisions", note_id]));
+        assert_eq!(revisions.as_array().unwrap().len(), 3);
+        dispatch_ass(json!(["profile:sync:model", "delete", "note", {"id": note_id}]));
+        let revisions = dispatch_ass(json!(["profile:note:revisions", note_id]));
+        assert_eq!(revisions.as_array().unwrap().len(), 0);
+
+        end(handle);
+    }
+}
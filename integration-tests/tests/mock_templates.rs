@@ -0,0 +1,79 @@
+include!("../src/util.rs");
+#[allow(dead_code)]
+mod mock_api { include!("../src/mock_api.rs"); }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::mock_api::MockApi;
+
+    #[test]
+    fn mock_templates() {
+        let mock = MockApi::start();
+        let handle = init_with_api(Some(mock.endpoint()));
+
+        dispatch_ass(json!(["app:wipe-app-data"]));
+        dispatch_ass(json!(["user:join", "mock-templates@turtlapp.com", "slappy"]));
+        wait_on("user:login");
+        dispatch_ass(json!(["sync:start"]));
+        wait_on("profile:loaded");
+        wait_on("profile:indexed");
+
+        let profile_data = dispatch_ass(json!(["profile:load"]));
+        let space_id: String = jedi::get(&["spaces", "0", "id"], &profile_data).unwrap();
+        let board = dispatch_ass(json!([
+            "profile:sync:model",
+            "add",
+            "board",
+            {"title": "meetings", "space_id": space_id},
+        ]));
+        let board_id: String = jedi::get(&["id"], &board).unwrap();
+
+        let template = dispatch_ass(json!([
+            "profile:template:save",
+            {
+                "space_id": space_id,
+                "board_id": board_id,
+                "type": "text",
+                "title": "Standup {{date}}",
+                "text": "run by {{username}} in {{board}}. topic: {{topic}}",
+                "tags": ["standup"],
+            },
+        ]));
+        let template_id: String = jedi::get(&["id"], &template).unwrap();
+        let templates = dispatch_ass(json!(["profile:template:list", space_id]));
+        assert_eq!(templates.as_array().unwrap().len(), 1);
+        assert_eq!(jedi::get::<String>(&["0", "title"], &templates).unwrap(), "Standup {{date}}");
+        assert!(mock.wait_for(|state| {
+            state.objects.get("template").map(|x| x.contains_key(&template_id)).unwrap_or(false)
+        }));
+
+        let note = dispatch_ass(json!([
+            "profile:note:create-from-template",
+            template_id,
+            {"vars": {"topic": "turtles"}},
+        ]));
+        let title: String = jedi::get(&["title"], &note).unwrap();
+        assert!(title.starts_with("Standup 2"));
+        assert!(!title.contains("{{"));
+        assert_eq!(jedi::get::<String>(&["text"], &note).unwrap(), "run by mock-templates@turtlapp.com in meetings. topic: turtles");
+        assert_eq!(jedi::get::<String>(&["board_id"], &note).unwrap(), board_id);
+        assert_eq!(jedi::get::<Vec<String>>(&["tags"], &note).unwrap(), vec![String::from("standup")]);
+
+        // notes made from templates can be undone like any other
+        let note_id: String = jedi::get(&["id"], &note).unwrap();
+        let history = dispatch_ass(json!(["profile:history"]));
+        assert_eq!(jedi::get::<String>(&["0", "action"], &history).unwrap(), "add");
+        assert_eq!(jedi::get::<String>(&["0", "type"], &history).unwrap(), "note");
+        assert_eq!(jedi::get::<String>(&["0", "item_id"], &history).unwrap(), note_id);
+
+        dispatch_ass(json!(["profile:template:delete", template_id]));
+        let templates = dispatch_ass(json!(["profile:template:list"]));
+        assert_eq!(templates.as_array().unwrap().len(), 0);
+        assert!(mock.wait_for(|state| {
+            state.objects.get("template").map(|x| !x.contains_key(&template_id)).unwrap_or(true)
+        }));
+
+        end(handle);
+    }
+}
@@ -0,0 +1,77 @@
+include!("../src/util.rs");
+#[allow(dead_code)]
+mod mock_api { include!("../src/mock_api.rs"); }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::mock_api::MockApi;
+
+    fn find_notes(space_id: &String, trashed: bool) -> Vec<Value> {
+        let search = dispatch_ass(json!(["profile:find-notes", {"space_id": space_id, "trashed": trashed}]));
+        jedi::get(&["notes"], &search).unwrap()
+    }
+
+    #[test]
+    fn mock_trash() {
+        let mock = MockApi::start();
+        let handle = init_with_api(Some(mock.endpoint()));
+
+        dispatch_ass(json!(["app:wipe-app-data"]));
+        dispatch_ass(json!(["user:join", "mock-trash@turtlapp.com", "slappy"]));
+        wait_on("user:login");
+        dispatch_ass(json!(["sync:start"]));
+        wait_on("profile:loaded");
+        wait_on("profile:indexed");
+
+        let profile_data = dispatch_ass(json!(["profile:load"]));
+        let space_id: String = jedi::get(&["spaces", "0", "id"], &profile_data).unwrap();
+        let board = dispatch_ass(json!([
+            "profile:sync:model",
+            "add",
+            "board",
+            {"title": "old stuff", "space_id": space_id},
+        ]));
+        let board_id: String = jedi::get(&["id"], &board).unwrap();
+        let note = dispatch_ass(json!([
+            "profile:sync:model",
+            "add",
+            "note",
+            {"space_id": space_id, "board_id": board_id, "type": "text", "title": "old note", "text": "hi"},
+        ]));
+        let note_id: String = jedi::get(&["id"], &note).unwrap();
+        assert_eq!(find_notes(&space_id, false).len(), 1);
+
+        // deleting the board sends it (and its note) to the trash
+        dispatch_ass(json!(["profile:sync:model", "delete", "board", {"id": board_id}]));
+        let profile_data = dispatch_ass(json!(["profile:load"]));
+        let boards: Vec<Value> = jedi::get(&["boards"], &profile_data).unwrap();
+        assert!(!boards.iter().any(|b| b["id"] == json!(board_id)));
+        assert_eq!(find_notes(&space_id, false).len(), 0);
+        assert_eq!(find_notes(&space_id, true).len(), 1);
+        let trash = dispatch_ass(json!(["profile:trash:list"]));
+        assert_eq!(jedi::get::<String>(&["boards", "0", "id"], &trash).unwrap(), board_id);
+        assert_eq!(jedi::get::<String>(&["notes", "0", "id"], &trash).unwrap(), note_id);
+
+        // and restoring the board brings the note back with it
+        dispatch_ass(json!(["profile:trash:restore", "board", board_id]));
+        let profile_data = dispatch_ass(json!(["profile:load"]));
+        let boards: Vec<Value> = jedi::get(&["boards"], &profile_data).unwrap();
+        assert!(boards.iter().any(|b| b["id"] == json!(board_id)));
+        assert_eq!(find_notes(&space_id, false).len(), 1);
+        let trash = dispatch_ass(json!(["profile:trash:list"]));
+        assert_eq!(jedi::get::<Vec<Value>>(&["notes"], &trash).unwrap().len(), 0);
+
+        // emptying the trash deletes for good
+        dispatch_ass(json!(["profile:sync:model", "delete", "note", {"id": note_id}]));
+        assert_eq!(find_notes(&space_id, false).len(), 0);
+        dispatch_ass(json!(["profile:trash:empty"]));
+        let notes = dispatch_ass(json!(["profile:get-notes", [note_id]]));
+        assert_eq!(notes.as_array().unwrap().len(), 0);
+        assert!(mock.wait_for(|state| {
+            state.objects.get("note").map(|x| !x.contains_key(&note_id)).unwrap_or(true)
+        }));
+
+        end(handle);
+    }
+}
@@ -0,0 +1,99 @@
+include!("../src/util.rs");
+#[allow(dead_code)]
+mod mock_api { include!("../src/mock_api.rs"); }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::mock_api::MockApi;
+
+    fn add_note(space_id: &String, board_id: &String, title: &str) -> String {
+        let note = dispatch_ass(json!([
+            "profile:sync:model",
+            "add",
+            "note",
+            {
+                "title": title,
+                "type": "text",
+                "text": "don't delete me",
+                "space_id": space_id,
+                "board_id": board_id,
+            },
+        ]));
+        jedi::get(&["id"], &note).unwrap()
+    }
+
+    #[test]
+    fn mock_undo() {
+        let mock = MockApi::start();
+        let handle = init_with_api(Some(mock.endpoint()));
+
+        dispatch_ass(json!(["app:wipe-app-data"]));
+        dispatch_ass(json!(["user:join", "mock-undo@turtlapp.com", "slappy"]));
+        wait_on("user:login");
+        dispatch_ass(json!(["sync:start"]));
+        wait_on("profile:loaded");
+        wait_on("profile:indexed");
+
+        let profile_data = dispatch_ass(json!(["profile:load"]));
+        let space_id: String = jedi::get(&["spaces", "0", "id"], &profile_data).unwrap();
+        let board = dispatch_ass(json!([
+            "profile:sync:model",
+            "add",
+            "board",
+            {"title": "important things", "space_id": space_id},
+        ]));
+        let board_id: String = jedi::get(&["id"], &board).unwrap();
+        let note_ids = vec![
+            add_note(&space_id, &board_id, "thing one"),
+            add_note(&space_id, &board_id, "thing two"),
+        ];
+        assert!(mock.wait_for(|state| {
+            state.objects.get("note").map(|x| x.len() == 2).unwrap_or(false)
+        }));
+
+        // oops
+        dispatch_ass(json!(["profile:sync:model", "delete", "board", {"id": board_id}]));
+        assert!(mock.wait_for(|state| {
+            state.objects.get("board")
+                .and_then(|x| x.get(&board_id))
+                .map(|x| !x["trashed"].is_null())
+                .unwrap_or(false)
+        }));
+        let profile_data = dispatch_ass(json!(["profile:load"]));
+        let boards: Vec<Value> = jedi::get(&["boards"], &profile_data).unwrap();
+        assert!(!boards.iter().any(|b| b["id"] == json!(board_id)));
+
+        let history = dispatch_ass(json!(["profile:history"]));
+        assert_eq!(jedi::get::<String>(&["0", "action"], &history).unwrap(), "delete");
+        assert_eq!(jedi::get::<String>(&["0", "type"], &history).unwrap(), "board");
+        assert_eq!(jedi::get::<String>(&["0", "title"], &history).unwrap(), "important things");
+        assert_eq!(jedi::get::<usize>(&["0", "items"], &history).unwrap(), 3);
+        // the board/note adds are in there too
+        assert_eq!(history.as_array().unwrap().len(), 4);
+
+        let change = dispatch_ass(json!(["profile:undo"]));
+        assert_eq!(jedi::get::<String>(&["item_id"], &change).unwrap(), board_id);
+        assert_eq!(jedi::get::<bool>(&["undone"], &change).unwrap(), true);
+
+        // the board and its notes are back, locally and on the server
+        let profile_data = dispatch_ass(json!(["profile:load"]));
+        let boards: Vec<Value> = jedi::get(&["boards"], &profile_data).unwrap();
+        assert!(boards.iter().any(|b| b["id"] == json!(board_id) && b["title"] == json!("important things")));
+        let notes = dispatch_ass(json!(["profile:get-notes", note_ids]));
+        assert_eq!(notes.as_array().unwrap().len(), 2);
+        assert_eq!(jedi::get::<String>(&["0", "text"], &notes).unwrap(), "don't delete me");
+        assert!(mock.wait_for(|state| {
+            state.objects.get("board")
+                .and_then(|x| x.get(&board_id))
+                .map(|x| x["trashed"].is_null())
+                .unwrap_or(false)
+        }));
+
+        // can't undo the same change twice
+        let change_id: String = jedi::get(&["id"], &change).unwrap();
+        assert!(dispatch(json!(["profile:undo", change_id])).e != 0);
+
+        end(handle);
+    }
+}
@@ -4,12 +4,13 @@
 use ::std::sync::{RwLock, Mutex};
 use ::std::io::Read;
 use ::std::time::Duration;
+use ::std::cmp;
 use ::std::collections::HashMap;
 use ::config;
 use ::jedi::{self, Value, DeserializeOwned, Serialize};
 use ::error::{TResult, TError};
 use ::crypto;
-use ::reqwest::{self, blocking::RequestBuilder, blocking::Client, Url, Proxy};
+use ::reqwest::{self, blocking::RequestBuilder, blocking::Client, blocking::ClientBuilder, blocking::Response, Url, Proxy};
 pub use ::reqwest::Method;
 pub use ::reqwest::StatusCode;
 
@@ -90,6 +91,33 @@ impl ApiCaller {
         ApiCaller::from_req(self.req.form(form))
     }
 
+    /// Make the call, but rather than reading/parsing the body, hand back the
+    /// response so the caller can read it as it streams in. The timeout in
+    /// `apireq` applies to each read off the stream (not the whole thing), so
+    /// a stream that goes quiet for that long errors out rather than blocking
+    /// forever.
+    pub fn stream(self, apireq: ApiReq) -> TResult<Response> {
+        let mut cachekey = Vec::new();
+        let ApiReq { timeout } = apireq;
+        let client = client_options(Client::builder().timeout(timeout), &mut cachekey)?.build()?;
+        let ApiCaller { req: reqb } = self;
+        let req = reqb.build()?;
+        debug!("api::stream() -- req: {} {}", req.method(), req.url());
+        let mut res = client.execute(req)?;
+        if !res.status().is_success() {
+            let mut errstr = String::new();
+            if let Err(e) = res.read_to_string(&mut errstr) {
+                error!("api::stream() -- problem grabbing error message: {}", e);
+            }
+            let val = match jedi::parse(&errstr) {
+                Ok(x) => x,
+                Err(_) => Value::String(errstr),
+            };
+            return TErr!(TError::Api(res.status(), val));
+        }
+        Ok(res)
+    }
+
     pub fn call<T: DeserializeOwned>(self) -> TResult<T> {
         self.call_opt_impl(None)
     }
@@ -106,29 +134,7 @@ impl ApiCaller {
             client_builder = client_builder.timeout(timeout);
             cachekey.push(format!("timeout-{}", timeout.as_secs()));
         }
-        match config::get::<Option<String>>(&["api", "proxy"]) {
-            Ok(x) => {
-                if let Some(proxy_cfg) = x {
-                    debug!("api::call() -- req: using proxy: {}", proxy_cfg);
-                    let proxystr = format!("{}", proxy_cfg);
-                    cachekey.push(format!("proxy-{}", proxystr));
-                    client_builder = client_builder.proxy(Proxy::all(proxystr.as_str())?);
-                }
-            }
-            Err(_) => {}
-        }
-        match config::get::<Option<bool>>(&["api", "allow_invalid_ssl"]) {
-            Ok(x) => {
-                if let Some(allow_invalid_ssl) = x {
-                    if allow_invalid_ssl {
-                        debug!("api::call() -- req: allow invalid ssl");
-                        cachekey.push(String::from("allow-invalid-ssl"));
-                        client_builder = client_builder.danger_accept_invalid_certs(true);
-                    }
-                }
-            }
-            Err(_) => {}
-        }
+        let client_builder = client_options(client_builder, &mut cachekey)?;
         let cachekey_string: String = cachekey.join("///");
         let client = {
             let mut client_guard = lock!((*CLIENTS));
@@ -149,6 +155,9 @@ impl ApiCaller {
         res
             .map_err(|e| { toterr!(e) })
             .and_then(|mut res| {
+                let retry_after = res.headers().get("retry-after")
+                    .and_then(|x| x.to_str().ok())
+                    .and_then(parse_retry_after);
                 let mut out = String::new();
                 let str_res = res.read_to_string(&mut out)
                     .map_err(|e| toterr!(e))
@@ -165,6 +174,16 @@ impl ApiCaller {
                         Ok(x) => x,
                         Err(_) => Value::String(errstr),
                     };
+                    // if the api told us when to come back, pass it along so
+                    // the sync system can honor it (see sync::retry)
+                    let val = match (retry_after, val) {
+                        (Some(secs), Value::Object(mut obj)) => {
+                            obj.insert(String::from("retry_after"), json!(secs));
+                            Value::Object(obj)
+                        }
+                        (Some(secs), val) => json!({"message": val, "retry_after": secs}),
+                        (None, val) => val,
+                    };
                     return TErr!(TError::Api(res.status(), val));
                 }
                 str_res.map(move |x| (x, res))
@@ -187,6 +206,49 @@ impl ApiCaller {
     }
 }
 
+/// Apply the client options from our config (proxy, invalid ssl) to a client
+/// builder, noting each in the client's cache key
+fn client_options(mut client_builder: ClientBuilder, cachekey: &mut Vec<String>) -> TResult<ClientBuilder> {
+    match config::get::<Option<String>>(&["api", "proxy"]) {
+        Ok(x) => {
+            if let Some(proxy_cfg) = x {
+                debug!("api::call() -- req: using proxy: {}", proxy_cfg);
+                let proxystr = format!("{}", proxy_cfg);
+                cachekey.push(format!("proxy-{}", proxystr));
+                client_builder = client_builder.proxy(Proxy::all(proxystr.as_str())?);
+            }
+        }
+        Err(_) => {}
+    }
+    match config::get::<Option<bool>>(&["api", "allow_invalid_ssl"]) {
+        Ok(x) => {
+            if let Some(allow_invalid_ssl) = x {
+                if allow_invalid_ssl {
+                    debug!("api::call() -- req: allow invalid ssl");
+                    cachekey.push(String::from("allow-invalid-ssl"));
+                    client_builder = client_builder.danger_accept_invalid_certs(true);
+                }
+            }
+        }
+        Err(_) => {}
+    }
+    Ok(client_builder)
+}
+
+/// Parse a `Retry-After` header value (either a number of seconds or an HTTP
+/// date) into a number of seconds from now.
+fn parse_retry_after(val: &str) -> Option<u64> {
+    let val = val.trim();
+    match val.parse::<u64>() {
+        Ok(x) => Some(x),
+        Err(_) => {
+            let date = ::time::strptime(val, "%a, %d %b %Y %H:%M:%S GMT").ok()?;
+            let secs = date.to_timespec().sec - ::time::get_time().sec;
+            Some(cmp::max(secs, 0) as u64)
+        }
+    }
+}
+
 /// Used to store some info we want when we send a response to call_end()
 pub struct CallInfo {
     method: Method,
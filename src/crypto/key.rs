@@ -1,21 +1,88 @@
 //! This submodule defines a a cryptographic key
 
+use ::std::fmt;
+use ::std::sync::Arc;
+use ::std::sync::atomic::{AtomicBool, Ordering};
+
 use ::serde::{ser, de};
 
 use ::crypto::error::CResult;
+use ::crypto::low;
+
+/// Whether or not new keys should try to lock their memory so it never gets
+/// swapped to disk. Off by default, since mlock is limited (sometimes severely)
+/// by the OS. Set via `crypto.mlock_keys` in the config.
+static MLOCK_KEYS: AtomicBool = AtomicBool::new(false);
+
+/// Enable/disable locking key memory for all keys created from here on out.
+pub fn set_mlock(enabled: bool) {
+    MLOCK_KEYS.store(enabled, Ordering::SeqCst);
+}
+
+/// Holds the raw bytes of a key. This is the only place the key material
+/// actually lives: it is zeroed (and unlocked if it was locked) when dropped.
+struct KeyData {
+    bytes: Vec<u8>,
+    locked: bool,
+}
+
+impl KeyData {
+    fn new(mut bytes: Vec<u8>) -> KeyData {
+        let locked = if MLOCK_KEYS.load(Ordering::SeqCst) && bytes.len() > 0 {
+            match low::mlock(bytes.as_mut_slice()) {
+                Ok(_) => true,
+                Err(e) => {
+                    warn!("KeyData::new() -- {}", e);
+                    false
+                }
+            }
+        } else {
+            false
+        };
+        KeyData {
+            bytes: bytes,
+            locked: locked,
+        }
+    }
+}
+
+impl Default for KeyData {
+    fn default() -> KeyData {
+        KeyData::new(Vec::new())
+    }
+}
+
+impl Drop for KeyData {
+    fn drop(&mut self) {
+        if self.locked {
+            // munlock zeroes the buffer for us
+            match low::munlock(self.bytes.as_mut_slice()) {
+                Ok(_) => return,
+                Err(e) => warn!("KeyData::drop() -- {}", e),
+            }
+        }
+        low::memzero(self.bytes.as_mut_slice());
+    }
+}
 
-/// A type we'll use to represent crypto keys
-#[derive(Debug, Default)]
+/// A type we'll use to represent crypto keys.
+///
+/// Cloning a key does not copy the key material: clones share one buffer,
+/// which is zeroed once the last clone is dropped. This means we can pass keys
+/// around (keychain entries, login tokens, etc) without leaving stray copies of
+/// them all over the heap.
+#[derive(Default)]
 pub struct Key {
     /// Holds the actual bytes for our key
-    data: Vec<u8>,
+    data: Arc<KeyData>,
 }
 
 impl Key {
-    /// Create a new key from some keydata
+    /// Create a new key from some keydata. Takes ownership of the data, which
+    /// is zeroed when the key (and all its clones) are dropped.
     pub fn new(data: Vec<u8>) -> Key {
         Key {
-            data: data,
+            data: Arc::new(KeyData::new(data)),
         }
     }
 
@@ -26,31 +93,33 @@ impl Key {
 
     /// Return a ref to this key's data
     pub fn data<'a>(&'a self) -> &'a Vec<u8> {
-        &self.data
-    }
-
-    /// Consume this Key and convert it into its underlying data
-    #[allow(dead_code)]
-    pub fn into_data(self) -> Vec<u8> {
-        self.data
+        &self.data.bytes
     }
 
     /// Return this key's data length
     #[allow(dead_code)]
     pub fn len(&self) -> usize {
-        self.data.len()
+        self.data.bytes.len()
     }
 }
 
 impl Clone for Key {
     fn clone(&self) -> Key {
-        Key::new(self.data().clone())
+        Key {
+            data: self.data.clone(),
+        }
     }
 }
 
 impl PartialEq for Key {
     fn eq(&self, other: &Key) -> bool {
-        self.data() == other.data()
+        low::secure_compare(self.data(), other.data()).unwrap_or(false)
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Key {{ <{} bytes redacted> }}", self.len())
     }
 }
 
@@ -96,5 +165,20 @@ mod tests {
         let ser_key = jedi::stringify(&key).unwrap();
         assert_eq!(ser_key, String::from(r#""XExP/+h80Fm06fEqKsKoE5GwaDRY88pObH+y6YCTWzQ=""#));
     }
-}
 
+    #[test]
+    fn clones_share_key_data() {
+        let key = Key::new(vec![1, 2, 3, 4]);
+        let key2 = key.clone();
+        assert_eq!(key, key2);
+        assert_eq!(key.data().as_ptr(), key2.data().as_ptr());
+        drop(key);
+        assert_eq!(key2.data(), &vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn redacts_debug_output() {
+        let key = Key::new(vec![92, 76, 79, 255]);
+        assert_eq!(format!("{:?}", key), "Key { <4 bytes redacted> }");
+    }
+}
@@ -83,6 +83,25 @@ pub fn rand_bytes(len: usize) -> CResult<Vec<u8>> {
     Ok(sodiumoxide::randombytes::randombytes(len))
 }
 
+/// Overwrite a buffer with zeros in a way the compiler won't optimize out.
+pub fn memzero(data: &mut [u8]) {
+    sodiumoxide::utils::memzero(data);
+}
+
+/// Lock a buffer's pages into memory so they are never swapped to disk. Fails
+/// if the OS refuses (RLIMIT_MEMLOCK, unsupported platform, etc).
+pub fn mlock(data: &mut [u8]) -> CResult<()> {
+    sodiumoxide::utils::mlock(data)
+        .map_err(|_| CryptoError::Msg(format!("crypto::low::mlock() -- failed to lock {} bytes", data.len())))
+}
+
+/// Unlock a buffer previously locked with `mlock()`. This also zeroes the
+/// buffer.
+pub fn munlock(data: &mut [u8]) -> CResult<()> {
+    sodiumoxide::utils::munlock(data)
+        .map_err(|_| CryptoError::Msg(format!("crypto::low::munlock() -- failed to unlock {} bytes", data.len())))
+}
+
 /// Generate a random u64. Uses rand_bytes() and bit shifting to build a u64.
 pub fn rand_int() -> CResult<u64> {
     let bytes = rand_bytes(8)?;
@@ -214,6 +233,39 @@ pub mod asym {
     }
 }
 
+pub mod sign {
+    use ::crypto::error::{CryptoError, CResult};
+    use ::sodiumoxide::crypto::sign::ed25519;
+
+    /// Generate a public/private keypair for signing data
+    pub fn keygen() -> CResult<(Vec<u8>, Vec<u8>)> {
+        let (pk, sk) = ed25519::gen_keypair();
+        Ok((pk.0.to_vec(), sk.0.to_vec()))
+    }
+
+    /// Create a detached signature of some data
+    pub fn sign(our_privkey: &[u8], data: &[u8]) -> CResult<Vec<u8>> {
+        let privkey = match ed25519::SecretKey::from_slice(our_privkey) {
+            Some(x) => x,
+            None => return Err(CryptoError::BadData(String::from("crypto::low::sign::sign() -- bad private key given"))),
+        };
+        Ok(ed25519::sign_detached(data, &privkey).0.to_vec())
+    }
+
+    /// Verify a detached signature against some data
+    pub fn verify(their_pubkey: &[u8], data: &[u8], signature: &[u8]) -> CResult<bool> {
+        let pubkey = match ed25519::PublicKey::from_slice(their_pubkey) {
+            Some(x) => x,
+            None => return Err(CryptoError::BadData(String::from("crypto::low::sign::verify() -- bad public key given"))),
+        };
+        let signature = match ed25519::Signature::from_slice(signature) {
+            Some(x) => x,
+            None => return Err(CryptoError::BadData(String::from("crypto::low::sign::verify() -- bad signature given"))),
+        };
+        Ok(ed25519::verify_detached(&signature, data, &pubkey))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -424,5 +476,16 @@ mod tests {
         let decrypted_str = String::from_utf8(decrypted).unwrap();
         assert_eq!(decrypted_str, "I'M NOT A PERVERT");
     }
-}
 
+    #[test]
+    fn sign_verify() {
+        let (pk, sk) = sign::keygen().unwrap();
+        let (pk2, _sk2) = sign::keygen().unwrap();
+        let message = String::from("i'm the one who knocks");
+
+        let signature = sign::sign(sk.as_slice(), message.as_bytes()).unwrap();
+        assert!(sign::verify(pk.as_slice(), message.as_bytes(), signature.as_slice()).unwrap());
+        assert!(!sign::verify(pk.as_slice(), "i'm the one who rocks".as_bytes(), signature.as_slice()).unwrap());
+        assert!(!sign::verify(pk2.as_slice(), message.as_bytes(), signature.as_slice()).unwrap());
+    }
+}
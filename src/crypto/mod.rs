@@ -21,14 +21,23 @@ pub use ::crypto::low::{
     KEYGEN_OPS_DEFAULT,
     KEYGEN_MEM_DEFAULT,
     random_salt,
+    rand_bytes,
+    rand_float,
+    memzero,
 };
 pub use ::crypto::low::chacha20poly1305::{random_nonce, random_key, noncelen, keylen};
-pub use ::crypto::key::Key;
+pub use ::crypto::key::{Key, set_mlock};
 
 /// Stores our current crypto version. This gets encoded into a header in the
 /// ciphertext and lets the crypto module know how to handle the message.
 const CRYPTO_VERSION: u16 = 6;
 
+/// The crypto version used when encrypting with associated data. Identical to
+/// version 6, except the caller-supplied associated data is appended to the
+/// header before it's used as the AEAD auth data. Payloads from earlier
+/// versions carry no associated data and are decrypted without it.
+const CRYPTO_VERSION_AD: u16 = 7;
+
 /// Stores the available algorithms for symmetric crypto.
 const SYM_ALGORITHM: [&'static str; 1] = ["chacha20poly1305"];
 
@@ -210,11 +219,24 @@ pub fn serialize(data: &mut CryptoData) -> CResult<Vec<u8>> {
 /// contain *all* the data needed to decrypt the message encoded in a header
 /// (see deserialize() for more info).
 pub fn decrypt(key: &Key, ciphertext: Vec<u8>) -> CResult<Vec<u8>> {
+    decrypt_ad(key, ciphertext, &[])
+}
+
+/// Decrypt a message that was encrypted with associated data (see
+/// encrypt_ad()). If the associated data passed in doesn't match what the
+/// message was encrypted with, authentication fails.
+///
+/// Payloads older than CRYPTO_VERSION_AD don't authenticate any associated
+/// data, so `ad` is ignored for them.
+pub fn decrypt_ad(key: &Key, ciphertext: Vec<u8>, ad: &[u8]) -> CResult<Vec<u8>> {
     let deserialized = deserialize(ciphertext)?;
     let desc = &deserialized.desc;
     let nonce = &deserialized.nonce;
     let ciphertext = &deserialized.ciphertext;
-    let auth: Vec<u8> = serialize_header(&deserialized)?;
+    let mut auth: Vec<u8> = serialize_header(&deserialized)?;
+    if deserialized.version >= CRYPTO_VERSION_AD {
+        auth.extend_from_slice(ad);
+    }
     let decrypted = match SYM_ALGORITHM[desc.algorithm as usize] {
         "chacha20poly1305" => {
             low::chacha20poly1305::decrypt(key.data().as_slice(), nonce.as_slice(), auth.as_slice(), ciphertext.as_slice())?
@@ -226,16 +248,16 @@ pub fn decrypt(key: &Key, ciphertext: Vec<u8>) -> CResult<Vec<u8>> {
     Ok(decrypted)
 }
 
-/// Encrypt a message, given a key and the plaintext. This returns the
-/// ciphertext serialized via Turtl serialization format (see deserialize() for
-/// more info).
-///
-/// Note that this function *ONLY* supports encrypting the current crypto
-/// version (CRYPTO_VERSION). The idea is that later versions are most likely
-/// more secure or correct than earlier versions, so we just don't allow going
-/// back in time (although decrypt() supports all previous versions).
-pub fn encrypt(key: &Key, plaintext: Vec<u8>, op: CryptoOp) -> CResult<Vec<u8>> {
-    let version = CRYPTO_VERSION;
+/// Does the actual encryption for encrypt()/encrypt_ad(). The plaintext is
+/// zeroed once we're done with it, so callers can hand over copies of secret
+/// data (keys, etc) without leaving them lying around.
+fn encrypt_version(key: &Key, mut plaintext: Vec<u8>, op: CryptoOp, version: u16, ad: &[u8]) -> CResult<Vec<u8>> {
+    let res = encrypt_slice(key, plaintext.as_slice(), op, version, ad);
+    low::memzero(plaintext.as_mut_slice());
+    res
+}
+
+fn encrypt_slice(key: &Key, plaintext: &[u8], op: CryptoOp, version: u16, ad: &[u8]) -> CResult<Vec<u8>> {
     match op.algorithm {
         "chacha20poly1305" => {
             let nonce = match op.nonce {
@@ -244,8 +266,9 @@ pub fn encrypt(key: &Key, plaintext: Vec<u8>, op: CryptoOp) -> CResult<Vec<u8>>
             };
             let desc = PayloadDescription::new(version, op.algorithm)?;
             let mut data = CryptoData::new(version, desc, nonce, Vec::new());
-            let auth = serialize_header(&data)?;
-            data.ciphertext = low::chacha20poly1305::encrypt(key.data().as_slice(), data.nonce.as_slice(), auth.as_slice(), plaintext.as_slice())?;
+            let mut auth = serialize_header(&data)?;
+            auth.extend_from_slice(ad);
+            data.ciphertext = low::chacha20poly1305::encrypt(key.data().as_slice(), data.nonce.as_slice(), auth.as_slice(), plaintext)?;
             Ok(serialize(&mut data)?)
         }
         _ => {
@@ -254,6 +277,26 @@ pub fn encrypt(key: &Key, plaintext: Vec<u8>, op: CryptoOp) -> CResult<Vec<u8>>
     }
 }
 
+/// Encrypt a message, given a key and the plaintext. This returns the
+/// ciphertext serialized via Turtl serialization format (see deserialize() for
+/// more info).
+///
+/// Note that this function *ONLY* supports encrypting the current crypto
+/// version (CRYPTO_VERSION). The idea is that later versions are most likely
+/// more secure or correct than earlier versions, so we just don't allow going
+/// back in time (although decrypt() supports all previous versions).
+pub fn encrypt(key: &Key, plaintext: Vec<u8>, op: CryptoOp) -> CResult<Vec<u8>> {
+    encrypt_version(key, plaintext, op, CRYPTO_VERSION, &[])
+}
+
+/// Encrypt a message, binding it to a set of associated data. The associated
+/// data is *not* stored in the ciphertext: it's authenticated along with the
+/// header, and the exact same data must be handed to decrypt_ad() in order to
+/// open the message.
+pub fn encrypt_ad(key: &Key, plaintext: Vec<u8>, op: CryptoOp, ad: &[u8]) -> CResult<Vec<u8>> {
+    encrypt_version(key, plaintext, op, CRYPTO_VERSION_AD, ad)
+}
+
 /// Generate a key given a password and a salt
 pub fn gen_key(password: &[u8], salt: &[u8], cpu: usize, mem: usize) -> CResult<Key> {
     Ok(Key::new(low::gen_key(password, salt, cpu, mem)?))
@@ -310,6 +353,63 @@ pub mod asym {
     }
 }
 
+pub mod sign {
+    use ::crypto::key::Key;
+    use ::crypto::error::{CResult, CryptoError};
+    use ::crypto::low::sign as low_sign;
+
+    /// Stores our current signature version. This gets prepended to the
+    /// signature so we can change algorithms later on.
+    const SIGN_VERSION: u8 = 1;
+
+    /// Generate a signing keypair.
+    pub fn keygen() -> CResult<(Key, Key)> {
+        let (pk, sk) = low_sign::keygen()?;
+        Ok((Key::new(pk), Key::new(sk)))
+    }
+
+    /// Sign a message with our private signing key
+    pub fn sign(our_privkey: &Key, message: &[u8]) -> CResult<Vec<u8>> {
+        let mut signature = low_sign::sign(our_privkey.data().as_slice(), message)?;
+        let mut ser: Vec<u8> = Vec::with_capacity(signature.len() + 1);
+        ser.push(SIGN_VERSION);
+        ser.append(&mut signature);
+        Ok(ser)
+    }
+
+    /// Check a message's signature against someone's public signing key
+    pub fn verify(their_pubkey: &Key, message: &[u8], signature: &[u8]) -> CResult<bool> {
+        if signature.len() < 1 {
+            return Err(CryptoError::BadData(String::from("crypto::sign::verify() -- empty signature")));
+        }
+        match signature[0] {
+            1 => low_sign::verify(their_pubkey.data().as_slice(), message, &signature[1..]),
+            x => Err(CryptoError::NotImplemented(format!("crypto::sign::verify() -- found version {} (which is not implemented)", x))),
+        }
+    }
+}
+
+/// Generate a human-comparable fingerprint (or "safety number") for a set of
+/// public keys. Two people reading each other the same fingerprint means the
+/// keys in between them haven't been swapped out.
+///
+/// The format is six groups of five digits: "12345 67890 ..."
+pub fn fingerprint(pubkeys: &[&Key]) -> CResult<String> {
+    let mut data: Vec<u8> = Vec::from("turtl/fingerprint/v1".as_bytes());
+    for key in pubkeys {
+        data.extend(key.data().iter());
+    }
+    let hash = sha256(data.as_slice())?;
+    let groups = hash.chunks(5)
+        .take(6)
+        .map(|chunk| {
+            let num = chunk.iter().fold(0u64, |acc, &x| (acc << 8) + (x as u64));
+            format!("{:05}", num % 100000)
+        })
+        .collect::<Vec<_>>();
+    Ok(groups.join(" "))
+}
+
 #[cfg(test)]
 mod tests {
     //! Tests for our high-level Crypto module interface.
@@ -391,6 +491,28 @@ mod tests {
         assert_eq!(enc_str, "AAYBAAzGNuOg4N1zkQ2BlAiBbjNiYibICOs1NW18Jh/QfvdS+fR70+5kMnNCjXUSND05fU3m/FrcFZKPd3yQAl5gsP+4hWqkbWd+6/ip6HISeEz0NPBNTCWedSVgKYiEdnORSoiunl4l61vBmsyzQGnQl8fCYuerTLeGpq6j6Y5fBVmqmjWbmc5zeKqmg+LTfFUq9iNg5HoUPVKfjVm1aYlFG/fjMSk25j5zIgecFHAJOlQqtHXXPPCxwYLBoHBPsZE3kMu8jzE1QO8SAPOPyp2o3pD8fX1OhvqRHL/W34dqQzasmrscgvdvAy69l6nwbByOsjwvNSm2jWiNWGqFqxLgLXLy00r8A3E3hBDtQur4uo6Vs9ZSYn4mfLjEAyhyUsZeaoti8pKK5FVcJA9a//Blztbdmd8SPysXxks/6RvHIjy+aRCVxs/8Bw2Mv+AiSZ59dohNN4OUoVy3hNXk0RfdCDakw5AVq7xocAwmMLZeoWUgUt+Nb8ntt5W8KpfZVGMuxqIQoJoRMG7kf6TEHpL4vBOmosV0MwtLWkXwyXsx+zkP3GRw9mIcCkm5wEWpELYYzrOLmVQs4QHMetWsmyfTFOFlzVFPl7ctKlKuUOfbKETmrafvCNmoeOAWn58CXeEsD06ejrlg9zuPf5Vc3eIMSJ+EKIy8/eMLLFIDEzYkutqOfZoG6LJgevbgivLV7oXnG4kBF5pGVvwnpED4fTUFCFnc+MWATCN9aIJ58aLIdmF7TLYQwwXwNyyo9MvTJn/sEVjsbX/kpYrtknW1pjJ44e11du2Q5GpJXA4630g7BOOxooYTQgumoo/P3pPJnLjt9TJWPw7Q2h5rb2tqJowhltN19upncbOwMl1HPJcCqtOZOmttskMiDZGAjytiGOuD15TnfDUoZu3b97x0O6Nzm3RxGGBg4kQjC0q0RW0700EGGeCaiq9XAfUFIsS5XQ==");
     }
 
+    #[test]
+    fn binds_associated_data() {
+        let key = Key::random().unwrap();
+        let plain = String::from("get a job");
+        let enc = encrypt_ad(&key, Vec::from(plain.as_bytes()), CryptoOp::new("chacha20poly1305").unwrap(), b"note|1234").unwrap();
+        assert_eq!(&enc[0..2], &[0, 7]);
+        let dec = decrypt_ad(&key, enc.clone(), b"note|1234").unwrap();
+        assert_eq!(String::from_utf8(dec).unwrap(), plain);
+
+        // wrong (or missing) associated data should fail to authenticate
+        match decrypt_ad(&key, enc.clone(), b"note|5678") {
+            Err(CryptoError::Authentication(..)) => {}
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        assert!(decrypt(&key, enc).is_err());
+
+        // older payloads ignore associated data altogether
+        let enc = encrypt(&key, Vec::from(plain.as_bytes()), CryptoOp::new("chacha20poly1305").unwrap()).unwrap();
+        let dec = decrypt_ad(&key, enc, b"note|1234").unwrap();
+        assert_eq!(String::from_utf8(dec).unwrap(), plain);
+    }
+
     #[test]
     fn can_gen_random_keys() {
         // test a number of hashes
@@ -454,5 +576,22 @@ mod tests {
         let res = asym::decrypt(&her_pk, &her_sk, encrypted);
         assert!(res.is_err());
     }
+
+    #[test]
+    fn signs_and_fingerprints() {
+        let (pk, sk) = sign::keygen().unwrap();
+        let sig = sign::sign(&sk, b"invite|1234").unwrap();
+        assert_eq!(sig[0], 1);
+        assert!(sign::verify(&pk, b"invite|1234", sig.as_slice()).unwrap());
+        assert!(!sign::verify(&pk, b"invite|5678", sig.as_slice()).unwrap());
+
+        let (pk2, _sk2) = sign::keygen().unwrap();
+        let print1 = fingerprint(&[&pk]).unwrap();
+        let print2 = fingerprint(&[&pk2]).unwrap();
+        assert_eq!(print1.len(), 35);
+        assert_eq!(print1, fingerprint(&[&pk]).unwrap());
+        assert!(print1 != print2);
+        assert!(fingerprint(&[&pk, &pk2]).unwrap() != fingerprint(&[&pk2, &pk]).unwrap());
+    }
 }
 
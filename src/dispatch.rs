@@ -11,18 +11,23 @@
 use ::jedi::{self, Value};
 use ::error::{TResult, TError};
 use ::config;
-use ::util::{self, logger};
+use ::util::{self, logger, thumbnail};
 use ::turtl::Turtl;
 use ::search::Query;
+use ::trash;
+use ::reminders;
 use ::profile::{Profile, Export, ImportMode};
 use ::models::model::Model;
 use ::models::protected::Protected;
 use ::models::user::User;
 use ::models::space::Space;
 use ::models::space_member::SpaceMember;
-use ::models::note::Note;
+use ::models::note::{self, Note};
+use ::models::checklist;
+use ::models::revision;
+use ::models::template;
 use ::models::invite::{Invite, InviteRequest};
-use ::models::file::FileData;
+use ::models::file::{self, FileData};
 use ::models::sync_record::{SyncAction, SyncType, SyncRecord};
 use ::models::feedback::Feedback;
 use ::clippo::{self, CustomParser};
@@ -45,15 +50,21 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
         }
         "user:login-from-token" => {
             let token: String = jedi::get(&["2"], &data)?;
-            turtl.login_token(token)?;
+            if turtl.login_token(token)? {
+                // old-style token, so give the UI one that'll keep working
+                messaging::ui_event("user:login-token:replace", &User::get_login_token(turtl)?)?;
+            }
             let user_guard = lockr!(turtl.user);
             user_guard.data()
         }
         "user:login-from-saved" => {
             let user_id: String = jedi::get(&["2"], &data)?;
             let key: Key = jedi::get(&["3"], &data)?;
-            let token = User::restore_login(user_id, key)?;
-            turtl.login_token(token)?;
+            let token = User::restore_login(user_id, key.clone())?;
+            if turtl.login_token(token)? {
+                // old-style saved login, so re-save it with a new token
+                User::resave_login(turtl, &key)?;
+            }
             let user_guard = lockr!(turtl.user);
             user_guard.data()
         }
@@ -117,6 +128,17 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
             turtl.change_user_password(current_username, current_password, new_username, new_password)?;
             Ok(json!({}))
         }
+        "user:generate-recovery-key" => {
+            let recovery_key = User::generate_recovery_key(turtl)?;
+            Ok(Value::String(recovery_key))
+        }
+        "user:recover" => {
+            let username: String = jedi::get(&["2"], &data)?;
+            let recovery_key: String = jedi::get(&["3"], &data)?;
+            let new_password: String = jedi::get(&["4"], &data)?;
+            turtl.recover_account(username, recovery_key, new_password)?;
+            Ok(json!({}))
+        }
         "user:delete-account" => {
             messaging::ui_event("user:logout:clear-cookie", &Value::Null)
                 .unwrap_or_else(|e| error!("dispatch::dispatch() -- error sending ui event: {}", e));
@@ -139,11 +161,23 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
             let key = User::save_login(turtl)?;
             Ok(json!({"user_id": turtl.user_id()?, "key": key}))
         }
+        "user:revoke-saved-logins" => {
+            let user_id: String = match jedi::get_opt(&["2"], &data) {
+                Some(x) => x,
+                None => turtl.user_id()?,
+            };
+            User::revoke_logins(turtl, &user_id)?;
+            Ok(json!({}))
+        }
         "user:find-by-email" => {
             let email: String = jedi::get(&["2"], &data)?;
             let user = User::find_by_email(turtl, &email)?;
             Ok(jedi::to_val(&user)?)
         }
+        "user:get-fingerprint" => {
+            let user_guard = lockr!(turtl.user);
+            Ok(Value::String(user_guard.fingerprint()?))
+        }
         "app:connected" => {
             let connguard = lockr!(turtl.connected);
             let connected: bool = *connguard;
@@ -199,13 +233,37 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
             Ok(json!({}))
         }
         "sync:status" => {
-            Ok(Value::Bool(turtl.sync_running()))
+            Ok(jedi::to_val(&turtl.sync_status()?)?)
         }
         "sync:shutdown" => {
             let wait: bool = jedi::get_opt(&["2"], &data).unwrap_or(true);
             turtl.sync_shutdown(wait)?;
             Ok(json!({}))
         }
+        "sync:get-excluded-spaces" => {
+            let excluded = sync::selective::excluded_spaces(turtl)?;
+            Ok(jedi::to_val(&excluded)?)
+        }
+        "sync:set-excluded-spaces" => {
+            let excluded: Vec<String> = jedi::get(&["2"], &data)?;
+            sync::selective::set_excluded_spaces(turtl, excluded)?;
+            Ok(json!({}))
+        }
+        "sync:set-metered" => {
+            let metered: bool = jedi::get(&["2"], &data)?;
+            let mut sync_config_guard = lockw!(turtl.sync_config);
+            sync_config_guard.metered = metered;
+            Ok(json!({}))
+        }
+        "sync:get-download-settings" => {
+            let settings = sync::files::cache::get_download_settings(turtl)?;
+            Ok(jedi::to_val(&settings)?)
+        }
+        "sync:set-download-settings" => {
+            let settings = jedi::get(&["2"], &data)?;
+            sync::files::cache::set_download_settings(turtl, settings)?;
+            Ok(json!({}))
+        }
         "sync:get-pending" => {
             let pending = SyncRecord::get_all_pending(turtl)?;
             Ok(jedi::to_val(&pending)?)
@@ -223,10 +281,14 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
         "profile:load" => {
             let user_guard = lockr!(turtl.user);
             let profile_guard = lockr!(turtl.profile);
+            // trashed boards are listed via `profile:trash:list`
+            let boards = profile_guard.boards.iter()
+                .filter(|x| x.trashed.is_none())
+                .collect::<Vec<_>>();
             let profile_data = json!({
                 "user": &user_guard.as_ref(),
                 "spaces": &profile_guard.spaces,
-                "boards": &profile_guard.boards,
+                "boards": &boards,
                 "invites": &profile_guard.invites,
             });
             Ok(profile_data)
@@ -243,7 +305,17 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
             sync_record.action = action;
             sync_record.ty = ty;
             sync_record.data = Some(modeldata);
-            sync_model::dispatch(turtl, sync_record)
+            sync::journal::dispatch(turtl, sync_record)
+        }
+        "profile:history" => {
+            let limit: usize = jedi::get_opt(&["2"], &data).unwrap_or(50);
+            let history = sync::journal::history(turtl, limit)?;
+            Ok(jedi::to_val(&history)?)
+        }
+        "profile:undo" => {
+            let change_id: Option<String> = jedi::get_opt(&["2"], &data);
+            let change = sync::journal::undo(turtl, change_id)?;
+            Ok(jedi::to_val(&change)?)
         }
         "profile:space:set-owner" => {
             let space_id = jedi::get(&["2"], &data)?;
@@ -277,6 +349,18 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
             space.delete_member(turtl, &user_id)?;
             Ok(space.data()?)
         }
+        "profile:verify-member" => {
+            let space_id: String = jedi::get(&["2"], &data)?;
+            let user_id: String = jedi::get(&["3"], &data)?;
+            let fingerprint: String = jedi::get(&["4"], &data)?;
+            let mut profile_guard = lockw!(turtl.profile);
+            let space = match Profile::finder(&mut profile_guard.spaces, &space_id) {
+                Some(s) => s,
+                None => return TErr!(TError::MissingData(format!("couldn't find space {}", space_id))),
+            };
+            let verified = space.verify_member(turtl, &user_id, &fingerprint)?;
+            Ok(json!({"verified": verified}))
+        }
         "profile:space:leave" => {
             let space_id: String = jedi::get(&["2"], &data)?;
             let mut profile_guard = lockw!(turtl.profile);
@@ -290,11 +374,12 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
         "profile:space:send-invite" => {
             let req: InviteRequest = jedi::get(&["2"], &data)?;
             let mut profile_guard = lockw!(turtl.profile);
+            let claims = Space::member_claims(&profile_guard.spaces, &req.to_user);
             let space = match Profile::finder(&mut profile_guard.spaces, &req.space_id) {
                 Some(s) => s,
                 None => return TErr!(TError::MissingData(format!("couldn't find space {}", req.space_id))),
             };
-            space.send_invite(turtl, req)?;
+            space.send_invite(turtl, req, &claims)?;
             Ok(space.data()?)
         }
         "profile:space:edit-invite" => {
@@ -331,8 +416,10 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
         }
         "profile:get-notes" => {
             let note_ids = jedi::get(&["2"], &data)?;
-            let notes: Vec<Note> = turtl.load_notes(&note_ids)?;
-            Ok(jedi::to_val(&notes)?)
+            let mut notes: Vec<Note> = turtl.load_notes(&note_ids)?;
+            let val = jedi::to_val(&notes)?;
+            for note in notes.iter_mut() { note.wipe(); }
+            Ok(val)
         }
         "profile:find-notes" => {
             let qry: Query = match jedi::get(&["2"], &data) {
@@ -347,13 +434,15 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
             }
             let search = search_guard.as_ref().expect("turtl::dispatch::dispatch() -- profile:find-notes -- search_guard is none");
             let (note_ids, total) = search.find(&qry)?;
-            let notes: Vec<Note> = turtl.load_notes(&note_ids)?;
+            let mut notes: Vec<Note> = turtl.load_notes(&note_ids)?;
             let tags: Vec<(String, i32)> = search.find_tags(&qry)?;
-            Ok(json!({
+            let val = json!({
                 "notes": notes,
                 "tags": tags,
                 "total": total,
-            }))
+            });
+            for note in notes.iter_mut() { note.wipe(); }
+            Ok(val)
         }
         "profile:find-tags" => {
             let qry: Query = match jedi::get(&["2"], &data) {
@@ -372,13 +461,141 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
                 "tags": tags,
             }))
         }
+        "profile:note:download-file" => {
+            let note_id: String = jedi::get(&["2"], &data)?;
+            let file_id: Option<String> = jedi::get_opt(&["3"], &data);
+            let mut note = file::load_note(turtl, &note_id)?;
+            let attachment = file::find_attachment(&note, file_id.as_ref());
+            note.wipe();
+            sync::files::incoming::download_now(turtl, &note_id, &attachment?.id_or_else()?)?;
+            Ok(json!({}))
+        }
         "profile:note:get-file" => {
-            let note_id = jedi::get(&["2"], &data)?;
-            let notes: Vec<Note> = turtl.load_notes(&vec![note_id])?;
-            let bin = FileData::load_file(turtl, &notes[0])?;
-            let base64 = crypto::to_base64(&bin)?;
+            let note_id: String = jedi::get(&["2"], &data)?;
+            let file_id: Option<String> = jedi::get_opt(&["3"], &data);
+            let mut note = file::load_note(turtl, &note_id)?;
+            let bin = file::find_attachment(&note, file_id.as_ref())
+                .and_then(|attachment| FileData::load_file(turtl, &note, &attachment));
+            note.wipe();
+            let base64 = crypto::to_base64(&bin?)?;
             Ok(Value::String(base64))
         }
+        "profile:note:file:open" => {
+            let note_id: String = jedi::get(&["2"], &data)?;
+            let file_id: Option<String> = jedi::get_opt(&["3"], &data);
+            file::open_handle(turtl, &note_id, file_id.as_ref())
+        }
+        "profile:note:file:read" => {
+            let handle_id: String = jedi::get(&["2"], &data)?;
+            let offset: usize = jedi::get(&["3"], &data)?;
+            let length: usize = jedi::get(&["4"], &data)?;
+            let chunk = file::read_handle(turtl, &handle_id, offset, length)?;
+            Ok(json!({
+                "offset": offset,
+                "length": chunk.len(),
+                "data": crypto::to_base64(&chunk)?,
+            }))
+        }
+        "profile:note:file:close" => {
+            let handle_id: String = jedi::get(&["2"], &data)?;
+            file::close_handle(turtl, &handle_id)?;
+            Ok(json!({}))
+        }
+        "profile:note:file:export" => {
+            let note_id: String = jedi::get(&["2"], &data)?;
+            let file_id: Option<String> = jedi::get_opt(&["3"], &data);
+            let path: String = jedi::get(&["4"], &data)?;
+            let size = file::export(turtl, &note_id, file_id.as_ref(), &path)?;
+            Ok(json!({"size": size}))
+        }
+        "profile:note:checklist:toggle" => {
+            let note_id: String = jedi::get(&["2"], &data)?;
+            let item_id: String = jedi::get(&["3"], &data)?;
+            let done: Option<bool> = jedi::get_opt(&["4"], &data);
+            checklist::toggle(turtl, &note_id, &item_id, done)
+        }
+        "profile:note:get-thumbnail" => {
+            let note_id: String = jedi::get(&["2"], &data)?;
+            let file_id: Option<String> = jedi::get_opt(&["3"], &data);
+            let mut note = file::load_note(turtl, &note_id)?;
+            let thumb = file::find_attachment(&note, file_id.as_ref())
+                .and_then(|attachment| FileData::load_thumbnail(turtl, &note, &attachment));
+            note.wipe();
+            let thumb = thumb?;
+            Ok(json!({
+                "type": thumbnail::mime_type(&thumb),
+                "data": crypto::to_base64(&thumb)?,
+            }))
+        }
+        "profile:note:backlinks" => {
+            let note_id: String = jedi::get(&["2"], &data)?;
+            let mut notes = note::backlinks(turtl, &note_id)?;
+            let val = jedi::to_val(&notes)?;
+            for note in notes.iter_mut() { note.wipe(); }
+            Ok(val)
+        }
+        "profile:note:revisions" => {
+            let note_id: String = jedi::get(&["2"], &data)?;
+            let revisions = revision::list(turtl, &note_id)?;
+            Ok(jedi::to_val(&revisions)?)
+        }
+        "profile:note:get-revision" => {
+            let revision_id: String = jedi::get(&["2"], &data)?;
+            let compare_to: Option<String> = jedi::get_opt(&["3"], &data);
+            revision::get(turtl, &revision_id, compare_to)
+        }
+        "profile:note:restore-revision" => {
+            let revision_id: String = jedi::get(&["2"], &data)?;
+            revision::restore(turtl, &revision_id)
+        }
+        "profile:template:list" => {
+            let space_id: Option<String> = jedi::get_opt(&["2"], &data);
+            let templates = template::list(turtl, space_id.as_ref())?;
+            Ok(jedi::to_val(&templates)?)
+        }
+        "profile:template:get" => {
+            let template_id: String = jedi::get(&["2"], &data)?;
+            let template = template::get(turtl, &template_id)?;
+            Ok(jedi::to_val(&template)?)
+        }
+        "profile:template:save" => {
+            let modeldata: Value = jedi::get(&["2"], &data)?;
+            let mut sync_record = SyncRecord::default();
+            sync_record.action = match jedi::get_opt::<String>(&["id"], &modeldata) {
+                Some(_) => SyncAction::Edit,
+                None => SyncAction::Add,
+            };
+            sync_record.ty = SyncType::Template;
+            sync_record.data = Some(modeldata);
+            sync::journal::dispatch(turtl, sync_record)
+        }
+        "profile:template:delete" => {
+            let template_id: String = jedi::get(&["2"], &data)?;
+            let mut sync_record = SyncRecord::default();
+            sync_record.action = SyncAction::Delete;
+            sync_record.ty = SyncType::Template;
+            sync_record.data = Some(json!({"id": template_id}));
+            sync::journal::dispatch(turtl, sync_record)?;
+            Ok(json!({}))
+        }
+        "profile:note:create-from-template" => {
+            let template_id: String = jedi::get(&["2"], &data)?;
+            let options: Value = jedi::get_opt(&["3"], &data).unwrap_or(json!({}));
+            template::create_note(turtl, &template_id, &options)
+        }
+        "profile:trash:list" => {
+            trash::list(turtl)
+        }
+        "profile:trash:restore" => {
+            let ty: SyncType = jedi::get(&["2"], &data)?;
+            let item_id: String = jedi::get(&["3"], &data)?;
+            trash::restore(turtl, &ty, &item_id)?;
+            Ok(json!({}))
+        }
+        "profile:trash:empty" => {
+            trash::empty(turtl)?;
+            Ok(json!({}))
+        }
         "profile:export" => {
             let export = Profile::export(turtl)?;
             Ok(jedi::to_val(&export)?)
@@ -432,6 +649,20 @@ fn dispatch_event(cmd: &String, turtl: &Turtl, data: Value) -> TResult<()> {
         "sync:incoming" => {
             sync::incoming::process_incoming_sync(turtl)?;
         }
+        "reminders:check" => {
+            // this doesn't fail, so do it first rather than skip it when the
+            // check does
+            file::expire_handles(turtl);
+            reminders::check(turtl)?;
+        }
+        "trash:purge" => {
+            trash::purge_expired(turtl)?;
+        }
+        "file:downloaded" => {
+            let note_id: String = jedi::get(&["note_id"], &data)?;
+            let file_id: String = jedi::get(&["file_id"], &data)?;
+            file::extract_text(turtl, &note_id, &file_id)?;
+        }
         "user:edit" => {
             let mut user_guard = lockw!(turtl.user);
             user_guard.merge_fields(&data)?;
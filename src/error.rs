@@ -284,6 +284,7 @@ from_err!(::glob::GlobError);
 from_err!(::log::SetLoggerError);
 from_err!(::reqwest::Error);
 from_err!(::url::ParseError);
+from_err!(::image::ImageError);
 
 pub type BoxFuture<T, E> = Box<dyn (::futures::Future<Item = T, Error = E>) + Send>;
 pub type TResult<T> = Result<T, TError>;
@@ -14,6 +14,7 @@ extern crate futures;
 extern crate futures_cpupool;
 extern crate glob;
 extern crate hex;
+extern crate image;
 extern crate jedi;
 #[macro_use]
 extern crate lazy_static;
@@ -23,6 +24,7 @@ extern crate log;
 extern crate log_panics;
 extern crate migrate;
 extern crate num_cpus;
+extern crate pdf_extract;
 #[macro_use]
 extern crate protected_derive;
 #[macro_use]
@@ -54,6 +56,8 @@ mod models;
 mod profile;
 mod storage;
 mod search;
+mod trash;
+mod reminders;
 mod dispatch;
 mod schema;
 mod turtl;
@@ -86,6 +90,11 @@ pub fn init(config_str: String) -> TResult<()> {
         env::set_var("SSL_CERT_FILE", cert);
     }
 
+    match config::get::<Option<bool>>(&["crypto", "mlock_keys"]) {
+        Ok(Some(x)) => crypto::set_mlock(x),
+        _ => {}
+    }
+
     // create our data_folder
     let data_folder = config::get::<String>(&["data_folder"])?;
     if data_folder != ":memory:" {
@@ -432,6 +441,21 @@ pub mod c_api {
         unsafe { CString::from_raw(lasterr) };
         0
     }
+
+    #[no_mangle]
+    pub extern fn turtlc_set_device_key(key_bytes: *const u8, key_len: usize) -> i32 {
+        if key_bytes.is_null() || key_len == 0 {
+            ::models::user::set_host_device_key(None);
+            return 0;
+        }
+        if key_len != ::crypto::keylen() {
+            cerror!("turtlc_set_device_key() -- bad key length: {} (expected {})", key_len, ::crypto::keylen());
+            return -1;
+        }
+        let key_vec = unsafe { ::std::slice::from_raw_parts(key_bytes, key_len).to_vec() };
+        ::models::user::set_host_device_key(Some(::crypto::Key::new(key_vec)));
+        0
+    }
 }
 
 // -----------------------------------------------------------------------------
@@ -23,6 +23,14 @@ protected! {
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(public)]
         pub meta: Option<Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub version: Option<i64>,
+        /// When this was put in the trash (see `trash`), if it's in there.
+        /// Always serialized so taking it out of the trash sticks.
+        #[serde(default)]
+        #[protected_field(public)]
+        pub trashed: Option<i64>,
 
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(private)]
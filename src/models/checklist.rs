@@ -0,0 +1,168 @@
+//! Checklist notes hold a list of items (each with its own text, done state and
+//! optional due date) instead of free-form text. The items live in the note's
+//! private `items` field, so they're encrypted along with the rest of the note.
+
+use ::std::collections::HashSet;
+use ::jedi::Value;
+use ::error::{TResult, TError};
+use ::models::model::{self, Model};
+use ::models::note::Note;
+use ::models::space::Space;
+use ::models::file;
+use ::models::protected::Protected;
+use ::models::sync_record::SyncAction;
+use ::models::validate;
+use ::sync::sync_model;
+use ::turtl::Turtl;
+use ::lib_permissions::Permission;
+use ::time;
+
+/// The `type` checklist notes have
+pub const CHECKLIST_TYPE: &'static str = "checklist";
+
+/// One item on a checklist
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ChecklistItem {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub text: String,
+    #[serde(default)]
+    pub done: bool,
+    /// When this item is due (unix timestamp, seconds), if ever
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub due: Option<i64>,
+}
+
+/// Whether a note is a checklist
+pub fn is_checklist(note: &Note) -> bool {
+    note.type_.as_ref().map(|x| x == CHECKLIST_TYPE).unwrap_or(false)
+}
+
+/// Give any items the UI just added an id, so they can be toggled later
+pub fn assign_ids(items: &mut Vec<ChecklistItem>) -> TResult<()> {
+    for item in items.iter_mut() {
+        if item.id.is_none() { item.id = Some(model::cid()?); }
+    }
+    Ok(())
+}
+
+/// Check a checklist note's items. Called from `Validate for Note`.
+pub fn validate(note: &Note) -> Vec<(String, String)> {
+    let mut errors = Vec::new();
+    if !is_checklist(note) { return errors; }
+    let items = match note.items.as_ref() {
+        Some(x) => x,
+        None => {
+            errors.push(validate::entry("items", t!("Checklist notes need a list of items")));
+            return errors;
+        }
+    };
+    let mut seen = HashSet::new();
+    for item in items {
+        if item.text.trim() == "" {
+            errors.push(validate::entry("items", t!("Checklist items can't be empty")));
+            break;
+        }
+        if let Some(ref id) = item.id {
+            if !seen.insert(id) {
+                errors.push(validate::entry("items", t!("Checklist items must have unique ids")));
+                break;
+            }
+        }
+    }
+    errors
+}
+
+/// Tally up a note's items, for search. Returns (open items, done items, the
+/// soonest due date of the open items).
+pub fn counts(note: &Note) -> (i64, i64, Option<i64>) {
+    let items = match note.items.as_ref() {
+        Some(x) => x,
+        None => return (0, 0, None),
+    };
+    let open = items.iter().filter(|x| !x.done);
+    let next_due = open.clone().filter_map(|x| x.due).min();
+    let num_open = open.count() as i64;
+    (num_open, items.len() as i64 - num_open, next_due)
+}
+
+/// Mark one of a checklist's items done (or not). If `done` is None, the item
+/// is flipped. Only the item changes: everything else about the note stays as
+/// it's stored, so the UI doesn't have to send the whole note back. Returns
+/// the updated note.
+pub fn toggle(turtl: &Turtl, note_id: &String, item_id: &String, done: Option<bool>) -> TResult<Value> {
+    let mut note = file::load_note(turtl, note_id)?;
+    let res = toggle_in(turtl, &mut note, item_id, done);
+    note.wipe();
+    res
+}
+
+fn toggle_in(turtl: &Turtl, note: &mut Note, item_id: &String, done: Option<bool>) -> TResult<Value> {
+    Space::permission_check(turtl, &note.space_id, &Permission::EditNote)?;
+    {
+        let item = note.items.as_mut()
+            .and_then(|x| x.iter_mut().find(|i| i.id.as_ref() == Some(item_id)));
+        let item = match item {
+            Some(x) => x,
+            None => return TErr!(TError::NotFound(format!("item {} not found in note {:?}", item_id, note.id()))),
+        };
+        item.done = done.unwrap_or(!item.done);
+    }
+    note.mod_ = Some(time::get_time().sec as i64);
+    sync_model::save_model(SyncAction::Edit, turtl, note, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::jedi;
+    use ::models::validate::Validate;
+
+    #[test]
+    fn validates_items() {
+        let note: Note = jedi::from_val(json!({"space_id": "1234", "user_id": 51, "type": "checklist"})).unwrap();
+        assert_eq!(note.validate().len(), 1);
+        let note: Note = jedi::from_val(json!({
+            "space_id": "1234",
+            "user_id": 51,
+            "type": "checklist",
+            "items": [{"id": "1", "text": "get a job"}, {"id": "1", "text": "sir"}],
+        })).unwrap();
+        assert_eq!(note.validate().len(), 1);
+        let note: Note = jedi::from_val(json!({
+            "space_id": "1234",
+            "user_id": 51,
+            "type": "checklist",
+            "items": [{"id": "1", "text": "get a job"}, {"text": "  "}],
+        })).unwrap();
+        assert_eq!(note.validate().len(), 1);
+        let mut note: Note = jedi::from_val(json!({
+            "space_id": "1234",
+            "user_id": 51,
+            "type": "checklist",
+            "items": [{"text": "get a job", "due": 1500000000}, {"text": "sir"}],
+        })).unwrap();
+        assign_ids(note.items.as_mut().unwrap()).unwrap();
+        assert!(note.items.as_ref().unwrap().iter().all(|x| x.id.is_some()));
+        assert_eq!(note.validate().len(), 0);
+    }
+
+    #[test]
+    fn counts_items() {
+        let note: Note = jedi::from_val(json!({
+            "space_id": "1234",
+            "user_id": 51,
+            "type": "checklist",
+            "items": [
+                {"id": "1", "text": "eggs", "done": true, "due": 1400000000},
+                {"id": "2", "text": "ham", "due": 1600000000},
+                {"id": "3", "text": "toast", "due": 1500000000},
+                {"id": "4", "text": "jam"},
+            ],
+        })).unwrap();
+        assert_eq!(counts(&note), (3, 1, Some(1500000000)));
+        let note: Note = jedi::from_val(json!({"space_id": "1234", "user_id": 51, "type": "text"})).unwrap();
+        assert_eq!(counts(&note), (0, 0, None));
+    }
+}
@@ -1,16 +1,39 @@
-use ::jedi::Value;
+//! Notes can hold any number of attachments. Each attachment is described by
+//! an entry in the note's (private) `attachments` list, which holds its id,
+//! name, etc, and the key its file is encrypted with. The file itself lives on
+//! disk (`u_<user>.n_<note>.f_<attachment>.enc`) and syncs separately from the
+//! note, via `FileData`. Images also get a thumbnail, encrypted with the same
+//! key and kept next to the file (`u_<user>.n_<note>.f_<attachment>.thumb.enc`).
+//! Thumbnails don't sync: devices that download an image make their own.
+//!
+//! Files are encrypted in chunks (see `ChunkLayout`) so they can be read a
+//! piece at a time without decrypting the whole thing. Files from before that
+//! are a single message: they still decrypt, and get chunked the first time
+//! they're read in pieces.
+//!
+//! Notes used to hold a single file (`Note.file`), encrypted with the note's
+//! key and stored as `u_<user>.n_<note>.enc`. These are brought over as an
+//! attachment with the same id as the note (and no key of its own) by
+//! `migrate_legacy()`.
+
+use ::jedi::{self, Value};
 use ::error::{TResult, TError};
 use ::storage::Storage;
-use ::models::model::Model;
+use ::models::model::{self, Model};
 use ::models::protected::{Keyfinder, Protected};
 use ::models::note::Note;
+use ::models::space::Space;
 use ::models::sync_record::{SyncAction, SyncType, SyncRecord};
 use ::models::validate::Validate;
 use ::sync::sync_model::{self, SyncModel, MemorySaver};
+use ::sync::files::cache;
+use ::sync::conflict;
 use ::turtl::Turtl;
+use ::lib_permissions::Permission;
 use ::std::mem;
-use ::crypto;
-use ::util;
+use ::std::collections::HashMap;
+use ::crypto::{self, Key};
+use ::util::{self, extract, thumbnail};
 use ::std::fs;
 use ::std::io::prelude::*;
 use ::std::path::PathBuf;
@@ -21,9 +44,25 @@ pub fn file_folder() -> TResult<String> {
     util::file_folder(Some("files"))
 }
 
+/// Find the files in our file folder matching a (glob) filename
+fn glob_files(filename: &String) -> TResult<Vec<PathBuf>> {
+    let mut filepath = PathBuf::from(file_folder()?);
+    filepath.push(filename);
+    let pathstr = match filepath.to_str() {
+        Some(x) => x,
+        None => return TErr!(TError::BadValue(format!("invalid path: {:?}", filepath))),
+    };
+    let mut res = Vec::new();
+    for file in glob::glob(pathstr)? {
+        res.push(file?);
+    }
+    Ok(res)
+}
+
 protected! {
     /// Defines the object we find inside of Note.File (a description of the
-    /// note's file with no actual file data...name, mime type, etc).
+    /// note's file with no actual file data...name, mime type, etc). This is
+    /// the old single-file layout: new files are `Attachment`s.
     #[derive(Serialize, Deserialize)]
     pub struct File {
         #[serde(skip_serializing_if = "Option::is_none")]
@@ -42,12 +81,376 @@ protected! {
     }
 }
 
+/// Describes one of a note's attachments. These live in the note's private
+/// `attachments` field, so all of this is encrypted along with the note.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Attachment {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The (base64) key this attachment's file is encrypted with. Attachments
+    /// migrated from the single-file layout don't have one, and use the note's
+    /// key instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub ty: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<Value>,
+    /// The text inside the file (see `util::extract`), so search can find it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+impl Attachment {
+    /// Grab this attachment's id
+    pub fn id_or_else(&self) -> TResult<String> {
+        match self.id.as_ref() {
+            Some(x) => Ok(x.clone()),
+            None => TErr!(TError::MissingField(String::from("Attachment.id"))),
+        }
+    }
+
+    /// Grab the key this attachment's file is encrypted with
+    pub fn file_key(&self, note: &Note) -> TResult<Key> {
+        match self.key.as_ref() {
+            Some(x) => Ok(Key::new(crypto::from_base64(x)?)),
+            None => note.key_or_else(),
+        }
+    }
+}
+
+/// Pull the files the UI wants saved out of a note's data, so they don't end
+/// up in the note itself. New files ride along in their attachment's
+/// `filedata` field (or, from older UIs and exports, in `file.filedata`).
+/// Returns the files by attachment id: attachments that aren't in `current`
+/// get a new id here.
+pub fn take_uploads(data: &mut Value, current: &Vec<Attachment>) -> TResult<Vec<(String, FileData)>> {
+    let legacy: Option<FileData> = jedi::get_opt(&["file", "filedata"], data);
+    if let Some(filedata) = legacy {
+        // turn the old-style file into an attachment, keeping whatever
+        // attachments the note already has
+        let mut attachments: Vec<Value> = match jedi::get_opt(&["attachments"], data) {
+            Some(x) => x,
+            None => jedi::from_val(jedi::to_val(current)?)?,
+        };
+        let file: Value = jedi::get_opt(&["file"], data).unwrap_or(json!({}));
+        attachments.push(json!({
+            "name": file.get("name"),
+            "type": file.get("type"),
+            "meta": file.get("meta"),
+            "filedata": filedata,
+        }));
+        jedi::remove(&["file"], data)?;
+        jedi::set(&["attachments"], data, &attachments)?;
+    }
+
+    let mut uploads = Vec::new();
+    let attachments = match data.get_mut("attachments").and_then(|x| x.as_array_mut()) {
+        Some(x) => x,
+        None => return Ok(uploads),
+    };
+    for attachment in attachments.iter_mut() {
+        let filedata: Option<FileData> = jedi::get_opt(&["filedata"], attachment);
+        let filedata = match filedata {
+            Some(x) => x,
+            None => continue,
+        };
+        jedi::remove(&["filedata"], attachment)?;
+        let id: Option<String> = jedi::get_opt(&["id"], attachment);
+        let id = match id {
+            Some(ref x) if current.iter().any(|a| a.id.as_ref() == Some(x)) => x.clone(),
+            _ => model::cid()?,
+        };
+        jedi::set(&["id"], attachment, &id)?;
+        if let Some(ref data) = filedata.data {
+            jedi::set(&["size"], attachment, &(data.len() as u64))?;
+            if let Some((width, height)) = thumbnail::dimensions(data) {
+                let mut meta: Value = jedi::get_opt(&["meta"], attachment).unwrap_or(json!({}));
+                if !meta.is_object() { meta = json!({}); }
+                jedi::set(&["width"], &mut meta, &width)?;
+                jedi::set(&["height"], &mut meta, &height)?;
+                jedi::set(&["meta"], attachment, &meta)?;
+            }
+            let ty: Option<String> = jedi::get_opt(&["type"], attachment);
+            let name: Option<String> = jedi::get_opt(&["name"], attachment);
+            let text = match extract::extract(ty.as_ref(), name.as_ref(), data) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("file::take_uploads() -- problem extracting text from {:?}: {}", name, e);
+                    None
+                }
+            };
+            match text {
+                Some(text) => jedi::set(&["text"], attachment, &text)?,
+                None => jedi::remove(&["text"], attachment)?,
+            }
+        }
+        uploads.push((id, filedata));
+    }
+    Ok(uploads)
+}
+
+/// Reconcile the attachments the UI gave us for a note with the ones it has
+/// now. Existing attachments keep their keys (the UI can't change those), new
+/// ones (those being uploaded, in `new_ids`) get a fresh key, and anything else
+/// is an error. If the UI didn't give us any attachments, the note keeps what
+/// it has. Returns the note's attachments, and those that were removed.
+pub fn merge_attachments(given: Option<Vec<Attachment>>, current: Vec<Attachment>, new_ids: &Vec<String>) -> TResult<(Vec<Attachment>, Vec<Attachment>)> {
+    let given = match given {
+        Some(x) => x,
+        None => return Ok((current, Vec::new())),
+    };
+    let mut attachments = Vec::with_capacity(given.len());
+    for mut attachment in given {
+        let id = attachment.id_or_else()?;
+        match current.iter().find(|x| x.id.as_ref() == Some(&id)) {
+            Some(existing) => {
+                attachment.key = existing.key.clone();
+                if !new_ids.contains(&id) {
+                    attachment.size = existing.size.clone();
+                    if attachment.text.is_none() { attachment.text = existing.text.clone(); }
+                }
+            }
+            None => {
+                if !new_ids.contains(&id) {
+                    return TErr!(TError::NotFound(format!("attachment {} not found (and has no file)", id)));
+                }
+                attachment.key = Some(crypto::to_base64(Key::random()?.data())?);
+            }
+        }
+        attachments.push(attachment);
+    }
+    let removed = current.into_iter()
+        .filter(|x| !attachments.iter().any(|a| a.id == x.id))
+        .collect::<Vec<_>>();
+    Ok((attachments, removed))
+}
+
+/// Files are encrypted in chunks of this many bytes, each its own message, so
+/// a file can be decrypted a piece at a time (see `ChunkLayout`)
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// What chunked files start with. Files from before files were chunked are a
+/// single message, which starts with its (two-byte, and much smaller) crypto
+/// version instead.
+const CHUNKED_MAGIC: &'static [u8] = b"TCHK";
+
+/// The version of the chunked layout we write
+const CHUNKED_VERSION: u8 = 1;
+
+/// How long a chunked file's header is: magic, version, chunk size, encrypted
+/// chunk size, file size
+const CHUNKED_HEADER_LEN: usize = 4 + 1 + 4 + 4 + 8;
+
+/// Describes how a chunked file is laid out. The header is followed by the
+/// chunks, back to back. Every chunk but the last holds `chunk_size` bytes, so
+/// every chunk but the last is `enc_chunk_size` bytes once encrypted, which
+/// tells us where any chunk lives without reading the ones before it.
+///
+/// Each chunk is bound to its place in the file (and the number of chunks the
+/// file has), so chunks can't be shuffled around or the file cut short. Even
+/// empty files get a chunk, so a file can't be passed off as empty either.
+#[derive(Debug, Clone, PartialEq)]
+struct ChunkLayout {
+    chunk_size: usize,
+    enc_chunk_size: usize,
+    /// The size of the decrypted file
+    size: u64,
+}
+
+impl ChunkLayout {
+    /// Parse a file's header. Returns None if this isn't a chunked file.
+    fn parse(header: &[u8]) -> TResult<Option<ChunkLayout>> {
+        if header.len() < CHUNKED_HEADER_LEN || &header[0..4] != CHUNKED_MAGIC {
+            return Ok(None);
+        }
+        if header[4] != CHUNKED_VERSION {
+            return TErr!(TError::BadValue(format!("unknown chunked file version {}", header[4])));
+        }
+        let num = |from: usize, len: usize| header[from..(from + len)].iter().fold(0u64, |acc, x| (acc << 8) + (*x as u64));
+        let layout = ChunkLayout {
+            chunk_size: num(5, 4) as usize,
+            enc_chunk_size: num(9, 4) as usize,
+            size: num(13, 8),
+        };
+        if layout.chunk_size == 0 || layout.enc_chunk_size == 0 {
+            return TErr!(TError::BadValue(String::from("chunked file has empty chunks")));
+        }
+        Ok(Some(layout))
+    }
+
+    fn header(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(CHUNKED_HEADER_LEN);
+        header.extend_from_slice(CHUNKED_MAGIC);
+        header.push(CHUNKED_VERSION);
+        for &(x, len) in [(self.chunk_size as u64, 4), (self.enc_chunk_size as u64, 4), (self.size, 8)].iter() {
+            for i in (0..len).rev() { header.push(((x >> (i * 8)) & 0xFF) as u8); }
+        }
+        header
+    }
+
+    /// How many chunks the file has
+    fn count(&self) -> usize {
+        ::std::cmp::max(1, ((self.size + self.chunk_size as u64 - 1) / self.chunk_size as u64) as usize)
+    }
+
+    /// Where (start, end) a chunk sits in a file of the given (encrypted)
+    /// length
+    fn span(&self, i: usize, file_len: u64) -> TResult<(u64, u64)> {
+        let start = (CHUNKED_HEADER_LEN + i * self.enc_chunk_size) as u64;
+        let end = if i + 1 == self.count() { file_len } else { start + self.enc_chunk_size as u64 };
+        if i >= self.count() || end > file_len || start >= end {
+            return TErr!(TError::BadValue(format!("chunk {} is missing from file", i)));
+        }
+        Ok((start, end))
+    }
+
+    fn ad(&self, i: usize) -> String {
+        format!("{}/{}", i, self.count())
+    }
+
+    /// Decrypt one of the file's chunks
+    fn open_chunk(&self, key: &Key, i: usize, enc: Vec<u8>) -> TResult<Vec<u8>> {
+        let mut chunk = crypto::decrypt_ad(key, enc, self.ad(i).as_bytes())?;
+        let expected = ::std::cmp::min(self.size - (i * self.chunk_size) as u64, self.chunk_size as u64);
+        if chunk.len() as u64 != expected {
+            crypto::memzero(chunk.as_mut_slice());
+            return TErr!(TError::BadValue(format!("chunk {} is the wrong size", i)));
+        }
+        Ok(chunk)
+    }
+}
+
+/// Encrypt a file's data in chunks (see `ChunkLayout`)
+fn encrypt_file(key: &Key, data: &[u8]) -> TResult<Vec<u8>> {
+    let mut layout = ChunkLayout { chunk_size: CHUNK_SIZE, enc_chunk_size: 0, size: data.len() as u64 };
+    let mut chunks = Vec::with_capacity(layout.count());
+    for i in 0..layout.count() {
+        let start = i * CHUNK_SIZE;
+        let end = ::std::cmp::min(start + CHUNK_SIZE, data.len());
+        let op = crypto::CryptoOp::new("chacha20poly1305")?;
+        chunks.push(crypto::encrypt_ad(key, Vec::from(&data[start..end]), op, layout.ad(i).as_bytes())?);
+    }
+    layout.enc_chunk_size = chunks[0].len();
+    let mut enc = layout.header();
+    for chunk in chunks { enc.extend(chunk); }
+    Ok(enc)
+}
+
+/// Decrypt a whole file, chunked or not
+fn decrypt_file(key: &Key, enc: Vec<u8>) -> TResult<Vec<u8>> {
+    let layout = match ChunkLayout::parse(enc.as_slice())? {
+        Some(x) => x,
+        None => return Ok(crypto::decrypt(key, enc)?),
+    };
+    let mut data = Vec::with_capacity(layout.size as usize);
+    for i in 0..layout.count() {
+        let (start, end) = layout.span(i, enc.len() as u64)?;
+        let chunk = layout.open_chunk(key, i, Vec::from(&enc[(start as usize)..(end as usize)]));
+        let mut chunk = match chunk {
+            Ok(x) => x,
+            Err(e) => {
+                crypto::memzero(data.as_mut_slice());
+                return Err(e);
+            }
+        };
+        data.extend_from_slice(chunk.as_slice());
+        crypto::memzero(chunk.as_mut_slice());
+    }
+    Ok(data)
+}
+
+/// Reads a chunked file off disk, decrypting only the chunks it needs
+struct ChunkReader {
+    file: fs::File,
+    /// The length of the (encrypted) file
+    len: u64,
+    key: Key,
+    layout: ChunkLayout,
+}
+
+impl ChunkReader {
+    /// Open a file for reading. Returns None if the file isn't chunked.
+    fn open(path: &PathBuf, key: Key) -> TResult<Option<ChunkReader>> {
+        let mut file = fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        let mut header = vec![0; ::std::cmp::min(len, CHUNKED_HEADER_LEN as u64) as usize];
+        file.read_exact(header.as_mut_slice())?;
+        let layout = match ChunkLayout::parse(header.as_slice())? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        Ok(Some(ChunkReader { file: file, len: len, key: key, layout: layout }))
+    }
+
+    /// The size of the decrypted file
+    fn size(&self) -> u64 {
+        self.layout.size
+    }
+
+    fn chunk(&mut self, i: usize) -> TResult<Vec<u8>> {
+        let (start, end) = self.layout.span(i, self.len)?;
+        let mut enc = vec![0; (end - start) as usize];
+        self.file.seek(::std::io::SeekFrom::Start(start))?;
+        self.file.read_exact(enc.as_mut_slice())?;
+        self.layout.open_chunk(&self.key, i, enc)
+    }
+
+    /// Decrypt the whole file into `out`, a chunk at a time. Returns the
+    /// number of bytes written.
+    fn write_to<W: Write>(&mut self, out: &mut W) -> TResult<u64> {
+        let mut written = 0;
+        for i in 0..self.layout.count() {
+            let mut chunk = self.chunk(i)?;
+            let res = out.write_all(chunk.as_slice());
+            written += chunk.len() as u64;
+            crypto::memzero(chunk.as_mut_slice());
+            res?;
+        }
+        Ok(written)
+    }
+
+    /// Read a range of bytes out of the file. Reading past the end of the file
+    /// gives back an empty Vec.
+    fn read(&mut self, offset: u64, length: usize) -> TResult<Vec<u8>> {
+        let start = ::std::cmp::min(offset, self.layout.size);
+        let end = ::std::cmp::min(start + length as u64, self.layout.size);
+        let mut out = Vec::with_capacity((end - start) as usize);
+        if start == end { return Ok(out); }
+        let chunk_size = self.layout.chunk_size as u64;
+        for i in (start / chunk_size)..((end - 1) / chunk_size + 1) {
+            let mut chunk = self.chunk(i as usize)?;
+            let chunk_start = i * chunk_size;
+            let from = (::std::cmp::max(start, chunk_start) - chunk_start) as usize;
+            let to = (::std::cmp::min(end, chunk_start + chunk.len() as u64) - chunk_start) as usize;
+            out.extend_from_slice(&chunk[from..to]);
+            crypto::memzero(chunk.as_mut_slice());
+        }
+        Ok(out)
+    }
+}
+
 protected! {
     /// Defines the object that holds actual file body data separately from the
-    /// metadata that lives in the Note object.
+    /// metadata that lives in the Note object. Its id is the id of the
+    /// attachment it holds the file for.
     #[derive(Serialize, Deserialize)]
     #[protected_modeltype(file)]
     pub struct FileData {
+        /// The note this file is attached to. Files from the single-file days
+        /// don't have this set: their id is the note's id.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub note_id: Option<String>,
+        /// The size of the file (unencrypted)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub size: Option<u64>,
+
         #[serde(with = "::util::ser::base64_converter")]
         #[serde(skip_serializing_if = "Option::is_none")]
         #[serde(default)]
@@ -73,6 +476,9 @@ impl SyncModel for FileData {
             sync_record.generate_id()?;
             // change the type. heh heh, yes, very clever indeed...
             sync_record.ty = SyncType::FileIncoming;
+            // hang onto the note id/size so we know where the file goes (and
+            // whether our download policy wants it)
+            sync_record.data = Some(self.data_for_storage()?);
             // ...and queue the file for download in our incoming sync queue
             sync_record.db_save(db, None)?;
         }
@@ -80,22 +486,30 @@ impl SyncModel for FileData {
     }
 
     // remove the file
-    fn db_delete(&self, _db: &mut Storage, _sync_item: Option<&SyncRecord>) -> TResult<()> {
+    fn db_delete(&self, db: &mut Storage, _sync_item: Option<&SyncRecord>) -> TResult<()> {
         let id = self.id_or_else()?;
 
         // we could use FileData::file_finder here, but we actually do want to
-        // find ALL files with this note ID and remove them. just a paranoid
+        // find ALL files with this ID and remove them. just a paranoid
         // precaution.
-        let mut filepath = PathBuf::from(file_folder()?);
-        filepath.push(FileData::filebuilder(None, Some(&id)));
-        let pathstr = match filepath.to_str() {
-            Some(x) => x,
-            None => return TErr!(TError::BadValue(format!("invalid path: {:?}", filepath))),
-        };
-        let files = glob::glob(&pathstr)?;
-        for file in files {
-            fs::remove_file(&file?)?;
+        for file in FileData::file_finder_all(None, None, Some(&id))? {
+            fs::remove_file(&file)?;
+        }
+        // also clean up any half-finished downloads and thumbnails, and the
+        // file from the single-file days if it never got moved over (legacy
+        // files are named after their note, which is also their id)
+        let filename = FileData::filebuilder(None, None, Some(&id));
+        let extras = vec![
+            format!("{}.part", filename),
+            FileData::thumbbuilder(None, None, &id),
+            format!("u_*.n_{}.enc", id),
+        ];
+        for extra in extras {
+            for file in glob_files(&extra)? {
+                fs::remove_file(&file)?;
+            }
         }
+        cache::forget(db, &id)?;
         Ok(())
     }
 
@@ -125,6 +539,7 @@ impl SyncModel for FileData {
             SyncAction::Delete => {
                 sync_record.data = Some(json!({
                     "id": self.id().expect("turtl::FileData.outgoing() -- delete -- self.id() is None").clone(),
+                    "note_id": self.note_id,
                 }));
             }
             _ => {
@@ -141,15 +556,23 @@ impl MemorySaver for FileData {
         let action = sync_item.action.clone();
         match action {
             SyncAction::Delete => {
-                // unwrap is ok. we will always have an id. hopefully. no, but
-                // we will.
-                let note_id = self.id().expect("turtl::FileData::.mem_update() -- delete -- self.id() IS NONE AAARRRGGGGHGHHGHHH").clone();
-                let mut notes = turtl.load_notes(&vec![note_id.clone()])?;
+                // if the note still lists this attachment, take it out. we'll
+                // usually hear about the note edit that removed it anyway, but
+                // we can't count on it.
+                let id = self.id_or_else()?;
+                let note_id = self.owner_id()?;
+                let mut notes = turtl.load_notes(&vec![note_id])?;
                 if notes.len() == 0 { return Ok(()); }
                 let note = &mut notes[0];
-                note.has_file = false;
-                note.file = None;
-                sync_model::save_model(SyncAction::Edit, turtl, note, true)?;
+                let listed = note.attachments.as_ref()
+                    .map(|x| x.iter().any(|a| a.id.as_ref() == Some(&id)))
+                    .unwrap_or(false);
+                if listed {
+                    let attachments = note.attachments.take().unwrap_or(Vec::new());
+                    note.attachments = Some(attachments.into_iter().filter(|a| a.id.as_ref() != Some(&id)).collect());
+                    sync_model::save_model(SyncAction::Edit, turtl, note, true)?;
+                }
+                note.wipe();
             }
             _ => {}
         }
@@ -158,56 +581,134 @@ impl MemorySaver for FileData {
 }
 
 impl FileData {
-    /// Builds a standard filename
-    fn filebuilder(user_id: Option<&String>, note_id: Option<&String>) -> String {
+    /// Builds a standard filename. Leaving out the attachment id also matches
+    /// files from the single-file layout (`u_<user>.n_<note>.enc`).
+    fn filebuilder(user_id: Option<&String>, note_id: Option<&String>, file_id: Option<&String>) -> String {
         // wildcard, bitches. YEEEEEEEEHAWW!!!
+        let wildcard = String::from("*");
+        match file_id {
+            Some(file_id) => {
+                format!(
+                    "u_{}.n_{}.f_{}.enc",
+                    user_id.unwrap_or(&wildcard),
+                    note_id.unwrap_or(&wildcard),
+                    file_id,
+                )
+            }
+            None => {
+                format!(
+                    "u_{}.n_{}.*enc",
+                    user_id.unwrap_or(&wildcard),
+                    note_id.unwrap_or(&wildcard),
+                )
+            }
+        }
+    }
+
+    /// Builds a thumbnail's filename
+    fn thumbbuilder(user_id: Option<&String>, note_id: Option<&String>, file_id: &String) -> String {
         let wildcard = String::from("*");
         format!(
-            "u_{}.n_{}.enc",
+            "u_{}.n_{}.f_{}.thumb.enc",
             user_id.unwrap_or(&wildcard),
             note_id.unwrap_or(&wildcard),
+            file_id,
         )
     }
 
-    /// Find the PathBuf for a file, given the pieces that build the filename
-    pub fn file_finder_all(user_id: Option<&String>, note_id: Option<&String>) -> TResult<Vec<PathBuf>> {
-        let mut filepath = PathBuf::from(file_folder()?);
-        filepath.push(FileData::filebuilder(user_id, note_id));
-        let pathstr = match filepath.to_str() {
-            Some(x) => x,
-            None => return TErr!(TError::BadValue(format!("invalid path: {:?}", filepath))),
-        };
-        let files = glob::glob(pathstr)?;
-        let mut res = Vec::new();
-        for file in files {
-            res.push(file?);
+    /// Grab the id of the note this file belongs to
+    pub fn owner_id(&self) -> TResult<String> {
+        match self.note_id.as_ref() {
+            Some(x) => Ok(x.clone()),
+            None => self.id_or_else(),
         }
-        Ok(res)
+    }
+
+    /// Grab the id of the note the file a sync record is for belongs to
+    pub fn sync_note_id(sync: &SyncRecord) -> String {
+        sync.data.as_ref()
+            .and_then(|x| jedi::get_opt(&["note_id"], x))
+            .unwrap_or(sync.item_id.clone())
+    }
+
+    /// Grab the API endpoint for one of a note's files. Files brought over from
+    /// the single-file days (the ones with the same id as their note) still
+    /// live at the note's old `attachment` endpoint.
+    pub fn attachment_url(note_id: &String, file_id: &String) -> String {
+        if note_id == file_id {
+            format!("/notes/{}/attachment", note_id)
+        } else {
+            format!("/notes/{}/attachments/{}", note_id, file_id)
+        }
+    }
+
+    /// Find the PathBuf for a file, given the pieces that build the filename
+    pub fn file_finder_all(user_id: Option<&String>, note_id: Option<&String>, file_id: Option<&String>) -> TResult<Vec<PathBuf>> {
+        glob_files(&FileData::filebuilder(user_id, note_id, file_id))
+    }
+
+    /// Find everything we keep on disk for a user and/or note: files,
+    /// thumbnails, and partial downloads
+    pub fn local_files(user_id: Option<&String>, note_id: Option<&String>) -> TResult<Vec<PathBuf>> {
+        let mut files = FileData::file_finder_all(user_id, note_id, None)?;
+        let partials = format!("{}.part", FileData::filebuilder(user_id, note_id, Some(&String::from("*"))));
+        files.append(&mut glob_files(&partials)?);
+        Ok(files)
     }
 
     /// Find the PathBuf for a file, given the pieces that build the filename
-    pub fn file_finder(user_id: Option<&String>, note_id: Option<&String>) -> TResult<PathBuf> {
-        let mut files = FileData::file_finder_all(user_id, note_id)?;
+    pub fn file_finder(user_id: Option<&String>, note_id: Option<&String>, file_id: Option<&String>) -> TResult<PathBuf> {
+        let mut files = FileData::file_finder_all(user_id, note_id, file_id)?;
         if files.len() < 1 {
             return TErr!(TError::NotFound(format!("file not found")));
         }
         Ok(files.swap_remove(0))
     }
 
-    /// Given a user_id/note_id, return the PathBuf to a location the file
-    /// should be saved.
-    pub fn new_file(user_id: &String, note_id: &String) -> TResult<PathBuf> {
+    /// Given a user_id/note_id/file_id, return the PathBuf to a location the
+    /// file should be saved.
+    pub fn new_file(user_id: &String, note_id: &String, file_id: &String) -> TResult<PathBuf> {
+        let mut filepath = PathBuf::from(file_folder()?);
+        filepath.push(FileData::filebuilder(Some(user_id), Some(note_id), Some(file_id)));
+        Ok(filepath)
+    }
+
+    /// Given a user_id/note_id/file_id, return the PathBuf we download a file
+    /// into before it's complete (see `sync::files::incoming`)
+    pub fn partial_file(user_id: &String, note_id: &String, file_id: &String) -> TResult<PathBuf> {
+        let mut filepath = PathBuf::from(file_folder()?);
+        filepath.push(format!("{}.part", FileData::filebuilder(Some(user_id), Some(note_id), Some(file_id))));
+        Ok(filepath)
+    }
+
+    /// Given a user_id/note_id/file_id, return the PathBuf to the file's
+    /// thumbnail
+    pub fn thumbnail_file(user_id: &String, note_id: &String, file_id: &String) -> TResult<PathBuf> {
+        let mut filepath = PathBuf::from(file_folder()?);
+        filepath.push(FileData::thumbbuilder(Some(user_id), Some(note_id), file_id));
+        Ok(filepath)
+    }
+
+    /// Where a note's file lived in the single-file layout
+    fn legacy_file(user_id: &String, note_id: &String) -> TResult<PathBuf> {
         let mut filepath = PathBuf::from(file_folder()?);
-        filepath.push(FileData::filebuilder(Some(user_id), Some(note_id)));
+        filepath.push(format!("u_{}.n_{}.enc", user_id, note_id));
         Ok(filepath)
     }
 
-    /// Load a note's file, if we have one.
-    pub fn load_file(turtl: &Turtl, note: &Note) -> TResult<Vec<u8>> {
+    /// Load one of a note's attachments, if we have it.
+    pub fn load_file(turtl: &Turtl, note: &Note, attachment: &Attachment) -> TResult<Vec<u8>> {
         let note_id = note.id_or_else()?;
-        let note_key = note.key_or_else()?;
+        let file_id = attachment.id_or_else()?;
+        let file_key = attachment.file_key(note)?;
 
-        let filename = FileData::file_finder(None, Some(&note_id))?;
+        let filename = FileData::file_finder(None, Some(&note_id), Some(&file_id))?;
+        {
+            let db_guard = lock!(turtl.db);
+            if let Some(db) = db_guard.as_ref() {
+                cache::touch(db, &file_id)?;
+            }
+        }
         let enc = {
             let mut file = fs::File::open(filename)?;
             let mut enc = Vec::new();
@@ -215,25 +716,98 @@ impl FileData {
             enc
         };
 
-        // decrypt the file using the turtl standard serialization format
-        let data = turtl.work.run(move || {
-            crypto::decrypt(&note_key, enc)
-                .map_err(|e| From::from(e))
+        let data = turtl.work.run(move || decrypt_file(&file_key, enc))?;
+        Ok(data)
+    }
+
+    /// Open one of a note's attachments for reading a chunk at a time (see
+    /// `ChunkReader`). Files from before files were chunked get chunked (in
+    /// place) first.
+    fn open_chunked(turtl: &Turtl, note: &Note, attachment: &Attachment) -> TResult<ChunkReader> {
+        let note_id = note.id_or_else()?;
+        let file_id = attachment.id_or_else()?;
+        let file_key = attachment.file_key(note)?;
+
+        let filename = FileData::file_finder(None, Some(&note_id), Some(&file_id))?;
+        {
+            let db_guard = lock!(turtl.db);
+            if let Some(db) = db_guard.as_ref() {
+                cache::touch(db, &file_id)?;
+            }
+        }
+        if let Some(reader) = ChunkReader::open(&filename, file_key.clone())? {
+            return Ok(reader);
+        }
+
+        info!("FileData::open_chunked() -- chunking file {}", file_id);
+        let enc = fs::read(&filename)?;
+        let key = file_key.clone();
+        let chunked = turtl.work.run(move || -> TResult<Vec<u8>> {
+            let mut data = crypto::decrypt(&key, enc)?;
+            let res = encrypt_file(&key, data.as_slice());
+            crypto::memzero(data.as_mut_slice());
+            res
         })?;
+        // write it next to the file (under a name our globs still pick up)
+        // and swap it in once it's all there
+        let tmp = filename.with_extension("chunking.enc");
+        fs::write(&tmp, chunked.as_slice())?;
+        fs::rename(&tmp, &filename)?;
+        match ChunkReader::open(&filename, file_key)? {
+            Some(x) => Ok(x),
+            None => TErr!(TError::BadValue(format!("file {} didn't chunk", file_id))),
+        }
+    }
 
-        Ok(data)
+    /// Load/decrypt the thumbnail for one of a note's (image) attachments. If
+    /// we have the file but no thumbnail (ie, we downloaded the file from
+    /// another device) we make one on the spot.
+    pub fn load_thumbnail(turtl: &Turtl, note: &Note, attachment: &Attachment) -> TResult<Vec<u8>> {
+        let user_id = turtl.user_id()?;
+        let note_id = note.id_or_else()?;
+        let file_id = attachment.id_or_else()?;
+        let file_key = attachment.file_key(note)?;
+
+        let thumbpath = FileData::thumbnail_file(&user_id, &note_id, &file_id)?;
+        if thumbpath.exists() {
+            let enc = {
+                let mut file = fs::File::open(&thumbpath)?;
+                let mut enc = Vec::new();
+                file.read_to_end(&mut enc)?;
+                enc
+            };
+            return turtl.work.run(move || {
+                crypto::decrypt(&file_key, enc)
+                    .map_err(|e| From::from(e))
+            });
+        }
+
+        let data = FileData::load_file(turtl, note, attachment)?;
+        let (thumb, enc_thumb) = turtl.work.run(move || -> TResult<(Vec<u8>, Vec<u8>)> {
+            let thumb = match thumbnail::generate(&data)? {
+                Some(x) => x,
+                None => return TErr!(TError::BadValue(String::from("attachment isn't an image"))),
+            };
+            let enc = crypto::encrypt(&file_key, thumb.clone(), crypto::CryptoOp::new("chacha20poly1305")?)?;
+            Ok((thumb, enc))
+        })?;
+        let mut fs_thumb = fs::File::create(&thumbpath)?;
+        fs_thumb.write_all(enc_thumb.as_slice())?;
+        Ok(thumb)
     }
 
-    /// Encrypt/save this file
-    pub fn save(&mut self, turtl: &Turtl, note: &mut Note) -> TResult<()> {
-        // grab some items we'll need to do our work (user_id/note_id for the
-        // filename, note_key for encrypting the file).
+    /// Encrypt/save this file as the given attachment of a note
+    pub fn save(&mut self, turtl: &Turtl, note: &Note, attachment: &Attachment) -> TResult<()> {
+        // grab some items we'll need to do our work (user_id/note_id/file_id
+        // for the filename, the attachment's key for encrypting the file).
         let user_id = turtl.user_id()?;
         let note_id = note.id_or_else()?;
-        let note_key = note.key_or_else()?;
+        let file_id = attachment.id_or_else()?;
+        let file_key = attachment.file_key(note)?;
 
-        // the file id should ref the note
-        self.id = Some(note_id.clone());
+        // the file id should ref the attachment (and its note)
+        self.id = Some(file_id.clone());
+        self.note_id = Some(note_id.clone());
 
         // rip the `data` field out of the FileData object
         let mut data: Option<Vec<u8>> = None;
@@ -244,19 +818,46 @@ impl FileData {
             Some(x) => x,
             None => return TErr!(TError::MissingField(format!("FileData.data"))),
         };
+        self.size = Some(data.len() as u64);
 
-        // encrypt the file using the turtl standard serialization format
-        let enc = turtl.work.run(move || {
-            crypto::encrypt(&note_key, data, crypto::CryptoOp::new("chacha20poly1305")?)
-                .map_err(|e| From::from(e))
+        // encrypt the file (in chunks, see `ChunkLayout`) and if it's an
+        // image, its thumbnail
+        let (enc, enc_thumb) = turtl.work.run(move || -> TResult<(Vec<u8>, Option<Vec<u8>>)> {
+            let thumb = match thumbnail::generate(&data) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("FileData.save() -- problem generating thumbnail: {}", e);
+                    None
+                }
+            };
+            let mut data = data;
+            let enc = encrypt_file(&file_key, data.as_slice());
+            crypto::memzero(data.as_mut_slice());
+            let enc = enc?;
+            let enc_thumb = match thumb {
+                Some(thumb) => Some(crypto::encrypt(&file_key, thumb, crypto::CryptoOp::new("chacha20poly1305")?)?),
+                None => None,
+            };
+            Ok((enc, enc_thumb))
         })?;
 
         // now, save the encrypted file data to disk
         let mut filepath = PathBuf::from(file_folder()?);
         util::create_dir(&filepath)?;
-        filepath.push(FileData::filebuilder(Some(&user_id), Some(&note_id)));
+        filepath.push(FileData::filebuilder(Some(&user_id), Some(&note_id), Some(&file_id)));
         let mut fs_file = fs::File::create(&filepath)?;
         fs_file.write_all(enc.as_slice())?;
+        let thumbpath = FileData::thumbnail_file(&user_id, &note_id, &file_id)?;
+        match enc_thumb {
+            Some(enc_thumb) => {
+                let mut fs_thumb = fs::File::create(&thumbpath)?;
+                fs_thumb.write_all(enc_thumb.as_slice())?;
+            }
+            None => {
+                // don't leave a thumbnail for a file we just overwrote
+                if thumbpath.exists() { fs::remove_file(&thumbpath)?; }
+            }
+        }
 
         // phew, now that all went smoothly, create a sync record for the saved
         // file (which will let the sync system know to upload our heroic file)
@@ -288,6 +889,288 @@ impl FileData {
         }
         Ok(())
     }
+
+    /// Delete one of a note's files (locally and on the API). This doesn't
+    /// touch the note: see `remove()` for that.
+    pub fn delete_file(turtl: &Turtl, note_id: &String, file_id: &String) -> TResult<()> {
+        let user_id = turtl.user_id()?;
+        let mut file = FileData::new();
+        file.id = Some(file_id.clone());
+        file.note_id = Some(note_id.clone());
+        with_db!{ db, turtl.db, file.outgoing(SyncAction::Delete, &user_id, db, false)? };
+        Ok(())
+    }
+}
+
+/// Grab a (decrypted) note by id
+pub fn load_note(turtl: &Turtl, note_id: &String) -> TResult<Note> {
+    let mut notes = turtl.load_notes(&vec![note_id.clone()])?;
+    if notes.len() == 0 {
+        return TErr!(TError::NotFound(format!("note {} not found", note_id)));
+    }
+    Ok(notes.swap_remove(0))
+}
+
+/// Find one of a note's attachments by id, or if None, its first attachment
+pub fn find_attachment(note: &Note, attachment_id: Option<&String>) -> TResult<Attachment> {
+    let found = note.attachments.as_ref()
+        .and_then(|x| {
+            x.iter()
+                .find(|a| attachment_id.map(|id| a.id.as_ref() == Some(id)).unwrap_or(true))
+                .cloned()
+        });
+    match found {
+        Some(x) => Ok(x),
+        None => TErr!(TError::NotFound(format!("attachment {:?} not found in note {:?}", attachment_id, note.id()))),
+    }
+}
+
+/// Remove an attachment (or if None, all attachments) from a note, deleting
+/// the file(s) that go with it.
+pub fn remove(turtl: &Turtl, note_id: &String, attachment_id: Option<&String>) -> TResult<()> {
+    let mut note = load_note(turtl, note_id)?;
+    let note = &mut note;
+    Space::permission_check(turtl, &note.space_id, &Permission::EditNote)?;
+    let (removed, kept): (Vec<Attachment>, Vec<Attachment>) = note.attachments.take()
+        .unwrap_or(Vec::new())
+        .into_iter()
+        .partition(|x| attachment_id.map(|id| x.id.as_ref() == Some(id)).unwrap_or(true));
+    if attachment_id.is_some() && removed.len() == 0 {
+        note.wipe();
+        return TErr!(TError::NotFound(format!("attachment {} not found in note {}", attachment_id.unwrap_or(&String::new()), note_id)));
+    }
+    note.attachments = Some(kept);
+    sync_model::save_model(SyncAction::Edit, turtl, note, false)?;
+    note.wipe();
+    for attachment in removed {
+        FileData::delete_file(turtl, note_id, &attachment.id_or_else()?)?;
+    }
+    Ok(())
+}
+
+/// Pull the text out of one of a note's files and save it into the note so
+/// search can find it. The device that uploads a file normally does this, so
+/// this only does anything for files that came from clients that didn't (or
+/// couldn't) and that we know how to read.
+pub fn extract_text(turtl: &Turtl, note_id: &String, attachment_id: &String) -> TResult<()> {
+    let mut note = load_note(turtl, note_id)?;
+    let res = extract_text_into(turtl, &mut note, attachment_id);
+    note.wipe();
+    res
+}
+
+fn extract_text_into(turtl: &Turtl, note: &mut Note, attachment_id: &String) -> TResult<()> {
+    // notes still on the single-file layout don't have anywhere to put it
+    let attachment = match find_attachment(note, Some(attachment_id)) {
+        Ok(x) => x,
+        Err(_) => return Ok(()),
+    };
+    if attachment.text.is_some() || !extract::can_extract(attachment.ty.as_ref(), attachment.name.as_ref()) {
+        return Ok(());
+    }
+    let data = FileData::load_file(turtl, note, &attachment)?;
+    // if we can't read it, say so with empty text so we don't keep trying
+    let text = match extract::extract(attachment.ty.as_ref(), attachment.name.as_ref(), &data) {
+        Ok(x) => x.unwrap_or(String::new()),
+        Err(e) => {
+            warn!("file::extract_text() -- problem extracting text from {}: {}", attachment_id, e);
+            String::new()
+        }
+    };
+    // extracting can take a while. if the note changed in the meantime (an
+    // edit here, or another device's copy coming in) we leave it be rather
+    // than push an edit on top of a version we never saw.
+    let mut current = load_note(turtl, &note.id_or_else()?)?;
+    let changed = conflict::version(&current)? != conflict::version(note)?;
+    current.wipe();
+    if changed {
+        info!("file::extract_text() -- note {:?} changed while extracting text from {}, skipping", note.id(), attachment_id);
+        return Ok(());
+    }
+    if let Some(ref mut attachments) = note.attachments {
+        for x in attachments.iter_mut() {
+            if x.id.as_ref() == Some(attachment_id) { x.text = Some(text.clone()); }
+        }
+    }
+    // if we can't edit the note, we still keep the text for ourselves
+    let can_edit = Space::permission_check(turtl, &note.space_id, &Permission::EditNote).is_ok();
+    sync_model::save_model(SyncAction::Edit, turtl, note, !can_edit)?;
+    Ok(())
+}
+
+/// The most we'll hand back from a file handle in one read
+const MAX_READ: usize = 1024 * 1024;
+
+/// The most file handles we'll keep open at once
+const MAX_HANDLES: usize = 8;
+
+/// How long (in seconds) a handle can go without being read before we close it
+const HANDLE_TIMEOUT: i64 = 300;
+
+fn now() -> i64 {
+    ::time::get_time().sec as i64
+}
+
+/// A file the UI is reading in pieces (see `open_handle()`), so big files
+/// don't have to cross over to the UI in one giant message.
+///
+/// The file stays encrypted on disk while it's open: reads only decrypt the
+/// chunks they need (see `ChunkLayout`).
+pub struct FileHandle {
+    reader: ChunkReader,
+    /// When this handle was last used (unix timestamp, seconds)
+    used: i64,
+}
+
+impl FileHandle {
+    /// Read a range of bytes out of the file (at most `MAX_READ`). Reading
+    /// past the end of the file gives back an empty chunk.
+    fn read(&mut self, offset: usize, length: usize) -> TResult<Vec<u8>> {
+        self.used = now();
+        self.reader.read(offset as u64, ::std::cmp::min(length, MAX_READ))
+    }
+}
+
+/// Close any file handles that haven't been used in a while
+pub fn expire_handles(turtl: &Turtl) {
+    expire(&mut lock!(turtl.file_handles));
+}
+
+fn expire(handles: &mut HashMap<String, FileHandle>) {
+    let cutoff = now() - HANDLE_TIMEOUT;
+    handles.retain(|_, handle| handle.used > cutoff);
+}
+
+/// Open one of a note's files so the UI can read it in ranges via
+/// `read_handle()`. Returns the handle's id along with the file's
+/// size/name/type. Call `close_handle()` when done (handles that sit unused
+/// are closed for you after a while).
+pub fn open_handle(turtl: &Turtl, note_id: &String, attachment_id: Option<&String>) -> TResult<Value> {
+    let mut note = load_note(turtl, note_id)?;
+    let res = find_attachment(&note, attachment_id)
+        .and_then(|attachment| {
+            let reader = FileData::open_chunked(turtl, &note, &attachment)?;
+            Ok((attachment, reader))
+        });
+    note.wipe();
+    let (mut attachment, reader) = res?;
+    if let Some(ref mut x) = attachment.key { util::wipe_string(x); }
+    let handle_id = model::cid()?;
+    let info = json!({
+        "handle": handle_id,
+        "size": reader.size(),
+        "name": attachment.name,
+        "type": attachment.ty,
+    });
+    let handle = FileHandle { reader: reader, used: now() };
+    // check and take our spot in one go, so handles opened at the same time
+    // can't all squeeze in under the limit
+    let mut handles = lock!(turtl.file_handles);
+    expire(&mut handles);
+    if handles.len() >= MAX_HANDLES {
+        return TErr!(TError::Msg(format!("too many open file handles (max {})", MAX_HANDLES)));
+    }
+    handles.insert(handle_id, handle);
+    Ok(info)
+}
+
+/// Read a range of bytes from an open file handle (at most `MAX_READ` at a
+/// time). Reading past the end of the file gives back an empty chunk.
+pub fn read_handle(turtl: &Turtl, handle_id: &String, offset: usize, length: usize) -> TResult<Vec<u8>> {
+    expire_handles(turtl);
+    let mut handles = lock!(turtl.file_handles);
+    match handles.get_mut(handle_id) {
+        Some(x) => x.read(offset, length),
+        None => TErr!(TError::NotFound(format!("file handle {} not found", handle_id))),
+    }
+}
+
+/// Close a file handle
+pub fn close_handle(turtl: &Turtl, handle_id: &String) -> TResult<()> {
+    match lock!(turtl.file_handles).remove(handle_id) {
+        Some(_) => Ok(()),
+        None => TErr!(TError::NotFound(format!("file handle {} not found", handle_id))),
+    }
+}
+
+/// Decrypt one of a note's files straight to the given path (ie, wherever the
+/// user wants it saved), a chunk at a time. Returns the number of bytes
+/// written.
+pub fn export(turtl: &Turtl, note_id: &String, attachment_id: Option<&String>, path: &String) -> TResult<u64> {
+    let mut note = load_note(turtl, note_id)?;
+    let reader = find_attachment(&note, attachment_id)
+        .and_then(|attachment| FileData::open_chunked(turtl, &note, &attachment));
+    note.wipe();
+    let mut reader = reader?;
+    let mut file = fs::File::create(path)?;
+    let res = reader.write_to(&mut file);
+    drop(file);
+    // don't leave half a file lying around
+    if res.is_err() {
+        if let Err(e) = fs::remove_file(path) {
+            warn!("file::export() -- problem removing partial export {}: {}", path, e);
+        }
+    }
+    res
+}
+
+/// Where we keep the ids of the notes `migrate_legacy()` has already seen
+const MIGRATED_KEY: &'static str = "files:migrated";
+
+/// Bring notes over from the single-file layout: a note's `file` becomes an
+/// attachment (with the note's id, and no key of its own) and its file moves
+/// to where attachments live. Notes that have an `attachments` list (even an
+/// empty one) have already been brought over. The old `file` field is left in
+/// place for older clients, so we remember which notes we've already looked
+/// at (otherwise we'd decrypt every one of them on every login).
+pub fn migrate_legacy(turtl: &Turtl) -> TResult<()> {
+    let user_id = turtl.user_id()?;
+    let mut migrated: Vec<String> = match with_db!{ db, turtl.db, db.kv_get(MIGRATED_KEY)? } {
+        Some(x) => jedi::parse(&x).unwrap_or(Vec::new()),
+        None => Vec::new(),
+    };
+    let note_ids = {
+        let notes: Vec<Note> = with_db!{ db, turtl.db, db.all(Note::tablename())? };
+        notes.into_iter()
+            .filter(|x| x.file.is_some())
+            .filter_map(|x| x.id().cloned())
+            .filter(|x| !migrated.contains(x))
+            .collect::<Vec<_>>()
+    };
+    if note_ids.len() == 0 { return Ok(()); }
+    for mut note in turtl.load_notes(&note_ids)? {
+        let note_id = note.id_or_else()?;
+        if note.attachments.is_some() {
+            migrated.push(note_id);
+            note.wipe();
+            continue;
+        }
+        let attachment = match note.file.as_ref() {
+            Some(file) => Attachment {
+                id: Some(note_id.clone()),
+                key: None,
+                name: file.name.clone(),
+                ty: file.ty.clone(),
+                size: file.size.clone(),
+                meta: file.meta.clone(),
+                text: None,
+            },
+            None => continue,
+        };
+        info!("file::migrate_legacy() -- moving file for note {} to attachments", note_id);
+        note.attachments = Some(vec![attachment]);
+        let legacy = FileData::legacy_file(&user_id, &note_id)?;
+        if legacy.exists() {
+            fs::rename(&legacy, FileData::new_file(&user_id, &note_id, &note_id)?)?;
+        }
+        // if we can't edit the note, we still bring it over for ourselves
+        let can_edit = Space::permission_check(turtl, &note.space_id, &Permission::EditNote).is_ok();
+        sync_model::save_model(SyncAction::Edit, turtl, &mut note, !can_edit)?;
+        migrated.push(note_id);
+        note.wipe();
+    }
+    with_db!{ db, turtl.db, db.kv_set(MIGRATED_KEY, &jedi::stringify(&migrated)?)? };
+    Ok(())
 }
 
 #[cfg(test)]
@@ -295,6 +1178,20 @@ mod tests {
     use super::*;
     use ::jedi;
 
+    /// Make a note to attach files to, along with an attachment (key and all)
+    fn note_with_attachment(turtl: &Turtl, attachment_id: &str) -> (Note, Attachment) {
+        let mut note: Note = jedi::from_val(json!({
+            "space_id": "6969",
+            "user_id": turtl.user_id().unwrap(),
+        })).unwrap();
+        note.generate_id().unwrap();
+        note.generate_key().unwrap();
+        let attachment_id = String::from(attachment_id);
+        let given = vec![Attachment { id: Some(attachment_id.clone()), ..Default::default() }];
+        let (mut attachments, _) = merge_attachments(Some(given), vec![], &vec![attachment_id]).unwrap();
+        (note, attachments.remove(0))
+    }
+
     #[test]
     fn filedata_serializes_to_from_base64() {
         let filedata: Vec<u8> = vec![73, 32, 67, 65, 78, 39, 84, 32, 66, 69, 76, 73, 69, 86, 69, 32, 73, 84, 39, 83, 32, 78, 79, 84, 32, 71, 79, 78, 79, 82, 82, 72, 69, 65, 33, 33];
@@ -311,14 +1208,7 @@ mod tests {
     #[test]
     fn can_save_and_load_files() {
         let turtl = ::turtl::tests::with_test(true);
-        let user_id = turtl.user_id().unwrap();
-
-        let mut note: Note = jedi::from_val(json!({
-            "space_id": "6969",
-            "user_id": user_id.clone(),
-        })).unwrap();
-        note.generate_id().unwrap();
-        note.generate_key().unwrap();
+        let (note, attachment) = note_with_attachment(&turtl, "1234");
 
         let filedata = jedi::stringify(&json!({
             "name": "flippy",
@@ -334,8 +1224,9 @@ mod tests {
         file.data = Some(Vec::from(filedata.as_bytes()));
 
         // talked to drew about encrypting and saving the file. sounds good.
-        file.save(&turtl, &mut note).unwrap();
-        let loaded = FileData::load_file(&turtl, &note).unwrap();
+        file.save(&turtl, &note, &attachment).unwrap();
+        assert_eq!(file.note_id, note.id().cloned());
+        let loaded = FileData::load_file(&turtl, &note, &attachment).unwrap();
 
         // see if the file contents match after decryption
         assert_eq!(String::from_utf8(loaded).unwrap(), r#"{"age":42,"dislikes":"slappy","likes":"slippy","lives":{"city":"santa cruz brahhhh"},"name":"flippy"}"#);
@@ -344,7 +1235,7 @@ mod tests {
         let db = db_guard.as_mut().unwrap();
         file.db_delete(db, None).unwrap();
 
-        match FileData::load_file(&turtl, &note) {
+        match FileData::load_file(&turtl, &note, &attachment) {
             Ok(_) => panic!("Found file for note {}, should be deleted", note.id().as_ref().unwrap()),
             Err(e) => {
                 let e = e.shed();
@@ -356,5 +1247,226 @@ mod tests {
             },
         }
     }
-}
 
+    #[test]
+    fn finds_partial_downloads() {
+        let turtl = ::turtl::tests::with_test(true);
+        let user_id = turtl.user_id().unwrap();
+        let (note, attachment) = note_with_attachment(&turtl, "3456");
+        let note_id = note.id().cloned().unwrap();
+        let file_id = attachment.id.clone().unwrap();
+        let partial = FileData::partial_file(&user_id, &note_id, &file_id).unwrap();
+        util::create_dir(file_folder().unwrap()).unwrap();
+        fs::write(&partial, "half a file").unwrap();
+
+        // deleting the note or wiping the user needs to get these too
+        assert!(FileData::local_files(None, Some(&note_id)).unwrap().contains(&partial));
+        assert!(FileData::local_files(Some(&user_id), None).unwrap().contains(&partial));
+        assert!(!FileData::file_finder_all(None, Some(&note_id), None).unwrap().contains(&partial));
+        fs::remove_file(&partial).unwrap();
+    }
+
+    #[test]
+    fn makes_image_thumbnails() {
+        let turtl = ::turtl::tests::with_test(true);
+        let user_id = turtl.user_id().unwrap();
+        let (note, attachment) = note_with_attachment(&turtl, "5678");
+        let note_id = note.id().cloned().unwrap();
+
+        let mut png = Vec::new();
+        ::image::DynamicImage::ImageRgb8(::image::RgbImage::new(1024, 512))
+            .write_to(&mut png, ::image::ImageOutputFormat::Png)
+            .unwrap();
+
+        // uploaded images get their dimensions in the attachment's meta
+        let mut data = json!({
+            "attachments": [{"name": "dark.png", "filedata": {"data": crypto::to_base64(&png).unwrap()}}],
+        });
+        take_uploads(&mut data, &vec![]).unwrap();
+        assert_eq!(jedi::get::<u32>(&["attachments", "0", "meta", "width"], &data).unwrap(), 1024);
+        assert_eq!(jedi::get::<u32>(&["attachments", "0", "meta", "height"], &data).unwrap(), 512);
+
+        let mut file: FileData = Default::default();
+        file.data = Some(png);
+        file.save(&turtl, &note, &attachment).unwrap();
+
+        let thumbpath = FileData::thumbnail_file(&user_id, &note_id, &String::from("5678")).unwrap();
+        assert!(thumbpath.exists());
+        let thumb = FileData::load_thumbnail(&turtl, &note, &attachment).unwrap();
+        assert_eq!(thumbnail::dimensions(&thumb), Some((256, 128)));
+
+        // devices that downloaded the file make their own
+        fs::remove_file(&thumbpath).unwrap();
+        let thumb = FileData::load_thumbnail(&turtl, &note, &attachment).unwrap();
+        assert_eq!(thumbnail::dimensions(&thumb), Some((256, 128)));
+        assert!(thumbpath.exists());
+
+        let mut db_guard = lock!(turtl.db);
+        let db = db_guard.as_mut().unwrap();
+        file.db_delete(db, None).unwrap();
+        assert!(!thumbpath.exists());
+    }
+
+    #[test]
+    fn chunks_files() {
+        let key = Key::random().unwrap();
+        for size in vec![0, 1, CHUNK_SIZE, CHUNK_SIZE * 2 + 1] {
+            let data = (0..size).map(|x| (x % 251) as u8).collect::<Vec<_>>();
+            let enc = encrypt_file(&key, data.as_slice()).unwrap();
+            let layout = ChunkLayout::parse(enc.as_slice()).unwrap().unwrap();
+            assert_eq!(layout.size, size as u64);
+            assert_eq!(decrypt_file(&key, enc).unwrap(), data);
+        }
+
+        // files from before files were chunked still decrypt
+        let data = Vec::from("the dude abides".as_bytes());
+        let enc = crypto::encrypt(&key, data.clone(), crypto::CryptoOp::new("chacha20poly1305").unwrap()).unwrap();
+        assert!(ChunkLayout::parse(enc.as_slice()).unwrap().is_none());
+        assert_eq!(decrypt_file(&key, enc).unwrap(), data);
+
+        // chunks can't be dropped, moved around, or passed off as a smaller
+        // file
+        let data = (0..(CHUNK_SIZE * 3)).map(|x| (x % 251) as u8).collect::<Vec<_>>();
+        let enc = encrypt_file(&key, data.as_slice()).unwrap();
+        let layout = ChunkLayout::parse(enc.as_slice()).unwrap().unwrap();
+        let (start, _) = layout.span(2, enc.len() as u64).unwrap();
+        assert!(decrypt_file(&key, Vec::from(&enc[0..(start as usize)])).is_err());
+        let mut short = layout.clone();
+        short.size = (CHUNK_SIZE * 2) as u64;
+        let mut enc2 = short.header();
+        enc2.extend_from_slice(&enc[CHUNKED_HEADER_LEN..(start as usize)]);
+        assert!(decrypt_file(&key, enc2).is_err());
+        let (start1, end1) = layout.span(1, enc.len() as u64).unwrap();
+        let mut enc3 = Vec::from(&enc[0..CHUNKED_HEADER_LEN]);
+        enc3.extend_from_slice(&enc[(start1 as usize)..(end1 as usize)]);
+        enc3.extend_from_slice(&enc[CHUNKED_HEADER_LEN..(start1 as usize)]);
+        enc3.extend_from_slice(&enc[(end1 as usize)..]);
+        assert!(decrypt_file(&key, enc3).is_err());
+    }
+
+    #[test]
+    fn reads_files_in_pieces() {
+        let turtl = ::turtl::tests::with_test(true);
+        let (note, attachment) = note_with_attachment(&turtl, "9012");
+        let mut file: FileData = Default::default();
+        file.data = Some(Vec::from("get a job, lebowski".as_bytes()));
+        file.save(&turtl, &note, &attachment).unwrap();
+
+        let reader = FileData::open_chunked(&turtl, &note, &attachment).unwrap();
+        assert_eq!(reader.size(), 19);
+        let handle_id = String::from("h1");
+        lock!(turtl.file_handles).insert(handle_id.clone(), FileHandle { reader: reader, used: now() });
+        assert_eq!(read_handle(&turtl, &handle_id, 0, 3).unwrap(), Vec::from("get".as_bytes()));
+        assert_eq!(read_handle(&turtl, &handle_id, 6, 3).unwrap(), Vec::from("job".as_bytes()));
+        assert_eq!(read_handle(&turtl, &handle_id, 11, 100).unwrap(), Vec::from("lebowski".as_bytes()));
+        assert_eq!(read_handle(&turtl, &handle_id, 100, 100).unwrap(), Vec::<u8>::new());
+        close_handle(&turtl, &handle_id).unwrap();
+        assert!(read_handle(&turtl, &handle_id, 0, 3).is_err());
+        assert!(close_handle(&turtl, &handle_id).is_err());
+
+        // reads that straddle chunks
+        let data = (0..(CHUNK_SIZE * 2 + 100)).map(|x| (x % 251) as u8).collect::<Vec<_>>();
+        file.data = Some(data.clone());
+        file.save(&turtl, &note, &attachment).unwrap();
+        let reader = FileData::open_chunked(&turtl, &note, &attachment).unwrap();
+        assert_eq!(reader.layout.count(), 3);
+        lock!(turtl.file_handles).insert(handle_id.clone(), FileHandle { reader: reader, used: now() });
+        let start = CHUNK_SIZE - 10;
+        let chunk = read_handle(&turtl, &handle_id, start, CHUNK_SIZE + 20).unwrap();
+        assert_eq!(chunk, Vec::from(&data[start..(start + CHUNK_SIZE + 20)]));
+        let chunk = read_handle(&turtl, &handle_id, CHUNK_SIZE * 2, 1000).unwrap();
+        assert_eq!(chunk, Vec::from(&data[(CHUNK_SIZE * 2)..]));
+
+        // handles nobody's touched in a while get closed
+        lock!(turtl.file_handles).get_mut(&handle_id).unwrap().used -= HANDLE_TIMEOUT + 1;
+        expire_handles(&turtl);
+        assert!(read_handle(&turtl, &handle_id, 0, 3).is_err());
+    }
+
+    #[test]
+    fn chunks_old_files_when_opened() {
+        let turtl = ::turtl::tests::with_test(true);
+        let user_id = turtl.user_id().unwrap();
+        let (note, attachment) = note_with_attachment(&turtl, "3344");
+        let note_id = note.id().cloned().unwrap();
+        let data = Vec::from("that rug really tied the room together".as_bytes());
+        let key = attachment.file_key(&note).unwrap();
+        let enc = crypto::encrypt(&key, data.clone(), crypto::CryptoOp::new("chacha20poly1305").unwrap()).unwrap();
+        let path = FileData::new_file(&user_id, &note_id, &String::from("3344")).unwrap();
+        util::create_dir(file_folder().unwrap()).unwrap();
+        fs::write(&path, enc.as_slice()).unwrap();
+        assert_eq!(FileData::load_file(&turtl, &note, &attachment).unwrap(), data);
+
+        let mut reader = FileData::open_chunked(&turtl, &note, &attachment).unwrap();
+        assert_eq!(reader.read(5, 3).unwrap(), Vec::from("rug".as_bytes()));
+        assert!(fs::read(&path).unwrap().starts_with(CHUNKED_MAGIC));
+        assert_eq!(FileData::load_file(&turtl, &note, &attachment).unwrap(), data);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn merges_attachments() {
+        let attachment = |id: &str, name: &str, key: Option<&str>| {
+            Attachment {
+                id: Some(String::from(id)),
+                key: key.map(String::from),
+                name: Some(String::from(name)),
+                ..Default::default()
+            }
+        };
+        let current = vec![attachment("a1", "one.txt", Some("a2V5MQ==")), attachment("a2", "two.txt", None)];
+
+        // the UI gave us nothing, so nothing changes
+        let (attachments, removed) = merge_attachments(None, current.clone(), &vec![]).unwrap();
+        assert_eq!(attachments, current);
+        assert_eq!(removed.len(), 0);
+
+        // renaming one, dropping another, adding a new one. the UI doesn't get
+        // to pick keys.
+        let given = vec![attachment("a1", "uno.txt", Some("bm9wZQ==")), attachment("a3", "three.txt", Some("bm9wZQ=="))];
+        let (attachments, removed) = merge_attachments(Some(given), current.clone(), &vec![String::from("a3")]).unwrap();
+        assert_eq!(attachments[0].name, Some(String::from("uno.txt")));
+        assert_eq!(attachments[0].key, Some(String::from("a2V5MQ==")));
+        assert!(attachments[1].key.is_some());
+        assert!(attachments[1].key != Some(String::from("bm9wZQ==")));
+        assert_eq!(removed, vec![attachment("a2", "two.txt", None)]);
+
+        // can't attach something that has no file
+        assert!(merge_attachments(Some(vec![attachment("a4", "four.txt", None)]), current.clone(), &vec![]).is_err());
+    }
+
+    #[test]
+    fn takes_uploads() {
+        let current = vec![Attachment { id: Some(String::from("a1")), ..Default::default() }];
+        let mut data = json!({
+            "title": "files",
+            "attachments": [
+                {"id": "a1", "name": "one.txt"},
+                {"id": "a1", "name": "one.txt", "filedata": {"data": "aGk="}},
+                {"name": "two.txt", "filedata": {"data": "aGV5"}},
+            ],
+        });
+        let uploads = take_uploads(&mut data, &current).unwrap();
+        assert_eq!(uploads.len(), 2);
+        assert_eq!(uploads[0].0, "a1");
+        assert_eq!(uploads[1].1.data, Some(Vec::from("hey".as_bytes())));
+        assert_eq!(jedi::get::<String>(&["attachments", "2", "id"], &data).unwrap(), uploads[1].0);
+        assert_eq!(jedi::get::<u64>(&["attachments", "2", "size"], &data).unwrap(), 3);
+        assert!(jedi::get_opt::<Value>(&["attachments", "2", "filedata"], &data).is_none());
+
+        // old-style files become attachments, and the note keeps the ones it
+        // has
+        let mut data = json!({
+            "title": "files",
+            "file": {"name": "old.txt", "type": "text/plain", "filedata": {"data": "aGk="}},
+        });
+        let uploads = take_uploads(&mut data, &current).unwrap();
+        assert_eq!(uploads.len(), 1);
+        assert!(jedi::get_opt::<Value>(&["file"], &data).is_none());
+        let attachments: Vec<Attachment> = jedi::get(&["attachments"], &data).unwrap();
+        assert_eq!(attachments.len(), 2);
+        assert_eq!(attachments[0].id, Some(String::from("a1")));
+        assert_eq!(attachments[1].name, Some(String::from("old.txt")));
+        assert_eq!(attachments[1].id.as_ref(), Some(&uploads[0].0));
+    }
+}
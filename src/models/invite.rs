@@ -10,6 +10,8 @@ use ::crypto::{self, Key};
 use ::jedi::{self, Value};
 use ::turtl::Turtl;
 use ::profile::Profile;
+use ::models::space_member::SpaceMember;
+use ::util;
 
 /// Used as our passphrase for our invites if we don't provide one.
 const DEFAULT_INVITE_PASSPHRASE: &'static str = "this is the default passphrase lol";
@@ -34,6 +36,13 @@ protected! {
 		pub is_pubkey_protected: bool,
         #[protected_field(public)]
 		pub title: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub from_sign_pubkey: Option<Key>,
+        #[serde(with = "::util::ser::base64_converter")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub signature: Option<Vec<u8>>,
 
         #[serde(with = "::util::ser::base64_converter")]
         #[serde(skip_serializing_if = "Option::is_none")]
@@ -53,6 +62,11 @@ pub struct InviteRequest {
     pub title: String,
     pub their_pubkey: Option<Key>,
     pub passphrase: Option<String>,
+    /// Send the invite even if `their_pubkey` doesn't match the keys we know
+    /// for the recipient (see `space_member::check_pubkey()`). Only for when
+    /// the user knows the recipient's keys changed.
+    #[serde(default)]
+    pub allow_key_mismatch: bool,
 }
 
 make_storable!(Invite, "invites");
@@ -95,9 +109,10 @@ impl MemorySaver for Invite {
 }
 
 impl Invite {
-    /// Convert an invite request+key into an invite, sealed and ready to send
-    pub fn from_invite_request(from_user_id: &String, from_username: &String, space_key: &Key, req: InviteRequest) -> TResult<Self> {
-        let InviteRequest { space_id, to_user, role, title, their_pubkey, passphrase } = req;
+    /// Convert an invite request+key into an invite, sealed (and signed with
+    /// the sender's signing keypair) and ready to send
+    pub fn from_invite_request(from_user_id: &String, from_username: &String, sign_keys: &(Key, Key), space_key: &Key, req: InviteRequest) -> TResult<Self> {
+        let InviteRequest { space_id, to_user, role, title, their_pubkey, passphrase, .. } = req;
         if title.trim() == "" {
             return TErr!(TError::MissingField(String::from("title")));
         }
@@ -115,7 +130,7 @@ impl Invite {
         invite.is_pubkey_protected = false;
         invite.title = title;
         invite.message = None;
-        invite.seal(their_pubkey, passphrase, space_key)?;
+        invite.seal(their_pubkey, passphrase, space_key, sign_keys)?;
         Ok(invite)
     }
 
@@ -133,8 +148,46 @@ impl Invite {
         Ok(())
     }
 
+    /// Build the data we sign for this invite. This covers everything the
+    /// recipient acts on, including the encrypted body (which holds the space
+    /// key), so the server can't swap any of it out without breaking the
+    /// signature.
+    fn signable(&self) -> TResult<Vec<u8>> {
+        model_getter!(get_field, "Invite.signable()");
+        let id = get_field!(self, id);
+        let body = get_field!(self, body);
+        let data = format!(
+            "turtl/invite/v1|{}|{}|{}|{}|{}|{}",
+            id,
+            self.space_id,
+            self.from_user_id,
+            self.to_user,
+            util::enum_to_string(&self.role)?,
+            body,
+        );
+        Ok(Vec::from(data.as_bytes()))
+    }
+
+    /// Check this invite's signature against the key it was sent with. Note
+    /// that a valid signature only proves the invite is intact: to know the
+    /// key belongs to the sender, it has to match a key the user verified (see
+    /// `SpaceMember::verify()`).
+    ///
+    /// Invites from older clients are unsigned, and return false.
+    pub fn verify_signature(&self) -> TResult<bool> {
+        let (pubkey, signature) = match (self.from_sign_pubkey.as_ref(), self.signature.as_ref()) {
+            (Some(pk), Some(sig)) => (pk, sig),
+            _ => return Ok(false),
+        };
+        let data = self.signable()?;
+        if !crypto::sign::verify(pubkey, data.as_slice(), signature.as_slice())? {
+            return TErr!(TError::BadValue(format!("invite {:?} has an invalid signature", self.id())));
+        }
+        Ok(true)
+    }
+
     /// Sealed with a kiss
-    pub fn seal(&mut self, their_pubkey: Option<Key>, passphrase: Option<String>, space_key: &Key) -> TResult<()> {
+    pub fn seal(&mut self, their_pubkey: Option<Key>, passphrase: Option<String>, space_key: &Key, sign_keys: &(Key, Key)) -> TResult<()> {
         let message = jedi::stringify(&json!({"space_key": space_key}))?;
         let mut message = Vec::from(message.as_bytes());
         if let Some(pubkey) = their_pubkey {
@@ -148,11 +201,18 @@ impl Invite {
         // talked to drew about generating a key. sounds good.
         self.gen_invite_key(passphrase)?;
         Protected::serialize(self)?;
+        let (ref sign_pubkey, ref sign_privkey) = *sign_keys;
+        self.from_sign_pubkey = Some(sign_pubkey.clone());
+        let data = self.signable()?;
+        self.signature = Some(crypto::sign::sign(sign_privkey, data.as_slice())?);
         Ok(())
     }
 
     /// Open a sealed invite
     pub fn open(&mut self, our_pubkey: &Key, our_privkey: &Key, passphrase: Option<String>) -> TResult<()> {
+        if !self.verify_signature()? {
+            warn!("Invite.open() -- invite {:?} is unsigned", self.id());
+        }
         self.gen_invite_key(passphrase)?;
         self.deserialize()?;
         let message = match self.message.as_ref() {
@@ -179,7 +239,8 @@ impl Invite {
         model_getter!(get_field, "Invite.accept()");
         let invite_id = get_field!(self, id);
         let url = format!("/spaces/{}/invites/accepted/{}", self.space_id, invite_id);
-        let spacedata: Value = turtl.api.post(url.as_str())?.call()?;
+        let member = SpaceMember::signed_claim(turtl, &self.space_id)?;
+        let spacedata: Value = turtl.api.post(url.as_str())?.json(&json!({"member": member})).call()?;
         incoming::ignore_syncs_maybe(turtl, &spacedata, "Invite.accept()");
         Ok(spacedata)
     }
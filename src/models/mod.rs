@@ -15,7 +15,10 @@ pub mod keychain;
 pub mod space;
 pub mod space_member;
 pub mod board;
+pub mod checklist;
 pub mod note;
+pub mod revision;
+pub mod template;
 pub mod file;
 pub mod invite;
 pub mod feedback;
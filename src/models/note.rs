@@ -1,15 +1,19 @@
 use ::turtl::Turtl;
-use ::error::TResult;
+use ::error::{TResult, TError};
+use ::regex::Regex;
 use ::models::model::Model;
 use ::models::validate::{self, Validate};
 use ::models::protected::{Keyfinder, Protected};
 use ::models::keychain::{Keychain, KeyRef, KeyType};
-use ::models::file::{File, FileData};
+use ::models::file::{File, FileData, Attachment};
+use ::models::checklist::{self, ChecklistItem};
+use ::models::revision::Revision;
 use ::models::sync_record::{SyncRecord, SyncAction};
 use ::crypto::Key;
 use ::sync::sync_model::{self, SyncModel, MemorySaver};
 use ::std::fs;
 use ::models::storable::Storable;
+use ::util;
 
 protected! {
     #[derive(Serialize, Deserialize)]
@@ -24,6 +28,8 @@ protected! {
         #[serde(default)]
         #[protected_field(public)]
         pub has_file: bool,
+        /// The one file notes used to be able to hold. Replaced by
+        /// `attachments` (see `models::file`), but kept for older clients.
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(public, submodel)]
         pub file: Option<File>,
@@ -31,6 +37,14 @@ protected! {
         #[serde(rename = "mod")]
         #[protected_field(public)]
         pub mod_: Option<i64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub version: Option<i64>,
+        /// When this was put in the trash (see `trash`), if it's in there.
+        /// Always serialized so taking it out of the trash sticks.
+        #[serde(default)]
+        #[protected_field(public)]
+        pub trashed: Option<i64>,
 
         #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
         #[protected_field(private)]
@@ -59,6 +73,21 @@ protected! {
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(private)]
         pub color: Option<i64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub attachments: Option<Vec<Attachment>>,
+        /// When this note is due (unix timestamp, seconds)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub due: Option<i64>,
+        /// When to remind the user about this note (see `reminders`)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub remind_at: Option<i64>,
+        /// A checklist note's items (see `models::checklist`)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub items: Option<Vec<ChecklistItem>>,
     }
 }
 
@@ -74,6 +103,7 @@ impl Validate for Note {
         if self.type_.as_ref().map(|x| x == "").unwrap_or(true) {
             errors.push(validate::entry("type", t!("This note is missing the `type` field")));
         }
+        errors.append(&mut checklist::validate(self));
         errors
     }
 }
@@ -81,9 +111,10 @@ impl Validate for Note {
 impl Note {
     /// Remove the files attached to this note, if any.
     fn clear_files(&self) -> TResult<()> {
-        // delete all local file(s) associated with this note
+        // delete all local file(s) associated with this note, including any
+        // half-finished downloads
         let note_id = self.id_or_else()?;
-        let files = FileData::file_finder_all(Some(&self.user_id), Some(&note_id))?;
+        let files = FileData::local_files(None, Some(&note_id))?;
         for file in files {
             fs::remove_file(&file)?;
         }
@@ -98,6 +129,41 @@ impl Note {
         Ok(())
     }
 
+    /// Zero out this note's decrypted fields. Call this once the note has been
+    /// handed off (ie serialized to the UI) and we don't need it anymore.
+    pub fn wipe(&mut self) {
+        {
+            let strings = vec![
+                &mut self.type_, &mut self.title, &mut self.url,
+                &mut self.username, &mut self.password, &mut self.text,
+                &mut self.embed,
+            ];
+            for field in strings {
+                if let Some(ref mut x) = *field { util::wipe_string(x); }
+                *field = None;
+            }
+        }
+        if let Some(ref mut tags) = self.tags {
+            for tag in tags.iter_mut() { util::wipe_string(tag); }
+        }
+        self.tags = None;
+        self.color = None;
+        self.due = None;
+        self.remind_at = None;
+        if let Some(ref mut attachments) = self.attachments {
+            for attachment in attachments.iter_mut() {
+                if let Some(ref mut x) = attachment.name { util::wipe_string(x); }
+                if let Some(ref mut x) = attachment.key { util::wipe_string(x); }
+                if let Some(ref mut x) = attachment.text { util::wipe_string(x); }
+            }
+        }
+        self.attachments = None;
+        if let Some(ref mut items) = self.items {
+            for item in items.iter_mut() { util::wipe_string(&mut item.text); }
+        }
+        self.items = None;
+    }
+
     /// Given a Turtl/note_id, grab that note's space_id (if it exists)
     pub fn get_space_id(turtl: &Turtl, note_id: &String) -> Option<String> {
         let mut db_guard = lock!(turtl.db);
@@ -111,6 +177,56 @@ impl Note {
             None => None,
         }
     }
+
+    /// Grab the ids of the notes this one links to. Links look like
+    /// `[[<note id>]]` (or `[[<note id>|some label]]`) and can go in the note's
+    /// text or its checklist items.
+    pub fn links(&self) -> Vec<String> {
+        lazy_static! {
+            static ref RE_LINK: Regex = Regex::new(r"\[\[\s*([0-9a-fA-F]+)\s*(\|[^\]]*)?\]\]").expect("turtl::Note.links() -- failed to compile regex");
+        }
+        let mut texts: Vec<&String> = self.text.iter().collect();
+        if let Some(ref items) = self.items {
+            for item in items { texts.push(&item.text); }
+        }
+        let mut links: Vec<String> = Vec::new();
+        for text in texts {
+            for caps in RE_LINK.captures_iter(text) {
+                let id = caps.at(1).unwrap_or("").to_lowercase();
+                if id == "" || Some(&id) == self.id() || links.contains(&id) { continue; }
+                links.push(id);
+            }
+        }
+        links
+    }
+}
+
+/// Grab the (decrypted) notes that link to a note. We have to be in the note's
+/// space to ask, and only get back notes from spaces we're in.
+pub fn backlinks(turtl: &Turtl, note_id: &String) -> TResult<Vec<Note>> {
+    let space_id = match Note::get_space_id(turtl, note_id) {
+        Some(x) => x,
+        None => return TErr!(TError::NotFound(format!("note {} not found", note_id))),
+    };
+    let in_space = |space_id: &String| -> bool {
+        let profile_guard = lockr!(turtl.profile);
+        profile_guard.spaces.iter().any(|x| x.id() == Some(space_id))
+    };
+    if !in_space(&space_id) {
+        return TErr!(TError::PermissionDenied(format!("user {:?} isn't in space {}", turtl.user_id().ok(), space_id)));
+    }
+    let note_ids = {
+        let search_guard = lock!(turtl.search);
+        match search_guard.as_ref() {
+            Some(search) => search.find_backlinks(note_id)?,
+            None => return TErr!(TError::MissingField(String::from("turtl is missing `search` object"))),
+        }
+    };
+    let notes = turtl.load_notes(&note_ids)?;
+    let (notes, mut hidden): (Vec<Note>, Vec<Note>) = notes.into_iter()
+        .partition(|x| in_space(&x.space_id));
+    for note in hidden.iter_mut() { note.wipe(); }
+    Ok(notes)
 }
 
 impl Keyfinder for Note {
@@ -213,6 +329,13 @@ impl MemorySaver for Note {
                 }
             }
             SyncAction::Delete => {
+                {
+                    let note_id = self.id_or_else()?;
+                    let mut db_guard = lock!(turtl.db);
+                    if let Some(db) = db_guard.as_mut() {
+                        Revision::clear(db, &note_id)?;
+                    }
+                }
                 let mut search_guard = lock!(turtl.search);
                 match search_guard.as_mut() {
                     Some(ref mut search) => search.unindex_note(&self)?,
@@ -26,7 +26,7 @@ use ::jedi::{self, Value, Map as JsonMap};
 use ::error::{TResult, TError, TFutureResult};
 use ::turtl::Turtl;
 use ::models::model::Model;
-use ::crypto::{self, Key, CryptoOp};
+use ::crypto::{self, Key, CryptoOp, CryptoError};
 use ::models::keychain::{KeyRef, Keychain};
 
 // -----------------------------------------------------------------------------
@@ -46,7 +46,7 @@ pub fn decrypt_key(decrypting_key: &Key, encrypted_key: &String) -> TResult<Key>
 
 /// Encrypt a decrypted key, mainly for storage self-decrypting keys with models
 pub fn encrypt_key(encrypting_key: &Key, key_to_encrypt: Key) -> TResult<String> {
-    let encrypted = crypto::encrypt(encrypting_key, key_to_encrypt.into_data(), crypto::CryptoOp::new("chacha20poly1305")?)?;
+    let encrypted = crypto::encrypt(encrypting_key, key_to_encrypt.data().clone(), crypto::CryptoOp::new("chacha20poly1305")?)?;
     let converted = crypto::to_base64(&encrypted)?;
     Ok(converted)
 }
@@ -284,6 +284,28 @@ pub trait Protected: Model + fmt::Debug {
         jedi::stringify(&self.data_for_storage()?).map_err(|e| toterr!(e))
     }
 
+    /// Grab the associated data we bind this model's encrypted `body` to. This
+    /// ties the body to the model's type and id, so a body lifted from one
+    /// model and dropped into another (say, by a less-than-honest server)
+    /// fails to decrypt instead of silently loading.
+    ///
+    /// Users are the exception: their id is handed out by the API *after* the
+    /// body is first encrypted (on join), so we only bind the type there.
+    fn auth_data(&self) -> Vec<u8> {
+        let model_type = self.model_type();
+        let id = if model_type == "user" {
+            None
+        } else {
+            self.id()
+        };
+        let mut ad = Vec::from(model_type.as_bytes());
+        ad.push(b'|');
+        if let Some(id) = id {
+            ad.extend_from_slice(id.as_bytes());
+        }
+        ad
+    }
+
     /// "Serializes" a model...returns all public data with an *encrypted* set
     /// of private data (in `body`).
     ///
@@ -309,7 +331,7 @@ pub trait Protected: Model + fmt::Debug {
                 None => return TErr!(TError::MissingField(format!("model {} ({}) missing `key`", id, self.model_type()))),
             };
             // government surveillance agencies *HATE* him!!!!1
-            body = crypto::encrypt(&key, Vec::from(json.as_bytes()), CryptoOp::new("chacha20poly1305")?)?;
+            body = crypto::encrypt_ad(&key, Vec::from(json.as_bytes()), CryptoOp::new("chacha20poly1305")?, self.auth_data().as_slice())?;
         }
         let body_base64 = crypto::to_base64(&body)?;
         self.set_body(body_base64);
@@ -337,7 +359,13 @@ pub trait Protected: Model + fmt::Debug {
                 Some(x) => x,
                 None => return TErr!(TError::MissingField(format!("model {} ({}) missing `key`", id, self.model_type()))),
             };
-            crypto::decrypt(key, body)?
+            match crypto::decrypt_ad(key, body, self.auth_data().as_slice()) {
+                Ok(x) => x,
+                Err(CryptoError::Authentication(_)) => {
+                    return TErr!(TError::Crypto(CryptoError::Authentication(format!("body for model {} ({}) failed to authenticate (wrong key, or the body belongs to another model)", id, self.model_type()))));
+                }
+                Err(e) => return Err(From::from(e)),
+            }
         };
         let json_str: String = match String::from_utf8(json_bytes) {
             Ok(x) => x,
@@ -548,6 +576,38 @@ mod tests {
         assert_eq!(dog2.tags.unwrap(), vec!["flappy", "noisy"]);
     }
 
+    #[test]
+    fn binds_body_to_model() {
+        let key = crypto::Key::random().unwrap();
+        let mut dog1: Dog = jedi::parse(&String::from(r#"{"id":"1111","size":12,"name":"barky"}"#)).unwrap();
+        let mut dog2: Dog = jedi::parse(&String::from(r#"{"id":"2222","size":40,"name":"growly"}"#)).unwrap();
+        dog1.set_key(Some(key.clone()));
+        dog2.set_key(Some(key.clone()));
+        dog1.serialize().unwrap();
+        dog2.serialize().unwrap();
+
+        // swap dog1's body into dog2. same key, but the body is bound to dog1.
+        let mut swapped = Dog::clone_from(dog2.data_for_storage().unwrap()).unwrap();
+        swapped.body = dog1.body.clone();
+        swapped.set_key(Some(key.clone()));
+        match swapped.deserialize().map_err(|e| e.shed()) {
+            Err(TError::Crypto(CryptoError::Authentication(..))) => {}
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        // same body in a different model type fails too
+        let mut junkyard: Junkyard = jedi::parse(&String::from(r#"{"id":"1111"}"#)).unwrap();
+        junkyard.body = dog1.body.clone();
+        junkyard.set_key(Some(key.clone()));
+        assert!(junkyard.deserialize().is_err());
+
+        // and the untampered body still decrypts
+        let mut dog1_2 = Dog::clone_from(dog1.data_for_storage().unwrap()).unwrap();
+        dog1_2.set_key(Some(key.clone()));
+        dog1_2.deserialize().unwrap();
+        assert_eq!(dog1_2.name.unwrap(), String::from("barky"));
+    }
+
     #[test]
     fn decrypts_utf8() {
         let mut note: Note = jedi::parse(&String::from(r#"{"id":"015ce7ea7f742af6297cf0cc29180f9cc45f4c80e5b30238581f845367f9c404ef3fb8fb0a5a00aa","space_id":"015bac22440a4944baee41b88207731eaeb7e2cc5c955fb8a05b028c1409aaf55024f5d26fa3001e","board_id":"015bac2244ea4944baee41b88207731eaeb7e2cc5c955fb8a05b028c1409aaf55024f5d26fa30034","user_id":51,"file":{},"keys":[{"s":"015bac22440a4944baee41b88207731eaeb7e2cc5c955fb8a05b028c1409aaf55024f5d26fa3001e","k":"AAYBAAyAjxgMehPHn+xMYOAW8/aGgxRrQN8FvB/lQoI2uX7khX8eQi2un4eFa73kboM6UAiCvSKGnmX9DNIwGk4="},{"b":"015bac2244ea4944baee41b88207731eaeb7e2cc5c955fb8a05b028c1409aaf55024f5d26fa30034","k":"AAYBAAy/IWmcQN42Iva4LqNg0eDAIU4slpoAZ/8487NJxXjISkd4HmOLxBPg/Lbf7pa5E/MB7pOsTHGLENcDoWw="},{"s":"015bac22440a4944baee41b88207731eaeb7e2cc5c955fb8a05b028c1409aaf55024f5d26fa3001e","k":"AAYBAAyAjxgMehPHn+xMYOAW8/aGgxRrQN8FvB/lQoI2uX7khX8eQi2un4eFa73kboM6UAiCvSKGnmX9DNIwGk4="},{"b":"015bac2244ea4944baee41b88207731eaeb7e2cc5c955fb8a05b028c1409aaf55024f5d26fa30034","k":"AAYBAAy/IWmcQN42Iva4LqNg0eDAIU4slpoAZ/8487NJxXjISkd4HmOLxBPg/Lbf7pa5E/MB7pOsTHGLENcDoWw="}],"mod":1498539524,"body":"AAYBAAw/xkOg209rBB+kSM2o8aKTvzsDuY0bcwN7W5zuwf+kFPCAEH/ERnxIbO1SOE4+Z3+WUwRDhsOSx9VR2gTON9bcMCWUiS1DP5oNWhLZ9HZxvF1dlpN6jnfTokeE7Aw0uVjIrSma3AW7vaA3tTokZdW9j7fpqzBYGZXrZT6+1/RAsKrHiayVGZdR//4iKoRZeysgsu8Hn6aaMhgJ+tSV9Kz7MZeKHJb2fxWVr1BTZQeRWoXKhjU="}"#)).unwrap();
@@ -0,0 +1,278 @@
+//! Revisions are prior versions of a note. Each time the user edits a note, the
+//! copy being replaced is kept as a revision so the user can look back through
+//! the note's history, see what changed, and restore an old version.
+//!
+//! A revision holds the note as it was stored (so, encrypted with the note's
+//! key), which means revisions need no keys of their own. They're kept locally
+//! (trimmed per `revisions.max_count`/`revisions.max_age`) and only sync to the
+//! API if `revisions.sync` is set.
+
+use ::jedi::{self, Value};
+use ::error::{TResult, TError};
+use ::config;
+use ::storage::Storage;
+use ::models::model::Model;
+use ::models::protected::{Keyfinder, Protected};
+use ::models::storable::Storable;
+use ::models::validate::Validate;
+use ::models::note::Note;
+use ::models::sync_record::{SyncAction, SyncType, SyncRecord};
+use ::lib_permissions::Permission;
+use ::models::space::Space;
+use ::sync::sync_model::{SyncModel, MemorySaver};
+use ::sync::journal;
+use ::turtl::Turtl;
+use ::util::merge::{self, DiffLine};
+use ::time;
+
+/// The (private) note fields a revision restores. Everything else (where the
+/// note lives, its file, etc) stays as it is now.
+const RESTORE_FIELDS: [&'static str; 12] = ["type", "title", "tags", "url", "username", "password", "text", "embed", "color", "items", "due", "remind_at"];
+
+protected! {
+    #[derive(Serialize, Deserialize)]
+    pub struct Revision {
+        #[protected_field(public)]
+        pub note_id: String,
+        #[protected_field(public)]
+        pub space_id: String,
+        #[serde(with = "::util::ser::int_converter")]
+        #[protected_field(public)]
+        pub user_id: String,
+        /// When the note was saved as this version
+        #[protected_field(public)]
+        pub created: i64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub version: Option<i64>,
+        /// The note, as it was stored
+        #[protected_field(public)]
+        pub note: Value,
+    }
+}
+
+make_storable!(Revision, "revisions");
+impl SyncModel for Revision {}
+impl Keyfinder for Revision {}
+impl Validate for Revision {}
+impl MemorySaver for Revision {}
+
+/// What the UI gets when listing a note's revisions
+#[derive(Serialize, Debug)]
+pub struct RevisionInfo {
+    pub id: String,
+    pub note_id: String,
+    pub created: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+}
+
+/// Whether revisions go out to the API
+fn sync_enabled() -> bool {
+    config::get(&["revisions", "sync"]).unwrap_or(false)
+}
+
+/// Find the revisions that fall outside of our limits (keeping at most
+/// `max_count`, none created before `cutoff`). Expects oldest first.
+fn expired<'a>(revisions: &'a Vec<Revision>, max_count: usize, cutoff: i64) -> Vec<&'a Revision> {
+    let keep_from = revisions.len().saturating_sub(max_count);
+    revisions.iter()
+        .enumerate()
+        .filter(|&(i, rev)| i < keep_from || rev.created < cutoff)
+        .map(|(_, rev)| rev)
+        .collect()
+}
+
+impl Revision {
+    /// Grab a note's revisions, oldest first
+    pub fn for_note(db: &Storage, note_id: &String) -> TResult<Vec<Revision>> {
+        let mut revisions: Vec<Revision> = db.find(Self::tablename(), "note_id", &vec![note_id.clone()])?;
+        revisions.sort_by(|a, b| (a.created, a.id()).cmp(&(b.created, b.id())));
+        Ok(revisions)
+    }
+
+    /// Keep the stored copy of a note that was just replaced by an edit, and
+    /// forget any revisions past our limits. Called by `journal::dispatch()`
+    /// for the user's edits, so changes the app makes on its own (toggling a
+    /// checklist item, trashing, merging) don't crowd out real versions.
+    /// Anything that isn't a note is ignored.
+    pub fn keep<T: Protected>(db: &mut Storage, user_id: &String, model: &T) -> TResult<()> {
+        if model.model_type() != "note" { return Ok(()); }
+        let note_id = model.id_or_else()?;
+        let stored = model.data_for_storage()?;
+        let mut revisions = Revision::for_note(db, &note_id)?;
+        // no sense in keeping the same version twice
+        if revisions.last().map(|x| x.note.get("body") == stored.get("body")).unwrap_or(false) {
+            return Ok(());
+        }
+
+        let now = time::get_time().sec as i64;
+        let skip_remote_sync = !sync_enabled();
+        let mut revision = Revision::new();
+        revision.generate_id()?;
+        revision.note_id = note_id;
+        revision.space_id = jedi::get(&["space_id"], &stored)?;
+        revision.user_id = user_id.clone();
+        revision.created = jedi::get_opt(&["mod"], &stored).unwrap_or(now);
+        revision.version = jedi::get_opt(&["version"], &stored);
+        revision.note = stored;
+        revision.outgoing(SyncAction::Add, user_id, db, skip_remote_sync)?;
+        revisions.push(revision);
+
+        let max_count: usize = config::get(&["revisions", "max_count"]).unwrap_or(20);
+        let max_age: i64 = config::get(&["revisions", "max_age"]).unwrap_or(90);
+        for old in expired(&revisions, max_count, now - (max_age * 86400)) {
+            old.outgoing(SyncAction::Delete, user_id, db, skip_remote_sync)?;
+        }
+        Ok(())
+    }
+
+    /// Remove a note's revisions from the local db. The API cleans up its own
+    /// copies when the note is deleted.
+    pub fn clear(db: &mut Storage, note_id: &String) -> TResult<()> {
+        for revision in Revision::for_note(db, note_id)? {
+            revision.db_delete(db, None)?;
+        }
+        Ok(())
+    }
+
+    /// Decrypt this revision's copy of the note
+    pub fn open(&self, turtl: &Turtl) -> TResult<Note> {
+        let mut note: Note = jedi::from_val(self.note.clone())?;
+        turtl.find_model_key(&mut note)?;
+        note.deserialize()?;
+        Ok(note)
+    }
+
+    fn info(&self, turtl: &Turtl) -> TResult<RevisionInfo> {
+        let title = match self.open(turtl) {
+            Ok(mut note) => {
+                let title = note.title.clone();
+                note.wipe();
+                title
+            }
+            Err(_) => None,
+        };
+        Ok(RevisionInfo {
+            id: self.id_or_else()?,
+            note_id: self.note_id.clone(),
+            created: self.created,
+            version: self.version.clone(),
+            title: title,
+        })
+    }
+
+    /// Grab a revision by id
+    fn get(turtl: &Turtl, revision_id: &String) -> TResult<Revision> {
+        let found: Option<Revision> = with_db!{ db, turtl.db, db.get(Self::tablename(), revision_id)? };
+        match found {
+            Some(x) => Ok(x),
+            None => TErr!(TError::NotFound(format!("revision {} not found", revision_id))),
+        }
+    }
+}
+
+/// Grab the current (decrypted) version of a note
+fn current_note(turtl: &Turtl, note_id: &String) -> TResult<Note> {
+    let mut notes = turtl.load_notes(&vec![note_id.clone()])?;
+    if notes.len() == 0 {
+        return TErr!(TError::NotFound(format!("note {} not found", note_id)));
+    }
+    Ok(notes.swap_remove(0))
+}
+
+/// List a note's revisions, newest first
+pub fn list(turtl: &Turtl, note_id: &String) -> TResult<Vec<RevisionInfo>> {
+    let revisions = with_db!{ db, turtl.db, Revision::for_note(db, note_id)? };
+    let mut infos = Vec::with_capacity(revisions.len());
+    for revision in revisions.iter().rev() {
+        infos.push(revision.info(turtl)?);
+    }
+    Ok(infos)
+}
+
+/// Grab a revision of a note, along with a diff (of the note's text) between
+/// the revision and either another revision (`compare_to`) or, if None, the
+/// note as it is now.
+pub fn get(turtl: &Turtl, revision_id: &String, compare_to: Option<String>) -> TResult<Value> {
+    let revision = Revision::get(turtl, revision_id)?;
+    let mut note = revision.open(turtl)?;
+    let mut other = match compare_to {
+        Some(ref id) => {
+            let other = Revision::get(turtl, id)?;
+            if other.note_id != revision.note_id {
+                return TErr!(TError::BadValue(format!("revisions {} and {} are from different notes", revision_id, id)));
+            }
+            other.open(turtl)?
+        }
+        None => current_note(turtl, &revision.note_id)?,
+    };
+    let diff: Option<Vec<DiffLine>> = {
+        let empty = String::new();
+        let old = note.text.as_ref().unwrap_or(&empty);
+        let new = other.text.as_ref().unwrap_or(&empty);
+        merge::diff(old, new)
+    };
+    let val = json!({
+        "revision": revision.info(turtl)?,
+        "note": note.data()?,
+        "diff": diff,
+    });
+    note.wipe();
+    other.wipe();
+    Ok(val)
+}
+
+/// Put a note back the way it was as of the given revision. This is saved like
+/// any other edit (so it can be undone, and the current version of the note
+/// becomes a revision itself).
+pub fn restore(turtl: &Turtl, revision_id: &String) -> TResult<Value> {
+    let revision = Revision::get(turtl, revision_id)?;
+    let current_space_id = match Note::get_space_id(turtl, &revision.note_id) {
+        Some(x) => x,
+        None => return TErr!(TError::NotFound(format!("note {} not found", revision.note_id))),
+    };
+    Space::permission_check(turtl, &current_space_id, &Permission::EditNote)?;
+    let mut old = revision.open(turtl)?;
+    let old_data = old.data()?;
+    old.wipe();
+
+    let mut data = json!({"id": revision.note_id});
+    for field in RESTORE_FIELDS.iter() {
+        data[*field] = old_data.get(*field).cloned().unwrap_or(Value::Null);
+    }
+    // restore the fields, but leave the note where it lives now
+    let current = current_note(turtl, &revision.note_id)?;
+    data["space_id"] = json!(current.space_id);
+    data["board_id"] = json!(current.board_id);
+
+    let mut sync_record = SyncRecord::default();
+    sync_record.action = SyncAction::Edit;
+    sync_record.ty = SyncType::Note;
+    sync_record.data = Some(data);
+    journal::dispatch(turtl, sync_record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_old_revisions() {
+        let revisions = (0..5)
+            .map(|i| {
+                let mut rev = Revision::new();
+                rev.id = Some(format!("{}", i));
+                rev.created = i * 100;
+                rev
+            })
+            .collect::<Vec<_>>();
+        let ids = |x: Vec<&Revision>| x.into_iter().map(|r| r.id().unwrap().clone()).collect::<Vec<_>>();
+        assert_eq!(ids(expired(&revisions, 3, 0)), vec!["0", "1"]);
+        assert_eq!(ids(expired(&revisions, 10, 250)), vec!["0", "1", "2"]);
+        assert_eq!(ids(expired(&revisions, 4, 50)), vec!["0"]);
+        assert_eq!(ids(expired(&revisions, 10, 0)).len(), 0);
+    }
+}
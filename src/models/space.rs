@@ -2,9 +2,11 @@ use ::error::{TResult, TError};
 use ::models::model::Model;
 use ::models::board::Board;
 use ::models::note::Note;
+use ::models::template::Template;
 use ::models::invite::{Invite, InviteRequest};
 use ::models::protected::{Keyfinder, Protected};
-use ::models::space_member::SpaceMember;
+use ::models::space_member::{self, SpaceMember};
+use ::models::user::User;
 use ::models::sync_record::{SyncRecord, SyncAction};
 use ::models::validate::{self, Validate};
 use ::models::keychain;
@@ -31,6 +33,9 @@ protected! {
         #[serde(default)]
         #[protected_field(public)]
         pub invites: Vec<Invite>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub version: Option<i64>,
 
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(private)]
@@ -151,6 +156,18 @@ impl MemorySaver for Space {
                     let note_id = note.id_or_else()?;
                     sync_model::delete_model::<Note>(turtl, &note_id, true)?;
                 }
+
+                let templates: Vec<Template> = {
+                    let db_guard = lock!(turtl.db);
+                    match *db_guard {
+                        Some(ref db) => db.find("templates", "space_id", &vec![space_id.clone()])?,
+                        None => vec![],
+                    }
+                };
+                for template in templates {
+                    let template_id = template.id_or_else()?;
+                    sync_model::delete_model::<Template>(turtl, &template_id, true)?;
+                }
                 // remove the space from memory
                 let mut profile_guard = lockw!(turtl.profile);
                 profile_guard.spaces.retain(|s| s.id() != Some(&space_id));
@@ -294,6 +311,13 @@ impl Space {
         Ok(())
     }
 
+    /// Verify a space member's fingerprint. Returns whether the fingerprint
+    /// matched (in which case the member's keys are remembered).
+    pub fn verify_member(&mut self, turtl: &Turtl, member_user_id: &String, fingerprint: &String) -> TResult<bool> {
+        let member = self.find_member_by_user_id_or_else(member_user_id)?;
+        member.verify(turtl, fingerprint)
+    }
+
     /// Leave the space (as the current user). Like delete, but without a
     /// permission check.
     pub fn leave(&mut self, turtl: &Turtl) -> TResult<()> {
@@ -309,8 +333,21 @@ impl Space {
         Ok(())
     }
 
-    /// Send an invite for this space to an unsuspecting
-    pub fn send_invite(&mut self, turtl: &Turtl, invite_request: InviteRequest) -> TResult<()> {
+    /// Grab the membership records (which may hold signed claims to their
+    /// keys) for the given username across the given spaces
+    pub fn member_claims(spaces: &Vec<Space>, username: &String) -> Vec<SpaceMember> {
+        spaces.iter()
+            .flat_map(|space| space.members.iter())
+            .filter(|member| member.username.to_lowercase() == username.to_lowercase())
+            .cloned()
+            .collect()
+    }
+
+    /// Send an invite for this space to an unsuspecting. `claims` are the
+    /// recipient's membership records from other spaces (see
+    /// `Space::member_claims()`), which the pubkey we seal the invite with
+    /// is checked against.
+    pub fn send_invite(&mut self, turtl: &Turtl, invite_request: InviteRequest, claims: &Vec<SpaceMember>) -> TResult<()> {
         turtl.assert_connected()?;
         let (user_id, username) = {
             let user_guard = lockr!(turtl.user);
@@ -329,7 +366,16 @@ impl Space {
             return TErr!(TError::BadValue(format!("{} is already invited to this space", invite_request.to_user)));
         }
 
-        let invite = Invite::from_invite_request(&user_id, &username, &space_key, invite_request)?;
+        // the pubkey came from the server, so don't seal the space key with it
+        // unless it checks out
+        if let Some(ref their_pubkey) = invite_request.their_pubkey {
+            if !invite_request.allow_key_mismatch {
+                space_member::check_pubkey(turtl, &invite_request.to_user, their_pubkey, claims)?;
+            }
+        }
+
+        let sign_keys = User::sign_keypair(turtl)?;
+        let invite = Invite::from_invite_request(&user_id, &username, &sign_keys, &space_key, invite_request)?;
         invite.send(turtl)?;
         self.invites.push(invite);
         Ok(())
@@ -352,6 +398,13 @@ impl Space {
             };
             invite.open(pubkey, privkey, passphrase)?;
         }
+        // if we've verified the sender before, make sure this invite came from
+        // the keys we verified and not some imposter (like, say, the server)
+        if let Some(verified) = space_member::get_verified_keys(turtl, &invite.from_user_id)? {
+            if invite.from_sign_pubkey.as_ref() != Some(&verified.sign_pubkey) {
+                return TErr!(TError::PermissionDenied(format!("invite {} was not signed by the keys you verified for {}", invite_id, invite.from_username)));
+            }
+        }
         let keyjson = match invite.message.as_ref() {
             Some(data) => jedi::parse(&String::from_utf8(data.clone())?)?,
             None => return TErr!(TError::MissingField(String::from("Invite.message"))),
@@ -1,11 +1,34 @@
-use ::error::TResult;
+use ::error::{TResult, TError};
 use ::lib_permissions::{Role, Permission};
 use ::turtl::Turtl;
 use ::jedi::{self, Value};
 use ::sync::incoming;
+use ::crypto::{self, Key};
+use ::models::user::{self, User};
+
+/// The k/v prefix we use to store member keys the user has verified
+const VERIFIED_KV_PREFIX: &'static str = "verified_member:";
+
+/// The k/v prefix we use to look up verified members by username (invites go
+/// out by email, before we know the recipient's user id)
+const VERIFIED_USERNAME_KV_PREFIX: &'static str = "verified_username:";
+
+/// A record of a member's keys that the current user has verified (by
+/// comparing fingerprints out of band). Stored in the user's local k/v.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VerifiedKeys {
+    pub fingerprint: String,
+    /// The member's encryption key. Records saved by older versions only have
+    /// the signing key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<Key>,
+    pub sign_pubkey: Key,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+}
 
 /// Holds information about a member of a space.
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct SpaceMember {
     /// Member id
     #[serde(with = "::util::ser::str_i64_converter")]
@@ -25,9 +48,154 @@ pub struct SpaceMember {
     pub created: String,
     /// When the membership was last updated
     pub updated: String,
+    /// The member's public (encryption) key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<Key>,
+    /// The member's public signing key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sign_pubkey: Option<Key>,
+    /// The member's signature over their own membership record, which ties
+    /// their username to their keys (see `signable()`)
+    #[serde(with = "::util::ser::base64_converter")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Vec<u8>>,
+}
+
+/// Build the data a member signs to claim their membership. Note that the
+/// role is left out, since admins are free to change it.
+fn signable(space_id: &String, user_id: &String, username: &String, pubkey: &Key, sign_pubkey: &Key) -> TResult<Vec<u8>> {
+    let data = format!(
+        "turtl/member/v1|{}|{}|{}|{}|{}",
+        space_id,
+        user_id,
+        username,
+        crypto::to_base64(pubkey.data())?,
+        crypto::to_base64(sign_pubkey.data())?,
+    );
+    Ok(Vec::from(data.as_bytes()))
+}
+
+/// Grab the keys the current user has verified for the given user, if any
+pub fn get_verified_keys(turtl: &Turtl, user_id: &String) -> TResult<Option<VerifiedKeys>> {
+    let key = format!("{}{}", VERIFIED_KV_PREFIX, user_id);
+    let verified = with_db!{ db, turtl.db, db.kv_get(key.as_str())? };
+    match verified {
+        Some(x) => Ok(Some(jedi::parse(&x)?)),
+        None => Ok(None),
+    }
+}
+
+/// Grab the keys the current user has verified for the given username, if any
+pub fn get_verified_keys_by_username(turtl: &Turtl, username: &String) -> TResult<Option<VerifiedKeys>> {
+    let key = format!("{}{}", VERIFIED_USERNAME_KV_PREFIX, username.to_lowercase());
+    let user_id = with_db!{ db, turtl.db, db.kv_get(key.as_str())? };
+    let verified = match user_id {
+        Some(user_id) => get_verified_keys(turtl, &user_id)?,
+        None => return Ok(None),
+    };
+    // make sure the username index isn't stale
+    Ok(verified.filter(|x| x.username.as_ref().map(|u| u.to_lowercase()) == Some(username.to_lowercase())))
+}
+
+/// Save a set of verified keys for the given user
+fn set_verified_keys(turtl: &Turtl, user_id: &String, verified: &VerifiedKeys) -> TResult<()> {
+    let key = format!("{}{}", VERIFIED_KV_PREFIX, user_id);
+    let val = jedi::stringify(verified)?;
+    with_db!{ db, turtl.db, db.kv_set(key.as_str(), &val)? };
+    if let Some(ref username) = verified.username {
+        let key = format!("{}{}", VERIFIED_USERNAME_KV_PREFIX, username.to_lowercase());
+        with_db!{ db, turtl.db, db.kv_set(key.as_str(), user_id)? };
+    }
+    Ok(())
+}
+
+/// Make sure a pubkey we're about to seal a space key with belongs to the
+/// given user, as far as we can tell. The pubkey comes (by way of the UI) from
+/// the server, so it has to match the keys the user verified for them, if
+/// any, and otherwise whatever signed membership claims they've made in the
+/// spaces we share with them (`claims`).
+pub fn check_pubkey(turtl: &Turtl, username: &String, pubkey: &Key, claims: &Vec<SpaceMember>) -> TResult<()> {
+    if let Some(verified) = get_verified_keys_by_username(turtl, username)? {
+        if let Some(ref verified_pubkey) = verified.pubkey {
+            if verified_pubkey != pubkey {
+                return TErr!(TError::PermissionDenied(format!("the key given for {} doesn't match the one you verified", username)));
+            }
+            return Ok(());
+        }
+    }
+    for member in claims {
+        if member.username.to_lowercase() != username.to_lowercase() { continue; }
+        if !member.verify_signature()? { continue; }
+        if member.pubkey.as_ref() != Some(pubkey) {
+            return TErr!(TError::PermissionDenied(format!("the key given for {} doesn't match the one they signed for space {}", username, member.space_id)));
+        }
+    }
+    Ok(())
 }
 
 impl SpaceMember {
+    /// Create the signed membership claim the current user sends along when
+    /// joining a space.
+    pub fn signed_claim(turtl: &Turtl, space_id: &String) -> TResult<Value> {
+        let user_id = turtl.user_id()?;
+        let (username, pubkey) = {
+            let user_guard = lockr!(turtl.user);
+            match user_guard.pubkey.as_ref() {
+                Some(pk) => (user_guard.username.clone(), pk.clone()),
+                None => return TErr!(TError::MissingField(String::from("User.pubkey"))),
+            }
+        };
+        let (sign_pubkey, sign_privkey) = User::sign_keypair(turtl)?;
+        let data = signable(space_id, &user_id, &username, &pubkey, &sign_pubkey)?;
+        let signature = crypto::sign::sign(&sign_privkey, data.as_slice())?;
+        Ok(json!({
+            "pubkey": pubkey,
+            "sign_pubkey": sign_pubkey,
+            "signature": crypto::to_base64(&signature)?,
+        }))
+    }
+
+    /// Check that this member's record is signed by the keys it lists. This
+    /// doesn't prove the keys belong to the member (compare fingerprints for
+    /// that), but it does mean the record is self-consistent.
+    pub fn verify_signature(&self) -> TResult<bool> {
+        let (pubkey, sign_pubkey, signature) = match (self.pubkey.as_ref(), self.sign_pubkey.as_ref(), self.signature.as_ref()) {
+            (Some(pk), Some(sign_pk), Some(sig)) => (pk, sign_pk, sig),
+            _ => return Ok(false),
+        };
+        let data = signable(&self.space_id, &self.user_id, &self.username, pubkey, sign_pubkey)?;
+        Ok(crypto::sign::verify(sign_pubkey, data.as_slice(), signature.as_slice())?)
+    }
+
+    /// Get this member's fingerprint
+    pub fn fingerprint(&self) -> TResult<String> {
+        user::fingerprint_keys(self.pubkey.as_ref(), self.sign_pubkey.as_ref())
+    }
+
+    /// Compare this member's fingerprint to one the user obtained out of band
+    /// (ie, read off the member's screen). If they match, we remember this
+    /// member's keys and flag any invites later signed by other keys.
+    ///
+    /// Returns whether or not the member was verified.
+    pub fn verify(&self, turtl: &Turtl, fingerprint: &String) -> TResult<bool> {
+        if !self.verify_signature()? {
+            return TErr!(TError::BadValue(format!("member {} has a missing or invalid signature", self.user_id)));
+        }
+        let our_fingerprint = self.fingerprint()?;
+        let normalize = |x: &String| -> String { x.chars().filter(|c| c.is_digit(10)).collect() };
+        if normalize(&our_fingerprint) != normalize(fingerprint) {
+            return Ok(false);
+        }
+        let verified = VerifiedKeys {
+            fingerprint: our_fingerprint,
+            pubkey: self.pubkey.clone(),
+            sign_pubkey: self.sign_pubkey.as_ref().expect("turtl::SpaceMember.verify() -- sign_pubkey is None after we verified the signature").clone(),
+            username: Some(self.username.clone()),
+        };
+        set_verified_keys(turtl, &self.user_id, &verified)?;
+        Ok(true)
+    }
+
     /// Save this item
     pub fn edit(&mut self, turtl: &Turtl, existing_member: Option<&mut SpaceMember>) -> TResult<()> {
         let member_data = jedi::to_val(self)?;
@@ -6,8 +6,10 @@ use ::storage::Storage;
 use ::turtl::Turtl;
 use ::sync::sync_model::SyncModel;
 use ::std::fmt::Display;
+use ::config;
 
-/// How many times a sync record can fail before it's "frozen"
+/// How many times a sync record can fail before it's "frozen" (unless set in
+/// `sync.retry.max_record_failures`)
 static MAX_ALLOWED_FAILURES: u32 = 3;
 
 /// Makes sure we only accept certain actions for syncing
@@ -50,6 +52,10 @@ pub enum SyncType {
     FileOutgoing,
     #[serde(rename = "invite")]
     Invite,
+    #[serde(rename = "revision")]
+    Revision,
+    #[serde(rename = "template")]
+    Template,
 }
 
 impl SyncType {
@@ -72,6 +78,21 @@ pub struct SyncError {
     pub msg: String,
 }
 
+/// Tracks how far along a file upload/download is, so if it's interrupted we
+/// can pick up where we left off instead of starting over
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct TransferState {
+    /// How many bytes have made it to the other side
+    pub offset: u64,
+    /// The size of the file, if we know it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// For uploads, the upload session the API gave us. For downloads, the
+    /// file's ETag. Either way, makes sure we're resuming the same transfer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
 protected! {
     /// Define a container for our sync records
     #[derive(Serialize, Deserialize)]
@@ -99,6 +120,11 @@ protected! {
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(public)]
         pub data: Option<Value>,
+        /// The version of the item this edit was made against, which lets the
+        /// server reject edits made to a stale copy
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub base_version: Option<i64>,
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(public)]
         pub error: Option<SyncError>,
@@ -111,6 +137,20 @@ protected! {
         #[serde(default)]
         #[protected_field(public)]
         pub blocked: bool,
+        /// Set when the server rejects this edit as a conflict. The record sits
+        /// out outgoing syncs until the incoming sync rebases it on top of the
+        /// remote copy (see `sync::conflict`)
+        #[serde(default)]
+        #[protected_field(public)]
+        pub awaiting_rebase: bool,
+        /// Partial transfer state for file syncs
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub transfer: Option<TransferState>,
+        /// Set by the incoming sync when this record conflicts with a local
+        /// edit that hasn't gone out yet (see `sync::conflict`)
+        #[serde(skip)]
+        pub conflict: bool,
     }
 }
 make_storable!(SyncRecord, "sync");
@@ -137,7 +177,7 @@ impl SyncRecord {
     }
 
     /// Given a DB and some params, grab all matching sync records
-    pub fn find(db: &mut Storage, ty: Option<SyncType>) -> TResult<Vec<SyncRecord>> {
+    pub fn find(db: &Storage, ty: Option<SyncType>) -> TResult<Vec<SyncRecord>> {
         let mut args = vec![];
         if let Some(x) = ty {
             let ty_string: String = jedi::parse(&jedi::stringify(&x)?)?;
@@ -157,7 +197,7 @@ impl SyncRecord {
     }
 
     /// Given a DB, find all sync records not matching `not_ty`.
-    pub fn allbut(db: &mut Storage, not_ty: &Vec<SyncType>) -> TResult<Vec<SyncRecord>> {
+    pub fn allbut(db: &Storage, not_ty: &Vec<SyncType>) -> TResult<Vec<SyncRecord>> {
         let syncs = SyncRecord::find(db, None)?
             .into_iter()
             .filter(|x| !not_ty.contains(&x.ty))
@@ -192,7 +232,9 @@ impl SyncRecord {
         let sync_record: Option<SyncRecord> = db.get("sync", &sync_id)?;
         match sync_record {
             Some(mut rec) => {
-                if rec.errcount > MAX_ALLOWED_FAILURES {
+                let max_failures = config::get(&["sync", "retry", "max_record_failures"])
+                    .unwrap_or(MAX_ALLOWED_FAILURES);
+                if rec.errcount > max_failures {
                     rec.frozen = true;
                 } else {
                     rec.errcount += 1;
@@ -207,6 +249,27 @@ impl SyncRecord {
         Ok(())
     }
 
+    /// Persist this (file) sync record's transfer state so an interrupted
+    /// transfer can resume where it left off. If the transfer has made progress
+    /// since we last saved, its failure count is reset: a flaky connection
+    /// that's still getting somewhere shouldn't get the record frozen. Records
+    /// that have been removed out from under us (ie, the note was deleted) are
+    /// left alone.
+    pub fn save_transfer(&self, db: &Storage) -> TResult<()> {
+        let sync_id = self.id_or_else()?;
+        let existing: Option<SyncRecord> = db.get("sync", &sync_id)?;
+        let mut rec = match existing {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let offset = |x: &SyncRecord| x.transfer.as_ref().map(|t| t.offset).unwrap_or(0);
+        if offset(self) > offset(&rec) {
+            rec.errcount = 0;
+        }
+        rec.transfer = self.transfer.clone();
+        db.save(&rec)
+    }
+
     /// Static method that tells the sync system to unfreeze a sync item so it
     /// gets queued to be included in the next outgoing sync.
     pub fn kick_frozen_sync(turtl: &Turtl, sync_id: &String) -> TResult<()> {
@@ -241,3 +304,29 @@ impl SyncRecord {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::schema;
+
+    #[test]
+    fn saves_transfer_state() {
+        let db = Storage::new(&String::from(":memory:"), schema::get_schema()).unwrap();
+        let mut sync: SyncRecord = jedi::from_val(json!({"id": "1", "action": "add", "item_id": "69", "user_id": 12, "type": "file:incoming", "errcount": 2})).unwrap();
+        db.save(&sync).unwrap();
+
+        // making progress resets our failures
+        sync.transfer = Some(TransferState { offset: 4096, size: Some(10000), token: Some(String::from("\"abc\"")) });
+        sync.save_transfer(&db).unwrap();
+        let saved: SyncRecord = db.get("sync", &String::from("1")).unwrap().unwrap();
+        assert_eq!(saved.transfer, sync.transfer);
+        assert_eq!(saved.errcount, 0);
+
+        // records that are gone stay gone
+        db.delete(&sync).unwrap();
+        sync.save_transfer(&db).unwrap();
+        let saved: Option<SyncRecord> = db.get("sync", &String::from("1")).unwrap();
+        assert!(saved.is_none());
+    }
+}
@@ -0,0 +1,237 @@
+//! Templates are note blueprints (title, text, tags, type, and the board new
+//! notes go in) kept per space and synced like any other space data, so a team
+//! can stamp out the same structure (meeting notes, say) without retyping it.
+//!
+//! A template's title and text can use variables (`{{date}}`, `{{time}}`,
+//! `{{username}}`, `{{space}}`, `{{board}}`, or anything the UI passes in) that
+//! get filled in when a note is created from it.
+
+use ::std::collections::HashMap;
+use ::regex::{Regex, Captures};
+use ::jedi::{self, Value};
+use ::time;
+use ::error::{TResult, TError};
+use ::crypto::Key;
+use ::models::model::Model;
+use ::models::validate::{self, Validate};
+use ::models::protected::{self, Keyfinder, Protected};
+use ::models::keychain::{Keychain, KeyRef, KeyType};
+use ::models::storable::Storable;
+use ::models::board::Board;
+use ::models::checklist::ChecklistItem;
+use ::models::sync_record::{SyncRecord, SyncAction, SyncType};
+use ::sync::sync_model::{SyncModel, MemorySaver};
+use ::sync::journal;
+use ::turtl::Turtl;
+
+protected! {
+    #[derive(Serialize, Deserialize)]
+    pub struct Template {
+        #[serde(with = "::util::ser::int_converter")]
+        #[protected_field(public)]
+        pub user_id: String,
+        #[protected_field(public)]
+        pub space_id: String,
+        /// The board notes made from this template go in (if any)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub board_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub version: Option<i64>,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub title: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub text: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub tags: Option<Vec<String>>,
+        #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub type_: Option<String>,
+        /// Items for checklist templates (see `models::checklist`)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub items: Option<Vec<ChecklistItem>>,
+    }
+}
+
+make_storable!(Template, "templates");
+impl SyncModel for Template {}
+impl MemorySaver for Template {}
+
+impl Validate for Template {
+    fn validate(&self) -> Vec<(String, String)> {
+        let mut errors = Vec::new();
+        if self.space_id == "" {
+            errors.push(validate::entry("space_id", t!("Please add a space id to this template")));
+        }
+        if self.title.as_ref().map(|x| x == "").unwrap_or(true) {
+            errors.push(validate::entry("title", t!("Please give your template a title")));
+        }
+        errors
+    }
+}
+
+impl Keyfinder for Template {
+    fn get_key_search(&self, turtl: &Turtl) -> TResult<Keychain> {
+        let mut keychain = Keychain::new();
+        let mut space_ids: Vec<String> = vec![self.space_id.clone()];
+        if let Some(keys) = self.keys.as_ref() {
+            for key in keys {
+                if key.ty == KeyType::Space { space_ids.push(key.id.clone()); }
+            }
+        }
+        let ty = String::from("space");
+        let profile_guard = lockr!(turtl.profile);
+        for space in &profile_guard.spaces {
+            if space.id().is_none() || space.key().is_none() { continue; }
+            let space_id = space.id().expect("turtl::Template.get_key_search() -- space id is None");
+            if !space_ids.contains(space_id) { continue; }
+            keychain.upsert_key(turtl, space_id, space.key().expect("turtl::Template.get_key_search() -- space key is None"), &ty)?;
+        }
+        Ok(keychain)
+    }
+
+    fn get_keyrefs(&self, turtl: &Turtl) -> TResult<Vec<KeyRef<Key>>> {
+        let mut refs: Vec<KeyRef<Key>> = Vec::new();
+        let profile_guard = lockr!(turtl.profile);
+        for space in &profile_guard.spaces {
+            if space.id() == Some(&self.space_id) && space.key().is_some() {
+                refs.push(KeyRef {
+                    id: self.space_id.clone(),
+                    ty: KeyType::Space,
+                    k: space.key().expect("turtl::Template.get_keyrefs() -- space key is None").clone(),
+                });
+            }
+        }
+        Ok(refs)
+    }
+}
+
+/// Fill in the `{{variables}}` in a bit of template text. Variables we don't
+/// have a value for are left alone.
+pub fn fill(text: &str, vars: &HashMap<String, String>) -> String {
+    lazy_static! {
+        static ref RE_VAR: Regex = Regex::new(r"\{\{\s*([a-zA-Z0-9_]+)\s*\}\}").expect("turtl::template::fill() -- failed to compile regex");
+    }
+    RE_VAR.replace_all(text, |caps: &Captures| {
+        let name = caps.at(1).unwrap_or("");
+        match vars.get(name) {
+            Some(x) => x.clone(),
+            None => String::from(caps.at(0).unwrap_or("")),
+        }
+    })
+}
+
+/// Grab (decrypted) templates, either all of them or just those in a space,
+/// sorted by title
+pub fn list(turtl: &Turtl, space_id: Option<&String>) -> TResult<Vec<Template>> {
+    let mut templates: Vec<Template> = with_db!{ db, turtl.db,
+        match space_id {
+            Some(space_id) => db.find(Template::tablename(), "space_id", &vec![space_id.clone()])?,
+            None => db.all(Template::tablename())?,
+        }
+    };
+    turtl.find_models_keys(&mut templates)?;
+    let mut templates = protected::map_deserialize(turtl, templates)?;
+    templates.sort_by(|a, b| a.title.cmp(&b.title));
+    Ok(templates)
+}
+
+/// Grab one (decrypted) template
+pub fn get(turtl: &Turtl, template_id: &String) -> TResult<Template> {
+    let template: Option<Template> = with_db!{ db, turtl.db, db.get(Template::tablename(), template_id)? };
+    let mut template = match template {
+        Some(x) => x,
+        None => return TErr!(TError::NotFound(format!("template {} not found", template_id))),
+    };
+    turtl.find_model_key(&mut template)?;
+    template.deserialize()?;
+    Ok(template)
+}
+
+/// Make a new note from a template. `options` can hold a `space_id`/`board_id`
+/// to put the note somewhere other than where the template says, and `vars`
+/// (extra variables to fill in). The note is saved through the sync system,
+/// same as if the UI had made it (so it shows up in the history and can be
+/// undone), and returned.
+pub fn create_note(turtl: &Turtl, template_id: &String, options: &Value) -> TResult<Value> {
+    let template = get(turtl, template_id)?;
+    let space_id: String = jedi::get_opt(&["space_id"], options).unwrap_or(template.space_id.clone());
+    let board_id: Option<String> = match jedi::get_opt::<String>(&["board_id"], options) {
+        Some(x) => Some(x),
+        // only use the template's board if it's (still) in the space
+        None => {
+            template.board_id.clone()
+                .filter(|x| Board::get_space_id(turtl, x).as_ref() == Some(&space_id))
+        }
+    };
+
+    let mut vars: HashMap<String, String> = HashMap::new();
+    let now = time::now();
+    vars.insert(String::from("date"), time::strftime("%Y-%m-%d", &now).unwrap_or(String::new()));
+    vars.insert(String::from("time"), time::strftime("%H:%M", &now).unwrap_or(String::new()));
+    vars.insert(String::from("username"), lockr!(turtl.user).username.clone());
+    {
+        let profile_guard = lockr!(turtl.profile);
+        let space_title = profile_guard.spaces.iter()
+            .find(|x| x.id() == Some(&space_id))
+            .and_then(|x| x.title.clone());
+        vars.insert(String::from("space"), space_title.unwrap_or(String::new()));
+        let board_title = board_id.as_ref()
+            .and_then(|id| profile_guard.boards.iter().find(|x| x.id() == Some(id)))
+            .and_then(|x| x.title.clone());
+        vars.insert(String::from("board"), board_title.unwrap_or(String::new()));
+    }
+    let extra: HashMap<String, String> = jedi::get_opt(&["vars"], options).unwrap_or(HashMap::new());
+    vars.extend(extra);
+
+    // checklist items start out not done, and get their own ids
+    let items: Option<Vec<ChecklistItem>> = template.items.as_ref().map(|items| {
+        items.iter()
+            .map(|x| ChecklistItem { id: None, text: fill(&x.text, &vars), done: false, due: x.due })
+            .collect()
+    });
+    let note = json!({
+        "space_id": space_id,
+        "board_id": board_id,
+        "type": template.type_.clone().unwrap_or(String::from("text")),
+        "title": template.title.as_ref().map(|x| fill(x, &vars)),
+        "text": template.text.as_ref().map(|x| fill(x, &vars)),
+        "tags": template.tags.clone().unwrap_or(Vec::new()),
+        "items": items,
+    });
+    let mut sync_record = SyncRecord::default();
+    sync_record.action = SyncAction::Add;
+    sync_record.ty = SyncType::Note;
+    sync_record.data = Some(note);
+    journal::dispatch(turtl, sync_record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_variables() {
+        let mut vars = HashMap::new();
+        vars.insert(String::from("date"), String::from("2017-07-04"));
+        vars.insert(String::from("username"), String::from("slappy"));
+        assert_eq!(fill("Standup {{date}}", &vars), "Standup 2017-07-04");
+        assert_eq!(fill("{{ username }} ran {{username}}'s meeting", &vars), "slappy ran slappy's meeting");
+        assert_eq!(fill("ask {{boss}} about {{date}}", &vars), "ask {{boss}} about 2017-07-04");
+        assert_eq!(fill("no vars here {{}}", &vars), "no vars here {{}}");
+    }
+
+    #[test]
+    fn validates() {
+        let template: Template = jedi::from_val(json!({"space_id": "1234", "user_id": 51})).unwrap();
+        assert_eq!(template.validate().len(), 1);
+        let template: Template = jedi::from_val(json!({"space_id": "1234", "user_id": 51, "title": "Standup"})).unwrap();
+        assert_eq!(template.validate().len(), 0);
+    }
+}
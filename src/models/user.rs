@@ -6,7 +6,8 @@ use ::api::{ApiReq, StatusCode};
 use ::models::model::{self, Model};
 use ::models::space::Space;
 use ::models::board::Board;
-use ::models::protected::{Keyfinder, Protected};
+use ::models::protected::{self, Keyfinder, Protected};
+use ::models::keychain::KeychainEntry;
 use ::models::sync_record::{SyncType, SyncAction, SyncRecord};
 use ::models::validate::{self, Validate};
 use ::turtl::Turtl;
@@ -18,12 +19,40 @@ use ::migrate::MigrateResult;
 use ::std::path::PathBuf;
 use ::std::io::prelude::*;
 use ::std::fs;
+use ::std::sync::RwLock;
+use ::time;
+use ::config;
 
 pub const CURRENT_AUTH_VERSION: u16 = 0;
+
+/// How many random bytes go into a recovery secret
+const RECOVERY_SECRET_LEN: usize = 32;
+
+/// The kv key our (generated) device key lives under
+const DEVICE_KEY_KV: &'static str = "device_key";
+
+/// The kv key we track issued login tokens under
+const LOGIN_TOKENS_KV: &'static str = "login_tokens";
+
+/// How long a login token is good for if `login_token_ttl` isn't configured
+/// (30 days)
+const LOGIN_TOKEN_TTL_DEFAULT: i64 = 60 * 60 * 24 * 30;
+
+/// The kv key prefix we mark users who've logged in with an old-style token
+/// under (see `LEGACY_TOKEN_KEY`)
+const LEGACY_TOKEN_USED_KV: &'static str = "legacy_token_used:";
+
 lazy_static! {
-    // this is the key used to encrypt login tokens. it's not meant as a real
-    // protection as much as it is a deterrent for lazy attackers
-    static ref TOKEN_KEY: Key = Key::new(vec![33, 98, 95, 119, 236, 248, 150, 31, 91, 187, 94, 119, 18, 81, 190, 80, 46, 249, 173, 255, 214, 194, 176, 88, 197, 208, 38, 234, 144, 33, 144, 52]);
+    /// The fixed key login tokens were encrypted with before they were bound
+    /// to a device. So upgrading doesn't log everyone out, a token (or saved
+    /// login) made with it works once per user, after which it gets replaced
+    /// with a device-bound one.
+    static ref LEGACY_TOKEN_KEY: Key = Key::new(vec![33, 98, 95, 119, 236, 248, 150, 31, 91, 187, 94, 119, 18, 81, 190, 80, 46, 249, 173, 255, 214, 194, 176, 88, 197, 208, 38, 234, 144, 33, 144, 52]);
+
+    /// A device key handed to us by the host platform (ie, pulled from the OS
+    /// keystore and passed in via `turtlc_set_device_key()`). If set, this is
+    /// used instead of the key we generate and store in the kv.
+    static ref HOST_DEVICE_KEY: RwLock<Option<Key>> = RwLock::new(None);
 }
 
 protected! {
@@ -49,6 +78,10 @@ protected! {
         #[protected_field(public)]
         pub pubkey: Option<Key>,
 
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub sign_pubkey: Option<Key>,
+
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(private)]
         pub settings: Option<HashMap<String, Value>>,
@@ -56,6 +89,10 @@ protected! {
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(private)]
         pub privkey: Option<Key>,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub sign_privkey: Option<Key>,
     }
 }
 
@@ -65,17 +102,112 @@ struct LoginToken {
     key: Key,
     auth: String,
     username: String,
+    /// Identifies this token in the device's list of issued tokens (which is
+    /// how we revoke them)
+    #[serde(default)]
+    token_id: String,
+    /// When this token stops working (unix timestamp)
+    #[serde(default)]
+    expires: i64,
 }
 
 impl LoginToken {
-    fn new(id: String, key: Key, auth: String, username: String) -> LoginToken {
-        LoginToken {
+    fn new(id: String, key: Key, auth: String, username: String) -> TResult<LoginToken> {
+        let ttl: i64 = config::get::<Option<i64>>(&["login_token_ttl"])
+            .unwrap_or(None)
+            .unwrap_or(LOGIN_TOKEN_TTL_DEFAULT);
+        Ok(LoginToken {
             id: id,
             key: key,
             auth: auth,
             username: username,
+            token_id: crypto::random_hash()?,
+            expires: time::get_time().sec + ttl,
+        })
+    }
+}
+
+/// An entry in our list of login tokens issued on this device
+#[derive(Serialize, Deserialize, Debug)]
+struct IssuedToken {
+    user_id: String,
+    expires: i64,
+}
+
+/// Set (or clear) the device key supplied by the host platform
+pub fn set_host_device_key(key: Option<Key>) {
+    let mut guard = lockw!(*HOST_DEVICE_KEY);
+    *guard = key;
+}
+
+/// Grab the key we use to encrypt login tokens on this device. Prefers the key
+/// handed to us by the host platform's keystore, otherwise uses a random key
+/// we generate once and keep in the kv store. Either way, login tokens are
+/// useless off the device that created them.
+fn device_key(turtl: &Turtl) -> TResult<Key> {
+    {
+        let guard = lockr!(*HOST_DEVICE_KEY);
+        if let Some(key) = guard.as_ref() {
+            return Ok(key.clone());
         }
     }
+    let kv_guard = lockr!(turtl.kv);
+    match kv_guard.kv_get(DEVICE_KEY_KV)? {
+        Some(x) => Ok(Key::new(crypto::from_base64(&x)?)),
+        None => {
+            let key = Key::random()?;
+            kv_guard.kv_set(DEVICE_KEY_KV, &crypto::to_base64(key.data())?)?;
+            Ok(key)
+        }
+    }
+}
+
+/// Load the tokens issued on this device, dropping any that have expired
+fn load_issued_tokens(turtl: &Turtl) -> TResult<HashMap<String, IssuedToken>> {
+    let kv_guard = lockr!(turtl.kv);
+    let mut tokens: HashMap<String, IssuedToken> = match kv_guard.kv_get(LOGIN_TOKENS_KV)? {
+        Some(x) => jedi::parse(&x)?,
+        None => HashMap::new(),
+    };
+    let now = time::get_time().sec;
+    tokens.retain(|_, x| x.expires > now);
+    Ok(tokens)
+}
+
+/// Save our issued tokens
+fn save_issued_tokens(turtl: &Turtl, tokens: &HashMap<String, IssuedToken>) -> TResult<()> {
+    let kv_guard = lockr!(turtl.kv);
+    kv_guard.kv_set(LOGIN_TOKENS_KV, &jedi::stringify(tokens)?)
+}
+
+/// Decrypt a login token and make sure it's still good. Also returns whether
+/// it's an old-style token (see `LEGACY_TOKEN_KEY`).
+fn open_login_token(turtl: &Turtl, token: String) -> TResult<(LoginToken, bool)> {
+    let token_encrypted = crypto::from_base64(&token)?;
+    let (token_raw, legacy) = match crypto::decrypt(&device_key(turtl)?, token_encrypted.clone()) {
+        Ok(x) => (x, false),
+        Err(_) => {
+            match crypto::decrypt(&LEGACY_TOKEN_KEY, token_encrypted) {
+                Ok(x) => (x, true),
+                Err(_) => return TErr!(TError::PermissionDenied(String::from("login token was not issued on this device"))),
+            }
+        }
+    };
+    let tokenjson = String::from_utf8(token_raw)?;
+    let token: LoginToken = jedi::parse(&tokenjson)?;
+    let valid = if legacy {
+        lockr!(turtl.kv).kv_get(&format!("{}{}", LEGACY_TOKEN_USED_KV, token.id))?.is_none()
+    } else {
+        let issued = load_issued_tokens(turtl)?;
+        match issued.get(&token.token_id) {
+            Some(x) => x.user_id == token.id && token.expires > time::get_time().sec,
+            None => false,
+        }
+    };
+    if !valid {
+        return TErr!(TError::PermissionDenied(String::from("login token has expired or been revoked")));
+    }
+    Ok((token, legacy))
 }
 
 make_storable!(User, "users");
@@ -126,6 +258,15 @@ impl MemorySaver for User {
     }
 }
 
+/// Generate a fingerprint from a user's public encryption and signing keys
+pub fn fingerprint_keys(pubkey: Option<&Key>, sign_pubkey: Option<&Key>) -> TResult<String> {
+    match (pubkey, sign_pubkey) {
+        (Some(pk), Some(sign_pk)) => Ok(crypto::fingerprint(&[pk, sign_pk])?),
+        (None, _) => TErr!(TError::MissingField(String::from("pubkey"))),
+        (_, None) => TErr!(TError::MissingField(String::from("sign_pubkey"))),
+    }
+}
+
 /// Generate a user's key given some variables or something
 fn generate_key(username: &String, password: &String, version: u16) -> TResult<Key> {
     let key: Key = match version {
@@ -159,6 +300,87 @@ pub fn generate_auth(username: &String, password: &String, version: u16) -> TRes
     Ok(key_auth)
 }
 
+/// Given a recovery secret (as handed to the user by
+/// `User::generate_recovery_key()`), derive the key that wraps the user's
+/// master key and the auth token we use to prove to the API that we hold the
+/// secret. The two are derived separately so the API never sees anything that
+/// can unwrap the master key.
+fn recovery_keys(secret: &String) -> TResult<(Key, String)> {
+    // be forgiving about formatting. people will be typing these in.
+    let normalized = secret.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase();
+    let secret_bin = match crypto::from_hex(&normalized) {
+        Ok(x) => x,
+        Err(_) => return TErr!(TError::BadValue(String::from("invalid recovery key"))),
+    };
+    if secret_bin.len() != RECOVERY_SECRET_LEN {
+        return TErr!(TError::BadValue(String::from("invalid recovery key")));
+    }
+    let mut keydata = Vec::from("turtl/recovery/key/".as_bytes());
+    keydata.extend_from_slice(secret_bin.as_slice());
+    let mut authdata = Vec::from("turtl/recovery/auth/".as_bytes());
+    authdata.extend_from_slice(secret_bin.as_slice());
+    let key = Key::new(crypto::sha256(keydata.as_slice())?);
+    let auth = crypto::to_hex(&crypto::sha256(authdata.as_slice())?)?;
+    Ok((key, auth))
+}
+
+/// Wrap a master key with a freshly-generated recovery secret. Returns the
+/// secret (formatted for the user to write down), the auth the API knows it
+/// by, and the wrapped master key.
+fn wrap_recovery_key(master_key: Key) -> TResult<(String, String, String)> {
+    let secret_hex = crypto::to_hex(&crypto::rand_bytes(RECOVERY_SECRET_LEN)?)?;
+    let (wrap_key, recovery_auth) = recovery_keys(&secret_hex)?;
+    let wrapped = protected::encrypt_key(&wrap_key, master_key)?;
+    // split the secret into groups so it's a bit easier on the eyes
+    let chunks = secret_hex.as_bytes()
+        .chunks(8)
+        .map(|x| String::from_utf8_lossy(x).into_owned())
+        .collect::<Vec<_>>();
+    Ok((chunks.join("-"), recovery_auth, wrapped))
+}
+
+/// Given what the API hands back for a recovery login, unwrap the user's
+/// master key and re-encrypt the user object and keychain with a key generated
+/// from the new password. Returns the data for our reset request (minus the
+/// recovery auth), which also clears out the recovery record.
+fn recovery_reset_data(wrap_key: &Key, wrapped: &String, user_id: &String, username: &String, new_password: &String, userdata: Value, keychain: Vec<Value>) -> TResult<Value> {
+    let master_key = match protected::decrypt_key(wrap_key, wrapped) {
+        Ok(x) => x,
+        Err(_) => return TErr!(TError::BadValue(String::from("invalid recovery key"))),
+    };
+
+    let (new_key, new_auth) = generate_auth(username, new_password, CURRENT_AUTH_VERSION)?;
+
+    let mut user: User = jedi::from_val(userdata)?;
+    user.id = Some(user_id.clone());
+    user.set_key(Some(master_key.clone()));
+    user.deserialize()?;
+    user.username = username.clone();
+    user.set_key(Some(new_key.clone()));
+    let new_userdata = Protected::serialize(&mut user)?;
+
+    let mut encrypted_keychain = Vec::with_capacity(keychain.len());
+    for entrydata in keychain {
+        let mut entry: KeychainEntry = jedi::from_val(entrydata)?;
+        entry.set_key(Some(master_key.clone()));
+        entry.deserialize()?;
+        entry.set_key(Some(new_key.clone()));
+        encrypted_keychain.push(Protected::serialize(&mut entry)?);
+    }
+
+    // NOTE: the recovery key wraps the master key we just replaced, so it has
+    // no business sticking around
+    Ok(json!({
+        "user": new_userdata,
+        "auth": new_auth,
+        "keychain": encrypted_keychain,
+        "recovery": Value::Null,
+    }))
+}
+
 /// A function that tries authenticating a username/password against various
 /// versions, starting from latest to earliest until it runs out of versions or
 /// we get a match.
@@ -241,16 +463,19 @@ impl User {
             })
     }
 
-    /// Log the user in given a token returned from get_login_token()
-    pub fn login_token(turtl: &Turtl, token: String) -> TResult<()> {
-        let token_encrypted = crypto::from_base64(&token)?;
-        let token_raw = crypto::decrypt(&(*TOKEN_KEY), token_encrypted)?;
-        let tokenjson = String::from_utf8(token_raw)?;
-        let token: LoginToken = jedi::parse(&tokenjson)?;
-        let LoginToken {id: _id, key, auth, username} = token;
+    /// Log the user in given a token returned from get_login_token(). Returns
+    /// true if the token is an old-style one (see `LEGACY_TOKEN_KEY`), in which
+    /// case the caller should replace it with a new one.
+    pub fn login_token(turtl: &Turtl, token: String) -> TResult<bool> {
+        let (token, legacy) = open_login_token(turtl, token)?;
+        let LoginToken {id, key, auth, username, ..} = token;
         let username = username.to_lowercase();
         do_login(turtl, &username, key, auth)?;
-        Ok(())
+        if legacy {
+            info!("User::login_token() -- logged in with an old-style token, it won't work again");
+            lockr!(turtl.kv).kv_set(&format!("{}{}", LEGACY_TOKEN_USED_KV, id), &String::from("1"))?;
+        }
+        Ok(legacy)
     }
 
     pub fn join(turtl: &Turtl, username: String, password: String) -> TResult<()> {
@@ -258,12 +483,15 @@ impl User {
         let username = username.to_lowercase();
         let (key, auth) = generate_auth(&username, &password, CURRENT_AUTH_VERSION)?;
         let (pk, sk) = crypto::asym::keygen()?;
+        let (sign_pk, sign_sk) = crypto::sign::keygen()?;
         let userdata = {
             let mut user = User::default();
             user.set_key(Some(key.clone()));
             user.username = username.clone();
             user.pubkey = Some(pk);
             user.privkey = Some(sk);
+            user.sign_pubkey = Some(sign_pk);
+            user.sign_privkey = Some(sign_sk);
             Protected::serialize(&mut user)?
         };
 
@@ -331,10 +559,13 @@ impl User {
             #[serde(deserialize_with = "::util::ser::opt_vec_str_i64_converter::deserialize")]
             sync_ids: Option<Vec<i64>>,
         }
+        // NOTE: the recovery key (if any) wraps the *old* master key, so it's
+        // useless after this. tell the API to toss it.
         let auth_change = json!({
             "user": new_userdata,
             "auth": new_auth,
             "keychain": encrypted_keychain,
+            "recovery": Value::Null,
         });
         let url = format!("/users/{}", user_id);
         let res: PWChangeResponse = turtl.api.put(&url[..])?.json(&auth_change).call()?;
@@ -380,6 +611,78 @@ impl User {
         Ok(())
     }
 
+    /// Generate a recovery key for the current user. We create a random secret,
+    /// use it to wrap the user's master key, and store the wrapped key with the
+    /// API. The secret is returned (and never stored anywhere) so the user can
+    /// write it down and use it with `User::recover()` if they forget their
+    /// password.
+    ///
+    /// Generating a new recovery key replaces the old one, and changing the
+    /// password invalidates it.
+    pub fn generate_recovery_key(turtl: &Turtl) -> TResult<String> {
+        turtl.assert_connected()?;
+        let (user_id, master_key) = {
+            let user_guard = lockr!(turtl.user);
+            (user_guard.id_or_else()?, user_guard.key_or_else()?)
+        };
+        let (secret, recovery_auth, wrapped) = wrap_recovery_key(master_key)?;
+        let url = format!("/users/{}/recovery", user_id);
+        turtl.api.put(url.as_str())?
+            .json(&json!({
+                "auth": recovery_auth,
+                "key": wrapped,
+            }))
+            .call::<Value>()?;
+        Ok(secret)
+    }
+
+    /// Reset a user's password using a recovery key generated by
+    /// `User::generate_recovery_key()`.
+    ///
+    /// We grab the wrapped master key (and the data encrypted with it) from the
+    /// API, unwrap it using the recovery secret, then re-encrypt the user
+    /// object and keychain with a key generated from the new password. Much
+    /// like `change_password()`, it's all or nothing: everything is sent to
+    /// the API in one request.
+    ///
+    /// The recovery key is used up once it's been used: the old recovery
+    /// record wraps a master key we no longer use, so we have the API toss it
+    /// along with the old password. The user can generate a new one once they
+    /// log in.
+    ///
+    /// Returns the user's id. Note that this does *not* log the user in.
+    pub fn recover(turtl: &Turtl, username: String, recovery_secret: String, new_password: String) -> TResult<String> {
+        validate_user(&username, &new_password)?;
+        let username = username.to_lowercase();
+        let (wrap_key, recovery_auth) = recovery_keys(&recovery_secret)?;
+
+        #[derive(Deserialize, Debug)]
+        struct RecoveryResponse {
+            #[serde(with = "::util::ser::int_converter")]
+            id: String,
+            key: String,
+            user: Value,
+            #[serde(default)]
+            keychain: Vec<Value>,
+        }
+        let res: RecoveryResponse = turtl.api.post("/users/recovery")?
+            .json(&json!({
+                "username": username,
+                "auth": recovery_auth,
+            }))
+            .call()?;
+        let RecoveryResponse { id: user_id, key: wrapped, user: userdata, keychain } = res;
+        let mut reset = recovery_reset_data(&wrap_key, &wrapped, &user_id, &username, &new_password, userdata, keychain)?;
+        reset["recovery_auth"] = json!(recovery_auth);
+
+        let url = format!("/users/recovery/{}", user_id);
+        turtl.api.put(url.as_str())?
+            .json(&reset)
+            .call::<Value>()?;
+        info!("User::recover() -- password reset via recovery key for user {}", user_id);
+        Ok(user_id)
+    }
+
     /// Once the user has joined, we set up a default profile for them.
     pub fn post_join(turtl: &Turtl, migrate_data: Option<MigrateResult>) -> TResult<()> {
         let user_id = {
@@ -536,24 +839,53 @@ impl User {
     }
 
     /// Make sure the user object has a keypair (for accepting invites/messages)
+    /// and a signing keypair (for proving who sent an invite).
     pub fn ensure_keypair(turtl: &Turtl) -> TResult<()> {
         let mut user_guard = lockw!(turtl.user);
+        if user_guard.privkey.is_some() && user_guard.sign_privkey.is_some() {
+            return Ok(());
+        }
+        let mut save_user = user_guard.clone()?;
         if user_guard.privkey.is_none() {
             info!("User::ensure_keypair() -- missing privkey, regenerating");
-            let mut save_user = user_guard.clone()?;
             let (pk, sk) = crypto::asym::keygen()?;
             // set the keypair into turtl.user as a stopgap until the sync goes
-            // through, then make sure the guard is dropped before updating
+            // through
             user_guard.pubkey = Some(pk.clone());
             user_guard.privkey = Some(sk.clone());
-            drop(user_guard);
-            save_user.pubkey = Some(pk.clone());
-            save_user.privkey = Some(sk.clone());
-            sync_model::save_model(SyncAction::Edit, turtl, &mut save_user, false)?;
+            save_user.pubkey = Some(pk);
+            save_user.privkey = Some(sk);
+        }
+        if user_guard.sign_privkey.is_none() {
+            info!("User::ensure_keypair() -- missing sign_privkey, regenerating");
+            let (pk, sk) = crypto::sign::keygen()?;
+            user_guard.sign_pubkey = Some(pk.clone());
+            user_guard.sign_privkey = Some(sk.clone());
+            save_user.sign_pubkey = Some(pk);
+            save_user.sign_privkey = Some(sk);
         }
+        // make sure the guard is dropped before updating
+        drop(user_guard);
+        sync_model::save_model(SyncAction::Edit, turtl, &mut save_user, false)?;
         Ok(())
     }
 
+    /// Get this user's fingerprint (a hash of their public keys). If two users
+    /// compare fingerprints out of band and they match, nobody in between
+    /// (including the server) has substituted keys on them.
+    pub fn fingerprint(&self) -> TResult<String> {
+        fingerprint_keys(self.pubkey.as_ref(), self.sign_pubkey.as_ref())
+    }
+
+    /// Grab the current user's signing keypair
+    pub fn sign_keypair(turtl: &Turtl) -> TResult<(Key, Key)> {
+        let user_guard = lockr!(turtl.user);
+        match (user_guard.sign_pubkey.as_ref(), user_guard.sign_privkey.as_ref()) {
+            (Some(pk), Some(sk)) => Ok((pk.clone(), sk.clone())),
+            _ => TErr!(TError::MissingField(String::from("User.sign_privkey"))),
+        }
+    }
+
     /// Resend a user's confirmation email
     pub fn resend_confirmation(turtl: &Turtl) -> TResult<()> {
         turtl.api.post("/users/confirmation/resend")?.call::<bool>()?;
@@ -569,30 +901,56 @@ impl User {
             Some(auth) => auth.clone(),
             None => return TErr!(TError::MissingField(String::from("turtl.user.auth"))),
         };
-        let token = LoginToken::new(turtl.user_id()?, user_guard.key_or_else()?, auth, user_guard.username.clone());
+        let token = LoginToken::new(turtl.user_id()?, user_guard.key_or_else()?, auth, user_guard.username.clone())?;
+        drop(user_guard);
+        let mut issued = load_issued_tokens(turtl)?;
+        issued.insert(token.token_id.clone(), IssuedToken { user_id: token.id.clone(), expires: token.expires });
+        save_issued_tokens(turtl, &issued)?;
         let tokenstr = jedi::stringify(&token)?;
-        // add a little bit more protection. obviously, an attacker can just
-        // grab this key from the source, but this might stop some less
-        // motivated folks.
-        let token_encrypted = crypto::encrypt(&(*TOKEN_KEY), Vec::from(tokenstr.as_bytes()), CryptoOp::new("chacha20poly1305")?)?;
+        // encrypt with our device key, meaning the token only works on the
+        // device it was issued on
+        let token_encrypted = crypto::encrypt(&device_key(turtl)?, Vec::from(tokenstr.as_bytes()), CryptoOp::new("chacha20poly1305")?)?;
         let token = crypto::to_base64(&token_encrypted)?;
         Ok(token)
     }
 
+    /// Revoke all login tokens (and saved logins) issued on this device for
+    /// the given user.
+    pub fn revoke_logins(turtl: &Turtl, user_id: &String) -> TResult<()> {
+        let mut issued = load_issued_tokens(turtl)?;
+        issued.retain(|_, x| &x.user_id != user_id);
+        save_issued_tokens(turtl, &issued)?;
+        let mut filepath = PathBuf::from(util::file_folder(None)?);
+        filepath.push(format!("{}.login", user_id));
+        if filepath.exists() {
+            fs::remove_file(&filepath)?;
+        }
+        info!("User::revoke_logins() -- revoked logins for user {}", user_id);
+        Ok(())
+    }
+
     /// Grab the currently logged-in user's login token, encrypt it with a
     /// random key, save the result to <data_folder>/<user_id>.login, and return
     /// the key.
     pub fn save_login(turtl: &Turtl) -> TResult<Key> {
+        let key: Key = Key::random()?;
+        User::resave_login(turtl, &key)?;
+        Ok(key)
+    }
+
+    /// Save the currently logged-in user's login token under a key the UI
+    /// already has (ie, to replace an old-style saved login after restoring
+    /// it).
+    pub fn resave_login(turtl: &Turtl, key: &Key) -> TResult<()> {
         let user_id = turtl.user_id()?;
         let login_token = User::get_login_token(turtl)?;
-        let key: Key = Key::random()?;
-        let enc = crypto::encrypt(&key, Vec::from(login_token.as_bytes()), CryptoOp::new("chacha20poly1305")?)?;
+        let enc = crypto::encrypt(key, Vec::from(login_token.as_bytes()), CryptoOp::new("chacha20poly1305")?)?;
         let mut filepath = PathBuf::from(util::file_folder(None)?);
         filepath.push(user_id + ".login");
         let mut fs_file = fs::File::create(&filepath)?;
         fs_file.write_all(enc.as_slice())?;
         info!("User::save_login() -- saved login to {:?}", filepath);
-        Ok(key)
+        Ok(())
     }
 
     /// Restores a login (saved via User::save_login()) given a user_id/key.
@@ -664,4 +1022,137 @@ mod tests {
         let (_key, auth) = generate_auth(&username, &password, 0).unwrap();
         assert_eq!(auth, "000601000c9af06607bbb78b0cab4e01f29a8d06da9a65e5698768b88ac4f4c04002c96fcfcb18a1644d5ba2546901452d0ebd6c162fe494997b52660d9d190ed525076523a1a576ea7596fdaec2e0f0606f3290bd6e5815f76889a4eada71fc20dad21703453928c74db36880cf6035922e3f7093ed1eef01a630750ebd8d64baaf34e325536011de40f3a72a4d95155ca32e851257d8bc7736d2d41c92213e93");
     }
+
+    #[test]
+    fn normalizes_recovery_keys() {
+        let secret = String::from("0123abcd-4567ef01-89abcdef-01234567-89abcdef-01234567-89abcdef-01234567");
+        let (key, auth) = recovery_keys(&secret).unwrap();
+        // case, spaces, and dashes don't matter
+        let typed = String::from(" 0123ABCD 4567EF01 89abcdef01234567-89ABCDEF--01234567 89abcdef 01234567\n");
+        let (typed_key, typed_auth) = recovery_keys(&typed).unwrap();
+        assert_eq!(typed_key, key);
+        assert_eq!(typed_auth, auth);
+        // the key and the auth are never the same thing
+        assert!(crypto::to_hex(key.data()).unwrap() != auth);
+
+        for bad in vec!["", "0123abcd", "0123abcd-4567ef01-89abcdef-01234567-89abcdef-01234567-89abcdef-0123456z", "0123abcd-4567ef01-89abcdef-01234567-89abcdef-01234567-89abcdef-01234567-00"] {
+            match recovery_keys(&String::from(bad)).map_err(|e| e.shed()) {
+                Err(TError::BadValue(_)) => {}
+                _ => panic!("bad recovery key was accepted: {}", bad),
+            }
+        }
+    }
+
+    #[test]
+    fn recovers_with_recovery_key() {
+        let turtl = ::turtl::tests::with_test(true);
+        let user_id = turtl.user_id().unwrap();
+        let (master_key, userdata) = {
+            let user_guard = lockr!(turtl.user);
+            let mut user = user_guard.clone().unwrap();
+            (user_guard.key_or_else().unwrap(), Protected::serialize(&mut user).unwrap())
+        };
+        let space_key = Key::random().unwrap();
+        let mut entry = KeychainEntry::new();
+        entry.set_key(Some(master_key.clone()));
+        entry.ty = String::from("space");
+        entry.user_id = user_id.clone();
+        entry.item_id = String::from("6969");
+        entry.k = Some(space_key.clone());
+        entry.generate_id().unwrap();
+        let keychain = vec![Protected::serialize(&mut entry).unwrap()];
+
+        let (secret, auth, wrapped) = wrap_recovery_key(master_key.clone()).unwrap();
+        let username = String::from("slippyslappy@turtlapp.com");
+        let password = String::from("forgot my old one");
+
+        // a recovery key that isn't ours can't unwrap the master key
+        let (wrong_key, _) = recovery_keys(&crypto::to_hex(&crypto::rand_bytes(RECOVERY_SECRET_LEN).unwrap()).unwrap()).unwrap();
+        match recovery_reset_data(&wrong_key, &wrapped, &user_id, &username, &password, userdata.clone(), keychain.clone()).map_err(|e| e.shed()) {
+            Err(TError::BadValue(_)) => {}
+            _ => panic!("recovered with the wrong recovery key"),
+        }
+
+        let (wrap_key, typed_auth) = recovery_keys(&secret.to_uppercase().replace("-", " ")).unwrap();
+        assert_eq!(typed_auth, auth);
+        let reset = recovery_reset_data(&wrap_key, &wrapped, &user_id, &username, &password, userdata, keychain).unwrap();
+        assert_eq!(reset["recovery"], Value::Null);
+
+        // everything opens with the new password now
+        let (new_key, new_auth) = generate_auth(&username, &password, CURRENT_AUTH_VERSION).unwrap();
+        assert_eq!(jedi::get::<String>(&["auth"], &reset).unwrap(), new_auth);
+        let mut user: User = jedi::get(&["user"], &reset).unwrap();
+        user.set_key(Some(new_key.clone()));
+        user.deserialize().unwrap();
+        assert_eq!(user.id(), Some(&user_id));
+        let mut entries: Vec<KeychainEntry> = jedi::get(&["keychain"], &reset).unwrap();
+        assert_eq!(entries.len(), 1);
+        entries[0].set_key(Some(new_key.clone()));
+        entries[0].deserialize().unwrap();
+        assert_eq!(entries[0].k, Some(space_key));
+        // ...and not with the old master key
+        entries[0].set_key(Some(master_key));
+        assert!(entries[0].deserialize().is_err());
+    }
+
+    #[test]
+    fn revokes_login_tokens() {
+        let turtl = ::turtl::tests::with_test(true);
+        let user_id = turtl.user_id().unwrap();
+        let token = User::get_login_token(&turtl).unwrap();
+        assert_eq!(load_issued_tokens(&turtl).unwrap().len(), 1);
+
+        User::revoke_logins(&turtl, &user_id).unwrap();
+        assert_eq!(load_issued_tokens(&turtl).unwrap().len(), 0);
+        match User::login_token(&turtl, token).map_err(|e| e.shed()) {
+            Err(TError::PermissionDenied(_)) => {}
+            _ => panic!("revoked login token was accepted"),
+        }
+    }
+
+    #[test]
+    fn login_tokens_are_device_bound() {
+        let turtl = ::turtl::tests::with_test(true);
+        let token = User::get_login_token(&turtl).unwrap();
+        // move this turtl to a "new device" by swapping out its device key
+        // (the kv is per-turtl in tests, unlike the host device key)
+        let new_key = Key::random().unwrap();
+        lockr!(turtl.kv).kv_set(DEVICE_KEY_KV, &crypto::to_base64(new_key.data()).unwrap()).unwrap();
+        match User::login_token(&turtl, token).map_err(|e| e.shed()) {
+            Err(TError::PermissionDenied(_)) => {}
+            _ => panic!("login token was accepted with the wrong device key"),
+        }
+    }
+
+    #[test]
+    fn legacy_login_tokens_work_once() {
+        let turtl = ::turtl::tests::with_test(true);
+        let legacy_token = {
+            let user_guard = lockr!(turtl.user);
+            let token = LoginToken {
+                id: turtl.user_id().unwrap(),
+                key: user_guard.key_or_else().unwrap(),
+                auth: user_guard.auth.clone().unwrap(),
+                username: user_guard.username.clone(),
+                ..Default::default()
+            };
+            let enc = crypto::encrypt(&LEGACY_TOKEN_KEY, Vec::from(jedi::stringify(&token).unwrap().as_bytes()), CryptoOp::new("chacha20poly1305").unwrap()).unwrap();
+            crypto::to_base64(&enc).unwrap()
+        };
+        let (token, legacy) = open_login_token(&turtl, legacy_token.clone()).unwrap();
+        assert!(legacy);
+        assert_eq!(token.id, turtl.user_id().unwrap());
+
+        // once it's been used to log in, it's no good
+        let used_key = format!("{}{}", LEGACY_TOKEN_USED_KV, turtl.user_id().unwrap());
+        lockr!(turtl.kv).kv_set(&used_key, &String::from("1")).unwrap();
+        match open_login_token(&turtl, legacy_token).map_err(|e| e.shed()) {
+            Err(TError::PermissionDenied(_)) => {}
+            _ => panic!("legacy login token was accepted twice"),
+        }
+
+        // tokens made with the device key aren't legacy
+        let token = User::get_login_token(&turtl).unwrap();
+        assert!(!open_login_token(&turtl, token).unwrap().1);
+    }
 }
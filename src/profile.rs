@@ -96,7 +96,7 @@ impl Profile {
     pub fn export(turtl: &Turtl) -> TResult<Export> {
         info!("Profile::export() -- running export");
         let mut export = Export::default();
-        export.schema_version = 2;
+        export.schema_version = 3;
         let profile_guard = lockr!(turtl.profile);
         let mut db_guard = lock!(turtl.db);
         let db = match db_guard.as_mut() {
@@ -127,14 +127,21 @@ impl Profile {
         export.notes = protected::map_deserialize(turtl, notes_encrypted)?;
         export.files = Vec::with_capacity(export.notes.len());
         for note in &export.notes {
-            match FileData::load_file(turtl, note) {
-                Ok(binary) => {
-                    let mut filedata = FileData::default();
-                    filedata.set_id(note.id_or_else()?);
-                    filedata.data = Some(binary);
-                    export.files.push(filedata);
+            let attachments = match note.attachments.as_ref() {
+                Some(x) => x,
+                None => continue,
+            };
+            for attachment in attachments {
+                match FileData::load_file(turtl, note, attachment) {
+                    Ok(binary) => {
+                        let mut filedata = FileData::default();
+                        filedata.set_id(attachment.id_or_else()?);
+                        filedata.note_id = Some(note.id_or_else()?);
+                        filedata.data = Some(binary);
+                        export.files.push(filedata);
+                    }
+                    Err(_) => {}    // we beleeze in nuzzing, lebowzki.
                 }
-                Err(_) => {}    // we beleeze in nuzzing, lebowzki.
             }
         }
         Ok(export)
@@ -297,11 +304,30 @@ impl Profile {
             let mut data = x.data()?;
             switch_id_if_needed(id_change_map, &mut data, "space_id")?;
             switch_id_if_needed(id_change_map, &mut data, "board_id")?;
-            // note that we grab the file via the old id.
-            if let Some(filedata) = file_idx.remove(old_id) {
-                // NOTE: no need to set/remove `file.id` here since it will be
-                // set when the note is saved.
-                jedi::set(&["file", "filedata"], &mut data, &filedata)?;
+            let attachments: Option<Vec<Value>> = jedi::get_opt(&["attachments"], &data);
+            match attachments {
+                Some(attachments) => {
+                    // hand each attachment its file. attachments whose file
+                    // didn't make it into the export are dropped.
+                    let attachments = attachments.into_iter()
+                        .filter_map(|mut attachment| {
+                            let id: String = jedi::get_opt(&["id"], &attachment)?;
+                            let filedata = file_idx.remove(&id)?;
+                            jedi::set(&["filedata"], &mut attachment, &filedata).ok()?;
+                            Some(attachment)
+                        })
+                        .collect::<Vec<_>>();
+                    jedi::set(&["attachments"], &mut data, &attachments)?;
+                }
+                None => {
+                    // exports from the single-file days key the file by the
+                    // note's (old) id.
+                    if let Some(filedata) = file_idx.remove(old_id) {
+                        // NOTE: no need to set/remove `file.id` here since it
+                        // will be set when the note is saved.
+                        jedi::set(&["file", "filedata"], &mut data, &filedata)?;
+                    }
+                }
             }
             Ok(data)
         }, &mut id_change_map, &mut result, &mut counter)?;
@@ -0,0 +1,166 @@
+//! Reminders let a note nag the user at a given time (its `remind_at`). A
+//! scheduler thread wakes up every `reminders.interval` seconds and has the
+//! main thread check for reminders that are due, each of which gets sent to the
+//! UI as a `note:reminder` event.
+//!
+//! Notes' reminder times are encrypted, so the scheduler doesn't know them
+//! itself: the check goes off the search index, which is built from the local
+//! db on login. Which reminders have already gone off is kept in the local db,
+//! so anything that came due while the app was closed fires the next time it
+//! starts, and nothing fires twice. Changing a note's `remind_at` sets it up to
+//! fire again.
+//!
+//! A device that's never checked before (a new install, or a new login) has no
+//! idea which reminders went off elsewhere, so it only fires reminders that
+//! came due within `GRACE_PERIOD` of its first check. Otherwise, logging in on
+//! a new device would set off every reminder the user ever had.
+
+use ::std::collections::HashMap;
+use ::std::sync::{Arc, Mutex};
+use ::std::sync::atomic::{AtomicBool, Ordering};
+use ::std::thread;
+use ::jedi;
+use ::error::TResult;
+use ::config;
+use ::messaging;
+use ::turtl::Turtl;
+use ::util;
+use ::time;
+
+/// Where we keep track of the reminders that have gone off
+const FIRED_KEY: &'static str = "reminders:fired";
+
+/// Where we keep the time of our first check
+const SINCE_KEY: &'static str = "reminders:since";
+
+/// How far back (in seconds) from our first check we fire reminders (one day)
+const GRACE_PERIOD: i64 = 60 * 60 * 24;
+
+lazy_static! {
+    /// Each check runs in its own dispatch thread, and a slow one can overlap
+    /// the next tick. This keeps them from both reading `FIRED_KEY` before
+    /// either writes it (and firing the same reminder twice).
+    static ref CHECK_LOCK: Mutex<()> = Mutex::new(());
+}
+
+fn now() -> i64 {
+    time::get_time().sec as i64
+}
+
+/// Runs our reminder checks. Stops when dropped.
+pub struct Scheduler {
+    alive: Arc<AtomicBool>,
+}
+
+impl Scheduler {
+    /// Start the scheduler thread
+    pub fn start() -> TResult<Scheduler> {
+        let interval: u64 = config::get(&["reminders", "interval"]).unwrap_or(30);
+        // an interval of 0 would have us check as fast as we can
+        let interval = ::std::cmp::max(interval, 1);
+        let alive = Arc::new(AtomicBool::new(true));
+        let alive2 = alive.clone();
+        thread::Builder::new().name(String::from("reminders")).spawn(move || {
+            loop {
+                if !alive2.load(Ordering::SeqCst) { break; }
+                messaging::app_event("reminders:check", &())
+                    .unwrap_or_else(|e| error!("reminders::Scheduler -- error sending check event: {}", e));
+                // sleep in small slices so we notice if we're stopped
+                for _ in 0..interval {
+                    if !alive2.load(Ordering::SeqCst) { break; }
+                    util::sleep(1000);
+                }
+            }
+            info!("reminders::Scheduler -- shut down");
+        })?;
+        Ok(Scheduler {
+            alive: alive,
+        })
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.alive.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Given the reminders that are due (note id, remind_at) and those that have
+/// already gone off, figure out which need to go off now. Reminders that are
+/// no longer due (the note was deleted, or its `remind_at` changed) are
+/// forgotten, and reminders from before `cutoff` never go off.
+fn pending(due: Vec<(String, i64)>, fired: &mut HashMap<String, i64>, cutoff: i64) -> Vec<(String, i64)> {
+    fired.retain(|id, at| due.iter().any(|x| &x.0 == id && &x.1 == at));
+    due.into_iter()
+        .filter(|x| x.1 >= cutoff)
+        .filter(|x| fired.get(&x.0) != Some(&x.1))
+        .collect()
+}
+
+/// Send out any reminders that are due and haven't gone off yet
+pub fn check(turtl: &Turtl) -> TResult<()> {
+    let _check_guard = lock!(CHECK_LOCK);
+    let due = {
+        let search_guard = lock!(turtl.search);
+        match search_guard.as_ref() {
+            Some(search) => search.find_reminders(now())?,
+            // no profile loaded, nothing to remind anyone of
+            None => return Ok(()),
+        }
+    };
+    let fired_json: Option<String> = with_db!{ db, turtl.db, db.kv_get(FIRED_KEY)? };
+    let mut fired: HashMap<String, i64> = match fired_json {
+        Some(x) => jedi::parse(&x).unwrap_or(HashMap::new()),
+        None => HashMap::new(),
+    };
+    let since_str: Option<String> = with_db!{ db, turtl.db, db.kv_get(SINCE_KEY)? };
+    let since = match since_str.and_then(|x| x.parse::<i64>().ok()) {
+        Some(x) => x,
+        None => {
+            let since = now();
+            with_db!{ db, turtl.db, db.kv_set(SINCE_KEY, &format!("{}", since))? };
+            since
+        }
+    };
+    for (note_id, remind_at) in pending(due, &mut fired, since - GRACE_PERIOD) {
+        let mut notes = turtl.load_notes(&vec![note_id.clone()])?;
+        if let Some(note) = notes.iter().next() {
+            messaging::ui_event("note:reminder", &json!({
+                "note": note,
+                "remind_at": remind_at,
+            }))?;
+        }
+        for note in notes.iter_mut() { note.wipe(); }
+        fired.insert(note_id, remind_at);
+    }
+    with_db!{ db, turtl.db, db.kv_set(FIRED_KEY, &jedi::stringify(&fired)?)? };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fires_reminders_once() {
+        let mut fired = HashMap::new();
+        let due = vec![(String::from("1111"), 100), (String::from("2222"), 200)];
+        let now = pending(due.clone(), &mut fired, 0);
+        assert_eq!(now, due);
+        for (id, at) in now { fired.insert(id, at); }
+        assert_eq!(pending(due.clone(), &mut fired, 0), vec![]);
+
+        // moving a reminder makes it go off again, and notes that are gone
+        // (or no longer due) are forgotten
+        let due = vec![(String::from("1111"), 150)];
+        assert_eq!(pending(due, &mut fired, 0), vec![(String::from("1111"), 150)]);
+        assert_eq!(fired.len(), 0);
+    }
+
+    #[test]
+    fn skips_reminders_from_before_the_cutoff() {
+        let mut fired = HashMap::new();
+        let due = vec![(String::from("1111"), 100), (String::from("2222"), 200)];
+        assert_eq!(pending(due, &mut fired, 150), vec![(String::from("2222"), 200)]);
+    }
+}
@@ -17,6 +17,8 @@ pub fn get_schema() -> Value {
             ]
         },
         "invites": {},
+        // recent changes, so they can be undone (see sync::journal)
+        "journal": {},
         "keychain": {
             "indexes": [
                 {"fields": ["item_id"]}
@@ -29,11 +31,23 @@ pub fn get_schema() -> Value {
                 {"fields": ["has_file"]}
             ]
         },
+        // prior versions of notes (see models::revision)
+        "revisions": {
+            "indexes": [
+                {"fields": ["note_id"]}
+            ]
+        },
         "spaces": {
             "indexes": [
                 {"fields": ["user_id"]}
             ]
         },
+        // note templates (see models::template)
+        "templates": {
+            "indexes": [
+                {"fields": ["space_id"]}
+            ]
+        },
         // formerly sync_outgoing, and it mostly is, but also used to queue
         // incoming file downloads
         "sync": {
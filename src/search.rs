@@ -15,6 +15,8 @@ use ::error::{TResult, TError};
 use ::models::model;
 use ::models::note::Note;
 use ::models::file::File;
+use ::models::checklist;
+use ::time;
 
 /// A query builder
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -34,6 +36,19 @@ pub struct Query {
     pub url: Option<String>,
     pub has_file: Option<bool>,
     pub color: Option<i32>,
+    /// Only checklists with (or without) items left to do
+    pub has_open_items: Option<bool>,
+    /// Only checklists with (or without) items that are done
+    pub has_done_items: Option<bool>,
+    /// Only checklists with an item left to do that's due by this time
+    pub due_before: Option<i64>,
+    /// Only notes that are (or aren't) past due
+    pub overdue: Option<bool>,
+    /// Only notes due in the next this-many seconds
+    pub upcoming: Option<i64>,
+    /// Search the trash instead of everything else
+    #[serde(default)]
+    pub trashed: bool,
     #[serde(default)]
     pub sort: String,
     #[serde(default)]
@@ -58,8 +73,9 @@ impl Search {
     /// Create a new Search object
     pub fn new() -> TResult<Search> {
         let idx = Clouseau::new()?;
-        idx.conn.execute("CREATE TABLE IF NOT EXISTS notes (id VARCHAR(64) PRIMARY KEY, space_id VARCHAR(96), board_id VARCHAR(96), has_file BOOL, created INTEGER, mod INTEGER, type VARCHAR(32), color INTEGER, url VARCHAR(256))", NO_PARAMS)?;
+        idx.conn.execute("CREATE TABLE IF NOT EXISTS notes (id VARCHAR(64) PRIMARY KEY, space_id VARCHAR(96), board_id VARCHAR(96), has_file BOOL, created INTEGER, mod INTEGER, type VARCHAR(32), color INTEGER, url VARCHAR(256), trashed BOOL, open_items INTEGER, done_items INTEGER, next_due INTEGER, due INTEGER, remind_at INTEGER)", NO_PARAMS)?;
         idx.conn.execute("CREATE TABLE IF NOT EXISTS notes_tags (id ROWID, note_id VARCHAR(64), tag VARCHAR(128))", NO_PARAMS)?;
+        idx.conn.execute("CREATE TABLE IF NOT EXISTS notes_links (id ROWID, note_id VARCHAR(64), target_id VARCHAR(64))", NO_PARAMS)?;
         Ok(Search {
             idx: idx,
         })
@@ -79,19 +95,24 @@ impl Search {
         }
         let board_id = get_field!(note, board_id, String::from(""));
         let board_id = if board_id == "" { None } else { Some(board_id) };
-        let has_file = note.has_file;
+        let has_file = note.has_file || note.attachments.as_ref().map(|x| x.len() > 0).unwrap_or(false);
         let mod_ = note.mod_;
         let type_ = get_field!(note, type_, String::from("text"));
         let color = get_field!(note, color, 0);
+        let trashed = note.trashed.is_some();
+        let (open_items, done_items, next_due) = checklist::counts(note);
         self.idx.conn.execute(
-            "INSERT INTO notes (id, space_id, board_id, has_file, created, mod, type, color, url) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
-            params![id, space_id, board_id, has_file, id_mod, mod_, type_, color, note.url]
+            "INSERT INTO notes (id, space_id, board_id, has_file, created, mod, type, color, url, trashed, open_items, done_items, next_due, due, remind_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![id, space_id, board_id, has_file, id_mod, mod_, type_, color, note.url, trashed, open_items, done_items, next_due, note.due, note.remind_at]
         )?;
 
         let tags = get_field!(note, tags, Vec::new());
         for tag in tags {
             self.idx.conn.execute("INSERT INTO notes_tags (note_id, tag) VALUES (?, ?)", &[&id, &tag])?;
         }
+        for target_id in note.links() {
+            self.idx.conn.execute("INSERT INTO notes_links (note_id, target_id) VALUES (?, ?)", &[&id, &target_id])?;
+        }
         let note_body = [
             get_field!(note, title, String::from("")),
             get_field!(note, text, String::from("")),
@@ -102,6 +123,19 @@ impl Search {
                 let file = get_field!(note, file, &fakefile);
                 get_field!(file, name, String::from(""))
             },
+            match note.attachments.as_ref() {
+                Some(attachments) => {
+                    attachments.iter()
+                        .flat_map(|x| x.name.iter().chain(x.text.iter()).cloned())
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                }
+                None => String::from(""),
+            },
+            match note.items.as_ref() {
+                Some(items) => items.iter().map(|x| x.text.clone()).collect::<Vec<_>>().join(" "),
+                None => String::from(""),
+            },
         ].join(" ");
         self.idx.index(&id, &note_body)?;
         Ok(())
@@ -113,6 +147,7 @@ impl Search {
         let id = get_field!(note, id);
         self.idx.conn.execute("DELETE FROM notes WHERE id = ?", &[&id])?;
         self.idx.conn.execute("DELETE FROM notes_tags where note_id = ?", &[&id])?;
+        self.idx.conn.execute("DELETE FROM notes_links where note_id = ?", &[&id])?;
         self.idx.unindex(&id)?;
         Ok(())
     }
@@ -141,6 +176,10 @@ impl Search {
         qry_vals.push(SearchVal::String(query.space_id.clone()));
         queries.push(space_qry.as_slice().join(""));
 
+        // trashed notes only turn up if we're looking in the trash
+        queries.push(String::from("SELECT id FROM notes WHERE trashed = ?"));
+        qry_vals.push(SearchVal::Bool(query.trashed));
+
         // this one is kind of weird. we basically do
         //   SELECT id FROM notes WHERE id IN (id1, id2)
         // there's probably a much better way, but this is easiest for now
@@ -206,21 +245,6 @@ impl Search {
             queries.push(tag_qry.as_slice().join(""));
         }
 
-        if query.exclude_tags.len() > 0 {
-            let mut excluded_tag_qry: Vec<&str> = Vec::with_capacity(query.exclude_tags.len() + 2);
-            excluded_tag_qry.push("SELECT note_id FROM notes_tags WHERE tag IN (");
-            for excluded_tag in &query.exclude_tags {
-                if excluded_tag == &query.exclude_tags[query.exclude_tags.len() - 1] {
-                    excluded_tag_qry.push("?");
-                } else {
-                    excluded_tag_qry.push("?,");
-                }
-                qry_vals.push(SearchVal::String(excluded_tag.clone()));
-            }
-            excluded_tag_qry.push(")");
-            exclude_queries.push(excluded_tag_qry.as_slice().join(""));
-        }
-
         if query.type_.is_some() {
             queries.push(String::from("SELECT id FROM notes WHERE type = ?"));
             qry_vals.push(SearchVal::String(query.type_.as_ref().expect("turtl::Search.find() -- query.type_ is None").clone()));
@@ -241,6 +265,60 @@ impl Search {
             qry_vals.push(SearchVal::Int(query.color.as_ref().expect("turtl::Search.find() -- query.color is None").clone()));
         }
 
+        if let Some(has_open_items) = query.has_open_items {
+            let op = if has_open_items { ">" } else { "=" };
+            queries.push(format!("SELECT id FROM notes WHERE type = ? AND open_items {} 0", op));
+            qry_vals.push(SearchVal::String(String::from(checklist::CHECKLIST_TYPE)));
+        }
+
+        if let Some(has_done_items) = query.has_done_items {
+            let op = if has_done_items { ">" } else { "=" };
+            queries.push(format!("SELECT id FROM notes WHERE type = ? AND done_items {} 0", op));
+            qry_vals.push(SearchVal::String(String::from(checklist::CHECKLIST_TYPE)));
+        }
+
+        if let Some(due_before) = query.due_before {
+            queries.push(String::from("SELECT id FROM notes WHERE next_due <= ?"));
+            qry_vals.push(SearchVal::BigInt(due_before));
+        }
+
+        if query.overdue.is_some() || query.upcoming.is_some() {
+            let now = time::get_time().sec as i64;
+            match query.overdue {
+                Some(true) => {
+                    queries.push(String::from("SELECT id FROM notes WHERE due < ?"));
+                    qry_vals.push(SearchVal::BigInt(now));
+                }
+                Some(false) => {
+                    queries.push(String::from("SELECT id FROM notes WHERE due IS NULL OR due >= ?"));
+                    qry_vals.push(SearchVal::BigInt(now));
+                }
+                None => {}
+            }
+            if let Some(upcoming) = query.upcoming {
+                queries.push(String::from("SELECT id FROM notes WHERE due >= ? AND due <= ?"));
+                qry_vals.push(SearchVal::BigInt(now));
+                qry_vals.push(SearchVal::BigInt(now.saturating_add(upcoming)));
+            }
+        }
+
+        // these go last, since they end up at the end of the final query (and
+        // our values need to be in the same order as the query's params)
+        if query.exclude_tags.len() > 0 {
+            let mut excluded_tag_qry: Vec<&str> = Vec::with_capacity(query.exclude_tags.len() + 2);
+            excluded_tag_qry.push("SELECT note_id FROM notes_tags WHERE tag IN (");
+            for excluded_tag in &query.exclude_tags {
+                if excluded_tag == &query.exclude_tags[query.exclude_tags.len() - 1] {
+                    excluded_tag_qry.push("?");
+                } else {
+                    excluded_tag_qry.push("?,");
+                }
+                qry_vals.push(SearchVal::String(excluded_tag.clone()));
+            }
+            excluded_tag_qry.push(")");
+            exclude_queries.push(excluded_tag_qry.as_slice().join(""));
+        }
+
         let filter_query = if queries.len() > 0 && exclude_queries.len() > 0 {
             let include = queries.as_slice().join(" intersect ");
             let exclude = exclude_queries.as_slice().join(" union ");
@@ -286,6 +364,26 @@ impl Search {
         Ok((note_ids, total))
     }
 
+    /// Find the (untrashed) notes with reminders that are due as of `now`.
+    /// Returns (note id, remind_at) pairs.
+    pub fn find_reminders(&self, now: i64) -> TResult<Vec<(String, i64)>> {
+        let mut prepared_qry = self.idx.conn.prepare("SELECT id, remind_at FROM notes WHERE remind_at <= ? AND trashed = ? ORDER BY remind_at ASC")?;
+        let rows = prepared_qry.query_map(params![now, false], |row| Ok((row.get_unwrap(0), row.get_unwrap(1))))?;
+        let mut reminders = Vec::new();
+        for entry in rows { reminders.push(entry?); }
+        Ok(reminders)
+    }
+
+    /// Find the (untrashed) notes that link to the given note. Returns the
+    /// linking notes' ids.
+    pub fn find_backlinks(&self, note_id: &String) -> TResult<Vec<String>> {
+        let mut prepared_qry = self.idx.conn.prepare("SELECT DISTINCT notes_links.note_id FROM notes_links INNER JOIN notes ON notes.id = notes_links.note_id WHERE notes_links.target_id = ? AND notes.trashed = ? ORDER BY notes_links.note_id ASC")?;
+        let rows = prepared_qry.query_map(params![note_id.to_lowercase(), false], |row| row.get(0))?;
+        let mut note_ids = Vec::new();
+        for id in rows { note_ids.push(id?); }
+        Ok(note_ids)
+    }
+
     /// Given a query object, find the tags that match it. This disregards page
     /// and per_page, since we want a list of all tags that match that result.
     pub fn find_tags(&self, query: &Query) -> TResult<Vec<(String, i32)>> {
@@ -350,7 +448,7 @@ impl Drop for Search {
 mod tests {
     use super::*;
 
-    use ::jedi;
+    use ::jedi::{self, Value};
     use ::models::note::Note;
 
     #[test]
@@ -359,6 +457,146 @@ mod tests {
         Search::new().unwrap();
     }
 
+    #[test]
+    fn hides_trashed_notes() {
+        let mut search = Search::new().unwrap();
+        let note1: Note = jedi::parse(&String::from(r#"{"id":"1111","space_id":"4455","user_id":69,"type":"text","title":"keep me","tags":["stuff"]}"#)).unwrap();
+        let note2: Note = jedi::parse(&String::from(r#"{"id":"2222","space_id":"4455","user_id":69,"type":"text","title":"toss me","tags":["stuff"],"trashed":1500000000}"#)).unwrap();
+        search.index_note(&note1).unwrap();
+        search.index_note(&note2).unwrap();
+
+        let query: Query = jedi::from_val(json!({"space_id": "4455", "text": "me"})).unwrap();
+        let (notes, total) = search.find(&query).unwrap();
+        assert_eq!(notes, vec!["1111"]);
+        assert_eq!(total, 1);
+        assert_eq!(search.find_tags(&query).unwrap(), vec![(String::from("stuff"), 1)]);
+
+        let query: Query = jedi::from_val(json!({"space_id": "4455", "trashed": true})).unwrap();
+        let (notes, _total) = search.find(&query).unwrap();
+        assert_eq!(notes, vec!["2222"]);
+    }
+
+    #[test]
+    fn finds_text_in_attachments() {
+        let mut search = Search::new().unwrap();
+        let note: Note = jedi::from_val(json!({
+            "id": "1111",
+            "space_id": "4455",
+            "user_id": 69,
+            "type": "file",
+            "title": "receipts",
+            "attachments": [
+                {"id": "a1", "name": "march.pdf", "text": "one rug, really tied the room together"},
+                {"id": "a2", "name": "bowling.png"},
+            ],
+        })).unwrap();
+        search.index_note(&note).unwrap();
+
+        for text in vec!["rug", "bowling"] {
+            let query: Query = jedi::from_val(json!({"space_id": "4455", "text": text})).unwrap();
+            let (notes, _total) = search.find(&query).unwrap();
+            assert_eq!(notes, vec!["1111"]);
+        }
+        let query: Query = jedi::from_val(json!({"space_id": "4455", "has_file": true})).unwrap();
+        let (notes, _total) = search.find(&query).unwrap();
+        assert_eq!(notes, vec!["1111"]);
+    }
+
+    #[test]
+    fn filters_checklists() {
+        let mut search = Search::new().unwrap();
+        let todo: Note = jedi::from_val(json!({
+            "id": "1111",
+            "space_id": "4455",
+            "user_id": 69,
+            "type": "checklist",
+            "title": "chores",
+            "items": [
+                {"id": "1", "text": "mow the lawn", "done": true},
+                {"id": "2", "text": "wash the car", "due": 1500000000},
+            ],
+        })).unwrap();
+        let done: Note = jedi::from_val(json!({
+            "id": "2222",
+            "space_id": "4455",
+            "user_id": 69,
+            "type": "checklist",
+            "title": "errands",
+            "items": [{"id": "1", "text": "buy milk", "done": true}],
+        })).unwrap();
+        let text: Note = jedi::from_val(json!({"id": "3333", "space_id": "4455", "user_id": 69, "type": "text", "title": "lawn care"})).unwrap();
+        search.index_note(&todo).unwrap();
+        search.index_note(&done).unwrap();
+        search.index_note(&text).unwrap();
+
+        let find = |query: Value| -> Vec<String> {
+            let query: Query = jedi::from_val(query).unwrap();
+            let (mut notes, _total) = search.find(&query).unwrap();
+            notes.sort();
+            notes
+        };
+        assert_eq!(find(json!({"space_id": "4455", "text": "car"})), vec!["1111"]);
+        assert_eq!(find(json!({"space_id": "4455", "has_open_items": true})), vec!["1111"]);
+        assert_eq!(find(json!({"space_id": "4455", "has_open_items": false})), vec!["2222"]);
+        assert_eq!(find(json!({"space_id": "4455", "has_done_items": true})), vec!["1111", "2222"]);
+        assert_eq!(find(json!({"space_id": "4455", "due_before": 1600000000})), vec!["1111"]);
+        assert_eq!(find(json!({"space_id": "4455", "due_before": 1400000000})), Vec::<String>::new());
+        // timestamps past what fits in an i32, alongside params that go at the
+        // end of the query
+        assert_eq!(find(json!({"space_id": "4455", "due_before": 5000000000i64, "exclude_tags": ["nope"]})), vec!["1111"]);
+    }
+
+    #[test]
+    fn finds_backlinks() {
+        let mut search = Search::new().unwrap();
+        let target: Note = jedi::from_val(json!({"id": "aaaa", "space_id": "4455", "user_id": 69, "type": "text", "text": "links to [[aaaa]] don't count"})).unwrap();
+        let linker: Note = jedi::from_val(json!({"id": "bbbb", "space_id": "4455", "user_id": 69, "type": "text", "text": "see [[AAAA]] and [[ aaaa | the other one]]"})).unwrap();
+        let list: Note = jedi::from_val(json!({
+            "id": "cccc",
+            "space_id": "4455",
+            "user_id": 69,
+            "type": "checklist",
+            "items": [{"id": "1", "text": "read [[aaaa|this]]"}],
+        })).unwrap();
+        let trashed: Note = jedi::from_val(json!({"id": "dddd", "space_id": "4455", "user_id": 69, "type": "text", "text": "[[aaaa]]", "trashed": 1500000000})).unwrap();
+        let bad: Note = jedi::from_val(json!({"id": "eeee", "space_id": "4455", "user_id": 69, "type": "text", "text": "[aaaa] [[not an id]]"})).unwrap();
+        assert_eq!(linker.links(), vec!["aaaa"]);
+        assert_eq!(target.links(), Vec::<String>::new());
+        for note in vec![&target, &linker, &list, &trashed, &bad] { search.index_note(note).unwrap(); }
+
+        assert_eq!(search.find_backlinks(&String::from("aaaa")).unwrap(), vec!["bbbb", "cccc"]);
+        assert_eq!(search.find_backlinks(&String::from("bbbb")).unwrap(), Vec::<String>::new());
+
+        // editing out the link drops it
+        let linker: Note = jedi::from_val(json!({"id": "bbbb", "space_id": "4455", "user_id": 69, "type": "text", "text": "nevermind"})).unwrap();
+        search.reindex_note(&linker).unwrap();
+        assert_eq!(search.find_backlinks(&String::from("aaaa")).unwrap(), vec!["cccc"]);
+    }
+
+    #[test]
+    fn filters_due_dates() {
+        let mut search = Search::new().unwrap();
+        let now = time::get_time().sec as i64;
+        let late: Note = jedi::from_val(json!({"id": "1111", "space_id": "4455", "user_id": 69, "type": "text", "due": now - 3600, "remind_at": now - 7200})).unwrap();
+        let soon: Note = jedi::from_val(json!({"id": "2222", "space_id": "4455", "user_id": 69, "type": "text", "due": now + 3600, "remind_at": now + 1800})).unwrap();
+        let later: Note = jedi::from_val(json!({"id": "3333", "space_id": "4455", "user_id": 69, "type": "text", "due": now + 864000})).unwrap();
+        let never: Note = jedi::from_val(json!({"id": "4444", "space_id": "4455", "user_id": 69, "type": "text"})).unwrap();
+        for note in vec![&late, &soon, &later, &never] { search.index_note(note).unwrap(); }
+
+        let find = |query: Value| -> Vec<String> {
+            let query: Query = jedi::from_val(query).unwrap();
+            let (mut notes, _total) = search.find(&query).unwrap();
+            notes.sort();
+            notes
+        };
+        assert_eq!(find(json!({"space_id": "4455", "overdue": true})), vec!["1111"]);
+        assert_eq!(find(json!({"space_id": "4455", "overdue": false})), vec!["2222", "3333", "4444"]);
+        assert_eq!(find(json!({"space_id": "4455", "upcoming": 86400})), vec!["2222"]);
+
+        assert_eq!(search.find_reminders(now).unwrap(), vec![(String::from("1111"), now - 7200)]);
+        assert_eq!(search.find_reminders(now + 3600).unwrap().len(), 2);
+    }
+
     #[test]
     fn index_unindex_filter() {
         fn parserrr(json: &str) -> Query {
@@ -0,0 +1,540 @@
+//! Handles conflicts between local edits that haven't made it to the server yet
+//! and edits to the same item coming in from another device.
+//!
+//! Spaces, boards, and notes carry a `version` that gets bumped on each local
+//! save. The first time we edit an item after it syncs, we stash the copy the
+//! edit was made against (the "base") in the local k/v store. If an incoming
+//! sync shows the item changed on the server after our base, we have a
+//! conflict. For notes, we try a three-way merge of the title/text, and if that
+//! fails we keep the local edit and save the remote one (files and all) as a
+//! new note. Anything else just keeps the local edit. Either way, the UI gets
+//! a `sync:conflict` event.
+//!
+//! Note that the base is stored in the same (encrypted) form as the item
+//! itself, so the sync thread can manage it without needing any keys.
+
+use ::jedi::{self, Value};
+use ::error::{TResult, TError};
+use ::storage::Storage;
+use ::models::model::Model;
+use ::models::protected::Protected;
+use ::models::storable::Storable;
+use ::models::note::Note;
+use ::models::file::{FileData, Attachment};
+use ::models::sync_record::{SyncRecord, SyncAction, SyncType};
+use ::sync::sync_model;
+use ::sync::files::incoming;
+use ::crypto;
+use ::turtl::Turtl;
+use ::util::merge;
+use ::messaging;
+
+/// The k/v prefix we store our base copies under
+const BASE_KV_PREFIX: &'static str = "sync:base:";
+
+/// The error code the API gives us when an outgoing edit was made against an
+/// old version of an item
+pub const CONFLICT_ERROR_CODE: &'static str = "409";
+
+/// What to do with an incoming change to an item
+#[derive(Debug, PartialEq)]
+pub enum Incoming {
+    /// We have no local edits waiting to go out, so save away
+    Apply,
+    /// Our local edits were made on top of this version (or a newer one)
+    Stale,
+    /// The item changed on the server since we started editing it locally
+    Conflict,
+}
+
+/// Lets the UI know we ran into a conflict, and what we did about it
+#[derive(Serialize, Debug)]
+pub struct ConflictEvent {
+    pub item_id: String,
+    #[serde(rename = "type")]
+    pub ty: SyncType,
+    /// One of "merged", "local", "copy", or "pending" (the server rejected an
+    /// outgoing edit and we're waiting on the incoming sync to resolve it)
+    pub resolution: &'static str,
+    /// If the remote edit was saved as a new note, this is the note's id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub copy_id: Option<String>,
+}
+
+impl ConflictEvent {
+    pub fn new(item_id: &String, ty: SyncType, resolution: &'static str) -> Self {
+        ConflictEvent {
+            item_id: item_id.clone(),
+            ty: ty,
+            resolution: resolution,
+            copy_id: None,
+        }
+    }
+}
+
+fn base_key(item_id: &String) -> String {
+    format!("{}{}", BASE_KV_PREFIX, item_id)
+}
+
+/// Whether or not we track versions for this model
+pub fn tracked<T: Protected>(model: &T) -> bool {
+    match model.model_type().as_str() {
+        "space" | "board" | "note" => true,
+        _ => false,
+    }
+}
+
+/// Grab a model's version (0 if it's never been versioned)
+pub fn version<T: Protected>(model: &T) -> TResult<i64> {
+    let data = jedi::to_val(model)?;
+    Ok(jedi::get_opt(&["version"], &data).unwrap_or(0))
+}
+
+/// Set a model's version. Models without a `version` field ignore this.
+fn set_version<T: Protected>(model: &mut T, version: i64) -> TResult<()> {
+    model.merge_fields(&json!({"version": version}))
+}
+
+/// Bump a model's version for a local save, making sure it comes out newer
+/// than the copy we had before.
+pub fn bump_version<T: Protected>(model: &mut T, previous: Option<&T>) -> TResult<()> {
+    if !tracked(model) { return Ok(()); }
+    let previous_version = match previous {
+        Some(x) => version(x)?,
+        None => 0,
+    };
+    if version(model)? > previous_version { return Ok(()); }
+    set_version(model, previous_version + 1)
+}
+
+/// Grab the local edits for an item that haven't gone out yet, in order
+fn pending_edits(db: &Storage, item_id: &String) -> TResult<Vec<SyncRecord>> {
+    let syncs = SyncRecord::find(db, None)?
+        .into_iter()
+        .filter(|x| &x.item_id == item_id && (x.action == SyncAction::Edit || x.action == SyncAction::MoveSpace))
+        .collect::<Vec<_>>();
+    Ok(syncs)
+}
+
+/// Grab the base copy of an item, if we have one
+pub fn get_base(db: &Storage, item_id: &String) -> TResult<Option<Value>> {
+    match db.kv_get(base_key(item_id).as_str())? {
+        Some(x) => Ok(Some(jedi::parse(&x)?)),
+        None => Ok(None),
+    }
+}
+
+fn set_base(db: &Storage, item_id: &String, data: &Value) -> TResult<()> {
+    db.kv_set(base_key(item_id).as_str(), &jedi::stringify(data)?)
+}
+
+/// Called before saving a local edit over `model`. If this is the first edit
+/// since the item last synced, `model` becomes the base for the edit.
+pub fn save_base<T: Protected>(db: &Storage, model: &T) -> TResult<()> {
+    if !tracked(model) { return Ok(()); }
+    let item_id = model.id_or_else()?;
+    if pending_edits(db, &item_id)?.len() > 0 { return Ok(()); }
+    set_base(db, &item_id, &model.data_for_storage()?)
+}
+
+/// Called once an outgoing sync record makes it to the server. The server now
+/// has that copy of the item, so it becomes the base for any edits still
+/// waiting to go out (or if there are none, we're done with the base).
+pub fn pushed(db: &Storage, sync: &SyncRecord) -> TResult<()> {
+    if sync.action == SyncAction::Delete || pending_edits(db, &sync.item_id)?.len() == 0 {
+        return db.kv_delete(base_key(&sync.item_id).as_str());
+    }
+    match sync.data.as_ref() {
+        Some(data) => set_base(db, &sync.item_id, data),
+        None => Ok(()),
+    }
+}
+
+/// Called when the server rejects an outgoing edit because the item changed
+/// since our base. Resending it would just get it rejected again, so it waits
+/// for the incoming sync to bring in the remote copy and rebase it.
+pub fn rejected(db: &Storage, sync: &SyncRecord) -> TResult<()> {
+    let sync_id = sync.id_or_else()?;
+    let mut rec: SyncRecord = match db.get("sync", &sync_id)? {
+        Some(x) => x,
+        // already gone, nothing to wait on
+        None => return Ok(()),
+    };
+    rec.awaiting_rebase = true;
+    rec.error = sync.error.clone();
+    db.save(&rec)
+}
+
+/// Set the version of our local copy of an item
+fn set_local_version<T>(db: &Storage, item_id: &String, version: i64) -> TResult<()>
+    where T: Protected + Storable
+{
+    if let Some(mut local) = db.get::<T>(T::tablename(), item_id)? {
+        set_version(&mut local, version)?;
+        db.save(&local)?;
+    }
+    Ok(())
+}
+
+/// Figure out what to do with an incoming change to an item
+pub fn check_incoming<T: Protected>(db: &Storage, incoming: &T) -> TResult<Incoming> {
+    if !tracked(incoming) { return Ok(Incoming::Apply); }
+    let item_id = incoming.id_or_else()?;
+    if pending_edits(db, &item_id)?.len() == 0 { return Ok(Incoming::Apply); }
+    let base_version = get_base(db, &item_id)?
+        .and_then(|x| jedi::get_opt::<i64>(&["version"], &x))
+        .unwrap_or(0);
+    if version(incoming)? > base_version {
+        Ok(Incoming::Conflict)
+    } else {
+        Ok(Incoming::Stale)
+    }
+}
+
+/// Keep our local edits to an item, but move them on top of the remote copy
+/// so they win once they go out. The remote copy becomes the new base, and any
+/// edits the server rejected are free to go out again.
+pub fn rebase<T>(db: &Storage, item_id: &String, remote: &Value) -> TResult<()>
+    where T: Protected + Storable
+{
+    let mut version = jedi::get_opt::<i64>(&["version"], remote).unwrap_or(0);
+    for mut sync in pending_edits(db, item_id)? {
+        sync.base_version = Some(version);
+        sync.awaiting_rebase = false;
+        version += 1;
+        if let Some(data) = sync.data.as_mut() {
+            jedi::set(&["version"], data, &version)?;
+        }
+        db.save(&sync)?;
+    }
+    set_local_version::<T>(db, item_id, version)?;
+    set_base(db, item_id, remote)
+}
+
+/// Drop the local edits for an item that haven't gone out yet. Only use this
+/// if you're about to save a copy of the item that supersedes them.
+fn drop_pending_edits(db: &Storage, item_id: &String) -> TResult<()> {
+    for sync in pending_edits(db, item_id)? {
+        db.delete(&sync)?;
+    }
+    Ok(())
+}
+
+/// Load and decrypt a note from its stored data
+fn open_note(turtl: &Turtl, data: Value) -> TResult<Note> {
+    let mut note: Note = jedi::from_val(data)?;
+    turtl.find_model_key(&mut note)?;
+    note.deserialize()?;
+    Ok(note)
+}
+
+/// For fields we don't merge: if we didn't touch it, take theirs.
+fn pick<T: PartialEq + Clone>(base: &T, ours: &T, theirs: &T) -> T {
+    if ours == base { theirs.clone() } else { ours.clone() }
+}
+
+/// Merge two optional strings via `merge::merge3`
+fn merge_text(base: &Option<String>, ours: &Option<String>, theirs: &Option<String>) -> Option<Option<String>> {
+    if ours == theirs { return Some(ours.clone()); }
+    let empty = String::new();
+    let merged = merge::merge3(
+        base.as_ref().unwrap_or(&empty),
+        ours.as_ref().unwrap_or(&empty),
+        theirs.as_ref().unwrap_or(&empty),
+    )?;
+    if merged == "" { Some(None) } else { Some(Some(merged)) }
+}
+
+/// Fill in the fields of a merged note that we don't merge line-by-line (see
+/// `pick()`)
+fn pick_fields(base: &Note, local: &mut Note, remote: &Note) {
+    local.board_id = pick(&base.board_id, &local.board_id, &remote.board_id);
+    local.tags = pick(&base.tags, &local.tags, &remote.tags);
+    local.url = pick(&base.url, &local.url, &remote.url);
+    local.username = pick(&base.username, &local.username, &remote.username);
+    local.password = pick(&base.password, &local.password, &remote.password);
+    local.embed = pick(&base.embed, &local.embed, &remote.embed);
+    local.color = pick(&base.color, &local.color, &remote.color);
+    local.items = pick(&base.items, &local.items, &remote.items);
+    local.due = pick(&base.due, &local.due, &remote.due);
+    local.remind_at = pick(&base.remind_at, &local.remind_at, &remote.remind_at);
+    local.attachments = union_attachments(&base.attachments, &local.attachments, &remote.attachments);
+}
+
+/// Merge two sets of attachments by id: we keep ours, plus any they added.
+/// Anything from the base that we don't have anymore, we removed.
+fn union_attachments(base: &Option<Vec<Attachment>>, ours: &Option<Vec<Attachment>>, theirs: &Option<Vec<Attachment>>) -> Option<Vec<Attachment>> {
+    let empty = Vec::new();
+    let had = |list: &Option<Vec<Attachment>>, a: &Attachment| {
+        list.as_ref().unwrap_or(&empty).iter().any(|x| x.id == a.id)
+    };
+    let added = theirs.as_ref().unwrap_or(&empty).iter()
+        .filter(|a| !had(ours, *a) && !had(base, *a))
+        .cloned()
+        .collect::<Vec<_>>();
+    match ours.clone() {
+        Some(mut attachments) => {
+            attachments.extend(added);
+            Some(attachments)
+        }
+        None if added.len() > 0 => Some(added),
+        None => None,
+    }
+}
+
+/// Build a new note holding the remote side of a conflict we couldn't merge
+fn conflicted_copy(remote: &Note, user_id: &String) -> Note {
+    let mut copy: Note = Default::default();
+    copy.space_id = remote.space_id.clone();
+    copy.board_id = remote.board_id.clone();
+    copy.user_id = user_id.clone();
+    copy.type_ = remote.type_.clone();
+    copy.title = Some(format!("{} {}", remote.title.as_ref().map(|x| x.as_str()).unwrap_or(""), t!("(conflicted copy)")).trim().to_string());
+    copy.tags = remote.tags.clone();
+    copy.url = remote.url.clone();
+    copy.username = remote.username.clone();
+    copy.password = remote.password.clone();
+    copy.text = remote.text.clone();
+    copy.embed = remote.embed.clone();
+    copy.color = remote.color.clone();
+    copy.items = remote.items.clone();
+    copy.due = remote.due;
+    copy.remind_at = remote.remind_at;
+    // the copy gets its own copies of the files (see `load_copy_files()`), so
+    // spell out the key for attachments that go by the note's
+    let remote_key = remote.key().and_then(|k| crypto::to_base64(k.data()).ok());
+    copy.attachments = remote.attachments.as_ref().map(|attachments| {
+        attachments.iter()
+            .map(|a| {
+                let mut a = a.clone();
+                if a.key.is_none() { a.key = remote_key.clone(); }
+                a
+            })
+            .collect()
+    });
+    copy
+}
+
+/// Grab the files for a conflicted copy's attachments from the remote note,
+/// downloading any we don't have yet. Attachments whose file we can't get at
+/// are left off the copy.
+fn load_copy_files(turtl: &Turtl, item_id: &String, remote: &Note, copy: &mut Note) -> Vec<(Attachment, FileData)> {
+    let attachments = match copy.attachments.take() {
+        Some(x) => x,
+        None => return Vec::new(),
+    };
+    let mut kept = Vec::with_capacity(attachments.len());
+    let mut files = Vec::with_capacity(attachments.len());
+    for attachment in attachments {
+        let loaded = attachment.id_or_else()
+            .and_then(|id| incoming::download_now(turtl, item_id, &id))
+            .and_then(|_| FileData::load_file(turtl, remote, &attachment));
+        match loaded {
+            Ok(data) => {
+                let mut filedata = FileData::new();
+                filedata.data = Some(data);
+                files.push((attachment.clone(), filedata));
+                kept.push(attachment);
+            }
+            Err(e) => warn!("conflict::load_copy_files() -- problem grabbing file {:?} of {}: {}", attachment.id, item_id, e),
+        }
+    }
+    copy.attachments = Some(kept);
+    files
+}
+
+/// Resolve a conflict between our local edit of a note and an incoming one.
+/// If the title/text merge cleanly, the merged note goes out as a new edit.
+/// Otherwise our edit wins and the remote note gets saved as a copy so nothing
+/// is lost.
+fn resolve_note(turtl: &Turtl, item_id: &String, remote_data: &Value) -> TResult<ConflictEvent> {
+    let remote = open_note(turtl, remote_data.clone())?;
+    let mut local = match turtl.load_notes(&vec![item_id.clone()])?.pop() {
+        Some(x) => x,
+        None => return TErr!(TError::NotFound(format!("note {} is missing locally", item_id))),
+    };
+    let base_data = with_db!{ db, turtl.db, get_base(db, item_id)? };
+    // if we can't get at the base, merge against nothing. this means any
+    // changes on both sides are conflicts, which is the safe choice.
+    let base = match base_data {
+        Some(data) => open_note(turtl, data).unwrap_or_else(|e| {
+            warn!("conflict::resolve_note() -- problem opening base for {}: {}", item_id, e);
+            Default::default()
+        }),
+        None => Default::default(),
+    };
+
+    let title = merge_text(&base.title, &local.title, &remote.title);
+    let text = merge_text(&base.text, &local.text, &remote.text);
+    let mut event = ConflictEvent::new(item_id, SyncType::Note, "merged");
+    match (title, text) {
+        (Some(title), Some(text)) => {
+            local.title = title;
+            local.text = text;
+            pick_fields(&base, &mut local, &remote);
+            let remote_version = remote.version.unwrap_or(0);
+            local.version = Some(remote_version + 1);
+            // the merged note includes our pending edits, so they can go. our
+            // local copy goes back to the remote version so the merge's
+            // edit is based on what the server has.
+            with_db!{ db, turtl.db,
+                drop_pending_edits(db, item_id)?;
+                set_local_version::<Note>(db, item_id, remote_version)?;
+            };
+            sync_model::save_model(SyncAction::Edit, turtl, &mut local, false)?;
+            with_db!{ db, turtl.db, set_base(db, item_id, remote_data)? };
+        }
+        _ => {
+            with_db!{ db, turtl.db, rebase::<Note>(db, item_id, remote_data)? };
+            let mut copy = conflicted_copy(&remote, &turtl.user_id()?);
+            let files = load_copy_files(turtl, item_id, &remote, &mut copy);
+            sync_model::save_model(SyncAction::Add, turtl, &mut copy, false)?;
+            for (attachment, mut filedata) in files {
+                filedata.save(turtl, &copy, &attachment)?;
+            }
+            event.resolution = "copy";
+            event.copy_id = copy.id().map(|x| x.clone());
+        }
+    }
+    Ok(event)
+}
+
+/// Resolve a conflict flagged by the incoming sync (see `SyncModel.incoming()`)
+/// and let the UI know what happened. This runs in the dispatch thread, since
+/// we need keys to get at the data.
+pub fn resolve(turtl: &Turtl, sync_item: &SyncRecord) -> TResult<()> {
+    let remote_data = match sync_item.data.as_ref() {
+        Some(x) => x,
+        None => return TErr!(TError::MissingData(format!("conflicted sync item {} is missing `data`", sync_item.item_id))),
+    };
+    let event = match sync_item.ty {
+        SyncType::Note => resolve_note(turtl, &sync_item.item_id, remote_data),
+        _ => Ok(ConflictEvent::new(&sync_item.item_id, sync_item.ty.clone(), "local")),
+    };
+    let event = match event {
+        Ok(x) => x,
+        Err(e) => {
+            // if merging blew up, fall back on keeping our edit
+            error!("conflict::resolve() -- problem resolving conflict for {}, keeping local copy: {}", sync_item.item_id, e);
+            with_db!{ db, turtl.db, rebase::<Note>(db, &sync_item.item_id, remote_data)? };
+            ConflictEvent::new(&sync_item.item_id, sync_item.ty.clone(), "local")
+        }
+    };
+    messaging::ui_event("sync:conflict", &event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::schema;
+    use ::crypto::Key;
+
+    fn note(version: i64) -> Note {
+        jedi::from_val(json!({"id": "1234", "space_id": "5678", "user_id": 12, "version": version})).unwrap()
+    }
+
+    fn with_fields(mut data: Value) -> Note {
+        data["space_id"] = json!("5678");
+        data["user_id"] = json!(12);
+        jedi::from_val(data).unwrap()
+    }
+
+    #[test]
+    fn picks_unmerged_fields() {
+        let base = with_fields(json!({"type": "checklist", "color": 1, "items": [{"id": "1", "text": "eggs"}]}));
+        let remote = with_fields(json!({"type": "checklist", "color": 2, "items": [{"id": "1", "text": "eggs"}]}));
+        let mut local = with_fields(json!({"type": "checklist", "color": 1, "items": [{"id": "1", "text": "eggs", "done": true}]}));
+        pick_fields(&base, &mut local, &remote);
+        assert_eq!(local.color, Some(2));
+        assert_eq!(jedi::to_val(&local.items).unwrap(), json!([{"id": "1", "text": "eggs", "done": true}]));
+
+        // we didn't touch the items, so theirs win
+        let remote = with_fields(json!({"type": "checklist", "items": [{"id": "1", "text": "eggs"}, {"id": "2", "text": "milk"}]}));
+        let mut local = with_fields(json!({"type": "checklist", "color": 1, "items": [{"id": "1", "text": "eggs"}]}));
+        pick_fields(&base, &mut local, &remote);
+        assert_eq!(local.items.as_ref().unwrap().len(), 2);
+
+        // same goes for when a note's due and when to remind about it
+        let base = with_fields(json!({"due": 1000, "remind_at": 900}));
+        let remote = with_fields(json!({"due": 2000, "remind_at": 900}));
+        let mut local = with_fields(json!({"due": 1000, "remind_at": 950}));
+        pick_fields(&base, &mut local, &remote);
+        assert_eq!((local.due, local.remind_at), (Some(2000), Some(950)));
+    }
+
+    #[test]
+    fn unions_attachments() {
+        let list = |ids: &[&str]| -> Option<Vec<Attachment>> {
+            Some(ids.iter().map(|id| Attachment { id: Some(id.to_string()), ..Default::default() }).collect())
+        };
+        let ids = |x: Option<Vec<Attachment>>| x.unwrap().into_iter().map(|a| a.id.unwrap()).collect::<Vec<_>>();
+        // we added 3, they added 4, and we removed 2
+        let merged = union_attachments(&list(&["1", "2"]), &list(&["1", "3"]), &list(&["1", "2", "4"]));
+        assert_eq!(ids(merged), vec!["1", "3", "4"]);
+        assert_eq!(ids(union_attachments(&None, &None, &list(&["1"]))), vec!["1"]);
+        assert!(union_attachments(&None, &None, &None).is_none());
+    }
+
+    #[test]
+    fn copies_conflicted_notes() {
+        let remote = with_fields(json!({"id": "1234", "type": "checklist", "title": "chores", "due": 2000, "remind_at": 1900, "items": [{"id": "1", "text": "dishes"}]}));
+        let copy = conflicted_copy(&remote, &String::from("12"));
+        assert!(copy.id().is_none());
+        assert_eq!(copy.space_id, "5678");
+        assert_eq!(copy.title, Some(String::from("chores (conflicted copy)")));
+        assert_eq!(jedi::to_val(&copy.items).unwrap(), json!([{"id": "1", "text": "dishes", "done": false}]));
+        assert_eq!((copy.due, copy.remind_at), (Some(2000), Some(1900)));
+
+        // attachments on the note's key get it spelled out, since the copy has
+        // a key of its own
+        let mut remote = with_fields(json!({"id": "1234", "attachments": [{"id": "1"}, {"id": "2", "key": "c2VjcmV0"}]}));
+        remote.set_key(Some(Key::new(vec![1, 2, 3])));
+        let copy = conflicted_copy(&remote, &String::from("12"));
+        let keys = copy.attachments.unwrap().into_iter().map(|a| a.key.unwrap()).collect::<Vec<_>>();
+        assert_eq!(keys, vec![String::from("AQID"), String::from("c2VjcmV0")]);
+    }
+
+    #[test]
+    fn detects_conflicts_and_rebases() {
+        let db = Storage::new(&String::from(":memory:"), schema::get_schema()).unwrap();
+        let item_id = String::from("1234");
+        db.save(&note(3)).unwrap();
+
+        // no local edits, so incoming changes go right in
+        assert_eq!(check_incoming(&db, &note(4)).unwrap(), Incoming::Apply);
+
+        // make a local edit on top of version 3
+        save_base(&db, &note(3)).unwrap();
+        let sync: SyncRecord = jedi::from_val(json!({"id": "1", "action": "edit", "item_id": "1234", "user_id": 12, "type": "note", "base_version": 3, "data": {"id": "1234", "version": 4}})).unwrap();
+        db.save(&sync).unwrap();
+        db.save(&note(4)).unwrap();
+        // another edit shouldn't move our base
+        save_base(&db, &note(4)).unwrap();
+        assert_eq!(get_base(&db, &item_id).unwrap().unwrap()["version"], json!(3));
+
+        assert_eq!(check_incoming(&db, &note(3)).unwrap(), Incoming::Stale);
+        assert_eq!(check_incoming(&db, &note(4)).unwrap(), Incoming::Conflict);
+
+        // the server turning our edit down parks it until we rebase
+        rejected(&db, &sync).unwrap();
+        let parked: SyncRecord = db.get("sync", &String::from("1")).unwrap().unwrap();
+        assert!(parked.awaiting_rebase);
+
+        // keep our edit, but move it on top of the remote version
+        rebase::<Note>(&db, &item_id, &json!({"id": "1234", "version": 6})).unwrap();
+        let sync: SyncRecord = db.get("sync", &String::from("1")).unwrap().unwrap();
+        assert_eq!(sync.base_version, Some(6));
+        assert!(!sync.awaiting_rebase);
+        assert_eq!(sync.data.as_ref().unwrap()["version"], json!(7));
+        let local: Note = db.get("notes", &item_id).unwrap().unwrap();
+        assert_eq!(local.version, Some(7));
+        assert_eq!(check_incoming(&db, &note(6)).unwrap(), Incoming::Stale);
+
+        // once our edit goes out, we're done with the base
+        db.delete(&sync).unwrap();
+        pushed(&db, &sync).unwrap();
+        assert!(get_base(&db, &item_id).unwrap().is_none());
+        assert_eq!(check_incoming(&db, &note(8)).unwrap(), Incoming::Apply);
+    }
+}
+
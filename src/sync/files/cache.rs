@@ -0,0 +1,295 @@
+//! Decides which attachments get downloaded to this device (and when), and
+//! keeps the ones we have under a disk budget by evicting the least recently
+//! used. Evicted (or never downloaded) files can be fetched on demand via
+//! `profile:note:download-file`.
+//!
+//! The download settings are per-device, so they live in the local db's k/v
+//! store and are never synced.
+
+use ::std::sync::{Arc, RwLock};
+use ::std::collections::HashMap;
+use ::std::fs;
+use ::std::path::PathBuf;
+use ::jedi;
+use ::error::{TResult, TError};
+use ::storage::Storage;
+use ::config;
+use ::time;
+use ::sync::SyncConfig;
+use ::turtl::Turtl;
+use ::messaging;
+use ::models::file::FileData;
+use ::models::sync_record::{SyncRecord, SyncType};
+
+/// Where we store our download settings
+const SETTINGS_KEY: &'static str = "sync:files:download";
+
+/// Where we store the last time each file was used
+const LRU_KEY: &'static str = "sync:files:lru";
+
+/// When incoming files get downloaded
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum DownloadPolicy {
+    /// Download everything as soon as we hear about it
+    #[serde(rename = "always")]
+    Always,
+    /// Download everything, but only while the network isn't metered (as
+    /// hinted by the UI via `sync:set-metered`)
+    #[serde(rename = "unmetered")]
+    Unmetered,
+    /// Only download files when asked to
+    #[serde(rename = "on-demand")]
+    OnDemand,
+    /// Download files under `DownloadSettings.max_size` automatically, and
+    /// anything bigger on demand
+    #[serde(rename = "under-size")]
+    UnderSize,
+}
+
+/// Our per-device settings for downloading files
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DownloadSettings {
+    pub policy: DownloadPolicy,
+    /// For the `under-size` policy, the biggest file (in bytes) we'll download
+    /// automatically
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    /// How much disk (in bytes) downloaded files can take up before we start
+    /// evicting the least recently used. None means no limit.
+    #[serde(default)]
+    pub budget: Option<u64>,
+}
+
+impl Default for DownloadSettings {
+    fn default() -> Self {
+        DownloadSettings {
+            policy: DownloadPolicy::Always,
+            max_size: None,
+            budget: config::get(&["sync", "files", "cache_budget"]).unwrap_or(None),
+        }
+    }
+}
+
+impl DownloadSettings {
+    /// Load our download settings (or the defaults if we've never set any)
+    pub fn load(db: &Storage) -> TResult<Self> {
+        match db.kv_get(SETTINGS_KEY)? {
+            Some(x) => Ok(jedi::parse(&x)?),
+            None => Ok(Default::default()),
+        }
+    }
+
+    /// Save our download settings
+    pub fn save(&self, db: &Storage) -> TResult<()> {
+        db.kv_set(SETTINGS_KEY, &jedi::stringify(self)?)
+    }
+
+    /// Whether a file (of the given size, if known) should be downloaded
+    /// without anyone asking for it
+    pub fn should_download(&self, size: Option<u64>, metered: bool) -> bool {
+        match self.policy {
+            DownloadPolicy::Always => true,
+            DownloadPolicy::Unmetered => !metered,
+            DownloadPolicy::OnDemand => false,
+            DownloadPolicy::UnderSize => {
+                match (size, self.max_size) {
+                    (_, None) => true,
+                    (Some(size), Some(max)) => size <= max,
+                    (None, Some(_)) => false,
+                }
+            }
+        }
+    }
+}
+
+/// Marks a file as being downloaded, so the file syncer and an on-demand
+/// download don't trip over each other. Released when dropped.
+pub struct DownloadClaim {
+    config: Arc<RwLock<SyncConfig>>,
+    file_id: String,
+}
+
+impl DownloadClaim {
+    /// Claim a file for downloading. Returns None if someone else is already
+    /// downloading it.
+    pub fn new(config: Arc<RwLock<SyncConfig>>, file_id: &String) -> Option<Self> {
+        let claimed = {
+            let mut guard = lockw!(config);
+            guard.downloading.insert(file_id.clone())
+        };
+        if !claimed { return None; }
+        Some(DownloadClaim {
+            config: config,
+            file_id: file_id.clone(),
+        })
+    }
+}
+
+impl Drop for DownloadClaim {
+    fn drop(&mut self) {
+        let mut guard = lockw!(self.config);
+        guard.downloading.remove(&self.file_id);
+    }
+}
+
+/// Load our file_id -> last used time map
+fn get_lru(db: &Storage) -> TResult<HashMap<String, i64>> {
+    match db.kv_get(LRU_KEY)? {
+        Some(x) => Ok(jedi::parse(&x)?),
+        None => Ok(HashMap::new()),
+    }
+}
+
+/// Note that a file was just used (downloaded, opened, etc)
+pub fn touch(db: &Storage, file_id: &String) -> TResult<()> {
+    let mut lru = get_lru(db)?;
+    lru.insert(file_id.clone(), time::get_time().sec);
+    db.kv_set(LRU_KEY, &jedi::stringify(&lru)?)
+}
+
+/// Forget about a file (it was deleted)
+pub fn forget(db: &Storage, file_id: &String) -> TResult<()> {
+    let mut lru = get_lru(db)?;
+    if lru.remove(file_id).is_some() {
+        db.kv_set(LRU_KEY, &jedi::stringify(&lru)?)?;
+    }
+    Ok(())
+}
+
+/// Given our files as (file_id, size, last used), figure out which to evict
+/// (least recently used first) to get under the budget. The files in `keep`
+/// are left alone.
+fn pick_evictions(mut files: Vec<(String, u64, i64)>, budget: u64, keep: &Vec<String>) -> Vec<String> {
+    let mut total: u64 = files.iter().map(|x| x.1).sum();
+    files.sort_by_key(|x| x.2);
+    let mut evict = Vec::new();
+    for (file_id, size, _) in files {
+        if total <= budget { break; }
+        if keep.contains(&file_id) { continue; }
+        total = total.saturating_sub(size);
+        evict.push(file_id);
+    }
+    evict
+}
+
+/// Pull the note and file ids out of one of our file's paths
+/// (`u_<user>.n_<note>.f_<file>.enc`). Thumbnails are tiny, so we leave them
+/// out of the cache (and keep them around after their file is evicted).
+fn path_ids(path: &PathBuf) -> Option<(String, String)> {
+    let name = path.file_name()?.to_str()?;
+    if !name.ends_with(".enc") || name.ends_with(".thumb.enc") { return None; }
+    let note_start = name.find(".n_")? + 3;
+    let file_start = name.find(".f_")? + 3;
+    let end = name.len() - 4;
+    if file_start <= note_start + 3 || end <= file_start { return None; }
+    Some((String::from(&name[note_start..(file_start - 3)]), String::from(&name[file_start..end])))
+}
+
+/// Let the UI know which files we evicted
+pub fn evicted_event(evicted: Vec<(String, String)>) -> TResult<()> {
+    for (note_id, file_id) in evicted {
+        messaging::ui_event("sync:file:evicted", &json!({"note_id": note_id, "file_id": file_id}))?;
+    }
+    Ok(())
+}
+
+/// If our downloaded files are over our disk budget, evict the least recently
+/// used until we're under it. Files waiting to be uploaded are never evicted,
+/// nor are the files in `keep`. Returns the (note id, file id) of each file we
+/// evicted.
+pub fn evict(db: &Storage, user_id: &String, keep: &Vec<String>) -> TResult<Vec<(String, String)>> {
+    let budget = match DownloadSettings::load(db)?.budget {
+        Some(x) => x,
+        None => return Ok(Vec::new()),
+    };
+    let mut keep = keep.clone();
+    for sync in SyncRecord::find(db, Some(SyncType::FileOutgoing))? {
+        keep.push(sync.item_id);
+    }
+    let lru = get_lru(db)?;
+    let mut files = Vec::new();
+    let mut paths = HashMap::new();
+    for path in FileData::file_finder_all(Some(user_id), None, None)? {
+        let (note_id, file_id) = match path_ids(&path) {
+            Some(x) => x,
+            None => continue,
+        };
+        let size = fs::metadata(&path)?.len();
+        let used = lru.get(&file_id).cloned().unwrap_or(0);
+        files.push((file_id.clone(), size, used));
+        paths.insert(file_id, (note_id, path));
+    }
+    let mut evicted = Vec::new();
+    for file_id in pick_evictions(files, budget, &keep) {
+        info!("cache::evict() -- evicting file {}", file_id);
+        if let Some((note_id, path)) = paths.remove(&file_id) {
+            fs::remove_file(&path)?;
+            evicted.push((note_id, file_id.clone()));
+        }
+        forget(db, &file_id)?;
+    }
+    Ok(evicted)
+}
+
+/// Get this device's download settings
+pub fn get_download_settings(turtl: &Turtl) -> TResult<DownloadSettings> {
+    let db_guard = lock!(turtl.db);
+    match db_guard.as_ref() {
+        Some(db) => DownloadSettings::load(db),
+        None => TErr!(TError::MissingField(String::from("Turtl.db"))),
+    }
+}
+
+/// Update this device's download settings, evicting files if we're now over
+/// our disk budget
+pub fn set_download_settings(turtl: &Turtl, settings: DownloadSettings) -> TResult<()> {
+    let user_id = turtl.user_id()?;
+    let evicted = {
+        let db_guard = lock!(turtl.db);
+        let db = match db_guard.as_ref() {
+            Some(x) => x,
+            None => return TErr!(TError::MissingField(String::from("Turtl.db"))),
+        };
+        settings.save(db)?;
+        evict(db, &user_id, &vec![])?
+    };
+    evicted_event(evicted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_download_policy() {
+        let mut settings = DownloadSettings { policy: DownloadPolicy::Always, max_size: None, budget: None };
+        assert!(settings.should_download(Some(9999999), true));
+        settings.policy = DownloadPolicy::Unmetered;
+        assert!(settings.should_download(None, false));
+        assert!(!settings.should_download(None, true));
+        settings.policy = DownloadPolicy::OnDemand;
+        assert!(!settings.should_download(Some(1), false));
+        settings.policy = DownloadPolicy::UnderSize;
+        settings.max_size = Some(1000);
+        assert!(settings.should_download(Some(1000), true));
+        assert!(!settings.should_download(Some(1001), false));
+        assert!(!settings.should_download(None, false));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let files = vec![
+            (String::from("n1"), 400, 300),
+            (String::from("n2"), 400, 100),
+            (String::from("n3"), 400, 200),
+            (String::from("n4"), 400, 0),
+        ];
+        assert_eq!(pick_evictions(files.clone(), 2000, &vec![]), Vec::<String>::new());
+        assert_eq!(pick_evictions(files.clone(), 1000, &vec![]), vec![String::from("n4"), String::from("n2")]);
+        assert_eq!(pick_evictions(files.clone(), 1000, &vec![String::from("n4")]), vec![String::from("n2"), String::from("n3")]);
+        assert_eq!(path_ids(&PathBuf::from("/tmp/files/u_12.n_abc123.f_def456.enc")), Some((String::from("abc123"), String::from("def456"))));
+        assert_eq!(path_ids(&PathBuf::from("/tmp/files/u_12.n_abc123.f_def456.enc.part")), None);
+        assert_eq!(path_ids(&PathBuf::from("/tmp/files/u_12.n_abc123.enc")), None);
+        assert_eq!(path_ids(&PathBuf::from("/tmp/files/u_12.n_abc123.f_def456.thumb.enc")), None);
+    }
+}
@@ -1,20 +1,33 @@
 use ::std::sync::{Arc, RwLock, Mutex};
 use ::sync::{SyncConfig, Syncer};
 use ::sync::sync_model::SyncModel;
+use ::sync::status::{Progress, Transfer};
+use ::sync::files::cache::{self, DownloadSettings, DownloadClaim};
 use ::storage::Storage;
-use ::api::{Api, Method};
+use ::api::{Api, Method, StatusCode};
 use ::messaging;
 use ::error::{TResult, TError};
-use ::models::sync_record::{SyncType, SyncRecord};
+use ::models::sync_record::{SyncType, SyncRecord, SyncAction};
+use ::models::note::Note;
+use ::models::model::Model;
+use ::turtl::Turtl;
 use ::models::file::FileData;
 use ::std::time::Duration;
 use ::std::fs;
-use ::std::io::{Read, Write};
+use ::std::io::{Read, Write, Seek, SeekFrom};
+use ::std::cmp;
 use ::jedi::{self, Value};
 use ::util;
 use ::config;
 use ::reqwest;
 
+/// How often (in bytes) we save our place while downloading a file
+const SAVE_INTERVAL: u64 = 1024 * 1024;
+
+/// How long (ms) an on-demand download waits for the file syncer to finish
+/// grabbing the same file
+const DOWNLOAD_WAIT: u64 = 60000;
+
 /// Holds the state for incoming files (download)
 pub struct FileSyncIncoming {
     /// Holds our sync config. Note that this is shared between the sync system
@@ -43,104 +56,219 @@ impl FileSyncIncoming {
         }
     }
 
-    /// Returns a list of note_ids for notes that have pending file downloads.
-    /// This uses the `sync` table.
+    /// Returns the sync records for pending file downloads our download policy
+    /// says we should grab. This uses the `sync` table.
     fn get_incoming_file_syncs(&self) -> TResult<Vec<SyncRecord>> {
-        let syncs = with_db!{ db, self.db,
-            SyncRecord::find(db, Some(SyncType::FileIncoming))
-        }?;
-        let mut final_syncs = Vec::with_capacity(syncs.len());
-        for sync in syncs {
-            // NOTE: in the normal sync process, we break on frozen. here, we
-            // continue. the reason being that file syncs don't necessarily
-            // benefit from being run in order like normal outgoing syncs do.
-            if sync.frozen { continue; }
-            final_syncs.push(sync);
+        let metered = {
+            let local_config = self.get_config();
+            let guard = lockr!(local_config);
+            guard.metered
+        };
+        with_db!{ db, self.db,
+            let settings = DownloadSettings::load(db)?;
+            let syncs = SyncRecord::find(db, Some(SyncType::FileIncoming))?;
+            let mut final_syncs = Vec::with_capacity(syncs.len());
+            for sync in syncs {
+                // NOTE: in the normal sync process, we break on frozen. here, we
+                // continue. the reason being that file syncs don't necessarily
+                // benefit from being run in order like normal outgoing syncs do.
+                if sync.frozen { continue; }
+                let size: Option<u64> = match sync.data.as_ref().and_then(|x| jedi::get_opt(&["size"], x)) {
+                    Some(x) => Some(x),
+                    // files from the single-file days have their size in the
+                    // note
+                    None => {
+                        let note: Option<Note> = db.get("notes", &FileData::sync_note_id(&sync))?;
+                        note.as_ref()
+                            .and_then(|x| x.file.as_ref())
+                            .and_then(|x| x.size)
+                    }
+                };
+                // files we skip here stay queued, so they can be grabbed on
+                // demand (or once our policy changes)
+                if !settings.should_download(size, metered) { continue; }
+                final_syncs.push(sync);
+            }
+            Ok(final_syncs)
         }
-        Ok(final_syncs)
     }
 
-    /// Given a sync record for an outgoing file, find the corresponding file
-    /// in our storage folder and stream it to our heroic API.
-    fn download_file(&mut self, sync: &SyncRecord) -> TResult<()> {
-        let note_id = &sync.item_id;
-        let user_id = {
-            let local_config = self.get_config();
-            let guard = lockr!(local_config);
-            match guard.user_id.as_ref() {
-                Some(x) => x.clone(),
-                None => return TErr!(TError::MissingField(String::from("SyncConfig.user_id"))),
+    /// Start a request for the file at `file_url`. If `offset` is non-zero, we
+    /// only ask for the bytes from there on, and pass along the `etag` we saw
+    /// last time so if the file changed, the server sends us the whole thing
+    /// instead.
+    fn request_file(&self, file_url: &String, offset: u64, etag: Option<&String>) -> TResult<reqwest::blocking::Response> {
+        let mut client_builder = reqwest::blocking::Client::builder()
+            .timeout(Duration::new(30, 0));
+        match config::get::<Option<String>>(&["api", "proxy"]) {
+            Ok(Some(proxy_cfg)) => {
+                client_builder = client_builder.proxy(reqwest::Proxy::http(format!("http://{}", proxy_cfg).as_str())?);
+            }
+            Ok(None) => {}
+            Err(_) => {}
+        }
+        let client = client_builder.build()?;
+        let mut req = client.request(Method::GET, reqwest::Url::parse(file_url.as_str())?);
+        if offset > 0 {
+            req = req.header("Range", format!("bytes={}-", offset));
+            if let Some(etag) = etag {
+                req = req.header("If-Range", etag.clone());
             }
+        }
+        // only add our auth junk if we're calling back to the turtl api!
+        let turtl_api_url: String = config::get(&["api", "endpoint"])?;
+        let req = if file_url.contains(turtl_api_url.as_str()) {
+            self.api.set_auth_headers(req)
+        } else {
+            req
         };
-        info!("FileSyncIncoming.download_file() -- syncing file for {}", note_id);
-
-        // define a container function that grabs our file and runs the download.
-        // if anything in here fails, we mark 
-        let download = |note_id, user_id| -> TResult<()> {
-            // generate the filename we'll save to, and open the file (we should
-            // test if the file can be created before we run off blasting API
-            // calls in every direction)
-            let file = FileData::new_file(user_id, note_id)?;
-            let parent = match file.parent() {
-                Some(path) => path.clone(),
-                None => return TErr!(TError::BadValue(format!("bad file path: {:?}", file))),
+        let res = client.execute(req.build()?)?;
+        let status = res.status().clone();
+        // a 416 means we asked for a range the server can't give us, which our
+        // caller handles by starting over
+        if status.as_u16() >= 400 && status != StatusCode::RANGE_NOT_SATISFIABLE {
+            let errstr = res.text()?;
+            let val = match jedi::parse(&errstr) {
+                Ok(x) => x,
+                Err(_) => Value::String(errstr),
             };
-            util::create_dir(parent)?;
-            let mut file = fs::File::create(&file)?;
+            return TErr!(TError::Api(status, val));
+        }
+        Ok(res)
+    }
 
+    /// Download a note's file into a partial file, picking up where we left
+    /// off if a previous attempt got interrupted. Once the download completes,
+    /// the partial file is moved into place. Our progress is saved into the
+    /// sync record as we go.
+    fn download(&self, sync: &mut SyncRecord, user_id: &String, file_num: usize, num_files: usize) -> TResult<()> {
+        let note_id = FileData::sync_note_id(sync);
+        let file_id = sync.item_id.clone();
+        // generate the filenames we'll save to, and open the partial file (we
+        // should test if the file can be created before we run off blasting
+        // API calls in every direction)
+        let file = FileData::new_file(user_id, &note_id, &file_id)?;
+        let partial = FileData::partial_file(user_id, &note_id, &file_id)?;
+        let parent = match file.parent() {
+            Some(path) => path.clone(),
+            None => return TErr!(TError::BadValue(format!("bad file path: {:?}", file))),
+        };
+        util::create_dir(parent)?;
+        let mut out = fs::OpenOptions::new().create(true).write(true).open(&partial)?;
+
+        // figure out where we left off. if the partial file and the sync record
+        // disagree, go with whichever is less far along.
+        let mut state = sync.transfer.clone().unwrap_or_default();
+        state.offset = cmp::min(state.offset, out.metadata()?.len());
+
+        let already_done = state.offset > 0 && state.size == Some(state.offset);
+        if !already_done {
             // start our API call to the note file attachment endpoint
-            let url = format!("/notes/{}/attachment", note_id);
+            let url = FileData::attachment_url(&note_id, &file_id);
             // grab the location of the file we'll be downloading
             let file_url: String = self.api.get(&url[..])?.call()?;
-            info!("FileSyncIncoming.download_file() -- grabbing file at URL {}", file_url);
+            info!("FileSyncIncoming.download_file() -- grabbing file at URL {} (from byte {})", file_url, state.offset);
 
-            let mut client_builder = reqwest::blocking::Client::builder()
-                .timeout(Duration::new(30, 0));
-            match config::get::<Option<String>>(&["api", "proxy"]) {
-                Ok(Some(proxy_cfg)) => {
-                    client_builder = client_builder.proxy(reqwest::Proxy::http(format!("http://{}", proxy_cfg).as_str())?);
+            let mut res = self.request_file(&file_url, state.offset, state.token.as_ref())?;
+            let range = res.headers().get("Content-Range")
+                .and_then(|x| x.to_str().ok())
+                .and_then(parse_content_range);
+            let resumed = res.status() == StatusCode::PARTIAL_CONTENT &&
+                range.and_then(|x| x.0) == Some(state.offset);
+            if !resumed && state.offset > 0 {
+                // if the server sent us the full file, great, we just start
+                // over. if it sent something we didn't ask for, ask again for
+                // the full file.
+                if res.status() != StatusCode::OK {
+                    info!("FileSyncIncoming.download_file() -- couldn't resume download for {}, starting over", file_id);
+                    res = self.request_file(&file_url, 0, None)?;
                 }
-                Ok(None) => {}
-                Err(_) => {}
-            }
-            let client = client_builder.build()?;
-            let req = client.request(Method::GET, reqwest::Url::parse(file_url.as_str())?);
-            // only add our auth junk if we're calling back to the turtl api!
-            let turtl_api_url: String = config::get(&["api", "endpoint"])?;
-            let req = if file_url.contains(turtl_api_url.as_str()) {
-                self.api.set_auth_headers(req)
-            } else {
-                req
-            };
-            let mut res = client.execute(req.build()?)?;
-            let status = res.status().clone();
-            if status.as_u16() >= 400 {
-                let errstr = res.text()?;
-                let val = match jedi::parse(&errstr) {
-                    Ok(x) => x,
-                    Err(_) => Value::String(errstr),
-                };
-                return TErr!(TError::Api(status, val));
+                state.offset = 0;
             }
-            // start streaming our API call into the file 4K at a time
-            let mut buf = [0; 4096];
-            loop {
-                let read = res.read(&mut buf[..])?;
-                // all done! (EOF)
-                if read <= 0 { break; }
-                let (read_bytes, _) = buf.split_at(read);
-                let written = file.write(read_bytes)?;
-                if read != written {
-                    return TErr!(TError::Msg(format!("problem downloading file: downloaded {} bytes, only saved {} wtf wtf lol", read, written)));
+            state.size = if resumed { range.and_then(|x| x.1) } else { res.content_length() };
+            state.token = res.headers().get("ETag")
+                .and_then(|x| x.to_str().ok())
+                .map(String::from);
+            out.set_len(state.offset)?;
+            out.seek(SeekFrom::Start(state.offset))?;
+
+            let mut progress = Progress::new(self.get_config(), Transfer {
+                syncer: self.get_name(),
+                note_id: note_id.clone(),
+                file_id: file_id.clone(),
+                direction: "download",
+                bytes: state.offset,
+                total: state.size,
+                file: file_num,
+                files: num_files,
+            });
+            // start streaming our API call into the file 4K at a time, saving
+            // our place every so often
+            let mut unsaved = 0;
+            let result = (|| -> TResult<()> {
+                let mut buf = [0; 4096];
+                loop {
+                    let read = res.read(&mut buf[..])?;
+                    // all done! (EOF)
+                    if read <= 0 { break; }
+                    out.write_all(&buf[0..read])?;
+                    state.offset += read as u64;
+                    progress.add(read as u64);
+                    unsaved += read as u64;
+                    if unsaved >= SAVE_INTERVAL {
+                        out.flush()?;
+                        sync.transfer = Some(state.clone());
+                        with_db!{ db, self.db, sync.save_transfer(db)? };
+                        unsaved = 0;
+                    }
                 }
+                Ok(())
+            })();
+            out.flush()?;
+            sync.transfer = Some(state.clone());
+            with_db!{ db, self.db, sync.save_transfer(db)? };
+            result?;
+            progress.publish();
+            if let Some(size) = state.size {
+                if state.offset != size {
+                    return TErr!(TError::Msg(format!("problem downloading file: got {} of {} bytes", state.offset, size)));
+                }
+            }
+        }
+
+        // we have the whole file. move it into place.
+        drop(out);
+        if file.exists() { fs::remove_file(&file)?; }
+        fs::rename(&partial, &file)?;
+        // any thumbnail we have is for the old file. a new one gets made the
+        // next time it's asked for.
+        let thumb = FileData::thumbnail_file(user_id, &note_id, &file_id)?;
+        if thumb.exists() { fs::remove_file(&thumb)?; }
+        Ok(())
+    }
+
+    /// Given a sync record for an incoming file, download the file from our
+    /// heroic API into our storage folder. `file_num` and `num_files` tell us
+    /// where this download falls in the current batch (for progress
+    /// reporting).
+    fn download_file(&mut self, sync: &mut SyncRecord, file_num: usize, num_files: usize) -> TResult<()> {
+        let note_id = FileData::sync_note_id(sync);
+        let file_id = sync.item_id.clone();
+        let user_id = {
+            let local_config = self.get_config();
+            let guard = lockr!(local_config);
+            match guard.user_id.as_ref() {
+                Some(x) => x.clone(),
+                None => return TErr!(TError::MissingField(String::from("SyncConfig.user_id"))),
             }
-            Ok(())
         };
+        info!("FileSyncIncoming.download_file() -- syncing file {} for {}", file_id, note_id);
 
-        match download(&note_id, &user_id) {
+        match self.download(sync, &user_id, file_num, num_files) {
             Ok(_) => {}
             Err(e) => {
-                // our download failed? send to our sync failure handler
+                // our download failed? send to our sync failure handler. our
+                // progress has already been saved, so next time we'll resume.
                 with_db!{ db, self.db,
                     SyncRecord::handle_failed_sync(db, sync)?;
                 };
@@ -149,16 +277,88 @@ impl FileSyncIncoming {
         }
 
         // if we're still here, the download succeeded. remove the sync record so
-        // we know to stop trying to download this file.
-        with_db!{ db, self.db, sync.db_delete(db, None)? };
+        // we know to stop trying to download this file, and make room for it
+        // if we're over our disk budget.
+        let evicted = with_db!{ db, self.db,
+            sync.db_delete(db, None)?;
+            cache::touch(db, &file_id)?;
+            cache::evict(db, &user_id, &vec![file_id.clone()])
+        }?;
 
         // let the UI know how great we are. you will love this app. tremendous
         // app. everyone says so.
-        messaging::ui_event("sync:file:downloaded", &json!({"note_id": note_id}))?;
+        messaging::ui_event("sync:file:downloaded", &json!({"note_id": note_id, "file_id": file_id}))?;
+        // we can't decrypt anything from here, so let the main thread pull
+        // the text out of the file for search
+        messaging::app_event("file:downloaded", &json!({"note_id": note_id, "file_id": file_id}))?;
+        cache::evicted_event(evicted)?;
         Ok(())
     }
 }
 
+/// Download one of a note's files right now, regardless of our download
+/// policy (ie, the user just opened it). Runs in the calling thread, and if the
+/// file syncer is already downloading the file, waits for it to finish.
+pub fn download_now(turtl: &Turtl, note_id: &String, file_id: &String) -> TResult<()> {
+    let user_id = turtl.user_id()?;
+    let mut syncer = FileSyncIncoming::new(turtl.sync_config.clone(), turtl.api.clone(), turtl.db.clone());
+    let mut waited = 0;
+    let _claim = loop {
+        match DownloadClaim::new(turtl.sync_config.clone(), file_id) {
+            Some(x) => break x,
+            None => {}
+        }
+        if waited >= DOWNLOAD_WAIT {
+            return TErr!(TError::TryAgain);
+        }
+        util::sleep(250);
+        waited += 250;
+    };
+
+    let sync = with_db!{ db, turtl.db,
+        // if we already have it, no need to download anything
+        if FileData::file_finder_all(None, Some(note_id), Some(file_id))?.len() > 0 {
+            cache::touch(db, file_id)?;
+            return Ok(());
+        }
+        let existing = SyncRecord::find(db, Some(SyncType::FileIncoming))?
+            .into_iter()
+            .find(|x| &x.item_id == file_id);
+        match existing {
+            Some(x) => x,
+            None => {
+                // the file was evicted (or never queued). queue it up.
+                let mut sync = SyncRecord::default();
+                sync.generate_id()?;
+                sync.action = SyncAction::Add;
+                sync.ty = SyncType::FileIncoming;
+                sync.user_id = user_id.clone();
+                sync.item_id = file_id.clone();
+                sync.data = Some(json!({"id": file_id, "note_id": note_id}));
+                db.save(&sync)?;
+                sync
+            }
+        }
+    };
+    let mut sync = sync;
+    // the user asked for this one specifically, so don't let old failures
+    // hold it back
+    sync.frozen = false;
+    syncer.download_file(&mut sync, 1, 1)
+}
+
+/// Parse a `Content-Range` header (`bytes <start>-<end>/<total>`) into its
+/// start and total. Either can be None (ie `bytes */1234`, or a total of `*`).
+fn parse_content_range(header: &str) -> Option<(Option<u64>, Option<u64>)> {
+    let mut parts = header.trim().splitn(2, ' ');
+    if parts.next() != Some("bytes") { return None; }
+    let mut range = parts.next()?.splitn(2, '/');
+    let span = range.next()?;
+    let total = range.next()?;
+    let start = span.split('-').next().and_then(|x| x.parse::<u64>().ok());
+    Some((start, total.parse::<u64>().ok()))
+}
+
 impl Syncer for FileSyncIncoming {
     fn get_name(&self) -> &'static str {
         "files:incoming"
@@ -182,8 +382,13 @@ impl Syncer for FileSyncIncoming {
 
     fn run_sync(&mut self) -> TResult<()> {
         let syncs = self.get_incoming_file_syncs()?;
-        for sync in &syncs {
-            self.download_file(sync)?;
+        let files = syncs.len();
+        for (idx, mut sync) in syncs.into_iter().enumerate() {
+            // if someone's already downloading this file (ie, on demand), let
+            // them
+            let claim = DownloadClaim::new(self.get_config(), &sync.item_id);
+            if claim.is_none() { continue; }
+            self.download_file(&mut sync, idx + 1, files)?;
             // if we've been disabled, return
             if !self.is_enabled() { return Ok(()); }
         }
@@ -191,4 +396,16 @@ impl Syncer for FileSyncIncoming {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn parses_content_range() {
+        assert_eq!(parse_content_range("bytes 200-1000/1001"), Some((Some(200), Some(1001))));
+        assert_eq!(parse_content_range("bytes 200-1000/*"), Some((Some(200), None)));
+        assert_eq!(parse_content_range("bytes */1001"), Some((None, Some(1001))));
+        assert_eq!(parse_content_range("items 0-10/11"), None);
+        assert_eq!(parse_content_range("bytes"), None);
+    }
+}
@@ -1,2 +1,3 @@
 pub mod outgoing;
 pub mod incoming;
+pub mod cache;
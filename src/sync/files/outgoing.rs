@@ -2,13 +2,40 @@ use ::std::sync::{Arc, RwLock, Mutex};
 use ::sync::{SyncConfig, Syncer};
 use ::sync::sync_model::SyncModel;
 use ::sync::incoming::SyncIncoming;
+use ::sync::status::{Progress, ProgressReader, Transfer};
 use ::storage::Storage;
 use ::api::{Api, ApiReq, StatusCode};
 use ::messaging;
 use ::error::{TResult, TError};
 use ::models::file::FileData;
-use ::models::sync_record::{SyncType, SyncRecord};
+use ::models::sync_record::{SyncType, SyncRecord, TransferState};
 use ::std::fs;
+use ::std::cmp;
+use ::std::io::{Read, Seek, SeekFrom};
+use ::std::path::PathBuf;
+use ::config;
+use ::reqwest;
+
+/// How big (in bytes) the pieces we upload files in are, unless set in
+/// `sync.files.upload_chunk_size`
+const DEFAULT_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// What the API sends back after an upload (or a piece of one)
+#[derive(Deserialize, Debug)]
+struct UploadRes {
+    /// For chunked uploads, how many bytes the API has so far
+    #[serde(default)]
+    offset: Option<u64>,
+    #[serde(default)]
+    #[serde(deserialize_with = "::util::ser::opt_vec_str_i64_converter::deserialize")]
+    sync_ids: Option<Vec<i64>>,
+}
+
+/// What the API sends back when we start a chunked upload
+#[derive(Deserialize, Debug)]
+struct UploadSession {
+    upload_id: String,
+}
 
 /// Holds the state for outgoing files (uploads)
 pub struct FileSyncOutgoing {
@@ -25,6 +52,10 @@ pub struct FileSyncOutgoing {
 
     /// Stores our syn run version
     run_version: i64,
+
+    /// How many files we've uploaded since our queue was last empty, so the
+    /// UI can show which file of the batch we're on
+    uploaded: usize,
 }
 
 impl FileSyncOutgoing {
@@ -35,6 +66,7 @@ impl FileSyncOutgoing {
             api: api,
             db: db,
             run_version: 0,
+            uploaded: 0,
         }
     }
 
@@ -64,10 +96,171 @@ impl FileSyncOutgoing {
         }
     }
 
+    /// Upload a file in one shot. This is what we fall back to if the API
+    /// doesn't do resumable uploads.
+    fn upload_whole(&self, note_id: &String, file_id: &String, path: &PathBuf, size: u64, file_num: usize, num_files: usize) -> TResult<UploadRes> {
+        let file = fs::File::open(path)?;
+        let progress = Progress::new(self.get_config(), Transfer {
+            syncer: self.get_name(),
+            note_id: note_id.clone(),
+            file_id: file_id.clone(),
+            direction: "upload",
+            bytes: 0,
+            total: Some(size),
+            file: file_num,
+            files: num_files,
+        });
+        let body = reqwest::blocking::Body::sized(ProgressReader::new(file, progress), size);
+        // start our API call to the note file attachment endpoint
+        let url = FileData::attachment_url(note_id, file_id);
+        self.api.put(&url[..])?
+            .header("Content-Type", "application/octet-stream")
+            .body(body)
+            .call_opt(ApiReq::new().timeout(60))
+    }
+
+    /// Find out where our upload session left off, or start a new session if
+    /// we don't have one (or it expired). Returns false if the API doesn't do
+    /// resumable uploads.
+    fn resume_session(&self, note_id: &String, file_id: &String, state: &mut TransferState) -> TResult<bool> {
+        if let Some(upload_id) = state.token.clone() {
+            let url = format!("{}/uploads/{}", FileData::attachment_url(note_id, file_id), upload_id);
+            match self.api.get(&url[..])?.call::<UploadRes>() {
+                Ok(res) => {
+                    state.offset = res.offset.unwrap_or(0);
+                    return Ok(true);
+                }
+                Err(e) => {
+                    match e.shed() {
+                        TError::Api(StatusCode::NOT_FOUND, _) => {
+                            info!("FileSyncOutgoing.resume_session() -- upload session {} expired, starting over", upload_id);
+                        }
+                        e => return Err(e),
+                    }
+                }
+            }
+        }
+        let url = format!("{}/uploads", FileData::attachment_url(note_id, file_id));
+        match self.api.post(&url[..])?.json(&json!({"size": state.size})).call::<UploadSession>() {
+            Ok(session) => {
+                state.token = Some(session.upload_id);
+                state.offset = 0;
+                Ok(true)
+            }
+            Err(e) => {
+                match e.shed() {
+                    TError::Api(StatusCode::NOT_FOUND, _) |
+                        TError::Api(StatusCode::METHOD_NOT_ALLOWED, _) |
+                        TError::Api(StatusCode::NOT_IMPLEMENTED, _) => Ok(false),
+                    e => Err(e),
+                }
+            }
+        }
+    }
+
+    /// Upload a file in chunks, saving our place in the sync record as we go
+    /// so an interrupted upload picks up where it left off. Returns None if the
+    /// API doesn't do resumable uploads.
+    fn upload_chunked(&self, sync: &mut SyncRecord, path: &PathBuf, size: u64, file_num: usize, num_files: usize) -> TResult<Option<UploadRes>> {
+        let note_id = FileData::sync_note_id(sync);
+        let file_id = sync.item_id.clone();
+        let mut state = sync.transfer.clone().unwrap_or_default();
+        // if the file changed since we started, our old session is no good
+        if state.size != Some(size) {
+            state = TransferState { offset: 0, size: Some(size), token: None };
+        }
+        if !self.resume_session(&note_id, &file_id, &mut state)? { return Ok(None); }
+        sync.transfer = Some(state.clone());
+        with_db!{ db, self.db, sync.save_transfer(db)? };
+        info!("FileSyncOutgoing.upload_chunked() -- uploading file for {} from byte {}", note_id, state.offset);
+
+        let chunk_size = cmp::max(config::get(&["sync", "files", "upload_chunk_size"]).unwrap_or(DEFAULT_CHUNK_SIZE), 1);
+        let upload_id = match state.token.as_ref() {
+            Some(x) => x.clone(),
+            None => return TErr!(TError::MissingField(String::from("TransferState.token"))),
+        };
+        let url = format!("{}/uploads/{}", FileData::attachment_url(&note_id, &file_id), upload_id);
+        let mut file = fs::File::open(path)?;
+        let mut progress = Progress::new(self.get_config(), Transfer {
+            syncer: self.get_name(),
+            note_id: note_id.clone(),
+            file_id: file_id.clone(),
+            direction: "upload",
+            bytes: state.offset,
+            total: Some(size),
+            file: file_num,
+            files: num_files,
+        });
+        loop {
+            let start = state.offset;
+            let len = cmp::min(chunk_size, size.saturating_sub(start));
+            let mut chunk = Vec::with_capacity(len as usize);
+            file.seek(SeekFrom::Start(start))?;
+            file.by_ref().take(len).read_to_end(&mut chunk)?;
+            if (chunk.len() as u64) != len {
+                return TErr!(TError::Msg(format!("file {} (note {}) changed while uploading", file_id, note_id)));
+            }
+            let res: TResult<UploadRes> = self.api.put(&url[..])?
+                .header("Content-Type", "application/octet-stream")
+                .header("Content-Range", format!("bytes {}-{}/{}", start, start + len - 1, size))
+                .body(chunk)
+                .call_opt(ApiReq::new().timeout(60));
+            let res = match res {
+                Ok(x) => x,
+                Err(e) => {
+                    match e.shed() {
+                        // our session went away. forget it so next time around
+                        // we start a new one (and don't mistake this for the
+                        // note not existing)
+                        TError::Api(StatusCode::NOT_FOUND, _) => {
+                            state.token = None;
+                            state.offset = 0;
+                            sync.transfer = Some(state);
+                            with_db!{ db, self.db, sync.save_transfer(db)? };
+                            return TErr!(TError::Msg(format!("upload session for file {} expired", file_id)));
+                        }
+                        e => return Err(e),
+                    }
+                }
+            };
+            state.offset = res.offset.unwrap_or(start + len);
+            sync.transfer = Some(state.clone());
+            with_db!{ db, self.db, sync.save_transfer(db)? };
+            if state.offset >= size {
+                progress.add(size - start);
+                progress.publish();
+                return Ok(Some(res));
+            }
+            if state.offset <= start {
+                return TErr!(TError::Msg(format!("upload for file {} isn't making progress (stuck at byte {})", file_id, start)));
+            }
+            progress.add(state.offset - start);
+        }
+    }
+
+    /// Find our file and upload it, resuming where we left off if the API
+    /// supports it. `file_num` and `num_files` tell us where this upload falls
+    /// in the current batch (for progress reporting).
+    fn upload(&self, sync: &mut SyncRecord, user_id: &String, file_num: usize, num_files: usize) -> TResult<UploadRes> {
+        let note_id = FileData::sync_note_id(sync);
+        let file_id = sync.item_id.clone();
+        let file = FileData::file_finder(Some(user_id), Some(&note_id), Some(&file_id))?;
+        info!("FileSyncOutgoing.upload_file() -- syncing file {:?}", file);
+        // make sure our local file exists before making API calls
+        let size = fs::metadata(&file)?.len();
+        if size > 0 {
+            if let Some(res) = self.upload_chunked(sync, &file, size, file_num, num_files)? {
+                return Ok(res);
+            }
+        }
+        self.upload_whole(&note_id, &file_id, &file, size, file_num, num_files)
+    }
+
     /// Given a sync record for an outgoing file, find the corresponding file
     /// in our storage folder and stream it to our heroic API.
     fn upload_file(&mut self, sync: &mut SyncRecord) -> TResult<()> {
-        let note_id = sync.item_id.clone();
+        let note_id = FileData::sync_note_id(sync);
+        let file_id = sync.item_id.clone();
         let user_id = {
             let local_config = self.get_config();
             let guard = lockr!(local_config);
@@ -77,30 +270,15 @@ impl FileSyncOutgoing {
             }
         };
 
-        #[derive(Deserialize, Debug)]
-        struct UploadRes {
-            #[serde(default)]
-            #[serde(deserialize_with = "::util::ser::opt_vec_str_i64_converter::deserialize")]
-            sync_ids: Option<Vec<i64>>,
-        }
-
-        // define a container function that grabs our file and runs the upload.
-        // if anything in here fails, we mark 
-        let upload = |note_id| -> TResult<UploadRes> {
-            let file = FileData::file_finder(Some(&user_id), Some(note_id))?;
-            info!("FileSyncOutgoing.upload_file() -- syncing file {:?}", file);
-            // open our local file. we should test if it's readable/exists
-            // before making API calls
-            let file = fs::File::open(&file)?;
-            // start our API call to the note file attachment endpoint
-            let url = format!("/notes/{}/attachment", note_id);
-            self.api.put(&url[..])?
-                .header("Content-Type", "application/octet-stream")
-                .body(file)
-                .call_opt(ApiReq::new().timeout(60))
-        };
+        // grab how many uploads we have queued (plus the ones we've already
+        // done) so the UI can show "2 of N"
+        let queued = with_db!{ db, self.db,
+            SyncRecord::find(db, Some(SyncType::FileOutgoing))
+        }?.into_iter().filter(|x| !x.frozen).count();
+        let file_num = self.uploaded + 1;
+        let num_files = cmp::max(self.uploaded + queued, file_num);
 
-        match upload(&note_id) {
+        match self.upload(sync, &user_id, file_num, num_files) {
             Ok(res) => {
                 match res.sync_ids.as_ref() {
                     Some(ids) => {
@@ -142,10 +320,11 @@ impl FileSyncOutgoing {
         // if we're still here, the upload succeeded. remove the sync record so
         // we know to stop trying to upload this file.
         with_db!{ db, self.db, sync.db_delete(db, None)? };
+        self.uploaded += 1;
 
         // let the UI know how great we are. you will love this app. tremendous
         // app. everyone says so.
-        messaging::ui_event("sync:file:uploaded", &json!({"note_id": note_id}))?;
+        messaging::ui_event("sync:file:uploaded", &json!({"note_id": note_id, "file_id": file_id}))?;
         Ok(())
     }
 }
@@ -173,8 +352,10 @@ impl Syncer for FileSyncOutgoing {
 
     fn run_sync(&mut self) -> TResult<()> {
         let sync_maybe = self.get_next_outgoing_file_sync()?;
-        if let Some(mut sync) = sync_maybe {
-            self.upload_file(&mut sync)?;
+        match sync_maybe {
+            Some(mut sync) => self.upload_file(&mut sync)?,
+            // all caught up, so the next upload starts a new batch
+            None => self.uploaded = 0,
         }
         Ok(())
     }
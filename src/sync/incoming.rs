@@ -4,6 +4,9 @@ use ::jedi::{self, Value};
 use ::error::{TResult, TError};
 use ::sync::{SyncConfig, Syncer};
 use ::sync::sync_model::{SyncModel, MemorySaver};
+use ::sync::conflict;
+use ::sync::selective;
+use ::sync::push::{self, Push};
 use ::storage::Storage;
 use ::rusqlite::NO_PARAMS;
 use ::api::{Api, ApiReq};
@@ -55,6 +58,8 @@ struct Handlers {
     note: models::note::Note,
     file: models::file::FileData,
     invite: models::invite::Invite,
+    revision: models::revision::Revision,
+    template: models::template::Template,
 }
 
 /// Lets the server know why we are asking for an incoming sync.
@@ -66,6 +71,9 @@ enum SyncReason {
     Reconnect,
     #[serde(rename = "initial")]
     Initial,
+    /// The API pushed us a notification that something changed
+    #[serde(rename = "push")]
+    Push,
 }
 
 /// Given a Value object with sync_ids, try to ignore the sync ids. Kids' stuff.
@@ -108,6 +116,10 @@ pub struct SyncIncoming {
     /// long-poll (if connected).
     connected: bool,
 
+    /// Our push notification stream, which (if the API has one) we wait on
+    /// instead of long-polling
+    push: Push,
+
     /// Stores our syn run version
     run_version: i64,
 }
@@ -123,6 +135,8 @@ impl SyncIncoming {
             note: models::note::Note::new(),
             file: models::file::FileData::new(),
             invite: models::invite::Invite::new(),
+            revision: models::revision::Revision::new(),
+            template: models::template::Template::new(),
         };
 
         SyncIncoming {
@@ -131,6 +145,7 @@ impl SyncIncoming {
             db: db,
             handlers: handlers,
             connected: false,
+            push: Push::new(),
             run_version: 0,
         }
     }
@@ -250,7 +265,7 @@ impl SyncIncoming {
         let ignored = self.get_ignored()?;
         let mut ignore_count = 0;
         // filter out ignored records
-        let mut records = records
+        let records = records
             .into_iter()
             .filter(|rec| {
                 match rec.id() {
@@ -269,32 +284,23 @@ impl SyncIncoming {
             .collect::<Vec<_>>();
 
         info!("SyncIncoming.update_local_db_from_api_sync() -- ignored {} incoming syncs", ignore_count);
+        let mut kept = Vec::with_capacity(records.len());
         with_db!{ db, self.db,
+            // grab the spaces this device doesn't sync (see sync::selective)
+            let excluded = selective::get_excluded(db)?;
             // start a transaction. running incoming sync is all or nothing.
             db.conn.execute("BEGIN TRANSACTION", NO_PARAMS)?;
-            for rec in &mut records {
-                self.run_sync_item(db, rec)?;
+            for mut rec in records {
+                if !selective::filter_incoming(db, &excluded, &mut rec)? { continue; }
+                self.run_sync_item(db, &mut rec)?;
+                kept.push(rec);
             }
             // save our sync id
             db.kv_set("sync_id", &sync_id.to_string())?;
             // ok, commit
             db.conn.execute("COMMIT TRANSACTION", NO_PARAMS)?;
         }
-
-        // send our incoming syncs into a queue that the Turtl/dispatch thread
-        // can read and process. The purpose is to run MemorySaver for the syncs
-        // which can only happen if we have access to Turtl, which we DO NOT
-        // at this particular juncture.
-        let sync_incoming_queue = {
-            let conf = self.get_config();
-            let sync_config_guard = lockr!(conf);
-            sync_config_guard.incoming_sync.clone()
-        };
-        // queue em
-        for rec in records { sync_incoming_queue.push(rec); }
-        // this is what tells our dispatch thread to load the queued incoming
-        // syncs and process them
-        messaging::app_event("sync:incoming", &())?;
+        self.queue_incoming(kept)?;
 
         // if we have extra sync data, send it off to the ui
         if let Some(extra) = extra.as_ref() {
@@ -310,6 +316,47 @@ impl SyncIncoming {
         Ok(())
     }
 
+    /// Send incoming syncs into a queue that the Turtl/dispatch thread can read
+    /// and process. The purpose is to run MemorySaver for the syncs, which can
+    /// only happen if we have access to Turtl, which we DO NOT at this
+    /// particular juncture.
+    fn queue_incoming(&self, records: Vec<SyncRecord>) -> TResult<()> {
+        let sync_incoming_queue = {
+            let conf = self.get_config();
+            let sync_config_guard = lockr!(conf);
+            sync_config_guard.incoming_sync.clone()
+        };
+        // queue em
+        for rec in records { sync_incoming_queue.push(rec); }
+        // this is what tells our dispatch thread to load the queued incoming
+        // syncs and process them
+        messaging::app_event("sync:incoming", &())
+    }
+
+    /// Pull in the data for spaces that were just included in selective sync
+    /// (see `sync::selective`). We grab the full profile and keep only what's
+    /// in those spaces. Our sync_id stays put, since the regular incoming sync
+    /// already has everything else covered.
+    fn backfill_spaces(&mut self) -> TResult<()> {
+        let space_ids = with_db!{ db, self.db, selective::get_backfill(db) }?;
+        if space_ids.len() == 0 { return Ok(()); }
+        info!("SyncIncoming.backfill_spaces() -- backfilling {} spaces", space_ids.len());
+        let syncdata: SyncResponse = self.api.get("/sync/full")?.call_opt(ApiReq::new().timeout(120))?;
+        self.set_connected(true);
+        let mut kept = Vec::new();
+        with_db!{ db, self.db,
+            db.conn.execute("BEGIN TRANSACTION", NO_PARAMS)?;
+            for mut rec in syncdata.records {
+                if !selective::filter_backfill(db, &space_ids, &rec)? { continue; }
+                self.run_sync_item(db, &mut rec)?;
+                kept.push(rec);
+            }
+            selective::clear_backfill(db, &space_ids)?;
+            db.conn.execute("COMMIT TRANSACTION", NO_PARAMS)?;
+        }
+        self.queue_incoming(kept)
+    }
+
     /// Sync an individual incoming sync item to our DB.
     fn run_sync_item(&self, db: &mut Storage, sync_item: &mut SyncRecord) -> TResult<()> {
         // check if we have missing data, and if so, if it's on purpose
@@ -336,6 +383,8 @@ impl SyncIncoming {
             SyncType::Note => self.handlers.note.incoming(db, sync_item),
             SyncType::File | SyncType::FileIncoming => self.handlers.file.incoming(db, sync_item),
             SyncType::Invite => self.handlers.invite.incoming(db, sync_item),
+            SyncType::Revision => self.handlers.revision.incoming(db, sync_item),
+            SyncType::Template => self.handlers.template.incoming(db, sync_item),
             SyncType::FileOutgoing => Ok(()),
         }?;
 
@@ -346,6 +395,12 @@ impl SyncIncoming {
         self.connected = yesno;
         self.connected(yesno);
     }
+
+    /// Let `sync:status` know whether changes are being pushed to us
+    fn set_push(&self, yesno: bool) {
+        let mut guard = lockw!(self.config);
+        guard.push = yesno;
+    }
 }
 
 impl Syncer for SyncIncoming {
@@ -391,11 +446,28 @@ impl Syncer for SyncIncoming {
         // after being previously disconnected, we can update our state
         // immediately instead of waiting 60s or w/e until the sync goes through
         let reason = if self.connected { SyncReason::Poll } else { SyncReason::Reconnect };
-        let res = match sync_id {
-            Some(ref x) => self.sync_from_api(x, reason),
+        // if any spaces were just included in selective sync, pull them in
+        // before we go back to waiting on changes
+        if sync_id.is_some() { self.backfill_spaces()?; }
+        let sync_id = match sync_id {
+            Some(x) => x,
             None => return TErr!(TError::MissingData(String::from("no sync_id present"))),
         };
-        res
+        if reason != SyncReason::Poll { return self.sync_from_api(&sync_id, reason); }
+        // if the API can tell us when something changes, wait on that instead
+        // of long-polling
+        let config = self.config.clone();
+        let run_version = self.run_version;
+        let wait = self.push.wait(&self.api, &sync_id, || {
+            let guard = lockr!(config);
+            guard.enabled && !guard.quit && guard.run_version == run_version
+        });
+        self.set_push(self.push.connected());
+        match wait {
+            push::Wait::Changed => self.sync_from_api(&sync_id, SyncReason::Push),
+            push::Wait::Idle => Ok(()),
+            push::Wait::Unavailable => self.sync_from_api(&sync_id, reason),
+        }
     }
 }
 
@@ -417,6 +489,11 @@ pub fn process_incoming_sync(turtl: &Turtl) -> TResult<()> {
             Some(x) => x,
             None => break,
         };
+        if sync_item.conflict {
+            conflict::resolve(turtl, &sync_item)?;
+            drop(sync_incoming_lock);
+            continue;
+        }
         fn mem_save<T>(turtl: &Turtl, mut sync_item: SyncRecord) -> TResult<()>
             where T: Protected + MemorySaver + Keyfinder
         {
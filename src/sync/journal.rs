@@ -0,0 +1,541 @@
+//! Keeps a local journal of recent changes the user made to their spaces,
+//! boards, and notes so a change can be undone. This is mainly so an accidental
+//! "delete board" (which takes all of the board's notes with it) isn't the end
+//! of the world.
+//!
+//! Each change the UI makes through `profile:sync:model` is recorded as a group
+//! of journal entries, one per item the change touches, holding the item as it
+//! was stored before the change. Stored items are already encrypted, so the
+//! journal never holds plaintext data. Undoing a change replays those prior
+//! versions back through the normal sync path (`sync_model`), which means the
+//! API (and the user's other devices) hear about the undo like any other edit.
+//!
+//! Note that files aren't journaled: notes restored from the journal come back
+//! without their attachment.
+//!
+//! Changes coming in from the API (or made by the sync system itself) aren't
+//! journaled, nor are undos.
+
+use ::std::collections::HashMap;
+use ::jedi::{self, Value};
+use ::error::{TResult, TError};
+use ::storage::Storage;
+use ::config;
+use ::crypto::Key;
+use ::turtl::Turtl;
+use ::models::model::{self, Model};
+use ::models::protected::{Protected, Keyfinder};
+use ::models::storable::Storable;
+use ::models::validate::Validate;
+use ::models::keychain::KeychainEntry;
+use ::models::space::Space;
+use ::models::board::Board;
+use ::models::note::Note;
+use ::models::revision::Revision;
+use ::models::sync_record::{SyncRecord, SyncAction, SyncType};
+use ::sync::sync_model::{self, SyncModel, MemorySaver};
+use ::lib_permissions::Permission;
+use ::trash;
+use ::time;
+
+protected! {
+    /// Holds one item touched by a change. A change can touch more than one
+    /// item (deleting a board deletes its notes), so entries for the same
+    /// change share a `group`.
+    #[derive(Serialize, Deserialize)]
+    pub struct JournalEntry {
+        #[protected_field(public)]
+        pub group: String,
+        #[protected_field(public)]
+        pub action: SyncAction,
+        #[serde(rename = "type")]
+        #[protected_field(public)]
+        pub ty: SyncType,
+        #[protected_field(public)]
+        pub item_id: String,
+        /// The item as it was stored before the change (encrypted). None if
+        /// the change added the item.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub before: Option<Value>,
+        #[protected_field(public)]
+        pub created: i64,
+        #[serde(default)]
+        #[protected_field(public)]
+        pub undone: bool,
+    }
+}
+make_storable!(JournalEntry, "journal");
+
+impl JournalEntry {
+    fn item(action: &SyncAction, ty: SyncType, item_id: &String, before: Option<Value>) -> Self {
+        let mut entry = JournalEntry::new();
+        entry.action = action.clone();
+        entry.ty = ty;
+        entry.item_id = item_id.clone();
+        entry.before = before;
+        entry
+    }
+}
+
+/// A change, as the UI sees it
+#[derive(Serialize, Debug)]
+pub struct Change {
+    /// The change's id (pass it to `profile:undo` to undo this change)
+    pub id: String,
+    pub action: SyncAction,
+    #[serde(rename = "type")]
+    pub ty: SyncType,
+    pub item_id: String,
+    /// The item's title, if it has one and we can decrypt it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    pub created: i64,
+    pub undone: bool,
+    /// How many items the change touched
+    pub items: usize,
+}
+
+/// Whether or not we journal changes to this type
+fn journaled(ty: &SyncType) -> bool {
+    match *ty {
+        SyncType::Space | SyncType::Board | SyncType::Note => true,
+        _ => false,
+    }
+}
+
+/// Turn some stored models into journal entries
+fn entries<T>(models: Vec<T>, action: &SyncAction, ty: SyncType) -> TResult<Vec<JournalEntry>>
+    where T: Protected
+{
+    let mut entries = Vec::with_capacity(models.len());
+    for model in models {
+        let before = model.data_for_storage()?;
+        entries.push(JournalEntry::item(action, ty.clone(), &model.id_or_else()?, Some(before)));
+    }
+    Ok(entries)
+}
+
+/// Grab the stored copies of everything a change to the given item is about to
+/// touch
+fn capture(db: &Storage, action: &SyncAction, ty: &SyncType, item_id: &String) -> TResult<Vec<JournalEntry>> {
+    let ids = vec![item_id.clone()];
+    let mut captured = Vec::new();
+    match (action, ty) {
+        (&SyncAction::Delete, &SyncType::Space) => {
+            // we need the space's key to put it back
+            captured.append(&mut entries(db.find::<KeychainEntry>("keychain", "item_id", &ids)?, action, SyncType::Keychain)?);
+            captured.append(&mut entries(db.by_id::<Space>("spaces", &ids)?, action, SyncType::Space)?);
+            captured.append(&mut entries(db.find::<Board>("boards", "space_id", &ids)?, action, SyncType::Board)?);
+            captured.append(&mut entries(db.find::<Note>("notes", "space_id", &ids)?, action, SyncType::Note)?);
+        }
+        (&SyncAction::Delete, &SyncType::Board) => {
+            captured.append(&mut entries(db.by_id::<Board>("boards", &ids)?, action, SyncType::Board)?);
+            captured.append(&mut entries(db.find::<Note>("notes", "board_id", &ids)?, action, SyncType::Note)?);
+        }
+        (_, &SyncType::Space) => {
+            captured.append(&mut entries(db.by_id::<Space>("spaces", &ids)?, action, SyncType::Space)?);
+        }
+        (_, &SyncType::Board) => {
+            captured.append(&mut entries(db.by_id::<Board>("boards", &ids)?, action, SyncType::Board)?);
+        }
+        (_, &SyncType::Note) => {
+            captured.append(&mut entries(db.by_id::<Note>("notes", &ids)?, action, SyncType::Note)?);
+        }
+        _ => {}
+    }
+    Ok(captured)
+}
+
+/// Split the journal (oldest first) into its changes, keeping the order
+fn groups<'a>(entries: &'a Vec<JournalEntry>) -> Vec<Vec<&'a JournalEntry>> {
+    let mut idx: HashMap<&String, usize> = HashMap::new();
+    let mut groups: Vec<Vec<&JournalEntry>> = Vec::new();
+    for entry in entries {
+        if let Some(i) = idx.get(&entry.group) {
+            groups[*i].push(entry);
+            continue;
+        }
+        idx.insert(&entry.group, groups.len());
+        groups.push(vec![entry]);
+    }
+    groups
+}
+
+/// Find the journal entries that fall outside of our limits (keeping at most
+/// `max_changes` changes, none created before `cutoff`)
+fn expired<'a>(entries: &'a Vec<JournalEntry>, max_changes: usize, cutoff: i64) -> Vec<&'a JournalEntry> {
+    let groups = groups(entries);
+    let keep_from = groups.len().saturating_sub(max_changes);
+    groups.into_iter()
+        .enumerate()
+        .filter(|&(i, ref group)| i < keep_from || group[0].created < cutoff)
+        .flat_map(|(_, group)| group.into_iter())
+        .collect()
+}
+
+/// Save a change into the journal, forgetting any changes past our limits
+fn record(turtl: &Turtl, mut entries: Vec<JournalEntry>) -> TResult<()> {
+    if entries.len() == 0 { return Ok(()); }
+    let max_changes: usize = config::get(&["journal", "max_changes"]).unwrap_or(100);
+    let max_age: i64 = config::get(&["journal", "max_age"]).unwrap_or(30);
+    let group = model::cid()?;
+    let now = time::get_time().sec as i64;
+    with_db!{ db, turtl.db,
+        for entry in &mut entries {
+            entry.generate_id()?;
+            entry.group = group.clone();
+            entry.created = now;
+            db.save(entry)?;
+        }
+        let journal: Vec<JournalEntry> = db.all(JournalEntry::tablename())?;
+        for entry in expired(&journal, max_changes, now - (max_age * 86400)) {
+            db.delete(entry)?;
+        }
+    }
+    Ok(())
+}
+
+/// Run a change from the UI through `sync_model::dispatch()`, recording it in
+/// the journal so it can be undone later
+pub fn dispatch(turtl: &Turtl, sync_record: SyncRecord) -> TResult<Value> {
+    if !journaled(&sync_record.ty) {
+        return sync_model::dispatch(turtl, sync_record);
+    }
+    let action = sync_record.action.clone();
+    let ty = sync_record.ty.clone();
+    let item_id: Option<String> = match sync_record.data {
+        Some(ref data) => jedi::get_opt(&["id"], data),
+        None => None,
+    };
+    let mut captured = match item_id {
+        Some(ref id) if action != SyncAction::Add => {
+            with_db!{ db, turtl.db, capture(db, &action, &ty, id)? }
+        }
+        _ => Vec::new(),
+    };
+    // the user editing a note keeps the version being replaced as a revision
+    let replaced: Option<Note> = match (&action, &ty, &item_id) {
+        (&SyncAction::Edit, &SyncType::Note, &Some(ref id)) => {
+            with_db!{ db, turtl.db, db.get(Note::tablename(), id)? }
+        }
+        _ => None,
+    };
+    let res = sync_model::dispatch(turtl, sync_record)?;
+    if let Some(note) = replaced {
+        let user_id = turtl.user_id()?;
+        let kept = with_db!{ db, turtl.db, Revision::keep(db, &user_id, &note) };
+        match kept {
+            Ok(_) => {}
+            Err(e) => warn!("journal::dispatch() -- problem keeping note revision: {}", e),
+        }
+    }
+    if action == SyncAction::Add {
+        if let Some(id) = jedi::get_opt::<String>(&["id"], &res) {
+            captured.push(JournalEntry::item(&action, ty, &id, None));
+        }
+    }
+    // the change went through, so don't fail it just because we couldn't
+    // write it down
+    match record(turtl, captured) {
+        Ok(_) => {}
+        Err(e) => warn!("journal::dispatch() -- problem saving change to journal: {}", e),
+    }
+    Ok(res)
+}
+
+/// Grab an item's stored (encrypted) data, if it exists
+fn stored<T>(turtl: &Turtl, id: &String) -> TResult<Option<Value>>
+    where T: Protected + Storable
+{
+    with_db!{ db, turtl.db,
+        match db.get::<T>(T::tablename(), id)? {
+            Some(x) => Ok(Some(x.data_for_storage()?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Whether or not an item of the given type exists
+fn exists(turtl: &Turtl, ty: &SyncType, id: &String) -> TResult<bool> {
+    let found = match *ty {
+        SyncType::Space => stored::<Space>(turtl, id)?,
+        SyncType::Board => stored::<Board>(turtl, id)?,
+        SyncType::Note => stored::<Note>(turtl, id)?,
+        _ => None,
+    };
+    Ok(found.is_some())
+}
+
+/// Decrypt a stored item, using the given key if we have one or finding it in
+/// the profile if not
+fn open<T>(turtl: &Turtl, data: &Value, key: Option<Key>) -> TResult<T>
+    where T: Protected + Keyfinder
+{
+    let mut model: T = jedi::from_val(data.clone())?;
+    match key {
+        Some(k) => model.set_key(Some(k)),
+        None => turtl.find_model_key(&mut model)?,
+    }
+    model.deserialize()?;
+    Ok(model)
+}
+
+/// Decrypt the keys a change took out of the keychain (when deleting a space)
+fn change_keys(turtl: &Turtl, group: &Vec<&JournalEntry>) -> TResult<HashMap<String, Key>> {
+    let mut keys = HashMap::new();
+    for entry in group {
+        if entry.ty != SyncType::Keychain { continue; }
+        let before = match entry.before {
+            Some(ref x) => x,
+            None => continue,
+        };
+        let keychain_entry: KeychainEntry = open(turtl, before, None)?;
+        if let Some(k) = keychain_entry.k {
+            keys.insert(keychain_entry.item_id, k);
+        }
+    }
+    Ok(keys)
+}
+
+/// Dispatch an action into the sync system, same as the UI would
+fn run(turtl: &Turtl, action: SyncAction, ty: &SyncType, data: Value) -> TResult<Value> {
+    let mut sync_record = SyncRecord::default();
+    sync_record.action = action;
+    sync_record.ty = ty.clone();
+    sync_record.data = Some(data);
+    sync_model::dispatch(turtl, sync_record)
+}
+
+/// Put an item back to the way it was before a change
+fn replay_model<T>(turtl: &Turtl, entry: &JournalEntry, before: &Value, keys: &HashMap<String, Key>) -> TResult<()>
+    where T: Protected + Storable + Keyfinder + SyncModel + MemorySaver + Validate + Sync + Send
+{
+    let mut before = before.clone();
+    if entry.ty == SyncType::Note {
+        // we don't keep files around, so don't bring back a note that thinks
+        // it has any
+        match jedi::remove(&["file"], &mut before) {
+            Ok(_) => {}
+            Err(_) => {}
+        }
+        jedi::set(&["has_file"], &mut before, &false)?;
+    }
+    let mut model: T = open(turtl, &before, keys.get(&entry.item_id).cloned())?;
+    if entry.ty == SyncType::Note {
+        model.merge_fields(&json!({"attachments": []}))?;
+    }
+    let mut data = model.data()?;
+
+    if !exists(turtl, &entry.ty, &entry.item_id)? {
+        // the item is gone, so put it back exactly as it was (same id and key)
+        let permission = match entry.ty {
+            SyncType::Board => Some(Permission::AddBoard),
+            SyncType::Note => Some(Permission::AddNote),
+            _ => None,
+        };
+        if let Some(permission) = permission {
+            let space_id: String = jedi::get(&["space_id"], &data)?;
+            Space::permission_check(turtl, &space_id, &permission)?;
+        }
+        sync_model::save_model(SyncAction::Add, turtl, &mut model, false)?;
+        return Ok(());
+    }
+
+    match entry.action {
+        // it went in the trash (unless it was in there already)
+        SyncAction::Delete => {
+            if jedi::get_opt::<i64>(&["trashed"], &data).is_none() {
+                trash::restore(turtl, &entry.ty, &entry.item_id)?;
+            }
+        }
+        SyncAction::MoveSpace => {
+            let moveback = json!({
+                "id": entry.item_id,
+                "space_id": jedi::get::<String>(&["space_id"], &data)?,
+                "board_id": jedi::get_opt::<String>(&["board_id"], &data),
+            });
+            run(turtl, SyncAction::MoveSpace, &entry.ty, moveback)?;
+        }
+        _ => {
+            // leave whatever attachments the note has now alone
+            if entry.ty == SyncType::Note {
+                match jedi::remove(&["attachments"], &mut data) {
+                    Ok(_) => {}
+                    Err(_) => {}
+                }
+            }
+            run(turtl, SyncAction::Edit, &entry.ty, data)?;
+        }
+    }
+    Ok(())
+}
+
+/// Undo one item of a change
+fn replay(turtl: &Turtl, entry: &JournalEntry, keys: &HashMap<String, Key>) -> TResult<()> {
+    let before = match entry.before {
+        Some(ref x) => x,
+        None => {
+            // the change added the item, so undoing it means deleting it
+            if exists(turtl, &entry.ty, &entry.item_id)? {
+                run(turtl, SyncAction::Delete, &entry.ty, json!({"id": entry.item_id}))?;
+            }
+            return Ok(());
+        }
+    };
+    match entry.ty {
+        SyncType::Space => replay_model::<Space>(turtl, entry, before, keys),
+        SyncType::Board => replay_model::<Board>(turtl, entry, before, keys),
+        SyncType::Note => replay_model::<Note>(turtl, entry, before, keys),
+        // keychain entries are only here for their keys
+        _ => Ok(()),
+    }
+}
+
+/// Try to grab the title of the item a journal entry is for
+fn title(turtl: &Turtl, entry: &JournalEntry, keys: &HashMap<String, Key>) -> TResult<Option<String>> {
+    fn get<T>(turtl: &Turtl, entry: &JournalEntry, keys: &HashMap<String, Key>) -> TResult<Option<String>>
+        where T: Protected + Storable + Keyfinder
+    {
+        let data = match entry.before {
+            Some(ref x) => x.clone(),
+            None => match stored::<T>(turtl, &entry.item_id)? {
+                Some(x) => x,
+                None => return Ok(None),
+            },
+        };
+        let model: T = open(turtl, &data, keys.get(&entry.item_id).cloned())?;
+        Ok(jedi::get_opt(&["title"], &model.data()?))
+    }
+    match entry.ty {
+        SyncType::Space => get::<Space>(turtl, entry, keys),
+        SyncType::Board => get::<Board>(turtl, entry, keys),
+        SyncType::Note => get::<Note>(turtl, entry, keys),
+        _ => Ok(None),
+    }
+}
+
+/// Describe a change for the UI
+fn describe(turtl: &Turtl, group: &Vec<&JournalEntry>) -> TResult<Option<Change>> {
+    let items = group.iter()
+        .filter(|x| x.ty != SyncType::Keychain)
+        .map(|x| *x)
+        .collect::<Vec<_>>();
+    let main = match items.first() {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let keys = change_keys(turtl, group).unwrap_or(HashMap::new());
+    Ok(Some(Change {
+        id: main.group.clone(),
+        action: main.action.clone(),
+        ty: main.ty.clone(),
+        item_id: main.item_id.clone(),
+        title: title(turtl, main, &keys).unwrap_or(None),
+        created: main.created,
+        undone: main.undone,
+        items: items.len(),
+    }))
+}
+
+/// Grab the most recent changes in the journal, newest first
+pub fn history(turtl: &Turtl, limit: usize) -> TResult<Vec<Change>> {
+    let journal: Vec<JournalEntry> = with_db!{ db, turtl.db, db.all(JournalEntry::tablename())? };
+    let mut changes = Vec::new();
+    for group in groups(&journal).into_iter().rev() {
+        if changes.len() >= limit { break; }
+        if let Some(change) = describe(turtl, &group)? {
+            changes.push(change);
+        }
+    }
+    Ok(changes)
+}
+
+/// Undo a change, or if `change_id` is None, the most recent change that hasn't
+/// been undone yet. Returns the change we undid.
+pub fn undo(turtl: &Turtl, change_id: Option<String>) -> TResult<Change> {
+    let journal: Vec<JournalEntry> = with_db!{ db, turtl.db, db.all(JournalEntry::tablename())? };
+    let found = match change_id {
+        Some(ref id) => groups(&journal).into_iter().find(|g| &g[0].group == id),
+        None => groups(&journal).into_iter().rev().find(|g| !g[0].undone),
+    };
+    let group = match found {
+        Some(x) => x,
+        None => return TErr!(TError::NotFound(String::from("no change to undo"))),
+    };
+    if group[0].undone {
+        return TErr!(TError::BadValue(format!("change {} was already undone", group[0].group)));
+    }
+
+    // grab the description first, since undoing an add deletes the item (and
+    // its title with it)
+    let change = describe(turtl, &group)?;
+    let keys = change_keys(turtl, &group)?;
+    for entry in &group {
+        replay(turtl, entry, &keys)?;
+    }
+    with_db!{ db, turtl.db,
+        for entry in &group {
+            let mut entry = Protected::clone(*entry)?;
+            entry.undone = true;
+            db.save(&entry)?;
+        }
+    }
+    match change {
+        Some(mut x) => {
+            x.undone = true;
+            Ok(x)
+        }
+        None => TErr!(TError::NotFound(String::from("no change to undo"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, group: &str, created: i64) -> JournalEntry {
+        let mut entry = JournalEntry::item(&SyncAction::Delete, SyncType::Note, &String::from(id), None);
+        entry.id = Some(String::from(id));
+        entry.group = String::from(group);
+        entry.created = created;
+        entry
+    }
+
+    fn ids(entries: Vec<&JournalEntry>) -> Vec<String> {
+        entries.into_iter().map(|x| x.item_id.clone()).collect()
+    }
+
+    #[test]
+    fn groups_entries_by_change() {
+        let journal = vec![
+            entry("1", "a", 100),
+            entry("2", "b", 200),
+            entry("3", "b", 200),
+            entry("4", "a", 100),
+            entry("5", "c", 300),
+        ];
+        let groups = groups(&journal);
+        assert_eq!(groups.len(), 3);
+        assert_eq!(ids(groups[0].clone()), vec!["1", "4"]);
+        assert_eq!(ids(groups[1].clone()), vec!["2", "3"]);
+        assert_eq!(ids(groups[2].clone()), vec!["5"]);
+    }
+
+    #[test]
+    fn expires_old_changes() {
+        let journal = vec![
+            entry("1", "a", 100),
+            entry("2", "b", 200),
+            entry("3", "b", 200),
+            entry("4", "c", 300),
+            entry("5", "d", 400),
+        ];
+        // too many changes
+        assert_eq!(ids(expired(&journal, 2, 0)), vec!["1", "2", "3"]);
+        // too old
+        assert_eq!(ids(expired(&journal, 10, 300)), vec!["1", "2", "3"]);
+        assert_eq!(ids(expired(&journal, 3, 150)), vec!["1"]);
+        assert_eq!(ids(expired(&journal, 10, 0)).len(), 0);
+    }
+}
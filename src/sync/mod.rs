@@ -18,18 +18,29 @@ mod macros;
 pub mod incoming;
 pub mod outgoing;
 pub mod files;
+pub mod conflict;
+pub mod retry;
+pub mod status;
+pub mod selective;
+pub mod push;
+pub mod journal;
 #[macro_use]
 pub mod sync_model;
 
 use ::std::thread;
+use ::std::cmp;
+use ::std::collections::{HashMap, HashSet};
 use ::std::sync::{Arc, RwLock, Mutex, mpsc};
 use ::config;
 use ::sync::outgoing::SyncOutgoing;
 use ::sync::incoming::SyncIncoming;
 use ::sync::files::outgoing::FileSyncOutgoing;
 use ::sync::files::incoming::FileSyncIncoming;
+use ::sync::retry::{RetryPolicy, RetryState};
+use ::sync::status::{self as sync_status, SyncerStatus};
 use ::models::sync_record::SyncRecord;
 use ::util;
+use ::time;
 use ::error::{TResult, TError};
 use ::storage::Storage;
 use ::api::Api;
@@ -78,6 +89,17 @@ pub struct SyncConfig {
     /// SyncIncoming thread (since the sync threads are all generalized). Deal
     /// with it.
     pub incoming_sync: Arc<MsQueue<SyncRecord>>,
+    /// Tracks the state of each of our syncers (by name) so the UI can see
+    /// what's going on via `sync:status`.
+    pub status: HashMap<String, SyncerStatus>,
+    /// Whether the UI has told us the network is metered (see the
+    /// `unmetered` file download policy)
+    pub metered: bool,
+    /// The (ids of the) files being downloaded right now
+    pub downloading: HashSet<String>,
+    /// Whether the API is pushing changes to us (as opposed to us long-polling
+    /// for them)
+    pub push: bool,
 }
 
 impl SyncConfig {
@@ -90,6 +112,10 @@ impl SyncConfig {
             skip_api_init: false,
             run_version: 0,
             incoming_sync: Arc::new(MsQueue::new()),
+            status: HashMap::new(),
+            metered: false,
+            downloading: HashSet::new(),
+            push: false,
         }
     }
 }
@@ -211,20 +237,55 @@ pub trait Syncer {
         }
 
         info!("sync::runner() -- {} main loop", self.get_name());
+        let policy = RetryPolicy::from_config();
+        let mut retry = RetryState::default();
         while !self.should_quit() {
-            let delay = self.get_delay();
+            let mut delay = self.get_delay();
             if self.is_enabled() {
+                self.update_status(|status| status.syncing = true);
                 match self.run_sync() {
-                    Err(e) => error!("sync::runner() -- {}: main loop: {}", self.get_name(), e),
-                    _ => (),
+                    Err(e) => {
+                        error!("sync::runner() -- {}: main loop: {}", self.get_name(), e);
+                        delay = cmp::max(delay, retry.failure(&policy, &e));
+                        info!("sync::runner() -- {}: backing off {}ms ({} failures)", self.get_name(), delay, retry.failures);
+                        let retry = retry.clone();
+                        self.update_status(move |status| {
+                            status.syncing = false;
+                            status.retry = retry;
+                        });
+                    }
+                    Ok(_) => {
+                        retry.success();
+                        let now = time::get_time().sec;
+                        self.update_status(move |status| {
+                            status.syncing = false;
+                            status.last_success = Some(now);
+                            status.retry = Default::default();
+                        });
+                    }
                 }
-                util::sleep(delay);
-            } else {
-                util::sleep(delay);
             }
+            self.wait(delay);
+        }
+    }
+
+    /// Sleep for the given number of ms, waking up early if we need to quit
+    fn wait(&self, delay: u64) {
+        let mut left = delay;
+        while left > 0 && !self.should_quit() {
+            let chunk = cmp::min(left, 500);
+            util::sleep(chunk);
+            left -= chunk;
         }
     }
 
+    /// Update our status in the sync config so the main thread can see it
+    fn update_status<F>(&self, update_fn: F)
+        where F: FnOnce(&mut SyncerStatus), Self: Sized
+    {
+        sync_status::update(&self.get_config(), self.get_name(), update_fn);
+    }
+
     /// Let the main thread know that we've (dis)connected to the API. Useful
     /// for updating the UI on our connection state
     fn connected(&mut self, yesno: bool) {
@@ -245,6 +306,7 @@ pub fn start(config: Arc<RwLock<SyncConfig>>, api: Arc<Api>, db: Arc<Mutex<Optio
         let mut config_guard = lockw!(config);
         (*config_guard).enabled = true;
         (*config_guard).quit = false;
+        (*config_guard).status.clear();
     }
 
     // some holders for our thread handles and init receivers
@@ -1,11 +1,16 @@
 use ::std::sync::{Arc, RwLock, Mutex};
-use ::error::TResult;
+use ::std::collections::{HashMap, HashSet};
+use ::std::cmp;
+use ::error::{TResult, TError};
 use ::sync::{SyncConfig, Syncer};
 use ::sync::incoming::{SyncIncoming, SyncResponseExtra};
+use ::sync::conflict::{self, ConflictEvent};
 use ::storage::Storage;
 use ::api::{Api, ApiReq};
 use ::messaging;
-use ::models::sync_record::{SyncType, SyncRecord};
+use ::models::sync_record::{SyncType, SyncRecord, SyncAction};
+use ::config;
+use ::jedi;
 
 #[derive(Deserialize, Debug)]
 struct SyncResponse {
@@ -23,6 +28,108 @@ struct SyncResponse {
     extra: Option<SyncResponseExtra>,
 }
 
+/// How many sync records we send per call to the API (unless set in the
+/// `sync.outgoing_batch_size` config)
+const DEFAULT_BATCH_SIZE: usize = 50;
+
+/// Collapse redundant sync records. Runs of add/edit/delete records for the
+/// same item turn into one record:
+///
+/// - edit + edit -> edit (each edit carries the full item)
+/// - add + edit -> add
+/// - edit + delete -> delete
+/// - add + delete -> nothing at all (the server never has to know)
+/// - delete + add -> edit (ie, a delete that was undone before it went out),
+///   made against the version the item had before it was deleted
+///
+/// Any other action (like move-space) ends the run for its item.
+///
+/// Returns the records to send (in order) and the records that were folded
+/// into others and can be removed.
+fn coalesce(syncs: Vec<SyncRecord>) -> (Vec<SyncRecord>, Vec<SyncRecord>) {
+    let mut keep: Vec<Option<SyncRecord>> = Vec::with_capacity(syncs.len());
+    let mut redundant = Vec::new();
+    // (type, item_id) -> the index in `keep` of the item's current run
+    let mut runs: HashMap<(String, String), usize> = HashMap::new();
+    for mut sync in syncs {
+        let key = (format!("{:?}", sync.ty), sync.item_id.clone());
+        match sync.action {
+            SyncAction::Add | SyncAction::Edit | SyncAction::Delete => {}
+            _ => {
+                runs.remove(&key);
+                keep.push(Some(sync));
+                continue;
+            }
+        }
+        let prev = match runs.get(&key) {
+            Some(idx) => keep[*idx].take(),
+            None => None,
+        };
+        if let Some(prev) = prev {
+            match (&prev.action, &sync.action) {
+                (&SyncAction::Add, &SyncAction::Delete) => {
+                    redundant.push(prev);
+                    redundant.push(sync);
+                    runs.remove(&key);
+                    continue;
+                }
+                (&SyncAction::Add, _) => { sync.action = SyncAction::Add; }
+                (&SyncAction::Delete, &SyncAction::Add) => { sync.action = SyncAction::Edit; }
+                _ => {}
+            }
+            // the combined change was made against the first change's base
+            if sync.action != SyncAction::Add && prev.base_version.is_some() {
+                sync.base_version = prev.base_version;
+            }
+            redundant.push(prev);
+        }
+        runs.insert(key, keep.len());
+        keep.push(Some(sync));
+    }
+    (keep.into_iter().filter_map(|x| x).collect(), redundant)
+}
+
+/// The items (spaces, boards, notes) a sync record's item lives in
+fn sync_parents(sync: &SyncRecord) -> Vec<String> {
+    let data = match sync.data.as_ref() {
+        Some(x) => x,
+        None => return Vec::new(),
+    };
+    ["space_id", "board_id", "note_id"].iter()
+        .filter_map(|field| jedi::get_opt::<String>(&[*field], data))
+        .filter(|x| x != &sync.item_id)
+        .collect()
+}
+
+/// Order our outgoing records so that an item never goes out before the add
+/// of the item it lives in (which coalescing can cause, by moving an add down
+/// to where its last edit was). Everything else keeps the order it was queued
+/// in, which is the order things actually happened.
+fn order_syncs(syncs: Vec<SyncRecord>) -> Vec<SyncRecord> {
+    // item_id -> the index of the record that adds it
+    let adds: HashMap<String, usize> = syncs.iter()
+        .enumerate()
+        .filter(|&(_, x)| x.action == SyncAction::Add)
+        .map(|(i, x)| (x.item_id.clone(), i))
+        .collect();
+    fn place(idx: usize, syncs: &Vec<SyncRecord>, adds: &HashMap<String, usize>, placed: &mut HashSet<usize>, order: &mut Vec<usize>) {
+        if !placed.insert(idx) { return; }
+        for parent in sync_parents(&syncs[idx]) {
+            if let Some(parent_idx) = adds.get(&parent) {
+                place(*parent_idx, syncs, adds, placed, order);
+            }
+        }
+        order.push(idx);
+    }
+    let mut placed = HashSet::new();
+    let mut order = Vec::with_capacity(syncs.len());
+    for idx in 0..syncs.len() {
+        place(idx, &syncs, &adds, &mut placed, &mut order);
+    }
+    let mut syncs = syncs.into_iter().map(|x| Some(x)).collect::<Vec<_>>();
+    order.into_iter().filter_map(|idx| syncs[idx].take()).collect()
+}
+
 /// Holds the state for data going from turtl -> API (outgoing sync data).
 pub struct SyncOutgoing {
     /// Holds our sync config. Note that this is shared between the sync system
@@ -58,11 +165,20 @@ impl SyncOutgoing {
             SyncRecord::allbut(db, &vec![SyncType::FileOutgoing, SyncType::FileIncoming])
         }?;
 
+        // edits the server turned down as conflicts wait for the incoming sync
+        // to rebase them, and anything queued after them for the same item
+        // waits along with them
+        let waiting = syncs.iter()
+            .filter(|x| x.awaiting_rebase)
+            .map(|x| x.item_id.clone())
+            .collect::<HashSet<_>>();
+
         // stop at our first frozen record! this creates a "block" that must be
         // cleared before syncing can continue.
         let mut final_syncs = Vec::with_capacity(syncs.len());
         for sync in syncs {
             if sync.frozen { break; }
+            if waiting.contains(&sync.item_id) { continue; }
             final_syncs.push(sync);
         }
         Ok(final_syncs)
@@ -86,53 +202,44 @@ impl SyncOutgoing {
                 None => String::from("<blank error>"),
             };
             warn!("SyncOutgoing.handle_sync_failures() -- failwhale: {:?}/{:?}: {}", failure.ty, failure.action, errmsg);
+            let is_conflict = failure.error.as_ref()
+                .map(|x| x.code == conflict::CONFLICT_ERROR_CODE)
+                .unwrap_or(false);
+            if is_conflict {
+                // the item changed on the server since we edited it. don't
+                // hold this against the sync record, but don't resend it
+                // either: the incoming sync will grab the remote copy and
+                // resolve the conflict for us.
+                with_db!{ db, self.db, conflict::rejected(db, failure)? };
+                messaging::ui_event("sync:conflict", &ConflictEvent::new(&failure.item_id, failure.ty.clone(), "pending"))?;
+                continue;
+            }
             with_db!{ db, self.db,
                 SyncRecord::handle_failed_sync(db, failure)?;
             }
         }
         messaging::ui_event("sync:outgoing:failure", fail)
     }
-}
-
-impl Syncer for SyncOutgoing {
-    fn get_name(&self) -> &'static str {
-        "outgoing"
-    }
-
-    fn get_config(&self) -> Arc<RwLock<SyncConfig>> {
-        self.config.clone()
-    }
 
-    fn get_delay(&self) -> u64 {
-        1000
-    }
-
-    fn set_run_version(&mut self, run_version: i64) {
-        self.run_version = run_version;
-    }
-
-    fn get_run_version(&self) -> i64 {
-        self.run_version
-    }
-
-    fn run_sync(&mut self) -> TResult<()> {
-        // get all our sync records queued to be sent out
-        let syncs = self.get_outgoing_syncs()?;
-        if syncs.len() == 0 { return Ok(()); }
-
-        // send our syncs out to the api, and remove and successful records from
-        // our local db
-        info!("SyncOutgoing.run_sync() -- sending {} sync items", syncs.len());
+    /// Send a batch of sync records to the API, and remove the successful ones
+    /// from our local db. Returns whether any of the records failed.
+    fn send_batch(&self, syncs: &[SyncRecord]) -> TResult<bool> {
+        info!("SyncOutgoing.send_batch() -- sending {} sync items", syncs.len());
         let sync_result: SyncResponse = self.api.post("/sync")?
             .json(&syncs)
             .call_opt(ApiReq::new().timeout(120))?;
-        info!("SyncOutgoing.run_sync() -- got {} successes, {} failed, {} blocked syncs", sync_result.success.len(), sync_result.failures.len(), sync_result.blocked.len());
+        info!("SyncOutgoing.send_batch() -- got {} successes, {} failed, {} blocked syncs", sync_result.success.len(), sync_result.failures.len(), sync_result.blocked.len());
 
         // clear out the successful syncs
         let mut err: TResult<()> = Ok(());
         for sync in &sync_result.success {
             // if the record synced successfully, we delete it here
             let res = self.delete_sync_record(sync);
+            // the server has this copy now, so update the base we track for
+            // conflicts (using the copy we sent, not the echo we got back)
+            let sent = syncs.iter().find(|x| x.id == sync.id).unwrap_or(sync);
+            let res0 = with_db!{ db, self.db, conflict::pushed(db, sent) };
+            if res0.is_err() && err.is_ok() { err = res0; }
             // grab any extra sync_ids created from this sync item (the api
             // keeps close track of them) and ignore them on the next incoming
             // sync. this keeps us from double-syncing some items.
@@ -153,10 +260,6 @@ impl Syncer for SyncOutgoing {
             self.handle_sync_failures(&sync_result.failures)?;
         }
 
-        // let the ui know we had an outgoing sync. there are cases where it
-        // will want to know this happened.
-        messaging::ui_event("sync:outgoing:complete", &())?;
-
         // if we have extra sync data, send it off to the ui
         if let Some(extra) = sync_result.extra.as_ref() {
             messaging::ui_event("sync:outgoing:extra", extra)?;
@@ -165,7 +268,77 @@ impl Syncer for SyncOutgoing {
         // if we did indeed get an error while deleting our sync records,
         // send the first error we got back. obviously there may be more
         // than one, but we can only do so much here to maintain resilience.
-        err
+        err?;
+        Ok(sync_result.failures.len() > 0)
+    }
+}
+
+impl Syncer for SyncOutgoing {
+    fn get_name(&self) -> &'static str {
+        "outgoing"
+    }
+
+    fn get_config(&self) -> Arc<RwLock<SyncConfig>> {
+        self.config.clone()
+    }
+
+    fn get_delay(&self) -> u64 {
+        1000
+    }
+
+    fn set_run_version(&mut self, run_version: i64) {
+        self.run_version = run_version;
+    }
+
+    fn get_run_version(&self) -> i64 {
+        self.run_version
+    }
+
+    fn run_sync(&mut self) -> TResult<()> {
+        // get all our sync records queued to be sent out
+        let syncs = self.get_outgoing_syncs()?;
+        if syncs.len() == 0 { return Ok(()); }
+
+        // collapse redundant records before sending anything
+        let (syncs, redundant) = coalesce(syncs);
+        if redundant.len() > 0 {
+            info!("SyncOutgoing.run_sync() -- coalesced {} redundant sync items", redundant.len());
+            with_db!{ db, self.db,
+                for sync in &redundant { db.delete(sync)?; }
+                for sync in &syncs { db.save(sync)?; }
+            }
+        }
+        let mut syncs = order_syncs(syncs);
+        if syncs.len() == 0 { return Ok(()); }
+        // deletes only carry a base so an undo can turn them back into an edit
+        // (see `coalesce()`). the server doesn't need it.
+        for sync in syncs.iter_mut() {
+            if sync.action == SyncAction::Delete { sync.base_version = None; }
+        }
+
+        // send our syncs out in batches. if a batch has failures, we stop
+        // there since later items may well depend on the failed ones.
+        let batch_size = config::get::<usize>(&["sync", "outgoing_batch_size"]).unwrap_or(DEFAULT_BATCH_SIZE);
+        let batch_size = cmp::max(batch_size, 1);
+        let mut had_failures = false;
+        for batch in syncs.chunks(batch_size) {
+            if self.should_quit() { break; }
+            if self.send_batch(batch)? {
+                had_failures = true;
+                break;
+            }
+        }
+
+        // let the ui know we had an outgoing sync. there are cases where it
+        // will want to know this happened.
+        messaging::ui_event("sync:outgoing:complete", &())?;
+
+        // if anything failed, let the runner know so it backs off instead of
+        // resending the failed records right away
+        if had_failures {
+            return TErr!(TError::TryAgain);
+        }
+        Ok(())
     }
 }
 
@@ -173,7 +346,7 @@ impl Syncer for SyncOutgoing {
 mod tests {
     use super::*;
     use ::std::sync::{Arc, RwLock, Mutex};
-    use ::models::sync_record::SyncRecord;
+    use ::models::sync_record::{SyncRecord, SyncAction};
     use ::jedi;
     use ::schema;
 
@@ -205,6 +378,114 @@ mod tests {
         assert_eq!(outgoing.len(), 2);
     }
 
+    #[test]
+    fn skips_syncs_awaiting_rebase() {
+        let mut sync_config = SyncConfig::new();
+        sync_config.skip_api_init = true;
+        let sync_config = Arc::new(RwLock::new(sync_config));
+        let api = Arc::new(Api::new());
+        let db = Storage::new(&String::from(":memory:"), schema::get_schema()).unwrap();
+        let db = Arc::new(Mutex::new(Some(db)));
+
+        let mut sync1 = sync("1", "edit", "note", "n1");
+        sync1.awaiting_rebase = true;
+        {
+            let mut db_guard = lock!(db);
+            let dbo = db_guard.as_mut().unwrap();
+            dbo.save(&sync1).unwrap();
+            dbo.save(&sync("2", "edit", "note", "n2")).unwrap();
+            dbo.save(&sync("3", "edit", "note", "n1")).unwrap();
+        }
+
+        let sync_outgoing = SyncOutgoing::new(sync_config, api, db);
+        let outgoing = sync_outgoing.get_outgoing_syncs().unwrap();
+        assert_eq!(ids(&outgoing), vec!["2"]);
+    }
+
+    fn sync(id: &str, action: &str, ty: &str, item_id: &str) -> SyncRecord {
+        jedi::from_val(json!({"id": id, "action": action, "item_id": item_id, "user_id": 12, "type": ty, "data": {"id": item_id, "rev": id}})).unwrap()
+    }
+
+    fn ids(syncs: &Vec<SyncRecord>) -> Vec<String> {
+        syncs.iter().map(|x| x.id.clone().unwrap()).collect()
+    }
+
+    #[test]
+    fn coalesces_syncs() {
+        let mut edit1 = sync("3", "edit", "note", "n2");
+        edit1.base_version = Some(6);
+        let syncs = vec![
+            sync("1", "add", "note", "n1"),
+            sync("2", "edit", "note", "n1"),
+            edit1,
+            sync("4", "edit", "note", "n2"),
+            sync("5", "edit", "note", "n1"),
+            sync("6", "add", "board", "b1"),
+            sync("7", "edit", "board", "b1"),
+            sync("8", "delete", "board", "b1"),
+            sync("9", "edit", "space", "s1"),
+            sync("10", "move-space", "note", "n2"),
+            sync("11", "edit", "note", "n2"),
+            sync("12", "delete", "space", "s1"),
+        ];
+        let (keep, redundant) = coalesce(syncs);
+        assert_eq!(ids(&keep), vec!["4", "5", "10", "11", "12"]);
+        assert_eq!(ids(&redundant), vec!["1", "3", "2", "6", "7", "8", "9"]);
+        // add + edit -> add, with the latest data
+        assert_eq!(keep[1].action, SyncAction::Add);
+        assert_eq!(keep[1].data.as_ref().unwrap()["rev"], json!("5"));
+        // edit + edit keeps the first edit's base version
+        assert_eq!(keep[0].action, SyncAction::Edit);
+        assert_eq!(keep[0].base_version, Some(6));
+        // edits after a move-space don't fold into it
+        assert_eq!(keep[2].action, SyncAction::MoveSpace);
+        assert_eq!(keep[3].action, SyncAction::Edit);
+        assert_eq!(keep[4].action, SyncAction::Delete);
+    }
+
+    #[test]
+    fn coalesces_undone_deletes() {
+        let mut delete = sync("1", "delete", "note", "n1");
+        delete.base_version = Some(4);
+        let syncs = vec![
+            delete,
+            sync("2", "delete", "board", "b1"),
+            sync("3", "add", "board", "b1"),
+            sync("4", "add", "note", "n1"),
+        ];
+        let (keep, redundant) = coalesce(syncs);
+        assert_eq!(ids(&redundant), vec!["2", "1"]);
+        // delete + add -> an edit made against the version from before the
+        // delete, not an add of an item the server already has
+        assert_eq!(ids(&keep), vec!["3", "4"]);
+        assert_eq!(keep[0].action, SyncAction::Edit);
+        assert_eq!(keep[1].action, SyncAction::Edit);
+        assert_eq!(keep[1].base_version, Some(4));
+        assert_eq!(keep[1].data.as_ref().unwrap()["rev"], json!("4"));
+    }
+
+    #[test]
+    fn orders_syncs_by_dependency() {
+        let with_data = |mut sync: SyncRecord, data: jedi::Value| {
+            sync.data = Some(data);
+            sync
+        };
+        let syncs = vec![
+            sync("1", "delete", "note", "n2"),
+            with_data(sync("2", "add", "note", "n1"), json!({"id": "n1", "space_id": "s1", "board_id": "b1"})),
+            with_data(sync("3", "add", "board", "b1"), json!({"id": "b1", "space_id": "s1"})),
+            with_data(sync("4", "edit", "note", "n3"), json!({"id": "n3", "space_id": "s2"})),
+            with_data(sync("5", "add", "space", "s1"), json!({"id": "s1"})),
+            sync("6", "delete", "board", "b2"),
+            sync("7", "add", "board", "b3"),
+        ];
+        let ordered = order_syncs(syncs);
+        // adds are pulled up ahead of the items that live in them, but
+        // everything else (like deleting a note, then adding a board) goes out
+        // in the order it happened
+        assert_eq!(ids(&ordered), vec!["1", "5", "3", "2", "4", "6", "7"]);
+    }
+
     #[test]
     fn deserializes_sync_response() {
         let typical_mac_user = String::from(r#"{
@@ -0,0 +1,315 @@
+//! Lets the API push incoming sync notifications to us over a server-sent
+//! events stream (`GET /sync/stream`) instead of us long-polling `/sync` over
+//! and over. The stream only tells us *that* something changed: we still grab
+//! the changes themselves from `/sync` like always.
+//!
+//! If the API doesn't have a stream endpoint we fall back to long-polling for
+//! good. If the stream drops (or goes quiet for too long), we long-poll while
+//! we back off and try to reconnect.
+
+use ::std::io::{BufRead, BufReader, Read};
+use ::std::sync::Arc;
+use ::std::sync::atomic::{AtomicBool, Ordering};
+use ::std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use ::std::thread;
+use ::std::time::{Duration, Instant};
+use ::error::{TResult, TError};
+use ::api::{Api, ApiReq, StatusCode};
+use ::config;
+use ::sync::retry::{RetryPolicy, RetryState};
+
+/// One event off the stream
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SseEvent {
+    pub event: String,
+    pub data: String,
+}
+
+/// Turns lines of a `text/event-stream` into events. Only knows the parts of
+/// the format we care about (event/data fields and comments).
+#[derive(Default)]
+pub struct SseParser {
+    event: Option<String>,
+    data: Vec<String>,
+}
+
+impl SseParser {
+    /// Feed the parser a line (minus the newline). Returns an event if the
+    /// line finished one.
+    pub fn line(&mut self, line: &str) -> Option<SseEvent> {
+        if line.is_empty() {
+            if self.event.is_none() && self.data.len() == 0 { return None; }
+            let event = SseEvent {
+                event: self.event.take().unwrap_or(String::from("message")),
+                data: self.data.join("\n"),
+            };
+            self.data.clear();
+            return Some(event);
+        }
+        // a comment, which servers use as a keepalive
+        if line.starts_with(':') {
+            return Some(SseEvent { event: String::from("ping"), data: String::new() });
+        }
+        let (field, val) = match line.find(':') {
+            Some(idx) => (&line[..idx], &line[idx + 1..]),
+            None => (line, ""),
+        };
+        let val = if val.starts_with(' ') { &val[1..] } else { val };
+        match field {
+            "event" => self.event = Some(String::from(val)),
+            "data" => self.data.push(String::from(val)),
+            _ => {}
+        }
+        None
+    }
+}
+
+/// What the stream tells us
+#[derive(Debug, PartialEq)]
+pub enum PushEvent {
+    /// Something changed on the server
+    Sync,
+    /// The server is still there, but has nothing for us
+    Ping,
+    /// The stream ended
+    Closed,
+}
+
+impl PushEvent {
+    fn from_sse(ev: &SseEvent) -> PushEvent {
+        match ev.event.as_ref() {
+            "ping" => PushEvent::Ping,
+            _ => PushEvent::Sync,
+        }
+    }
+}
+
+/// An open notification stream. The stream is read in its own thread so we
+/// can wait on it with a timeout. The thread exits when the stream does, or
+/// with the next line it reads after we're dropped. Streams from the API time
+/// out if they go quiet for a couple keepalive windows, so a dropped stream's
+/// thread doesn't hang around forever waiting on a dead connection.
+pub struct PushStream {
+    rx: Receiver<PushEvent>,
+    alive: Arc<AtomicBool>,
+}
+
+impl PushStream {
+    /// Open a stream with the API, asking to hear about anything after the
+    /// given sync id
+    pub fn connect(api: &Api, sync_id: &String) -> TResult<PushStream> {
+        let keepalive: u64 = config::get(&["sync", "push", "keepalive"]).unwrap_or(60);
+        let url = format!("/sync/stream?sync_id={}", sync_id);
+        let res = api.get(&url[..])?
+            .header("Accept", "text/event-stream")
+            .stream(ApiReq::new().timeout(keepalive * 2))?;
+        Ok(PushStream::from_reader(res))
+    }
+
+    /// Read events off of any old stream
+    pub fn from_reader<R: Read + Send + 'static>(reader: R) -> PushStream {
+        let (tx, rx) = mpsc::channel();
+        let alive = Arc::new(AtomicBool::new(true));
+        let alive2 = alive.clone();
+        thread::spawn(move || {
+            let mut parser = SseParser::default();
+            let mut reader = BufReader::new(reader);
+            loop {
+                let mut line = String::new();
+                match reader.read_line(&mut line) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+                if !alive2.load(Ordering::SeqCst) { return; }
+                if let Some(ev) = parser.line(line.trim_end_matches(|c| c == '\r' || c == '\n')) {
+                    if tx.send(PushEvent::from_sse(&ev)).is_err() { return; }
+                }
+            }
+            let _ = tx.send(PushEvent::Closed);
+        });
+        PushStream {
+            rx: rx,
+            alive: alive,
+        }
+    }
+
+    /// Wait (up to `timeout`) for the next event. Returns None on timeout.
+    pub fn next(&self, timeout: Duration) -> Option<PushEvent> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(x) => Some(x),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => Some(PushEvent::Closed),
+        }
+    }
+}
+
+impl Drop for PushStream {
+    fn drop(&mut self) {
+        self.alive.store(false, Ordering::SeqCst);
+    }
+}
+
+/// What the incoming syncer should do after waiting on a push
+#[derive(Debug, PartialEq)]
+pub enum Wait {
+    /// We can't push right now, so long-poll instead
+    Unavailable,
+    /// Something (may have) changed, grab it
+    Changed,
+    /// Nothing happened
+    Idle,
+}
+
+/// Tracks our notification stream (if any) and how we're backing off from
+/// failed connections
+pub struct Push {
+    stream: Option<PushStream>,
+    /// Set if the API told us it doesn't do streams
+    unsupported: bool,
+    retry: RetryState,
+    policy: RetryPolicy,
+    /// If we're backing off, when we can try connecting again
+    next_attempt: Option<Instant>,
+}
+
+impl Push {
+    pub fn new() -> Push {
+        Push {
+            stream: None,
+            unsupported: false,
+            retry: Default::default(),
+            policy: RetryPolicy::from_config(),
+            next_attempt: None,
+        }
+    }
+
+    /// Whether we have a stream open
+    pub fn connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Note that connecting (or staying connected) failed, and figure out when
+    /// to try again
+    fn failed(&mut self, err: &TError) {
+        self.stream = None;
+        let delay = self.retry.failure(&self.policy, err);
+        info!("Push.failed() -- push stream failed ({}), long-polling for the next {}ms", err, delay);
+        self.next_attempt = Some(Instant::now() + Duration::from_millis(delay));
+    }
+
+    /// Wait for the API to tell us something changed. Connects (or reconnects)
+    /// the stream as needed. Gives up after the `sync.push.keepalive` window
+    /// passes with no word from the server, or if `enabled` says we should
+    /// stop.
+    pub fn wait<F>(&mut self, api: &Api, sync_id: &String, enabled: F) -> Wait
+        where F: Fn() -> bool
+    {
+        let push_enabled = config::get(&["sync", "push", "enabled"]).unwrap_or(false);
+        if !push_enabled || self.unsupported { return Wait::Unavailable; }
+        if self.stream.is_none() {
+            if let Some(next) = self.next_attempt {
+                if Instant::now() < next { return Wait::Unavailable; }
+            }
+            match PushStream::connect(api, sync_id) {
+                Ok(stream) => {
+                    debug!("Push.wait() -- push stream connected");
+                    self.stream = Some(stream);
+                    self.next_attempt = None;
+                    // we may have missed something while connecting
+                    return Wait::Changed;
+                }
+                Err(e) => {
+                    match e.shed() {
+                        TError::Api(StatusCode::NOT_FOUND, _) |
+                            TError::Api(StatusCode::METHOD_NOT_ALLOWED, _) |
+                            TError::Api(StatusCode::NOT_IMPLEMENTED, _) => {
+                            info!("Push.wait() -- api doesn't do push, sticking with long-polling");
+                            self.unsupported = true;
+                        }
+                        e => self.failed(&e),
+                    }
+                    return Wait::Unavailable;
+                }
+            }
+        }
+
+        let keepalive: u64 = config::get(&["sync", "push", "keepalive"]).unwrap_or(60);
+        let deadline = Instant::now() + Duration::from_secs(keepalive);
+        loop {
+            if !enabled() { return Wait::Idle; }
+            let now = Instant::now();
+            if now >= deadline {
+                self.failed(&TError::Msg(format!("no word from the push stream in {}s", keepalive)));
+                return Wait::Unavailable;
+            }
+            // wait in small slices so we notice if sync gets shut off
+            let slice = ::std::cmp::min(deadline - now, Duration::from_secs(1));
+            let event = match self.stream.as_ref() {
+                Some(stream) => stream.next(slice),
+                None => return Wait::Unavailable,
+            };
+            match event {
+                None => continue,
+                Some(PushEvent::Ping) => {
+                    self.retry.success();
+                    return Wait::Idle;
+                }
+                Some(PushEvent::Sync) => {
+                    self.retry.success();
+                    return Wait::Changed;
+                }
+                Some(PushEvent::Closed) => {
+                    self.failed(&TError::Msg(String::from("push stream closed")));
+                    // grab anything we might have missed while it was down
+                    return Wait::Changed;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::std::io::Write;
+    use ::std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn parses_event_streams() {
+        let mut parser = SseParser::default();
+        let lines = vec![
+            ": hello",
+            "event: sync",
+            "data: {\"sync_id\":",
+            "data:  42}",
+            "",
+            "",
+            "data: plain",
+            "",
+        ];
+        let events = lines.into_iter().filter_map(|x| parser.line(x)).collect::<Vec<_>>();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].event, "ping");
+        assert_eq!(events[1], SseEvent { event: String::from("sync"), data: String::from("{\"sync_id\":\n 42}") });
+        assert_eq!(events[2], SseEvent { event: String::from("message"), data: String::from("plain") });
+    }
+
+    #[test]
+    fn reads_from_a_stand_in_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            conn.write_all(b": ping\n\nevent: sync\r\ndata: {\"sync_id\": 7}\r\n\r\n").unwrap();
+            thread::sleep(Duration::from_millis(200));
+            // hang up
+        });
+        let stream = PushStream::from_reader(TcpStream::connect(addr).unwrap());
+        let wait = Duration::from_secs(5);
+        assert_eq!(stream.next(wait), Some(PushEvent::Ping));
+        assert_eq!(stream.next(wait), Some(PushEvent::Sync));
+        assert_eq!(stream.next(Duration::from_millis(10)), None);
+        assert_eq!(stream.next(wait), Some(PushEvent::Closed));
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,200 @@
+//! The retry policy shared by all our syncers. Rather than hammering the API
+//! every second while it's down (or while we're offline), a syncer that fails
+//! backs off exponentially, with some jitter so a pile of clients don't all
+//! come back at the same moment. If the API tells us when to come back (via
+//! `Retry-After`) we listen.
+
+use ::std::cmp;
+use ::error::TError;
+use ::api::StatusCode;
+use ::jedi;
+use ::config;
+use ::crypto;
+use ::reqwest;
+use ::time;
+
+/// The broad category a sync failure falls into
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum FailureKind {
+    /// We couldn't reach the API at all
+    #[serde(rename = "network")]
+    Network,
+    /// The API had a problem (5xx) or asked us to slow down
+    #[serde(rename = "server")]
+    Server,
+    /// The API didn't like our request (4xx). Retrying quickly won't help.
+    #[serde(rename = "client")]
+    Client,
+    /// Something broke locally (db, crypto, etc)
+    #[serde(rename = "local")]
+    Local,
+}
+
+/// Figure out what kind of failure an error represents
+pub fn classify(err: &TError) -> FailureKind {
+    match *err {
+        TError::Wrapped(_, _, _, ref inner) => classify(inner),
+        TError::Api(ref status, _) | TError::Http(ref status, _) => {
+            if status.is_client_error() && *status != StatusCode::TOO_MANY_REQUESTS && *status != StatusCode::REQUEST_TIMEOUT {
+                FailureKind::Client
+            } else {
+                FailureKind::Server
+            }
+        }
+        TError::Boxed(ref x) => {
+            if x.downcast_ref::<reqwest::Error>().is_some() {
+                FailureKind::Network
+            } else {
+                FailureKind::Local
+            }
+        }
+        TError::Io(_) | TError::ConnectionRequired => FailureKind::Network,
+        _ => FailureKind::Local,
+    }
+}
+
+/// Grab the `Retry-After` (in seconds) the API sent along with an error, if
+/// any (see `api::ApiCaller.call_opt_impl()`)
+pub fn retry_after(err: &TError) -> Option<u64> {
+    match *err {
+        TError::Wrapped(_, _, _, ref inner) => retry_after(inner),
+        TError::Api(_, ref msg) => jedi::get_opt(&["retry_after"], msg),
+        _ => None,
+    }
+}
+
+/// Determines how long we wait after failures. Loaded from `sync.retry` in
+/// the config.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// The delay (ms) after the first failure. Doubles with each failure after.
+    pub base_delay: u64,
+    /// The longest (ms) we'll ever back off for (unless the API says otherwise)
+    pub max_delay: u64,
+    /// How much to randomly spread delays out by (0.2 = +/-20%)
+    pub jitter: f64,
+}
+
+impl RetryPolicy {
+    /// Load our retry policy from the config
+    pub fn from_config() -> Self {
+        let get = |key: &str, default: u64| -> u64 {
+            config::get(&["sync", "retry", key]).unwrap_or(default)
+        };
+        RetryPolicy {
+            base_delay: get("base_delay", 1000),
+            max_delay: get("max_delay", 300000),
+            jitter: config::get(&["sync", "retry", "jitter"]).unwrap_or(0.2),
+        }
+    }
+
+    /// How long (ms, before jitter) to wait after `failures` failures in a row
+    pub fn delay_for(&self, kind: FailureKind, failures: u32) -> u64 {
+        if failures == 0 { return 0; }
+        match kind {
+            FailureKind::Client => self.max_delay,
+            _ => {
+                let exp = cmp::min(failures - 1, 32);
+                cmp::min(self.base_delay.saturating_mul(1u64 << exp), self.max_delay)
+            }
+        }
+    }
+
+    /// Randomly spread a delay out by our jitter factor
+    fn jitter(&self, delay: u64) -> u64 {
+        if self.jitter <= 0.0 { return delay; }
+        let rand = crypto::rand_float().unwrap_or(0.5);
+        let factor = 1.0 + (self.jitter * ((rand * 2.0) - 1.0));
+        ((delay as f64) * factor) as u64
+    }
+}
+
+/// Tracks a syncer's failures. This is also what gets reported for each syncer
+/// via `sync:status`.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct RetryState {
+    /// How many runs in a row have failed
+    pub failures: u32,
+    /// The kind of failure we last saw
+    pub last_failure: Option<FailureKind>,
+    /// The last error we saw
+    pub last_error: Option<String>,
+    /// If we're backing off, when (unix timestamp) we'll try again
+    pub retry_at: Option<i64>,
+}
+
+impl RetryState {
+    /// Record a successful run
+    pub fn success(&mut self) {
+        *self = Default::default();
+    }
+
+    /// Record a failed run, returning how long (ms) to wait before the next
+    pub fn failure(&mut self, policy: &RetryPolicy, err: &TError) -> u64 {
+        let kind = classify(err);
+        self.failures += 1;
+        let delay = match retry_after(err) {
+            Some(secs) => secs.saturating_mul(1000),
+            None => policy.jitter(policy.delay_for(kind, self.failures)),
+        };
+        self.last_failure = Some(kind);
+        self.last_error = Some(format!("{}", err));
+        self.retry_at = Some(time::get_time().sec + ((delay / 1000) as i64));
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy { base_delay: 1000, max_delay: 60000, jitter: 0.0 }
+    }
+
+    #[test]
+    fn classifies_errors() {
+        assert_eq!(classify(&twrap!(TError::Api(StatusCode::BAD_GATEWAY, json!({})))), FailureKind::Server);
+        assert_eq!(classify(&TError::Api(StatusCode::TOO_MANY_REQUESTS, json!({}))), FailureKind::Server);
+        assert_eq!(classify(&TError::Api(StatusCode::FORBIDDEN, json!({}))), FailureKind::Client);
+        assert_eq!(classify(&TError::ConnectionRequired), FailureKind::Network);
+        assert_eq!(classify(&TError::MissingData(String::from("db"))), FailureKind::Local);
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let policy = policy();
+        let err = TError::Api(StatusCode::SERVICE_UNAVAILABLE, json!({}));
+        let mut state = RetryState::default();
+        let delays = (0..8).map(|_| state.failure(&policy, &err)).collect::<Vec<_>>();
+        assert_eq!(delays, vec![1000, 2000, 4000, 8000, 16000, 32000, 60000, 60000]);
+        assert_eq!(state.failures, 8);
+        assert_eq!(state.last_failure, Some(FailureKind::Server));
+        state.success();
+        assert_eq!(state.failures, 0);
+        assert_eq!(state.retry_at, None);
+
+        // no sense in retrying quickly if the API doesn't like what we sent
+        let err = TError::Api(StatusCode::BAD_REQUEST, json!({}));
+        assert_eq!(state.failure(&policy, &err), 60000);
+    }
+
+    #[test]
+    fn jitters_delays() {
+        let mut policy = policy();
+        policy.jitter = 0.5;
+        for _ in 0..20 {
+            let delay = policy.jitter(10000);
+            assert!(5000 <= delay && delay <= 15000);
+        }
+    }
+
+    #[test]
+    fn honors_retry_after() {
+        let policy = policy();
+        let err = twrap!(TError::Api(StatusCode::TOO_MANY_REQUESTS, json!({"message": "slow down", "retry_after": 120})));
+        let mut state = RetryState::default();
+        assert_eq!(state.failure(&policy, &err), 120000);
+    }
+}
+
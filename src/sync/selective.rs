@@ -0,0 +1,268 @@
+//! Selective sync lets a device skip some of the user's spaces entirely (say,
+//! a phone that only needs the "Shopping" space). The excluded spaces are a
+//! per-device setting kept in the local db's k/v store, and never synced.
+//!
+//! Incoming boards, notes and files in an excluded space are filtered out
+//! before they touch the db, and anything already stored locally for a space
+//! when it's excluded gets purged (locally only...the API never hears about
+//! it). When a space is included again, the incoming sync backfills its data
+//! from the full profile.
+//!
+//! Note that spaces themselves (and keychain entries, invites, etc) always
+//! sync. This lets the UI list every space so the user can pick and choose,
+//! and means new spaces sync by default.
+
+use ::jedi;
+use ::error::{TResult, TError};
+use ::storage::Storage;
+use ::turtl::Turtl;
+use ::models::model::Model;
+use ::models::board::Board;
+use ::models::note::Note;
+use ::models::template::Template;
+use ::models::file::FileData;
+use ::models::sync_record::{SyncRecord, SyncType, SyncAction};
+use ::sync::sync_model::{self, SyncModel};
+
+/// Where we store the spaces this device doesn't sync
+const EXCLUDED_KEY: &'static str = "sync:selective:excluded";
+
+/// Where we store the spaces waiting to be backfilled
+const BACKFILL_KEY: &'static str = "sync:selective:backfill";
+
+/// Grab a list of space ids from the k/v store
+fn get_list(db: &Storage, key: &str) -> TResult<Vec<String>> {
+    match db.kv_get(key)? {
+        Some(x) => Ok(jedi::parse(&x)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Save a list of space ids into the k/v store
+fn set_list(db: &Storage, key: &str, list: &Vec<String>) -> TResult<()> {
+    if list.len() == 0 {
+        db.kv_delete(key)
+    } else {
+        db.kv_set(key, &jedi::stringify(list)?)
+    }
+}
+
+/// Get the spaces this device doesn't sync
+pub fn get_excluded(db: &Storage) -> TResult<Vec<String>> {
+    get_list(db, EXCLUDED_KEY)
+}
+
+/// Get the spaces waiting to be backfilled
+pub fn get_backfill(db: &Storage) -> TResult<Vec<String>> {
+    get_list(db, BACKFILL_KEY)
+}
+
+/// Mark some spaces as backfilled
+pub fn clear_backfill(db: &Storage, done: &Vec<String>) -> TResult<()> {
+    let mut backfill = get_backfill(db)?;
+    backfill.retain(|x| !done.contains(x));
+    set_list(db, BACKFILL_KEY, &backfill)
+}
+
+/// Grab the space a (board/note) sync record's data lives in
+fn record_space(rec: &SyncRecord) -> Option<String> {
+    rec.data.as_ref().and_then(|x| jedi::get_opt(&["space_id"], x))
+}
+
+/// Decide whether an incoming sync record makes it into our db, given the
+/// spaces this device doesn't sync. Returns false if the record should be
+/// skipped. A board/note moving *into* an excluded space is turned into a
+/// delete, so our local copy gets purged.
+pub fn filter_incoming(db: &Storage, excluded: &Vec<String>, rec: &mut SyncRecord) -> TResult<bool> {
+    if excluded.len() == 0 { return Ok(true); }
+    match rec.ty {
+        SyncType::Board | SyncType::Note | SyncType::Template => {
+            if rec.action == SyncAction::Delete { return Ok(true); }
+            let space_id = match record_space(rec) {
+                Some(x) => x,
+                None => return Ok(true),
+            };
+            if !excluded.contains(&space_id) { return Ok(true); }
+            let exists = match rec.ty {
+                SyncType::Board => db.get::<Board>("boards", &rec.item_id)?.is_some(),
+                SyncType::Template => db.get::<Template>("templates", &rec.item_id)?.is_some(),
+                _ => db.get::<Note>("notes", &rec.item_id)?.is_some(),
+            };
+            if exists {
+                rec.action = SyncAction::Delete;
+            }
+            Ok(exists)
+        }
+        SyncType::File | SyncType::FileIncoming => {
+            if rec.action == SyncAction::Delete { return Ok(true); }
+            // files follow their note. if we skipped the note, skip the file.
+            Ok(db.get::<Note>("notes", &FileData::sync_note_id(rec))?.is_some())
+        }
+        SyncType::Revision => {
+            if rec.action == SyncAction::Delete { return Ok(true); }
+            // same goes for revisions
+            let note_id: Option<String> = rec.data.as_ref().and_then(|x| jedi::get_opt(&["note_id"], x));
+            match note_id {
+                Some(id) => Ok(db.get::<Note>("notes", &id)?.is_some()),
+                None => Ok(true),
+            }
+        }
+        _ => Ok(true),
+    }
+}
+
+/// Decide whether a record from the full profile belongs in a backfill of the
+/// given spaces. Only boards, notes, revisions, templates and files are
+/// backfilled: we already have everything else.
+pub fn filter_backfill(db: &Storage, spaces: &Vec<String>, rec: &SyncRecord) -> TResult<bool> {
+    match rec.ty {
+        SyncType::Board | SyncType::Note | SyncType::Revision | SyncType::Template => {
+            Ok(record_space(rec).map(|x| spaces.contains(&x)).unwrap_or(false))
+        }
+        SyncType::File | SyncType::FileIncoming => {
+            let note: Option<Note> = db.get("notes", &FileData::sync_note_id(rec))?;
+            Ok(note.map(|x| spaces.contains(&x.space_id)).unwrap_or(false))
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Get the spaces this device doesn't sync
+pub fn excluded_spaces(turtl: &Turtl) -> TResult<Vec<String>> {
+    let db_guard = lock!(turtl.db);
+    match db_guard.as_ref() {
+        Some(db) => get_excluded(db),
+        None => TErr!(TError::MissingField(String::from("Turtl.db"))),
+    }
+}
+
+/// Set which spaces this device doesn't sync. Spaces that are newly excluded
+/// have their boards/notes/files purged locally, and spaces that are no longer
+/// excluded are queued to be backfilled by the incoming sync.
+pub fn set_excluded_spaces(turtl: &Turtl, excluded: Vec<String>) -> TResult<()> {
+    let newly_excluded = {
+        let db_guard = lock!(turtl.db);
+        let db = match db_guard.as_ref() {
+            Some(x) => x,
+            None => return TErr!(TError::MissingField(String::from("Turtl.db"))),
+        };
+        let current = get_excluded(db)?;
+        let newly_excluded = excluded.iter()
+            .filter(|x| !current.contains(x))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut backfill = get_backfill(db)?;
+        backfill.retain(|x| !excluded.contains(x));
+        for space_id in current {
+            if !excluded.contains(&space_id) && !backfill.contains(&space_id) {
+                backfill.push(space_id);
+            }
+        }
+        set_list(db, EXCLUDED_KEY, &excluded)?;
+        set_list(db, BACKFILL_KEY, &backfill)?;
+        newly_excluded
+    };
+    for space_id in &newly_excluded {
+        purge_space(turtl, space_id)?;
+    }
+    Ok(())
+}
+
+/// Remove everything we have locally for a space (but not the space itself)
+/// without telling the API about it
+fn purge_space(turtl: &Turtl, space_id: &String) -> TResult<()> {
+    info!("selective::purge_space() -- purging local data for space {}", space_id);
+    let (boards, notes, templates): (Vec<Board>, Vec<Note>, Vec<Template>) = {
+        let db_guard = lock!(turtl.db);
+        match *db_guard {
+            Some(ref db) => {
+                (
+                    db.find("boards", "space_id", &vec![space_id.clone()])?,
+                    db.find("notes", "space_id", &vec![space_id.clone()])?,
+                    db.find("templates", "space_id", &vec![space_id.clone()])?,
+                )
+            }
+            None => (vec![], vec![], vec![]),
+        }
+    };
+    for template in templates {
+        let template_id = template.id_or_else()?;
+        sync_model::delete_model::<Template>(turtl, &template_id, true)?;
+    }
+    for board in boards {
+        let board_id = board.id_or_else()?;
+        sync_model::delete_model::<Board>(turtl, &board_id, true)?;
+    }
+    let mut note_ids = Vec::with_capacity(notes.len());
+    for note in notes {
+        let note_id = note.id_or_else()?;
+        sync_model::delete_model::<Note>(turtl, &note_id, true)?;
+        note_ids.push(note_id);
+    }
+
+    let mut db_guard = lock!(turtl.db);
+    let db = match db_guard.as_mut() {
+        Some(x) => x,
+        None => return TErr!(TError::MissingField(String::from("Turtl.db"))),
+    };
+    // the notes' files went with them, but not any that are partway
+    // downloaded. no sense in downloading files for notes we just purged,
+    // either.
+    for sync in SyncRecord::find(db, Some(SyncType::FileIncoming))? {
+        if note_ids.contains(&FileData::sync_note_id(&sync)) {
+            let mut file: FileData = Default::default();
+            file.set_id(sync.item_id.clone());
+            file.db_delete(db, None)?;
+            db.delete(&sync)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::schema;
+
+    fn sync(ty: &str, action: &str, item_id: &str, data: Option<::jedi::Value>) -> SyncRecord {
+        let mut rec: SyncRecord = jedi::from_val(json!({"id": "1", "action": action, "item_id": item_id, "user_id": 12, "type": ty})).unwrap();
+        rec.data = data;
+        rec
+    }
+
+    #[test]
+    fn filters_incoming_by_space() {
+        let db = Storage::new(&String::from(":memory:"), schema::get_schema()).unwrap();
+        let note: Note = jedi::from_val(json!({"id": "n1", "space_id": "s1", "user_id": 12})).unwrap();
+        db.save(&note).unwrap();
+        let excluded = vec![String::from("s2")];
+
+        // included spaces come through
+        let mut rec = sync("note", "add", "n2", Some(json!({"id": "n2", "space_id": "s1"})));
+        assert!(filter_incoming(&db, &excluded, &mut rec).unwrap());
+        // excluded spaces don't
+        let mut rec = sync("note", "add", "n3", Some(json!({"id": "n3", "space_id": "s2"})));
+        assert!(!filter_incoming(&db, &excluded, &mut rec).unwrap());
+        // moving a note we have into an excluded space purges it
+        let mut rec = sync("note", "move-space", "n1", Some(json!({"id": "n1", "space_id": "s2"})));
+        assert!(filter_incoming(&db, &excluded, &mut rec).unwrap());
+        assert_eq!(rec.action, SyncAction::Delete);
+        // files follow their notes
+        let mut rec = sync("file", "add", "n1", Some(json!({"id": "n1"})));
+        assert!(filter_incoming(&db, &excluded, &mut rec).unwrap());
+        let mut rec = sync("file", "add", "n3", Some(json!({"id": "n3"})));
+        assert!(!filter_incoming(&db, &excluded, &mut rec).unwrap());
+        // spaces always sync
+        let mut rec = sync("space", "add", "s2", Some(json!({"id": "s2"})));
+        assert!(filter_incoming(&db, &excluded, &mut rec).unwrap());
+
+        // backfills only grab what's in the spaces we asked for
+        let spaces = vec![String::from("s2")];
+        let rec = sync("note", "add", "n3", Some(json!({"id": "n3", "space_id": "s2"})));
+        assert!(filter_backfill(&db, &spaces, &rec).unwrap());
+        let rec = sync("note", "add", "n1", Some(json!({"id": "n1", "space_id": "s1"})));
+        assert!(!filter_backfill(&db, &spaces, &rec).unwrap());
+        let rec = sync("space", "add", "s2", Some(json!({"id": "s2"})));
+        assert!(!filter_backfill(&db, &spaces, &rec).unwrap());
+    }
+}
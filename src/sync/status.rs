@@ -0,0 +1,268 @@
+//! Tracks what the sync system is up to so the UI can show it: what each
+//! syncer is doing, what's waiting to go out, and how file transfers are
+//! coming along. The syncers write their state into the shared `SyncConfig`
+//! and the main thread reads it back out for `sync:status`.
+
+use ::std::sync::{Arc, RwLock};
+use ::std::collections::HashMap;
+use ::std::io::{self, Read};
+use ::std::time::{Duration, Instant};
+use ::sync::SyncConfig;
+use ::sync::retry::RetryState;
+use ::models::sync_record::SyncRecord;
+use ::messaging;
+use ::util;
+
+/// The minimum time between `sync:progress` events for a transfer
+const PROGRESS_INTERVAL: u64 = 500;
+
+/// The state of one of our syncers
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct SyncerStatus {
+    /// Whether the syncer is in the middle of a run
+    pub syncing: bool,
+    /// The last time (unix timestamp) a run finished without error
+    pub last_success: Option<i64>,
+    /// Failure/backoff info
+    pub retry: RetryState,
+    /// The file transfer in progress, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transfer: Option<Transfer>,
+}
+
+/// Describes a file upload/download in progress. This is also the payload of
+/// our `sync:progress` events.
+#[derive(Serialize, Debug, Clone)]
+pub struct Transfer {
+    /// The syncer running the transfer
+    pub syncer: &'static str,
+    /// The note the file belongs to
+    pub note_id: String,
+    /// The attachment being sent/received
+    pub file_id: String,
+    /// "upload" or "download"
+    pub direction: &'static str,
+    /// How many bytes we've sent/received
+    pub bytes: u64,
+    /// How many bytes there are in total (if we know)
+    pub total: Option<u64>,
+    /// Which file this is (starting at 1) out of `files`
+    pub file: usize,
+    /// The number of files in this batch of transfers
+    pub files: usize,
+}
+
+/// Counts of the sync records waiting to go out
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct PendingCounts {
+    pub total: usize,
+    /// Counts keyed by sync type ("note", "file:outgoing", etc)
+    pub by_type: HashMap<String, usize>,
+    /// How many records are frozen (failed too many times)
+    pub frozen: usize,
+}
+
+impl PendingCounts {
+    /// Tally up a set of pending sync records
+    pub fn from_syncs(syncs: &Vec<SyncRecord>) -> Self {
+        let mut counts: PendingCounts = Default::default();
+        for sync in syncs {
+            let ty = util::enum_to_string(&sync.ty).unwrap_or(String::from("unknown"));
+            *counts.by_type.entry(ty).or_insert(0) += 1;
+            counts.total += 1;
+            if sync.frozen { counts.frozen += 1; }
+        }
+        counts
+    }
+}
+
+/// Everything we report via `sync:status`
+#[derive(Serialize, Debug)]
+pub struct SyncStatus {
+    /// Whether syncing is running
+    pub running: bool,
+    /// Whether the API is pushing changes to us (otherwise we long-poll)
+    pub push: bool,
+    /// The last time (unix timestamp) we successfully heard from the API
+    pub last_synced: Option<i64>,
+    /// The state of each syncer, by name
+    pub syncers: HashMap<String, SyncerStatus>,
+    /// Counts of the changes waiting to go out
+    pub pending: PendingCounts,
+    /// The frozen sync records (minus their data), so the UI can offer to
+    /// unfreeze or remove them
+    pub frozen: Vec<SyncRecord>,
+}
+
+impl SyncStatus {
+    /// Build our sync status from the sync config and the pending records
+    pub fn new(running: bool, config: &SyncConfig, pending: &Vec<SyncRecord>) -> Self {
+        let frozen = pending.iter()
+            .filter(|x| x.frozen)
+            .map(|x| {
+                let mut rec = x.clone_shallow();
+                rec.id = x.id.clone();
+                rec.error = x.error.clone();
+                rec.errcount = x.errcount;
+                rec.frozen = true;
+                rec
+            })
+            .collect::<Vec<_>>();
+        SyncStatus {
+            running: running,
+            push: running && config.push,
+            last_synced: config.status.get("incoming").and_then(|x| x.last_success),
+            syncers: config.status.clone(),
+            pending: PendingCounts::from_syncs(pending),
+            frozen: frozen,
+        }
+    }
+}
+
+/// Update a syncer's status in the sync config
+pub fn update<F>(config: &Arc<RwLock<SyncConfig>>, syncer: &str, update_fn: F)
+    where F: FnOnce(&mut SyncerStatus)
+{
+    let mut guard = lockw!(config);
+    let status = guard.status.entry(String::from(syncer)).or_insert_with(Default::default);
+    update_fn(status);
+}
+
+/// Tracks a file transfer, updating the syncer's status and sending out
+/// `sync:progress` events as bytes move. Clears itself out of the status when
+/// dropped.
+pub struct Progress {
+    config: Arc<RwLock<SyncConfig>>,
+    transfer: Transfer,
+    last_event: Option<Instant>,
+}
+
+impl Progress {
+    /// Start tracking a transfer
+    pub fn new(config: Arc<RwLock<SyncConfig>>, transfer: Transfer) -> Self {
+        let mut progress = Progress {
+            config: config,
+            transfer: transfer,
+            last_event: None,
+        };
+        progress.publish();
+        progress
+    }
+
+    /// Record some bytes moving
+    pub fn add(&mut self, bytes: u64) {
+        self.transfer.bytes += bytes;
+        let due = match self.last_event {
+            Some(x) => x.elapsed() >= Duration::from_millis(PROGRESS_INTERVAL),
+            None => true,
+        };
+        if due { self.publish(); }
+    }
+
+    /// Push our progress into the sync status and let the UI know about it
+    pub fn publish(&mut self) {
+        self.last_event = Some(Instant::now());
+        let transfer = self.transfer.clone();
+        update(&self.config, self.transfer.syncer, |status| status.transfer = Some(transfer));
+        match messaging::ui_event("sync:progress", &self.transfer) {
+            Ok(_) => {}
+            Err(e) => warn!("Progress.publish() -- problem sending progress event: {}", e),
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        update(&self.config, self.transfer.syncer, |status| status.transfer = None);
+    }
+}
+
+/// Wraps a reader (like a file we're uploading) and tracks the bytes read
+/// through it.
+pub struct ProgressReader<R> {
+    inner: R,
+    progress: Progress,
+}
+
+impl<R: Read> ProgressReader<R> {
+    pub fn new(inner: R, progress: Progress) -> Self {
+        ProgressReader {
+            inner: inner,
+            progress: progress,
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read == 0 {
+            // all done, make sure the UI sees the final count
+            self.progress.publish();
+        } else {
+            self.progress.add(read as u64);
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::jedi;
+    use ::models::sync_record::SyncType;
+
+    #[test]
+    fn counts_pending_syncs() {
+        let mut syncs: Vec<SyncRecord> = vec![
+            json!({"id": "1", "action": "add", "item_id": "69", "user_id": 12, "type": "note"}),
+            json!({"id": "2", "action": "edit", "item_id": "69", "user_id": 12, "type": "note"}),
+            json!({"id": "3", "action": "add", "item_id": "70", "user_id": 12, "type": "board"}),
+            json!({"id": "4", "action": "add", "item_id": "69", "user_id": 12, "type": "file:outgoing"}),
+        ].into_iter().map(|x| jedi::from_val(x).unwrap()).collect();
+        syncs[2].frozen = true;
+        let counts = PendingCounts::from_syncs(&syncs);
+        assert_eq!(counts.total, 4);
+        assert_eq!(counts.frozen, 1);
+        assert_eq!(counts.by_type.get("note"), Some(&2));
+        assert_eq!(counts.by_type.get("board"), Some(&1));
+        assert_eq!(counts.by_type.get("file:outgoing"), Some(&1));
+
+        let mut config = SyncConfig::new();
+        config.status.insert(String::from("incoming"), SyncerStatus { last_success: Some(1234), ..Default::default() });
+        let status = SyncStatus::new(true, &config, &syncs);
+        assert_eq!(status.last_synced, Some(1234));
+        assert_eq!(status.frozen.len(), 1);
+        assert_eq!(status.frozen[0].id, Some(String::from("3")));
+        assert_eq!(status.frozen[0].ty, SyncType::Board);
+    }
+
+    #[test]
+    fn tracks_reader_progress() {
+        let config = Arc::new(RwLock::new(SyncConfig::new()));
+        let transfer = Transfer {
+            syncer: "files:outgoing",
+            note_id: String::from("69"),
+            file_id: String::from("70"),
+            direction: "upload",
+            bytes: 0,
+            total: Some(10000),
+            file: 1,
+            files: 1,
+        };
+        {
+            let data = vec![7u8; 10000];
+            let mut reader = ProgressReader::new(&data[..], Progress::new(config.clone(), transfer));
+            let mut out = Vec::new();
+            reader.read_to_end(&mut out).unwrap();
+            assert_eq!(out.len(), 10000);
+            let guard = lockr!(config);
+            let transfer = guard.status.get("files:outgoing").unwrap().transfer.as_ref().unwrap();
+            assert_eq!(transfer.bytes, 10000);
+        }
+        // once the transfer is done, it's cleared out
+        let guard = lockr!(config);
+        assert!(guard.status.get("files:outgoing").unwrap().transfer.is_none());
+    }
+}
+
@@ -16,8 +16,12 @@ use ::models::validate::Validate;
 use ::models::space::Space;
 use ::models::board::Board;
 use ::models::note::Note;
-use ::models::file::FileData;
+use ::models::template::Template;
+use ::models::file::{self, FileData, Attachment};
+use ::models::checklist;
+use ::sync::conflict::{self, Incoming, ConflictEvent};
 use ::lib_permissions::Permission;
+use ::trash;
 use ::jedi::{self, Value};
 use ::turtl::Turtl;
 use ::std::mem;
@@ -57,10 +61,32 @@ pub trait SyncModel: Protected + Storable + Keyfinder + Sync + Send + 'static {
                 mem::swap(sync_item.data.as_mut().expect("turtl::SyncModel.incoming() -- sync_item.data is None!!!2"), &mut data);
                 debug!("sync::incoming() -- {} / data: {:?}", self.model_type(), jedi::stringify(&data)?);
                 let model: Self = jedi::from_val(data)?;
-                model.db_save(db, Some(sync_item as &SyncRecord))?;
-                // set the data back into the sync record so's we'll have it
-                // handy when we run our trusty sync handler
-                sync_item.data = Some(model.data_for_storage()?);
+                match conflict::check_incoming(db, &model)? {
+                    Incoming::Apply => {
+                        model.db_save(db, Some(sync_item as &SyncRecord))?;
+                        // set the data back into the sync record so's we'll
+                        // have it handy when we run our trusty sync handler
+                        sync_item.data = Some(model.data_for_storage()?);
+                    }
+                    Incoming::Stale => {
+                        // we have local edits made on top of this already,
+                        // so keep those
+                        sync_item.data = Some(self.local_data(db, &sync_item.item_id)?);
+                    }
+                    Incoming::Conflict => {
+                        let remote = model.data_for_storage()?;
+                        if sync_item.ty == SyncType::Note {
+                            // notes get merged once we have access to keys
+                            // (see `sync::conflict::resolve()`)
+                            sync_item.conflict = true;
+                            sync_item.data = Some(remote);
+                        } else {
+                            conflict::rebase::<Self>(db, &sync_item.item_id, &remote)?;
+                            messaging::ui_event("sync:conflict", &ConflictEvent::new(&sync_item.item_id, sync_item.ty.clone(), "local"))?;
+                            sync_item.data = Some(self.local_data(db, &sync_item.item_id)?);
+                        }
+                    }
+                }
                 Ok(())
             }
         }
@@ -69,6 +95,21 @@ pub trait SyncModel: Protected + Storable + Keyfinder + Sync + Send + 'static {
     /// Allows a model to save itself to the outgoing sync database (or perform
     /// any custom needed actual in addition/instead).
     fn outgoing(&self, action: SyncAction, user_id: &String, db: &mut Storage, skip_remote_sync: bool) -> TResult<()> {
+        // the version of the stored copy we're changing, which is what the
+        // server has (or will have, once our earlier edits go out) and so is
+        // the base for this change. deletes hang onto it so if the delete is
+        // undone before it goes out, the resulting edit has a base (see
+        // `outgoing::coalesce()`)
+        let base_version = match action {
+            SyncAction::Add => None,
+            _ if conflict::tracked(self) => {
+                match db.get::<Self>(self.table(), &self.id_or_else()?)? {
+                    Some(x) => Some(conflict::version(&x)?),
+                    None => None,
+                }
+            }
+            _ => None,
+        };
         match action {
             SyncAction::Delete => {
                 self.db_delete(db, None)?;
@@ -95,9 +136,18 @@ pub trait SyncModel: Protected + Storable + Keyfinder + Sync + Send + 'static {
                 sync_record.data = Some(self.data_for_storage()?);
             }
         }
+        sync_record.base_version = base_version;
         sync_record.db_save(db, None)
     }
 
+    /// Grab the stored data for the local copy of this model's type
+    fn local_data(&self, db: &mut Storage, item_id: &String) -> TResult<Value> {
+        match db.get::<Self>(self.table(), item_id)? {
+            Some(x) => x.data_for_storage(),
+            None => TErr!(TError::NotFound(format!("{} {} is missing locally", self.model_type(), item_id))),
+        }
+    }
+
     /// Gives us the option to skip an incoming sync. Some sync records are just
     /// indicators for something happening as opposed to data changes (for
     /// instance the "change-password" sync action).
@@ -157,8 +207,8 @@ pub fn save_model<T>(action: SyncAction, turtl: &Turtl, model: &mut T, skip_remo
 {
     model.do_validate(model.model_type())?;
     {
-        let db_guard = lock!(turtl.db);
-        let db = match (*db_guard).as_ref() {
+        let mut db_guard = lock!(turtl.db);
+        let db = match (*db_guard).as_mut() {
             Some(x) => x,
             None => return TErr!(TError::MissingField(format!("Turtl.db ({})", model.model_type()))),
         };
@@ -166,6 +216,7 @@ pub fn save_model<T>(action: SyncAction, turtl: &Turtl, model: &mut T, skip_remo
         if action == SyncAction::Add {
             model.generate_id()?;
             model.generate_key()?;
+            conflict::bump_version(model, None)?;
         } else {
             let got_model = db.get::<T>(model.table(), model.id().expect("turtl::sync_model::save_model() -- model.id() is Nooooooooooone"))?;
             match got_model {
@@ -181,6 +232,8 @@ pub fn save_model<T>(action: SyncAction, turtl: &Turtl, model: &mut T, skip_remo
                         }
                         None => {}
                     }
+                    conflict::save_base(db, &db_model)?;
+                    conflict::bump_version(model, Some(&db_model))?;
                 },
                 None => (),
             }
@@ -248,6 +301,21 @@ pub fn delete_model<T>(turtl: &Turtl, id: &String, skip_remote_sync: bool) -> TR
     Ok(())
 }
 
+/// Grab whether (and when) the stored copy of an item was put in the trash
+fn trashed_state<T>(turtl: &Turtl, id: Option<&String>) -> TResult<Option<i64>>
+    where T: Protected + Storable
+{
+    let id = match id {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let stored: Option<T> = with_db!{ db, turtl.db, db.get(T::tablename(), id)? };
+    match stored {
+        Some(x) => Ok(jedi::get_opt(&["trashed"], &x.data_for_storage()?).unwrap_or(None)),
+        None => Ok(None),
+    }
+}
+
 /// Given a sync record, dispatch it into the sync system, calling the
 /// appropriate functions and running any permissions checks.
 pub fn dispatch(turtl: &Turtl, sync_record: SyncRecord) -> TResult<Value> {
@@ -314,14 +382,27 @@ pub fn dispatch(turtl: &Turtl, sync_record: SyncRecord) -> TResult<Value> {
                     if action == SyncAction::Add {
                         model.user_id = turtl.user_id()?;
                     }
+                    // things only go in/out of the trash via the trash
+                    model.trashed = trashed_state::<Board>(turtl, model.id())?;
                     save_model(action, turtl, &mut model, false)?
                 }
                 SyncType::Note => {
-                    let filemebbe: Option<FileData> = jedi::get_opt(&["file", "filedata"], &modeldata);
-                    match jedi::remove(&["file", "filedata"], &mut modeldata) {
-                        Ok(_) => {}
-                        Err(_) => {}
-                    }
+                    // grab the attachments the note has now, so we know what
+                    // the UI is adding/removing
+                    let current: Option<Vec<Attachment>> = match action {
+                        SyncAction::Edit => {
+                            let note_id: String = jedi::get(&["id"], &modeldata)?;
+                            let mut notes = turtl.load_notes(&vec![note_id])?;
+                            let attachments = notes.iter_mut().next().and_then(|x| x.attachments.take());
+                            for note in notes.iter_mut() { note.wipe(); }
+                            attachments
+                        }
+                        _ => None,
+                    };
+                    let had_list = current.is_some();
+                    let current = current.unwrap_or(Vec::new());
+                    let uploads = file::take_uploads(&mut modeldata, &current)?;
+                    let given: Option<Vec<Attachment>> = jedi::get_opt(&["attachments"], &modeldata);
                     let mut note: Note = jedi::from_val(modeldata)?;
                     let permission = match &action {
                         &SyncAction::Add => Permission::AddNote,
@@ -332,20 +413,51 @@ pub fn dispatch(turtl: &Turtl, sync_record: SyncRecord) -> TResult<Value> {
                     if action == SyncAction::Add {
                         note.user_id = turtl.user_id()?;
                     }
+                    note.trashed = trashed_state::<Note>(turtl, note.id())?;
                     // always set to false. this is a public field that
                     // we let the server manage for us
                     note.has_file = false;
                     let now = time::get_time();
                     note.mod_ = Some(now.sec as i64);
+                    let new_ids = uploads.iter().map(|x| x.0.clone()).collect::<Vec<_>>();
+                    let given_list = given.is_some();
+                    let (attachments, removed) = file::merge_attachments(given, current, &new_ids)?;
+                    // notes that have never had a list (ie, still on the
+                    // single-file layout) keep not having one
+                    note.attachments = if had_list || given_list { Some(attachments) } else { None };
+                    if let Some(ref mut items) = note.items {
+                        checklist::assign_ids(items)?;
+                    }
                     let note_data = save_model(action, turtl, &mut note, false)?;
-                    match filemebbe {
-                        Some(mut file) => {
-                            file.save(turtl, &mut note)?;
+                    for (id, mut filedata) in uploads {
+                        let attachment = note.attachments.as_ref()
+                            .and_then(|x| x.iter().find(|a| a.id.as_ref() == Some(&id)))
+                            .cloned();
+                        if let Some(attachment) = attachment {
+                            filedata.save(turtl, &note, &attachment)?;
                         }
-                        None => {}
+                    }
+                    let note_id = note.id_or_else()?;
+                    for attachment in removed {
+                        FileData::delete_file(turtl, &note_id, &attachment.id_or_else()?)?;
                     }
                     note_data
                 }
+                SyncType::Template => {
+                    let mut model: Template = jedi::from_val(modeldata)?;
+                    let permission = match &action {
+                        &SyncAction::Add => Permission::AddBoard,
+                        &SyncAction::Edit => Permission::EditBoard,
+                        _ => return TErr!(TError::BadValue(format!("couldn't find permission for {:?}/{:?}", ty, action))),
+                    };
+                    // templates shape a space much like boards do, so they
+                    // go by the same permissions
+                    Space::permission_check(turtl, &model.space_id, &permission)?;
+                    if action == SyncAction::Add {
+                        model.user_id = turtl.user_id()?;
+                    }
+                    save_model(action, turtl, &mut model, false)?
+                }
                 _ => {
                     return TErr!(TError::BadValue(format!("cannot direct sync an item of type {:?}", ty)));
                 }
@@ -375,17 +487,32 @@ pub fn dispatch(turtl: &Turtl, sync_record: SyncRecord) -> TResult<Value> {
                 SyncType::Board => {
                     let model = get_model::<Board>(turtl, &id)?;
                     Space::permission_check(turtl, &model.space_id, &Permission::DeleteBoard)?;
-                    delete_model::<Board>(turtl, &id, false)?;
+                    if trash::enabled() && model.trashed.is_none() {
+                        trash::trash_board(turtl, &id)?;
+                    } else {
+                        delete_model::<Board>(turtl, &id, false)?;
+                    }
                 }
                 SyncType::Note => {
                     let model = get_model::<Note>(turtl, &id)?;
                     Space::permission_check(turtl, &model.space_id, &Permission::DeleteNote)?;
-                    delete_model::<Note>(turtl, &id, false)?;
+                    if trash::enabled() && model.trashed.is_none() {
+                        trash::trash_note(turtl, &id)?;
+                    } else {
+                        delete_model::<Note>(turtl, &id, false)?;
+                    }
+                }
+                SyncType::Template => {
+                    let model = get_model::<Template>(turtl, &id)?;
+                    Space::permission_check(turtl, &model.space_id, &Permission::DeleteBoard)?;
+                    delete_model::<Template>(turtl, &id, false)?;
                 }
                 SyncType::File => {
-                    let model = get_model::<Note>(turtl, &id)?;
-                    Space::permission_check(turtl, &model.space_id, &Permission::EditNote)?;
-                    delete_model::<FileData>(turtl, &id, false)?;
+                    // older UIs delete a note's (one) file by the note's id
+                    match jedi::get_opt::<String>(&["note_id"], &modeldata) {
+                        Some(note_id) => file::remove(turtl, &note_id, Some(&id))?,
+                        None => file::remove(turtl, &id, None)?,
+                    }
                 }
                 _ => {
                     return TErr!(TError::BadValue(format!("cannot direct sync an item of type {:?}", ty)));
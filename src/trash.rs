@@ -0,0 +1,246 @@
+//! The trash gives deleted notes and boards a second chance. Deleting a note
+//! or board (from the UI) marks it as trashed instead of deleting it, which
+//! hides it from searches (and trashed boards from the profile) but leaves it
+//! in place. Items stay in the trash for `trash.retention` days, after which
+//! they're purged: we run the real delete, which syncs out like always.
+//!
+//! Trashing a board trashes its notes along with it, and restoring the board
+//! brings them back.
+//!
+//! Deleting something that's already in the trash deletes it for good, as does
+//! deleting a space (spaces have members, and it's up to the owner to decide
+//! when everyone loses access). Setting `trash.retention` to 0 skips the trash
+//! entirely.
+//!
+//! Expired items get purged when sync starts, and then every `PURGE_INTERVAL`
+//! for as long as we're logged in (see `Purger`).
+
+use ::std::sync::Arc;
+use ::std::sync::atomic::{AtomicBool, Ordering};
+use ::std::thread;
+use ::jedi::Value;
+use ::error::{TResult, TError};
+use ::config;
+use ::turtl::Turtl;
+use ::models::model::Model;
+use ::models::protected::Protected;
+use ::models::space::Space;
+use ::models::board::Board;
+use ::models::note::Note;
+use ::models::sync_record::{SyncAction, SyncType};
+use ::sync::sync_model;
+use ::lib_permissions::Permission;
+use ::messaging;
+use ::util;
+use ::time;
+
+/// How often (in seconds) we purge expired items while the app is open
+const PURGE_INTERVAL: u64 = 60 * 60;
+
+/// How long (in days) things stay in the trash
+fn retention() -> i64 {
+    config::get(&["trash", "retention"]).unwrap_or(30)
+}
+
+/// Whether or not deletes go to the trash
+pub fn enabled() -> bool {
+    retention() > 0
+}
+
+fn now() -> i64 {
+    time::get_time().sec as i64
+}
+
+/// Grab a (decrypted) board from the profile
+fn load_board(turtl: &Turtl, board_id: &String) -> TResult<Board> {
+    let profile_guard = lockr!(turtl.profile);
+    match profile_guard.boards.iter().find(|x| x.id() == Some(board_id)) {
+        Some(board) => board.clone(),
+        None => TErr!(TError::NotFound(format!("board {} not found", board_id))),
+    }
+}
+
+/// Grab the (stored) notes in a board
+fn board_notes(turtl: &Turtl, board_id: &String) -> TResult<Vec<Note>> {
+    let notes: Vec<Note> = with_db!{ db, turtl.db, db.find("notes", "board_id", &vec![board_id.clone()])? };
+    Ok(notes)
+}
+
+/// Save a note as (un)trashed
+fn set_note_trashed(turtl: &Turtl, note_id: &String, trashed: Option<i64>) -> TResult<()> {
+    let mut notes = turtl.load_notes(&vec![note_id.clone()])?;
+    if notes.len() == 0 {
+        return TErr!(TError::NotFound(format!("note {} not found", note_id)));
+    }
+    let note = &mut notes[0];
+    note.trashed = trashed;
+    sync_model::save_model(SyncAction::Edit, turtl, note, false)?;
+    note.wipe();
+    Ok(())
+}
+
+/// Put a note in the trash
+pub fn trash_note(turtl: &Turtl, note_id: &String) -> TResult<()> {
+    set_note_trashed(turtl, note_id, Some(now()))
+}
+
+/// Put a board (and its notes) in the trash
+pub fn trash_board(turtl: &Turtl, board_id: &String) -> TResult<()> {
+    let trashed = now();
+    let mut board = load_board(turtl, board_id)?;
+    board.trashed = Some(trashed);
+    sync_model::save_model(SyncAction::Edit, turtl, &mut board, false)?;
+    for note in board_notes(turtl, board_id)? {
+        if note.trashed.is_some() { continue; }
+        set_note_trashed(turtl, &note.id_or_else()?, Some(trashed))?;
+    }
+    Ok(())
+}
+
+/// Take an item out of the trash. Restoring a board restores the notes that
+/// were trashed along with it. Does nothing if the item isn't in the trash.
+pub fn restore(turtl: &Turtl, ty: &SyncType, item_id: &String) -> TResult<()> {
+    match *ty {
+        SyncType::Board => {
+            let mut board = load_board(turtl, item_id)?;
+            let trashed = match board.trashed {
+                Some(x) => x,
+                None => return Ok(()),
+            };
+            Space::permission_check(turtl, &board.space_id, &Permission::EditBoard)?;
+            board.trashed = None;
+            sync_model::save_model(SyncAction::Edit, turtl, &mut board, false)?;
+            for note in board_notes(turtl, item_id)? {
+                if note.trashed != Some(trashed) { continue; }
+                set_note_trashed(turtl, &note.id_or_else()?, None)?;
+            }
+        }
+        SyncType::Note => {
+            let mut notes = turtl.load_notes(&vec![item_id.clone()])?;
+            if notes.len() == 0 {
+                return TErr!(TError::NotFound(format!("note {} not found", item_id)));
+            }
+            let note = &mut notes[0];
+            if note.trashed.is_none() { return Ok(()); }
+            Space::permission_check(turtl, &note.space_id, &Permission::EditNote)?;
+            // if the note's board is still in the trash, the note comes out
+            // on its own
+            let board_trashed = match note.board_id {
+                Some(ref board_id) => load_board(turtl, board_id).map(|x| x.trashed.is_some()).unwrap_or(false),
+                None => false,
+            };
+            if board_trashed { note.board_id = None; }
+            note.trashed = None;
+            sync_model::save_model(SyncAction::Edit, turtl, note, false)?;
+            note.wipe();
+        }
+        _ => return TErr!(TError::BadValue(format!("{:?} items don't go in the trash", ty))),
+    }
+    Ok(())
+}
+
+/// Grab what's in the trash, oldest first, as (boards, note ids)
+fn trashed(turtl: &Turtl) -> TResult<(Vec<Board>, Vec<String>)> {
+    let mut boards = {
+        let profile_guard = lockr!(turtl.profile);
+        let mut boards = Vec::new();
+        for board in profile_guard.boards.iter().filter(|x| x.trashed.is_some()) {
+            boards.push(board.clone()?);
+        }
+        boards
+    };
+    let notes: Vec<Note> = with_db!{ db, turtl.db, db.all("notes")? };
+    let mut notes = notes.into_iter()
+        .filter(|x| x.trashed.is_some())
+        .collect::<Vec<_>>();
+    notes.sort_by_key(|x| x.trashed);
+    boards.sort_by_key(|x| x.trashed);
+    let note_ids = notes.iter()
+        .filter_map(|x| x.id().map(|id| id.clone()))
+        .collect::<Vec<_>>();
+    Ok((boards, note_ids))
+}
+
+/// List what's in the trash, newest first
+pub fn list(turtl: &Turtl) -> TResult<Value> {
+    let (mut boards, note_ids) = trashed(turtl)?;
+    boards.reverse();
+    let mut notes = turtl.load_notes(&note_ids)?;
+    notes.reverse();
+    let val = json!({
+        "boards": boards,
+        "notes": notes,
+    });
+    for note in notes.iter_mut() { note.wipe(); }
+    Ok(val)
+}
+
+/// Delete everything in the trash that was trashed before `cutoff` for good
+fn purge(turtl: &Turtl, cutoff: i64) -> TResult<()> {
+    let (boards, note_ids) = trashed(turtl)?;
+    // deleting a board takes its notes with it, so do boards first
+    for board in boards {
+        if board.trashed.unwrap_or(0) >= cutoff { continue; }
+        let board_id = board.id_or_else()?;
+        if Space::permission_check(turtl, &board.space_id, &Permission::DeleteBoard).is_err() { continue; }
+        sync_model::delete_model::<Board>(turtl, &board_id, false)?;
+    }
+    let notes: Vec<Note> = with_db!{ db, turtl.db, db.by_id("notes", &note_ids)? };
+    for note in notes {
+        if note.trashed.unwrap_or(0) >= cutoff { continue; }
+        let note_id = note.id_or_else()?;
+        if Space::permission_check(turtl, &note.space_id, &Permission::DeleteNote).is_err() { continue; }
+        sync_model::delete_model::<Note>(turtl, &note_id, false)?;
+    }
+    Ok(())
+}
+
+/// Delete everything in the trash for good
+pub fn empty(turtl: &Turtl) -> TResult<()> {
+    purge(turtl, i64::max_value())
+}
+
+/// Delete anything that's been in the trash longer than `trash.retention`
+pub fn purge_expired(turtl: &Turtl) -> TResult<()> {
+    let retention = retention();
+    if retention <= 0 { return Ok(()); }
+    purge(turtl, now() - (retention * 86400))
+}
+
+/// Purges expired items every `PURGE_INTERVAL` while we're logged in, so an
+/// app that stays open for days still empties out old trash. Stops when
+/// dropped.
+pub struct Purger {
+    alive: Arc<AtomicBool>,
+}
+
+impl Purger {
+    /// Start the purge timer. The first purge happens on sync start, so we
+    /// wait an interval before sending our first `trash:purge` event.
+    pub fn start() -> TResult<Purger> {
+        let alive = Arc::new(AtomicBool::new(true));
+        let alive2 = alive.clone();
+        thread::Builder::new().name(String::from("trash-purge")).spawn(move || {
+            loop {
+                // sleep in small slices so we notice if we're stopped
+                for _ in 0..PURGE_INTERVAL {
+                    if !alive2.load(Ordering::SeqCst) { break; }
+                    util::sleep(1000);
+                }
+                if !alive2.load(Ordering::SeqCst) { break; }
+                messaging::app_event("trash:purge", &())
+                    .unwrap_or_else(|e| error!("trash::Purger -- error sending purge event: {}", e));
+            }
+            info!("trash::Purger -- shut down");
+        })?;
+        Ok(Purger {
+            alive: alive,
+        })
+    }
+}
+
+impl Drop for Purger {
+    fn drop(&mut self) {
+        self.alive.store(false, Ordering::SeqCst);
+    }
+}
@@ -24,12 +24,15 @@ use ::models::board::Board;
 use ::models::invite::Invite;
 use ::models::keychain::KeychainEntry;
 use ::models::note::Note;
-use ::models::file::FileData;
+use ::models::file::{self, FileData};
 use ::models::sync_record::{SyncRecord, SyncAction};
 use ::messaging::{self, Messenger, Response};
 use ::sync::{self, SyncConfig, SyncState};
 use ::sync::sync_model::MemorySaver;
+use ::sync::status::SyncStatus;
 use ::search::Search;
+use ::trash;
+use ::reminders;
 use ::schema;
 use ::migrate::{self, MigrateResult};
 use ::std::collections::HashMap;
@@ -95,6 +98,15 @@ pub struct Turtl {
     pub incoming_sync_lock: Mutex<()>,
     /// Whether or not we're connected to the API
     pub connected: RwLock<bool>,
+    /// Files the UI is reading in pieces, by handle id (see
+    /// `models::file::open_handle()`)
+    pub file_handles: Mutex<HashMap<String, file::FileHandle>>,
+    /// Checks for notes' reminders while we're logged in/syncing (see
+    /// `reminders`)
+    pub reminders: Mutex<Option<reminders::Scheduler>>,
+    /// Purges expired items from the trash while we're logged in/syncing (see
+    /// `trash`)
+    pub trash_purger: Mutex<Option<trash::Purger>>,
 }
 
 impl Turtl {
@@ -122,6 +134,9 @@ impl Turtl {
             sync_state: Arc::new(RwLock::new(None)),
             connected: RwLock::new(false),
             incoming_sync_lock: Mutex::new(()),
+            file_handles: Mutex::new(HashMap::new()),
+            reminders: Mutex::new(None),
+            trash_purger: Mutex::new(None),
         };
         Ok(turtl)
     }
@@ -140,16 +155,21 @@ impl Turtl {
         }
     }
 
-    /// Send a success response to a remote request
+    /// Send a success response to a remote request. Once the response is
+    /// serialized, the data it was built from is wiped, since it often holds
+    /// decrypted profile data. Note that the serialized message itself is not:
+    /// it's handed off to the UI, and is the UI's to deal with from there.
     pub fn msg_success(&self, mid: &String, data: Value) -> TResult<()> {
         let reqres_append_mid: bool = config::get(&["messaging", "reqres_append_mid"])?;
         if reqres_append_mid {
-            let res = Response::new(0, data);
+            let mut res = Response::new(0, data);
             let msg = jedi::stringify(&res)?;
+            util::wipe_value(&mut res.d);
             self.remote_send(Some(mid.clone()), msg)
         } else {
-            let res = Response::new_w_id(mid.clone(), 0, data);
+            let mut res = Response::new_w_id(mid.clone(), 0, data);
             let msg = jedi::stringify(&res)?;
+            util::wipe_value(&mut res.d);
             self.remote_send(None, msg)
         }
     }
@@ -233,10 +253,12 @@ impl Turtl {
         self.post_login()
     }
 
-    /// Log a user in using a login token
-    pub fn login_token(&self, token: String) -> TResult<()> {
-        User::login_token(self, token)?;
-        self.post_login()
+    /// Log a user in using a login token. Returns true if the token was an
+    /// old-style one that should be replaced (see `User::login_token()`).
+    pub fn login_token(&self, token: String) -> TResult<bool> {
+        let legacy = User::login_token(self, token)?;
+        self.post_login()?;
+        Ok(legacy)
     }
 
     /// DO Create a new user account
@@ -281,6 +303,7 @@ impl Turtl {
             profile_guard.wipe();
             *profile_guard = Profile::new();
         }
+        lock!(self.file_handles).clear();
         self.sync_shutdown(false)?;
         self.close_user_db()?;
         self.close_search();
@@ -311,6 +334,18 @@ impl Turtl {
         Ok(())
     }
 
+    /// Reset a user's password using their recovery key. Once the API has the
+    /// re-encrypted data, any local data we have for the user is stale (it's
+    /// encrypted with the old master key) so we log in briefly to find it and
+    /// wipe it. The user is left logged out.
+    pub fn recover_account(&self, username: String, recovery_secret: String, new_password: String) -> TResult<()> {
+        User::recover(self, username.clone(), recovery_secret, new_password.clone())?;
+        User::login(self, username, new_password, user::CURRENT_AUTH_VERSION)?;
+        self.set_user_id();
+        self.wipe_user_data()?;
+        Ok(())
+    }
+
     /// Delete the current user's account (if they are logged in derr)
     pub fn delete_account(&self) -> TResult<()> {
         self.assert_connected()?;
@@ -397,11 +432,35 @@ impl Turtl {
         // let your freak flag fly, incoming syncs
         drop(sync_lock);
 
+        // bring any notes from the single-file days over to attachments
+        match file::migrate_legacy(self) {
+            Ok(_) => {}
+            Err(e) => warn!("Turtl.sync_start() -- problem migrating note files: {}", e),
+        }
+
+        // clean out anything that's been in the trash too long
+        match trash::purge_expired(self) {
+            Ok(_) => {}
+            Err(e) => warn!("Turtl.sync_start() -- problem purging the trash: {}", e),
+        }
+        match trash::Purger::start() {
+            Ok(x) => *lock!(self.trash_purger) = Some(x),
+            Err(e) => warn!("Turtl.sync_start() -- problem starting the trash purger: {}", e),
+        }
+
+        // now that our notes are indexed, we know when their reminders are
+        match reminders::Scheduler::start() {
+            Ok(x) => *lock!(self.reminders) = Some(x),
+            Err(e) => warn!("Turtl.sync_start() -- problem starting reminders: {}", e),
+        }
+
         Ok(())
     }
 
     /// Shut down the sync system
     pub fn sync_shutdown(&self, join: bool) -> TResult<()> {
+        lock!(self.reminders).take();
+        lock!(self.trash_purger).take();
         let mut guard = lockw!(self.sync_state);
         info!("turtl.sync_shutdown() -- has state? {}", guard.is_some());
         if guard.is_none() { return Ok(()); }
@@ -452,6 +511,17 @@ impl Turtl {
         }
     }
 
+    /// Gather up everything the UI might want to know about the state of
+    /// syncing: what each syncer is up to, what's waiting to go out, and any
+    /// transfers in progress.
+    pub fn sync_status(&self) -> TResult<SyncStatus> {
+        let pending = with_db!{ db, self.db,
+            SyncRecord::find(db, None)
+        }?;
+        let sync_config_guard = lockr!(self.sync_config);
+        Ok(SyncStatus::new(self.sync_running(), &sync_config_guard, &pending))
+    }
+
     /// Returns whether or not syncing has been initialized (ie, sync_start has
     /// been called). Basically just tests for the presence of sync_state.
     pub fn sync_ready(&self) -> bool {
@@ -773,8 +843,8 @@ impl Turtl {
             info!("turtl.wipe_app_data() -- removing {}", path.display());
         }
 
-        // wipe all note files
-        let files = FileData::file_finder_all(None, None)?;
+        // wipe all note files (and partial downloads)
+        let files = FileData::local_files(None, None)?;
         for file in files {
             fs::remove_file(&file)?;
             info!("turtl.wipe_app_data() -- removing {}", file.display());
@@ -797,7 +867,7 @@ impl Turtl {
             fs::remove_file(&db_loc)?;
         }
 
-        let files = FileData::file_finder_all(Some(&user_id), None)?;
+        let files = FileData::local_files(Some(&user_id), None)?;
         for file in files {
             fs::remove_file(&file)?;
             info!("turtl.wipe_user_data() -- removing {}", file.display());
@@ -0,0 +1,199 @@
+//! Pulls the text out of attached files (plain text, Markdown, HTML, and the
+//! text layer of PDFs) so search can find what's inside them, not just their
+//! names. Extractors are pluggable: anything implementing `Extractor` can be
+//! added via `register()`.
+
+use ::std::sync::RwLock;
+use ::std::panic;
+use ::regex::Regex;
+use ::pdf_extract;
+use ::error::{TResult, TError};
+use ::util;
+
+/// The most text (in bytes) we keep from any one file. This ends up in the
+/// note, so we don't want a 500 page PDF in there.
+pub const MAX_TEXT: usize = 256 * 1024;
+
+/// Something that knows how to get the text out of a type of file
+pub trait Extractor: Send + Sync {
+    /// Whether we handle files with the given mime type/extension (both
+    /// lowercase, either can be "")
+    fn handles(&self, ty: &str, ext: &str) -> bool;
+
+    /// Get the text out of a file
+    fn extract(&self, data: &[u8]) -> TResult<String>;
+}
+
+/// Text files are already text
+pub struct PlainText;
+
+impl Extractor for PlainText {
+    fn handles(&self, ty: &str, ext: &str) -> bool {
+        ty.starts_with("text/") || ext == "txt" || ext == "log" || ext == "csv"
+    }
+
+    fn extract(&self, data: &[u8]) -> TResult<String> {
+        util::decode_text(data)
+    }
+}
+
+/// Markdown, minus the markup
+pub struct Markdown;
+
+impl Extractor for Markdown {
+    fn handles(&self, ty: &str, ext: &str) -> bool {
+        ty == "text/markdown" || ty == "text/x-markdown" || ext == "md" || ext == "markdown"
+    }
+
+    fn extract(&self, data: &[u8]) -> TResult<String> {
+        lazy_static! {
+            static ref RE_IMAGE_LINK: Regex = Regex::new(r"!?\[([^\]]*)\]\(([^)]*)\)").expect("turtl::extract::Markdown -- failed to compile regex");
+            static ref RE_LINE_START: Regex = Regex::new(r"(?m)^[ \t]*(#+|>+|[-*+]|\d+\.)[ \t]+").expect("turtl::extract::Markdown -- failed to compile regex");
+            static ref RE_EMPHASIS: Regex = Regex::new(r"[*_`~]+").expect("turtl::extract::Markdown -- failed to compile regex");
+        }
+        let text = util::decode_text(data)?;
+        let text = RE_IMAGE_LINK.replace_all(&text, "$1 $2");
+        let text = RE_LINE_START.replace_all(&text, "");
+        Ok(RE_EMPHASIS.replace_all(&text, ""))
+    }
+}
+
+/// HTML, minus the tags (and scripts/styles)
+pub struct Html;
+
+impl Extractor for Html {
+    fn handles(&self, ty: &str, ext: &str) -> bool {
+        ty == "text/html" || ty == "application/xhtml+xml" || ext == "html" || ext == "htm"
+    }
+
+    fn extract(&self, data: &[u8]) -> TResult<String> {
+        lazy_static! {
+            static ref RE_SCRIPT: Regex = Regex::new(r"(?is)<(script|style)[^>]*>.*?</(script|style)\s*>").expect("turtl::extract::Html -- failed to compile regex");
+            static ref RE_TAG: Regex = Regex::new(r"(?s)<[^>]*>").expect("turtl::extract::Html -- failed to compile regex");
+            static ref RE_SPACE: Regex = Regex::new(r"\s+").expect("turtl::extract::Html -- failed to compile regex");
+        }
+        let html = util::decode_text(data)?;
+        let text = RE_SCRIPT.replace_all(&html, " ");
+        let text = RE_TAG.replace_all(&text, " ");
+        let text = text
+            .replace("&nbsp;", " ")
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&#39;", "'")
+            .replace("&amp;", "&");
+        Ok(String::from(RE_SPACE.replace_all(&text, " ").trim()))
+    }
+}
+
+/// The text layer of a PDF (scanned PDFs without one give us nothing)
+pub struct Pdf;
+
+impl Extractor for Pdf {
+    fn handles(&self, ty: &str, ext: &str) -> bool {
+        ty == "application/pdf" || ext == "pdf"
+    }
+
+    fn extract(&self, data: &[u8]) -> TResult<String> {
+        // pdf_extract panics on some malformed files, so don't let it take us
+        // down with it
+        let data = Vec::from(data);
+        match panic::catch_unwind(move || pdf_extract::extract_text_from_mem(&data)) {
+            Ok(Ok(text)) => Ok(text),
+            Ok(Err(e)) => TErr!(TError::BadValue(format!("problem reading PDF: {:?}", e))),
+            Err(_) => TErr!(TError::BadValue(String::from("problem reading PDF: extractor panicked"))),
+        }
+    }
+}
+
+lazy_static! {
+    /// Our extractors. The first one that handles a file wins.
+    static ref EXTRACTORS: RwLock<Vec<Box<dyn Extractor>>> = RwLock::new(vec![
+        Box::new(Markdown),
+        Box::new(Html),
+        Box::new(Pdf),
+        Box::new(PlainText),
+    ]);
+}
+
+/// Add an extractor. It gets first crack at any file it handles.
+pub fn register(extractor: Box<dyn Extractor>) {
+    lockw!(EXTRACTORS).insert(0, extractor);
+}
+
+/// Lowercase a file's mime type and grab its (lowercase) extension
+fn normalize(ty: Option<&String>, name: Option<&String>) -> (String, String) {
+    let ty = ty.map(|x| x.to_lowercase()).unwrap_or(String::new());
+    let ext = name
+        .and_then(|x| x.rsplit('.').next().filter(|ext| ext.len() < x.len()).map(|ext| ext.to_lowercase()))
+        .unwrap_or(String::new());
+    (ty, ext)
+}
+
+/// Whether we know how to get the text out of a file
+pub fn can_extract(ty: Option<&String>, name: Option<&String>) -> bool {
+    let (ty, ext) = normalize(ty, name);
+    lockr!(EXTRACTORS).iter().any(|x| x.handles(&ty, &ext))
+}
+
+/// Get the text out of a file, given its mime type and/or name. Returns None if
+/// we don't know how. The text is cut off at `MAX_TEXT` bytes.
+pub fn extract(ty: Option<&String>, name: Option<&String>, data: &[u8]) -> TResult<Option<String>> {
+    let (ty, ext) = normalize(ty, name);
+    let mut text = {
+        let extractors = lockr!(EXTRACTORS);
+        let extractor = match extractors.iter().find(|x| x.handles(&ty, &ext)) {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        extractor.extract(data)?
+    };
+    if text.len() > MAX_TEXT {
+        let mut end = MAX_TEXT;
+        while !text.is_char_boundary(end) { end -= 1; }
+        text.truncate(end);
+    }
+    Ok(Some(text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(ty: &str, name: &str, data: &str) -> Option<String> {
+        extract(Some(&String::from(ty)), Some(&String::from(name)), data.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn extracts_text() {
+        assert_eq!(run("text/plain", "notes.txt", "get a job, sir"), Some(String::from("get a job, sir")));
+        assert_eq!(run("", "NOTES.TXT", "hi"), Some(String::from("hi")));
+        assert_eq!(run("image/png", "larry.png", "hi"), None);
+        assert_eq!(run("", "README", "hi"), None);
+        assert!(can_extract(None, Some(&String::from("manual.PDF"))));
+        assert!(!can_extract(Some(&String::from("application/zip")), None));
+    }
+
+    #[test]
+    fn extracts_markdown() {
+        let md = "# Shopping\n\n* **eggs** and _ham_\n1. see [the list](https://turtlapp.com)\n> `quoted`";
+        assert_eq!(
+            run("text/markdown", "list.md", md),
+            Some(String::from("Shopping\n\neggs and ham\nsee the list https://turtlapp.com\nquoted"))
+        );
+    }
+
+    #[test]
+    fn extracts_html() {
+        let html = "<html><head><style>p { color: red; }</style><script>alert('hi');</script></head><body><p>Tom &amp; Jerry</p><br/>say&nbsp;&quot;hi&quot;</body></html>";
+        assert_eq!(run("text/html", "page.html", html), Some(String::from("Tom & Jerry say \"hi\"")));
+    }
+
+    #[test]
+    fn truncates_text() {
+        let big = "ü".repeat(MAX_TEXT);
+        let text = run("text/plain", "big.txt", &big).unwrap();
+        assert!(text.len() <= MAX_TEXT);
+        assert!(text.chars().all(|x| x == 'ü'));
+    }
+}
@@ -0,0 +1,196 @@
+//! A small line-based three-way merge, used to reconcile a local edit and a
+//! remote edit made to the same piece of text (ie, two devices editing a note
+//! while offline). Also does plain two-way diffs (ie, between two revisions of
+//! a note).
+
+/// Past this many lines (base lines * edited lines) we don't bother diffing
+/// and just call it a conflict. Keeps us from eating all the memory in the
+/// world on giant notes.
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// Find the longest common subsequence between two sets of lines, returned as
+/// (a index, b index) pairs in order. Returns None if the inputs are too large
+/// to diff.
+fn lcs(a: &[&str], b: &[&str]) -> Option<Vec<(usize, usize)>> {
+    if a.len().saturating_mul(b.len()) > MAX_DIFF_CELLS { return None; }
+    let width = b.len() + 1;
+    let mut table = vec![0usize; (a.len() + 1) * width];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            table[i * width + j] = if a[i] == b[j] {
+                table[(i + 1) * width + j + 1] + 1
+            } else {
+                ::std::cmp::max(table[(i + 1) * width + j], table[i * width + j + 1])
+            };
+        }
+    }
+    let mut matches = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            matches.push((i, j));
+            i += 1;
+            j += 1;
+        } else if table[(i + 1) * width + j] >= table[i * width + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    Some(matches)
+}
+
+/// What happened to a line between two versions of some text
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub enum DiffOp {
+    #[serde(rename = "same")]
+    Same,
+    #[serde(rename = "add")]
+    Add,
+    #[serde(rename = "remove")]
+    Remove,
+}
+
+/// One line of a diff
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DiffLine {
+    pub op: DiffOp,
+    pub line: String,
+}
+
+impl DiffLine {
+    fn new(op: DiffOp, line: &str) -> Self {
+        DiffLine { op: op, line: String::from(line) }
+    }
+}
+
+/// Diff two versions of some text, line by line. Returns None if the text is
+/// too large to diff.
+pub fn diff(old: &str, new: &str) -> Option<Vec<DiffLine>> {
+    let old_lines = old.split('\n').collect::<Vec<_>>();
+    let new_lines = new.split('\n').collect::<Vec<_>>();
+    let matches = lcs(&old_lines, &new_lines)?;
+
+    let mut lines = Vec::with_capacity(::std::cmp::max(old_lines.len(), new_lines.len()));
+    let (mut old_idx, mut new_idx) = (0, 0);
+    // tack on an anchor past the end so we pick up any trailing changes
+    for (old_anchor, new_anchor) in matches.into_iter().chain(Some((old_lines.len(), new_lines.len()))) {
+        for line in &old_lines[old_idx..old_anchor] { lines.push(DiffLine::new(DiffOp::Remove, line)); }
+        for line in &new_lines[new_idx..new_anchor] { lines.push(DiffLine::new(DiffOp::Add, line)); }
+        if old_anchor < old_lines.len() {
+            lines.push(DiffLine::new(DiffOp::Same, old_lines[old_anchor]));
+        }
+        old_idx = old_anchor + 1;
+        new_idx = new_anchor + 1;
+    }
+    Some(lines)
+}
+
+/// Merge two edits (`ours` and `theirs`) of the same `base` text. Changes that
+/// touch different lines are combined; if both sides changed the same lines
+/// differently, we can't merge and return None.
+pub fn merge3(base: &str, ours: &str, theirs: &str) -> Option<String> {
+    if ours == theirs || base == theirs { return Some(String::from(ours)); }
+    if base == ours { return Some(String::from(theirs)); }
+
+    let base_lines = base.split('\n').collect::<Vec<_>>();
+    let our_lines = ours.split('\n').collect::<Vec<_>>();
+    let their_lines = theirs.split('\n').collect::<Vec<_>>();
+
+    // map each base line to its matching line in ours/theirs (if any)
+    let mut in_ours: Vec<Option<usize>> = vec![None; base_lines.len()];
+    let mut in_theirs: Vec<Option<usize>> = vec![None; base_lines.len()];
+    for (b, o) in lcs(&base_lines, &our_lines)? { in_ours[b] = Some(o); }
+    for (b, t) in lcs(&base_lines, &their_lines)? { in_theirs[b] = Some(t); }
+
+    // walk the base lines that survived on both sides. the chunks between
+    // these anchors are where the edits happened.
+    let mut merged: Vec<&str> = Vec::with_capacity(::std::cmp::max(our_lines.len(), their_lines.len()));
+    let (mut base_idx, mut our_idx, mut their_idx) = (0, 0, 0);
+    for i in 0..(base_lines.len() + 1) {
+        let (our_anchor, their_anchor) = if i == base_lines.len() {
+            (our_lines.len(), their_lines.len())
+        } else {
+            match (in_ours[i], in_theirs[i]) {
+                (Some(o), Some(t)) => (o, t),
+                _ => continue,
+            }
+        };
+        let base_chunk = &base_lines[base_idx..i];
+        let our_chunk = &our_lines[our_idx..our_anchor];
+        let their_chunk = &their_lines[their_idx..their_anchor];
+        if our_chunk == base_chunk || our_chunk == their_chunk {
+            merged.extend_from_slice(their_chunk);
+        } else if their_chunk == base_chunk {
+            merged.extend_from_slice(our_chunk);
+        } else {
+            return None;
+        }
+        if i < base_lines.len() { merged.push(base_lines[i]); }
+        base_idx = i + 1;
+        our_idx = our_anchor + 1;
+        their_idx = their_anchor + 1;
+    }
+    Some(merged.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_trivial_edits() {
+        assert_eq!(merge3("hello", "hello", "hello"), Some(String::from("hello")));
+        assert_eq!(merge3("hello", "hello there", "hello"), Some(String::from("hello there")));
+        assert_eq!(merge3("hello", "hello", "hi"), Some(String::from("hi")));
+        assert_eq!(merge3("hello", "hi", "hi"), Some(String::from("hi")));
+        assert_eq!(merge3("hello", "hi", "howdy"), None);
+    }
+
+    #[test]
+    fn merges_edits_to_different_lines() {
+        let base = "get milk\nget eggs\nget bread\ncall mom";
+        let ours = "get milk\nget eggs\nget rye bread\ncall mom";
+        let theirs = "get oat milk\nget eggs\nget bread\ncall mom\nwalk dog";
+        assert_eq!(
+            merge3(base, ours, theirs),
+            Some(String::from("get oat milk\nget eggs\nget rye bread\ncall mom\nwalk dog"))
+        );
+        // removals on one side, additions on the other
+        let ours = "get milk\nget bread\ncall mom";
+        let theirs = "shopping:\nget milk\nget eggs\nget bread\ncall mom";
+        assert_eq!(
+            merge3(base, ours, theirs),
+            Some(String::from("shopping:\nget milk\nget bread\ncall mom"))
+        );
+    }
+
+    #[test]
+    fn diffs_lines() {
+        let old = "get milk\nget eggs\nget bread";
+        let new = "get oat milk\nget eggs\nget bread\ncall mom";
+        let ops = diff(old, new).unwrap()
+            .into_iter()
+            .map(|x| (x.op, x.line))
+            .collect::<Vec<_>>();
+        assert_eq!(ops, vec![
+            (DiffOp::Remove, String::from("get milk")),
+            (DiffOp::Add, String::from("get oat milk")),
+            (DiffOp::Same, String::from("get eggs")),
+            (DiffOp::Same, String::from("get bread")),
+            (DiffOp::Add, String::from("call mom")),
+        ]);
+        assert!(diff("same", "same").unwrap().iter().all(|x| x.op == DiffOp::Same));
+    }
+
+    #[test]
+    fn fails_on_overlapping_edits() {
+        let base = "line one\nline two\nline three";
+        let ours = "line one\nline 2\nline three";
+        let theirs = "line one\nline deux\nline three";
+        assert_eq!(merge3(base, ours, theirs), None);
+        // same edit on both sides is fine, though
+        assert_eq!(merge3(base, ours, ours), Some(String::from(ours)));
+    }
+}
+
@@ -8,6 +8,7 @@ use ::std::fmt::Debug;
 use ::jedi::{self, Value, Serialize};
 use ::config;
 use ::encoding_rs;
+use ::crypto;
 
 macro_rules! do_lock {
     ($lock:expr) => {{
@@ -38,6 +39,9 @@ macro_rules! lockw {
 
 pub mod logger;
 pub mod thredder;
+pub mod merge;
+pub mod extract;
+pub mod thumbnail;
 #[macro_use]
 pub mod ser;
 #[macro_use]
@@ -116,3 +120,26 @@ pub fn decode_text(bytes: &[u8]) -> TResult<String> {
     Err(TError::BadValue(format!("unable to decode bytes to string")))
 }
 
+
+/// Zero out a string's contents in place and then empty it. Used to scrub
+/// decrypted data once we're done with it.
+pub fn wipe_string(string: &mut String) {
+    // zero bytes are valid utf8, so the string stays valid throughout
+    unsafe { crypto::memzero(string.as_mut_vec().as_mut_slice()); }
+    string.clear();
+}
+
+/// Recursively wipe every string value in a JSON value. Object keys are left
+/// alone (they're field names, not data).
+pub fn wipe_value(val: &mut Value) {
+    match *val {
+        Value::String(ref mut x) => wipe_string(x),
+        Value::Array(ref mut arr) => {
+            for x in arr.iter_mut() { wipe_value(x); }
+        }
+        Value::Object(ref mut obj) => {
+            for (_, x) in obj.iter_mut() { wipe_value(x); }
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,112 @@
+//! Makes small previews of image attachments, so the UI can show a list of
+//! image notes without pulling and decrypting every full-sized image.
+
+use ::std::io::Cursor;
+use ::image::{self, DynamicImage, GenericImageView, ImageFormat, ImageOutputFormat};
+use ::error::TResult;
+
+/// Thumbnails are no bigger than this (in pixels) on either side
+pub const THUMBNAIL_SIZE: u32 = 256;
+
+/// The quality we save JPEG thumbnails at
+const JPEG_QUALITY: u8 = 80;
+
+/// We don't decode images bigger than this on either side...
+const MAX_DIMENSION: u32 = 16384;
+
+/// ...or with more pixels than this in total. Attachments come from whoever
+/// shares a space with us, and a tiny file can claim to be a gigantic image.
+const MAX_PIXELS: u64 = 64 * 1024 * 1024;
+
+/// Grab an image's (width, height) by reading its header, or None if this
+/// isn't an image we understand.
+pub fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    image::io::Reader::new(Cursor::new(data))
+        .with_guessed_format().ok()?
+        .into_dimensions().ok()
+}
+
+/// Make a thumbnail from an image. Images with transparency get a PNG, all
+/// others a JPEG. Returns None if this isn't an image we understand (or it's
+/// too big to decode).
+pub fn generate(data: &[u8]) -> TResult<Option<Vec<u8>>> {
+    // check the header before we decode anything
+    match dimensions(data) {
+        Some((width, height)) => {
+            if width > MAX_DIMENSION || height > MAX_DIMENSION || (width as u64) * (height as u64) > MAX_PIXELS {
+                return Ok(None);
+            }
+        }
+        None => return Ok(None),
+    }
+    let reader = match image::io::Reader::new(Cursor::new(data)).with_guessed_format() {
+        Ok(x) => x,
+        Err(_) => return Ok(None),
+    };
+    let img = match reader.decode() {
+        Ok(x) => x,
+        Err(_) => return Ok(None),
+    };
+    let img = if img.width() > THUMBNAIL_SIZE || img.height() > THUMBNAIL_SIZE {
+        img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+    } else {
+        img
+    };
+    let mut thumb = Vec::new();
+    if img.color().has_alpha() {
+        DynamicImage::ImageRgba8(img.to_rgba8()).write_to(&mut thumb, ImageOutputFormat::Png)?;
+    } else {
+        DynamicImage::ImageRgb8(img.to_rgb8()).write_to(&mut thumb, ImageOutputFormat::Jpeg(JPEG_QUALITY))?;
+    }
+    Ok(Some(thumb))
+}
+
+/// Get the mime type of a thumbnail we generated
+pub fn mime_type(thumb: &[u8]) -> &'static str {
+    match image::guess_format(thumb) {
+        Ok(ImageFormat::Png) => "image/png",
+        _ => "image/jpeg",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    fn encode(img: DynamicImage) -> Vec<u8> {
+        let mut data = Vec::new();
+        img.write_to(&mut data, ImageOutputFormat::Png).unwrap();
+        data
+    }
+
+    #[test]
+    fn makes_thumbnails() {
+        let wide = encode(DynamicImage::ImageRgb8(RgbImage::from_pixel(800, 400, Rgb([200, 10, 10]))));
+        assert_eq!(dimensions(&wide), Some((800, 400)));
+        let thumb = generate(&wide).unwrap().unwrap();
+        assert_eq!(mime_type(&thumb), "image/jpeg");
+        assert_eq!(dimensions(&thumb), Some((256, 128)));
+
+        // small images stay small, and keep their transparency
+        let tiny = encode(DynamicImage::ImageRgba8(RgbaImage::from_pixel(20, 30, Rgba([0, 0, 0, 100]))));
+        let thumb = generate(&tiny).unwrap().unwrap();
+        assert_eq!(mime_type(&thumb), "image/png");
+        assert_eq!(dimensions(&thumb), Some((20, 30)));
+    }
+
+    #[test]
+    fn skips_huge_images() {
+        // a 1x(MAX_DIMENSION + 1) image is tiny on disk, but over our limit
+        let tall = encode(DynamicImage::ImageRgb8(RgbImage::from_pixel(1, MAX_DIMENSION + 1, Rgb([0, 0, 0]))));
+        assert_eq!(dimensions(&tall), Some((1, MAX_DIMENSION + 1)));
+        assert_eq!(generate(&tall).unwrap(), None);
+    }
+
+    #[test]
+    fn ignores_non_images() {
+        let text = Vec::from("slappy says hi".as_bytes());
+        assert_eq!(dimensions(&text), None);
+        assert_eq!(generate(&text).unwrap(), None);
+    }
+}